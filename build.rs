@@ -0,0 +1,17 @@
+fn main() {
+    #[cfg(feature = "node")]
+    napi_build::setup();
+
+    #[cfg(feature = "grpc")]
+    {
+        // protoc is vendored so that building doesn't depend on the system one
+        std::env::set_var(
+            "PROTOC",
+            protoc_bin_vendored::protoc_bin_path().expect("vendored protoc is available"),
+        );
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/cephalopod.proto"], &["proto"])
+            .expect("proto files compile");
+    }
+}
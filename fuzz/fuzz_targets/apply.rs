@@ -0,0 +1,74 @@
+//! Feeds arbitrary sequences of transactions into the state
+//!
+//! Transactions may be rejected, but applying them must never panic nor end
+//! with an integrity error. Amounts are bounded, so that they can't overflow.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use cephalopod::amount::Amount;
+use cephalopod::model::{CephalopodError, State, Transaction, TransactionType};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Type {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Representment,
+    Unlock,
+    Transfer,
+    Reversal,
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    tpe: Type,
+    client: u8,
+    tx: u8,
+    amount: Option<i32>,
+    to: Option<u8>,
+}
+
+impl Input {
+    fn transaction(&self) -> Transaction {
+        let tpe = match self.tpe {
+            Type::Deposit => TransactionType::Deposit,
+            Type::Withdrawal => TransactionType::Withdrawal,
+            Type::Dispute => TransactionType::Dispute,
+            Type::Resolve => TransactionType::Resolve,
+            Type::Chargeback => TransactionType::Chargeback,
+            Type::Representment => TransactionType::Representment,
+            Type::Unlock => TransactionType::Unlock,
+            Type::Transfer => TransactionType::Transfer,
+            Type::Reversal => TransactionType::Reversal,
+        };
+        Transaction {
+            tpe,
+            // a few clients and ids, so that transactions often refer to each other
+            client: u16::from(self.client % 8),
+            tx: u32::from(self.tx % 32),
+            amount: self.amount.map(|amount| Amount::new(i64::from(amount), 4)),
+            to: self.to.map(|to| u16::from(to % 8)),
+            reason: None,
+            currency: Default::default(),
+            timestamp: None,
+            tenant: None,
+            tag: Default::default(),
+        }
+    }
+}
+
+fuzz_target!(|inputs: Vec<Input>| {
+    let mut state = State::new();
+    for input in &inputs {
+        let transaction = input.transaction();
+        if let Err(CephalopodError::IntegrityError { error, .. }) =
+            state.apply_transaction(&transaction)
+        {
+            panic!("integrity error {} after {:?}", error, transaction);
+        }
+    }
+});
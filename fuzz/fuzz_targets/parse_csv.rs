@@ -0,0 +1,30 @@
+//! Feeds arbitrary bytes through the path from CSV rows to transactions
+//!
+//! Parsing may reject the input, but must never panic.
+
+#![no_main]
+
+use cephalopod::model::Transaction;
+use cephalopod::schema::{Schema, SchemaVersion};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // read like the input of the command line tool
+    let mut rdr = csv::Reader::from_reader(data);
+    let headers = match rdr.headers() {
+        Ok(headers) => headers.clone(),
+        Err(_) => return,
+    };
+    let version = SchemaVersion::detect(&headers);
+    let schema = Schema::new(&headers, version, true).ok();
+    for record in rdr.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        let result: Result<Transaction, _> = record.deserialize(Some(&headers));
+        if let (Err(_), Some(schema)) = (result, &schema) {
+            schema.check_row(&record);
+        }
+    }
+});
@@ -0,0 +1,42 @@
+/* C interface of the cephalopod engine, built with `cargo build --release --features ffi` */
+
+#ifndef CEPHALOPOD_H
+#define CEPHALOPOD_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Results of cephalopod_apply_transaction_json */
+#define CEPHALOPOD_OK 0
+#define CEPHALOPOD_REJECTED 1
+#define CEPHALOPOD_INTEGRITY_ERROR 2
+#define CEPHALOPOD_INVALID_ARGUMENT -1
+
+typedef struct CephalopodState CephalopodState;
+
+/* Creates an empty state following the policy given as JSON, or the default policy if NULL.
+ * Returns NULL if the policy can't be parsed. */
+CephalopodState *cephalopod_create_state(const char *policy);
+
+/* Releases the state, does nothing if it's NULL. */
+void cephalopod_free_state(CephalopodState *state);
+
+/* Applies the transaction given as JSON (with the same fields as rows of the input),
+ * returning one of the CEPHALOPOD_* codes. Unless the transaction has been applied,
+ * the reason is passed through error (if not NULL), to be released with
+ * cephalopod_free_string. */
+int cephalopod_apply_transaction_json(CephalopodState *state, const char *transaction, char **error);
+
+/* Exports balances of all accounts as a JSON array, to be released with cephalopod_free_string.
+ * Returns NULL if the state is NULL. */
+char *cephalopod_export_accounts_json(const CephalopodState *state);
+
+/* Releases a string returned by the library, does nothing if it's NULL. */
+void cephalopod_free_string(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,191 @@
+//! Per-client activity patterns for the suspicious-activity report and the fraud heuristics
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{State, Transaction, TransactionType};
+
+/// Counts of a client's applied transactions telling anomalous patterns
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientActivity {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    /// Disputes of the client's transactions
+    pub disputes: u64,
+    pub chargebacks: u64,
+    /// Withdrawals following a deposit within the cycle window
+    pub rapid_cycles: u64,
+    /// All applied transactions of the client
+    pub transactions: u64,
+    /// Number of the client's transactions when its account got locked for the first time
+    pub locked_after: Option<u64>,
+    /// Timestamp of the latest deposit, if it had one
+    last_deposit: Option<u64>,
+}
+
+impl ClientActivity {
+    /// Chargebacks per deposit, zero without deposits
+    pub fn chargeback_ratio(&self) -> f64 {
+        if self.deposits == 0 {
+            return 0.0;
+        }
+        self.chargebacks as f64 / self.deposits as f64
+    }
+
+    pub fn heuristics(&self) -> Heuristics {
+        Heuristics {
+            disputes: self.disputes,
+            chargeback_ratio: self.chargeback_ratio(),
+            locked_after: self.locked_after,
+        }
+    }
+}
+
+/// Fraud heuristics of a client, appended to its rows of the accounts export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Heuristics {
+    pub disputes: u64,
+    /// Chargebacks per deposit
+    pub chargeback_ratio: f64,
+    /// Number of the client's transactions when its account got locked, empty if it never was
+    pub locked_after: Option<u64>,
+}
+
+/// Limits of activity beyond which a client is reported
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds {
+    /// Number of disputes
+    pub disputes: u64,
+    /// Chargebacks per deposit
+    pub chargeback_ratio: f64,
+    /// Seconds after a deposit within which a withdrawal makes a rapid cycle
+    pub cycle_window: u64,
+    /// Number of rapid cycles
+    pub rapid_cycles: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            disputes: 3,
+            chargeback_ratio: 0.25,
+            cycle_window: 3600,
+            rapid_cycles: 3,
+        }
+    }
+}
+
+/// Row of the suspicious-activity report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SuspiciousClient {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    /// Thresholds reached, separated with `;`: `disputes`, `chargeback_ratio` or `rapid_cycles`
+    pub reasons: String,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub chargebacks: u64,
+    pub chargeback_ratio: f64,
+    pub rapid_cycles: u64,
+}
+
+/// Activity of all clients, recorded as transactions are applied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Activity {
+    thresholds: Thresholds,
+    clients: BTreeMap<(Option<u32>, u16), ClientActivity>,
+}
+
+impl Activity {
+    pub fn new(thresholds: Thresholds) -> Activity {
+        Activity {
+            thresholds,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    pub fn client(&self, tenant: Option<u32>, client: u16) -> Option<&ClientActivity> {
+        self.clients.get(&(tenant, client))
+    }
+
+    /// Heuristics of the client, all zero if it has no recorded activity
+    pub fn heuristics(&self, tenant: Option<u32>, client: u16) -> Heuristics {
+        self.client(tenant, client)
+            .map(ClientActivity::heuristics)
+            .unwrap_or_default()
+    }
+
+    /// Records a transaction successfully applied to the state
+    pub fn record(&mut self, state: &State, tx: &Transaction) {
+        let window = self.thresholds.cycle_window;
+        let activity = self.clients.entry((tx.tenant, tx.client)).or_default();
+        activity.transactions += 1;
+        let locked = state
+            .account(tx.client)
+            .is_some_and(|account| account.locked);
+        if locked && activity.locked_after.is_none() {
+            activity.locked_after = Some(activity.transactions);
+        }
+        match tx.tpe {
+            TransactionType::Deposit => {
+                activity.deposits += 1;
+                activity.last_deposit = tx.timestamp;
+            }
+            TransactionType::Withdrawal => {
+                activity.withdrawals += 1;
+                if let (Some(deposited), Some(withdrawn)) = (activity.last_deposit, tx.timestamp) {
+                    if withdrawn.saturating_sub(deposited) <= window {
+                        activity.rapid_cycles += 1;
+                        // each deposit starts a single cycle
+                        activity.last_deposit = None;
+                    }
+                }
+            }
+            TransactionType::Dispute => activity.disputes += 1,
+            TransactionType::Chargeback => activity.chargebacks += 1,
+            _ => {}
+        }
+    }
+
+    /// Clients reaching any of the thresholds, ordered by tenant and client
+    pub fn suspicious(&self) -> Vec<SuspiciousClient> {
+        let thresholds = &self.thresholds;
+        self.clients
+            .iter()
+            .filter_map(|(&(tenant, client), activity)| {
+                let ratio = activity.chargeback_ratio();
+                let reasons: Vec<&str> = [
+                    ("disputes", activity.disputes >= thresholds.disputes),
+                    (
+                        "chargeback_ratio",
+                        activity.chargebacks > 0 && ratio >= thresholds.chargeback_ratio,
+                    ),
+                    (
+                        "rapid_cycles",
+                        activity.rapid_cycles >= thresholds.rapid_cycles,
+                    ),
+                ]
+                .iter()
+                .filter(|(_, reached)| *reached)
+                .map(|(reason, _)| *reason)
+                .collect();
+                if reasons.is_empty() {
+                    return None;
+                }
+                Some(SuspiciousClient {
+                    tenant,
+                    client,
+                    reasons: reasons.join(";"),
+                    deposits: activity.deposits,
+                    withdrawals: activity.withdrawals,
+                    disputes: activity.disputes,
+                    chargebacks: activity.chargebacks,
+                    chargeback_ratio: ratio,
+                    rapid_cycles: activity.rapid_cycles,
+                })
+            })
+            .collect()
+    }
+}
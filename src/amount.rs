@@ -0,0 +1,184 @@
+//! Monetary amounts
+//!
+//! By default amounts are represented with `Decimal`. Enabling the
+//! `minor-units` feature replaces it with a fixed-point `i64` representation
+//! with four decimal places, which is considerably faster and smaller, but
+//! rejects inputs with higher precision.
+
+use std::fmt;
+#[cfg(not(feature = "minor-units"))]
+use std::str::FromStr;
+
+#[cfg(not(feature = "minor-units"))]
+use rust_decimal::prelude::*;
+use rust_decimal::RoundingStrategy;
+use serde::de::{self, Deserializer, Visitor};
+
+#[cfg(feature = "minor-units")]
+mod minor;
+
+#[cfg(feature = "minor-units")]
+pub use minor::{Amount, ParseAmountError};
+#[cfg(not(feature = "minor-units"))]
+pub use rust_decimal::Decimal as Amount;
+
+/// Maximum number of fractional digits handled by the fast path
+pub const FAST_PATH_MAX_SCALE: u32 = 4;
+
+/// Number of fractional digits amounts are expected to have at most, see `Policy::excess_precision`
+pub const MAX_SCALE: u32 = 4;
+
+// 18 digits always fit in i64, so the fast path never has to check for overflow
+const FAST_PATH_MAX_DIGITS: usize = 18;
+
+/// Parses plain decimal amounts of the form `[-]digits[.digits]`
+///
+/// Returns the mantissa and the number of fractional digits (the scale), so
+/// that the value is `mantissa * 10^-scale`. Anything more exotic (exponents,
+/// whitespace, more than four fractional digits, very long numbers) is
+/// rejected with `None` and should be handled by the general parser.
+pub fn parse_fixed(s: &str) -> Option<(i64, u32)> {
+    let bytes = s.as_bytes();
+    let (negative, digits) = match bytes.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, bytes),
+    };
+
+    let (int_part, frac_part) = match digits.iter().position(|&b| b == b'.') {
+        Some(dot) => (&digits[..dot], Some(&digits[dot + 1..])),
+        None => (digits, None),
+    };
+
+    if int_part.is_empty() {
+        return None;
+    }
+    let frac_part = match frac_part {
+        Some(frac) if frac.is_empty() || frac.len() > FAST_PATH_MAX_SCALE as usize => {
+            return None;
+        }
+        Some(frac) => frac,
+        None => &[],
+    };
+    if int_part.len() + frac_part.len() > FAST_PATH_MAX_DIGITS {
+        return None;
+    }
+
+    let mut mantissa: i64 = 0;
+    for &b in int_part.iter().chain(frac_part) {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        mantissa = mantissa * 10 + i64::from(b - b'0');
+    }
+
+    if negative {
+        mantissa = -mantissa;
+    }
+    Some((mantissa, frac_part.len() as u32))
+}
+
+/// Parses a value expressed in minor units, i.e. multiplied by `10^FAST_PATH_MAX_SCALE`
+pub fn parse_minor_units(s: &str) -> Option<i64> {
+    let (mantissa, scale) = parse_fixed(s)?;
+    mantissa.checked_mul(10i64.pow(FAST_PATH_MAX_SCALE - scale))
+}
+
+/// Parses an amount, trying the fast path first and falling back to `Decimal::from_str`
+///
+/// The scale of the input is preserved, so `1.50` is parsed to a value that is
+/// displayed as `1.50`, exactly as the general parser would do.
+#[cfg(not(feature = "minor-units"))]
+pub fn parse_amount(s: &str) -> Result<Amount, rust_decimal::Error> {
+    match parse_fixed(s) {
+        Some((mantissa, scale)) => Ok(Decimal::new(mantissa, scale)),
+        None => Decimal::from_str(s),
+    }
+}
+
+/// Parses an amount into minor units, rejecting more than four decimal places
+#[cfg(feature = "minor-units")]
+pub fn parse_amount(s: &str) -> Result<Amount, ParseAmountError> {
+    s.parse()
+}
+
+/// Whether the amount has more than `MAX_SCALE` fractional digits, ignoring trailing zeros
+#[cfg(not(feature = "minor-units"))]
+pub fn is_too_precise(amount: Amount) -> bool {
+    amount.normalize().scale() > MAX_SCALE
+}
+
+/// Whether the amount has more than `MAX_SCALE` fractional digits, never true for minor units
+#[cfg(feature = "minor-units")]
+pub fn is_too_precise(_amount: Amount) -> bool {
+    false
+}
+
+/// Nearest floating point number, for statistics only
+#[cfg(not(feature = "minor-units"))]
+pub fn to_f64(amount: Amount) -> f64 {
+    ToPrimitive::to_f64(&amount).unwrap_or_default()
+}
+
+/// Nearest floating point number, for statistics only
+#[cfg(feature = "minor-units")]
+pub fn to_f64(amount: Amount) -> f64 {
+    amount.minor_units() as f64 / 10f64.powi(FAST_PATH_MAX_SCALE as i32)
+}
+
+/// Rounds the amount to `MAX_SCALE` fractional digits
+#[cfg(not(feature = "minor-units"))]
+pub fn round(amount: Amount, strategy: RoundingStrategy) -> Amount {
+    amount.round_dp_with_strategy(MAX_SCALE, strategy)
+}
+
+/// Rounds the amount to `MAX_SCALE` fractional digits, which minor units never exceed
+#[cfg(feature = "minor-units")]
+pub fn round(amount: Amount, _strategy: RoundingStrategy) -> Amount {
+    amount
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a decimal amount")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+        parse_amount(v).map_err(E::custom)
+    }
+}
+
+struct OptionalAmountVisitor;
+
+impl<'de> Visitor<'de> for OptionalAmountVisitor {
+    type Value = Option<Amount>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an optional decimal amount")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<Amount>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<Amount>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<Amount>, D::Error> {
+        deserializer.deserialize_str(AmountVisitor).map(Some)
+    }
+}
+
+/// Deserializes an optional amount using `parse_amount`
+///
+/// Meant to be used with `#[serde(deserialize_with = "...")]`; avoids the
+/// intermediate `String` allocation of the default `Decimal` implementation.
+pub fn deserialize_optional<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Amount>, D::Error> {
+    deserializer.deserialize_option(OptionalAmountVisitor)
+}
@@ -0,0 +1,159 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Neg;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+use super::{parse_minor_units, FAST_PATH_MAX_SCALE as SCALE};
+
+const ONE: i64 = 10i64.pow(SCALE);
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum ParseAmountError {
+    #[error("invalid amount")]
+    Invalid,
+
+    #[error("amount has more than {} decimal places", SCALE)]
+    TooPrecise,
+
+    #[error("amount out of range")]
+    OutOfRange,
+}
+
+/// Fixed-point amount stored as a number of minor units (1/10000)
+///
+/// Mirrors the subset of the `Decimal` API used by the engine, so the rest of
+/// the code doesn't care which representation has been compiled in. As amounts
+/// come from the input, the only arithmetic is through the `checked_*` methods,
+/// there are no operators that could panic on overflow. The range is symmetric
+/// (`i64::MIN` units are out of it), so negation is the exception, it always fits.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(i64::MAX);
+
+    /// Creates amount with value `num * 10^-scale`
+    ///
+    /// Panics if the scale is greater than 4 or the value doesn't fit.
+    pub fn new(num: i64, scale: u32) -> Amount {
+        assert!(scale <= SCALE, "scale exceeds {} decimal places", SCALE);
+        num.checked_mul(10i64.pow(SCALE - scale))
+            .and_then(Amount::from_units)
+            .expect("amount overflow")
+    }
+
+    /// Panics if the number of units is out of range, i.e. `i64::MIN`
+    pub const fn from_minor_units(units: i64) -> Amount {
+        assert!(units != i64::MIN, "amount overflow");
+        Amount(units)
+    }
+
+    fn from_units(units: i64) -> Option<Amount> {
+        if units == i64::MIN {
+            None
+        } else {
+            Some(Amount(units))
+        }
+    }
+
+    pub const fn minor_units(&self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).and_then(Amount::from_units)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).and_then(Amount::from_units)
+    }
+
+    pub const fn is_sign_negative(&self) -> bool {
+        self.0 < 0
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Amount, ParseAmountError> {
+        if let Some(units) = parse_minor_units(s) {
+            return Ok(Amount(units));
+        }
+
+        let value = Decimal::from_str(s)
+            .map_err(|_| ParseAmountError::Invalid)?
+            .normalize();
+        if value.scale() > SCALE {
+            return Err(ParseAmountError::TooPrecise);
+        }
+        let units = value
+            .mantissa()
+            .checked_mul(10i128.pow(SCALE - value.scale()))
+            .ok_or(ParseAmountError::OutOfRange)?;
+        i64::try_from(units)
+            .ok()
+            .and_then(Amount::from_units)
+            .ok_or(ParseAmountError::OutOfRange)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        let (int, frac) = (units / ONE as u64, units % ONE as u64);
+        if frac == 0 {
+            write!(f, "{}{}", sign, int)
+        } else {
+            let frac = format!("{:0width$}", frac, width = SCALE as usize);
+            write!(f, "{}{}.{}", sign, int, frac.trim_end_matches('0'))
+        }
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a decimal amount with at most four decimal places")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+        v.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
@@ -0,0 +1,126 @@
+//! Anonymization of input files for sharing, behind `cephalopod anonymize`
+//!
+//! Client, transaction and tenant ids are replaced with their ranks among all
+//! ids of the file, starting with 1, so that every reference stays consistent
+//! and ids keep their order. Amounts can be scaled by a common factor. Rows are
+//! rewritten as they are otherwise, including invalid values, to reproduce
+//! the same behaviour of the engine.
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use csv::StringRecord;
+use rust_decimal::Decimal;
+
+use crate::amount::MAX_SCALE;
+
+/// Columns with client ids, both referring to the same clients
+const CLIENT_COLUMNS: [&str; 2] = ["client", "to"];
+
+/// Ids of a kind found in a file, in order
+#[derive(Debug, Clone)]
+struct IdSet<T>(BTreeSet<T>);
+
+impl<T> Default for IdSet<T> {
+    fn default() -> Self {
+        IdSet(BTreeSet::new())
+    }
+}
+
+impl<T: Ord + Copy + Hash + TryFrom<usize>> IdSet<T> {
+    fn ranks(&self) -> HashMap<T, T> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &id)| Some((id, T::try_from(index + 1).ok()?)))
+            .collect()
+    }
+}
+
+/// Ids found in a file, collected by a first pass over it
+#[derive(Debug, Clone, Default)]
+pub struct Ids {
+    clients: IdSet<u16>,
+    txs: IdSet<u32>,
+    tenants: IdSet<u32>,
+}
+
+impl Ids {
+    pub fn new() -> Ids {
+        Ids::default()
+    }
+
+    /// Collects the ids of a row, ignoring values which aren't valid ids
+    pub fn collect(&mut self, headers: &StringRecord, record: &StringRecord) {
+        for (name, value) in headers.iter().zip(record.iter()) {
+            match name {
+                name if CLIENT_COLUMNS.contains(&name) => {
+                    self.clients.0.extend(value.parse::<u16>().ok())
+                }
+                "tx" => self.txs.0.extend(value.parse::<u32>().ok()),
+                "tenant" => self.tenants.0.extend(value.parse::<u32>().ok()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Rewrites rows of a file with the collected ids replaced and amounts scaled
+#[derive(Debug, Clone)]
+pub struct Anonymizer {
+    clients: HashMap<u16, u16>,
+    txs: HashMap<u32, u32>,
+    tenants: HashMap<u32, u32>,
+    /// Factor amounts are multiplied by, if any
+    scale: Option<Decimal>,
+}
+
+impl Anonymizer {
+    pub fn new(ids: &Ids, scale: Option<Decimal>) -> Anonymizer {
+        Anonymizer {
+            clients: ids.clients.ranks(),
+            txs: ids.txs.ranks(),
+            tenants: ids.tenants.ranks(),
+            scale,
+        }
+    }
+
+    /// Anonymized row, with values which aren't valid ids or amounts left as they are
+    ///
+    /// Fields beyond the header, if any, are kept as well.
+    pub fn anonymize(&self, headers: &StringRecord, record: &StringRecord) -> StringRecord {
+        record
+            .iter()
+            .enumerate()
+            .map(
+                |(index, value)| match headers.get(index).unwrap_or_default() {
+                    name if CLIENT_COLUMNS.contains(&name) => remap(&self.clients, value),
+                    "tx" => remap(&self.txs, value),
+                    "tenant" => remap(&self.tenants, value),
+                    "amount" => self.scale(value),
+                    _ => value.to_string(),
+                },
+            )
+            .collect()
+    }
+
+    fn scale(&self, value: &str) -> String {
+        let (scale, amount) = match (self.scale, value.parse::<Decimal>()) {
+            (Some(scale), Ok(amount)) => (scale, amount),
+            _ => return value.to_string(),
+        };
+        match amount.checked_mul(scale) {
+            Some(scaled) => scaled.round_dp(MAX_SCALE).normalize().to_string(),
+            None => value.to_string(),
+        }
+    }
+}
+
+fn remap<T: FromStr + Eq + Hash + ToString>(ids: &HashMap<T, T>, value: &str) -> String {
+    match value.parse().ok().and_then(|id| ids.get(&id)) {
+        Some(id) => id.to_string(),
+        None => value.to_string(),
+    }
+}
@@ -0,0 +1,255 @@
+//! Periodic checks of invariants of the engine state
+//!
+//! The audit keeps its own account of the net movements of every client,
+//! derived from the types of applied transactions rather than from the
+//! balance changes made by the engine. Every few transactions it checks that
+//! held funds aren't negative, that balances match the movements and that
+//! held funds match the open disputes and authorizations. Any violation is a
+//! bug in the engine, so processing should stop.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{IntegrityError, State, Transaction, TransactionType};
+use crate::tenant::Tenants;
+
+/// Invariant violated in the state of a tenant
+#[derive(Error, Debug, Clone, Copy)]
+#[error("audit after {processed} transactions failed for tenant {tenant:?}: {error}")]
+pub struct AuditFailure {
+    pub tenant: Option<u32>,
+    pub processed: u64,
+    pub error: IntegrityError,
+}
+
+/// Expected balances of all clients, checked against the state every `interval` transactions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Audit {
+    interval: u64,
+    /// Transactions processed since the audit started
+    processed: u64,
+    /// Net of applied movements by tenant, client and currency, i.e. the expected total balance
+    expected: BTreeMap<(Option<u32>, u16, Currency), Amount>,
+    /// Held funds not backed by any transaction (e.g. seeded from initial accounts)
+    unbacked: BTreeMap<(Option<u32>, u16, Currency), Amount>,
+    /// First movement, by tenant, which didn't fit in the expected balances, failing every check
+    #[serde(default)]
+    overflow: Option<(Option<u32>, IntegrityError)>,
+}
+
+impl Audit {
+    /// Starts auditing, taking the current balances as correct
+    pub fn new(interval: u64, tenants: &Tenants) -> Audit {
+        let mut audit = Audit {
+            interval,
+            processed: 0,
+            expected: BTreeMap::new(),
+            unbacked: BTreeMap::new(),
+            overflow: None,
+        };
+        for (tenant, state) in tenants.iter() {
+            let backed = state.held_by_transactions();
+            for (&client, account) in state.iter_clients() {
+                for (&currency, balance) in &account.balances {
+                    let key = (tenant, client, currency);
+                    let held = backed.get(&(client, currency)).copied().unwrap_or_default();
+                    let amounts = balance
+                        .available
+                        .checked_add(balance.held)
+                        .zip(balance.held.checked_sub(held));
+                    let (total, unbacked) = match amounts {
+                        Some(amounts) => amounts,
+                        None => {
+                            let error = IntegrityError::FundsOverflow { client, currency };
+                            audit.overflow.get_or_insert((tenant, error));
+                            continue;
+                        }
+                    };
+                    audit.expected.insert(key, total);
+                    if unbacked != Amount::ZERO {
+                        audit.unbacked.insert(key, unbacked);
+                    }
+                }
+            }
+        }
+        audit
+    }
+
+    /// Whether the transaction would be skipped as an identical duplicate or a repeated
+    /// resolve or chargeback, if applied successfully
+    pub fn is_skipped(state: &State, tx: &Transaction) -> bool {
+        state.is_repeated_outcome(tx)
+            || matches!(
+                tx.tpe,
+                TransactionType::Deposit
+                    | TransactionType::Withdrawal
+                    | TransactionType::Transfer
+                    | TransactionType::Authorize
+            ) && state.transaction(tx.tx).is_some()
+    }
+
+    /// Records movements of a transaction that has been applied to the state
+    pub fn record(&mut self, state: &State, tx: &Transaction) {
+        let amount = tx.amount.unwrap_or_default();
+        let referenced = match tx.tpe {
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Representment
+            | TransactionType::Reversal
+            | TransactionType::Capture
+            | TransactionType::Void => state.transaction(tx.tx),
+            _ => None,
+        };
+        // funds returned to the client by a dispute of a withdrawal or the sending side of a transfer
+        let debit = referenced.is_some_and(|referenced| {
+            referenced.client == tx.client
+                && matches!(
+                    referenced.tpe,
+                    TransactionType::Withdrawal
+                        | TransactionType::Authorize
+                        | TransactionType::Transfer
+                )
+        });
+        let referenced_amount = referenced
+            .and_then(|referenced| referenced.amount)
+            .unwrap_or_default();
+        let currency = referenced.map_or(tx.currency, |referenced| referenced.currency);
+        let movement = match (tx.tpe, debit) {
+            (TransactionType::Deposit, _) | (TransactionType::Adjustment, _) => amount,
+            (TransactionType::Withdrawal, _) | (TransactionType::Fee, _) => -amount,
+            (TransactionType::Transfer, _) => {
+                if let Some(to) = tx.to {
+                    self.add(tx, to, tx.currency, amount);
+                }
+                -amount
+            }
+            (TransactionType::Capture, _) => -referenced_amount,
+            // disputed withdrawals are credited back as held funds, a chargeback only releases them
+            (TransactionType::Dispute, true)
+            | (TransactionType::Reversal, true)
+            | (TransactionType::Representment, false) => referenced_amount,
+            (TransactionType::Resolve, true)
+            | (TransactionType::Representment, true)
+            | (TransactionType::Chargeback, false)
+            | (TransactionType::Reversal, false) => -referenced_amount,
+            _ => Amount::ZERO,
+        };
+        self.add(tx, tx.client, currency, movement);
+    }
+
+    fn add(&mut self, tx: &Transaction, client: u16, currency: Currency, amount: Amount) {
+        if amount == Amount::ZERO {
+            return;
+        }
+        let expected = self
+            .expected
+            .entry((tx.tenant, client, currency))
+            .or_insert(Amount::ZERO);
+        match expected.checked_add(amount) {
+            Some(total) => *expected = total,
+            None => {
+                let error = IntegrityError::AmountOverflow { tx: tx.tx };
+                self.overflow.get_or_insert((tx.tenant, error));
+            }
+        }
+    }
+
+    /// Counts a processed transaction, checking the invariants if it's time for it
+    pub fn tick(&mut self, tenants: &Tenants) -> Result<(), AuditFailure> {
+        self.processed += 1;
+        if self.processed.is_multiple_of(self.interval) {
+            self.check(tenants)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the invariants in states of all tenants
+    pub fn check(&self, tenants: &Tenants) -> Result<(), AuditFailure> {
+        if let Some((tenant, error)) = self.overflow {
+            return Err(AuditFailure {
+                tenant,
+                processed: self.processed,
+                error,
+            });
+        }
+        for (tenant, state) in tenants.iter() {
+            self.check_state(tenant, state)
+                .map_err(|error| AuditFailure {
+                    tenant,
+                    processed: self.processed,
+                    error,
+                })?;
+        }
+        Ok(())
+    }
+
+    fn check_state(&self, tenant: Option<u32>, state: &State) -> Result<(), IntegrityError> {
+        let mut expected: BTreeMap<(u16, Currency), Amount> = self
+            .expected
+            .iter()
+            .filter(|((expected_tenant, _, _), _)| *expected_tenant == tenant)
+            .map(|(&(_, client, currency), &amount)| ((client, currency), amount))
+            .collect();
+        let backed = state.held_by_transactions();
+        for (&client, account) in state.iter_clients() {
+            for (&currency, balance) in &account.balances {
+                if balance.held < Amount::ZERO {
+                    return Err(IntegrityError::NegativeHeldFunds {
+                        client,
+                        currency,
+                        held: balance.held,
+                    });
+                }
+                let total = balance
+                    .total()
+                    .ok_or(IntegrityError::FundsOverflow { client, currency })?;
+                let expected_total = expected.remove(&(client, currency)).unwrap_or_default();
+                if total != expected_total {
+                    return Err(IntegrityError::BalanceMismatch {
+                        client,
+                        currency,
+                        total,
+                        expected: expected_total,
+                    });
+                }
+                let expected_held = backed
+                    .get(&(client, currency))
+                    .copied()
+                    .unwrap_or_default()
+                    .checked_add(
+                        self.unbacked
+                            .get(&(tenant, client, currency))
+                            .copied()
+                            .unwrap_or_default(),
+                    )
+                    .ok_or(IntegrityError::FundsOverflow { client, currency })?;
+                if balance.held != expected_held {
+                    return Err(IntegrityError::HeldFundsMismatch {
+                        client,
+                        currency,
+                        held: balance.held,
+                        expected: expected_held,
+                    });
+                }
+            }
+        }
+        // movements of balances missing from the state
+        match expected
+            .into_iter()
+            .find(|(_, amount)| *amount != Amount::ZERO)
+        {
+            Some(((client, currency), amount)) => Err(IntegrityError::BalanceMismatch {
+                client,
+                currency,
+                total: Amount::ZERO,
+                expected: amount,
+            }),
+            None => Ok(()),
+        }
+    }
+}
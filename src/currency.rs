@@ -0,0 +1,94 @@
+//! Currency codes
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+const MAX_LEN: usize = 8;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum ParseCurrencyError {
+    #[error("currency code longer than {} characters", MAX_LEN)]
+    TooLong,
+
+    #[error("currency code contains characters other than ASCII letters and digits")]
+    Invalid,
+}
+
+/// Currency code of up to eight ASCII letters or digits, e.g. `USD`
+///
+/// Codes are normalized to uppercase. The default (empty) code denotes the
+/// currency of transactions that don't specify one.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; MAX_LEN]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(MAX_LEN);
+        // only ASCII characters are ever stored
+        std::str::from_utf8(&self.0[..len]).expect("non-ASCII currency code")
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0[0] == 0
+    }
+}
+
+impl FromStr for Currency {
+    type Err = ParseCurrencyError;
+
+    fn from_str(s: &str) -> Result<Currency, ParseCurrencyError> {
+        if s.len() > MAX_LEN {
+            return Err(ParseCurrencyError::TooLong);
+        }
+        let mut code = [0; MAX_LEN];
+        for (dst, &b) in code.iter_mut().zip(s.as_bytes()) {
+            if !b.is_ascii_alphanumeric() {
+                return Err(ParseCurrencyError::Invalid);
+            }
+            *dst = b.to_ascii_uppercase();
+        }
+        Ok(Currency(code))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+struct CurrencyVisitor;
+
+impl<'de> Visitor<'de> for CurrencyVisitor {
+    type Value = Currency;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a currency code")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Currency, E> {
+        v.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Currency, D::Error> {
+        deserializer.deserialize_str(CurrencyVisitor)
+    }
+}
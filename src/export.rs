@@ -0,0 +1,91 @@
+//! CSV export of accounts, the output of the command line tool
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::activity::Activity;
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::Balance;
+use crate::tenant::Tenants;
+
+/// Row of the output, i.e. balance of an account in a single currency
+///
+/// Also read back to seed accounts of a new run (`--initial-accounts`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedClient {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    /// Empty if the sum of available and held funds doesn't fit in an amount
+    pub total: Option<Amount>,
+    pub locked: bool,
+    /// Risk flags separated with `;`
+    pub flags: String,
+}
+
+/// Balances of all accounts, one per client and currency
+///
+/// Accounts without any funds are still listed, in the default currency.
+pub fn accounts(tenants: &Tenants) -> impl Iterator<Item = ExportedClient> + '_ {
+    tenants.iter().flat_map(|(tenant, state)| {
+        state.iter_clients().flat_map(move |(&id, account)| {
+            let mut balances: Vec<(Currency, Balance)> = account
+                .balances
+                .iter()
+                .map(|(&currency, &balance)| (currency, balance))
+                .collect();
+            if balances.is_empty() {
+                balances.push(Default::default());
+            }
+            let flags: Vec<String> = account.flags.iter().map(|flag| flag.to_string()).collect();
+            let flags = flags.join(";");
+            balances
+                .into_iter()
+                .map(move |(currency, balance)| ExportedClient {
+                    tenant,
+                    client: id,
+                    currency,
+                    available: balance.available,
+                    held: balance.held,
+                    total: balance.total(),
+                    locked: account.locked,
+                    flags: flags.clone(),
+                })
+        })
+    })
+}
+
+/// Writes balances of all accounts as CSV, see `accounts`
+pub fn write_accounts<W: io::Write>(tenants: &Tenants, wtr: W) {
+    write_rows(accounts(tenants), wtr)
+}
+
+/// Writes the rows as CSV, in the format of `write_accounts`
+pub fn write_rows<W: io::Write>(rows: impl IntoIterator<Item = ExportedClient>, wtr: W) {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    for client in rows {
+        wtr.serialize(client).unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
+
+/// Writes balances of all accounts as CSV with the fraud heuristics of their clients appended
+pub fn write_accounts_with_heuristics<W: io::Write>(
+    tenants: &Tenants,
+    activity: &Activity,
+    wtr: W,
+) {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    for client in accounts(tenants) {
+        let heuristics = activity.heuristics(client.tenant, client.client);
+        wtr.serialize((client, heuristics)).unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
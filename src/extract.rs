@@ -0,0 +1,89 @@
+//! Extraction of the records affecting some clients, behind `cephalopod extract`
+//!
+//! Besides the records of the clients, the subset keeps what their outcomes
+//! depend on: records of clients they have made transfers with, in either
+//! direction and transitively, and any other records with the same transaction
+//! ids, e.g. disputes filed by other clients or deposits with a duplicate id.
+//! Replaying the subset gives the clients the same accounts as the whole file.
+
+use std::collections::{HashMap, HashSet};
+
+use csv::StringRecord;
+
+/// Ids of a record the extraction depends on, missing if they aren't valid
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordIds {
+    pub tenant: Option<u32>,
+    pub client: Option<u16>,
+    pub to: Option<u16>,
+    pub tx: Option<u32>,
+}
+
+impl RecordIds {
+    pub fn of(headers: &StringRecord, record: &StringRecord) -> RecordIds {
+        let mut ids = RecordIds::default();
+        for (name, value) in headers.iter().zip(record.iter()) {
+            match name {
+                "tenant" => ids.tenant = value.parse().ok(),
+                "client" => ids.client = value.parse().ok(),
+                "to" => ids.to = value.parse().ok(),
+                "tx" => ids.tx = value.parse().ok(),
+                _ => {}
+            }
+        }
+        ids
+    }
+}
+
+/// Client of a tenant
+type ClientKey = (Option<u32>, u16);
+
+/// Which of the records to keep to reproduce the accounts of the clients, in any tenant
+pub fn select(records: &[RecordIds], clients: &[u16]) -> Vec<bool> {
+    let mut transfers: HashMap<ClientKey, Vec<ClientKey>> = HashMap::new();
+    let mut selected: HashSet<ClientKey> = HashSet::new();
+    for ids in records {
+        if let Some(client) = ids.client {
+            if clients.contains(&client) {
+                selected.insert((ids.tenant, client));
+            }
+            if let Some(to) = ids.to {
+                if clients.contains(&to) {
+                    selected.insert((ids.tenant, to));
+                }
+                transfers
+                    .entry((ids.tenant, client))
+                    .or_default()
+                    .push((ids.tenant, to));
+                transfers
+                    .entry((ids.tenant, to))
+                    .or_default()
+                    .push((ids.tenant, client));
+            }
+        }
+    }
+    let mut pending: Vec<ClientKey> = selected.iter().copied().collect();
+    while let Some(client) = pending.pop() {
+        for &other in transfers.get(&client).into_iter().flatten() {
+            if selected.insert(other) {
+                pending.push(other);
+            }
+        }
+    }
+
+    let involves = |ids: &RecordIds| {
+        [ids.client, ids.to]
+            .iter()
+            .flatten()
+            .any(|&client| selected.contains(&(ids.tenant, client)))
+    };
+    let txs: HashSet<(Option<u32>, u32)> = records
+        .iter()
+        .filter(|ids| involves(ids))
+        .filter_map(|ids| Some((ids.tenant, ids.tx?)))
+        .collect();
+    records
+        .iter()
+        .map(|ids| involves(ids) || ids.tx.is_some_and(|tx| txs.contains(&(ids.tenant, tx))))
+        .collect()
+}
@@ -0,0 +1,167 @@
+//! C interface of the engine, built with the `ffi` feature (see `include/cephalopod.h`)
+//!
+//! The state is an opaque pointer created with `cephalopod_create_state` and
+//! released with `cephalopod_free_state`. Transactions are passed as JSON
+//! objects with the same fields as rows of the input, and accounts are
+//! exported as JSON (see `json::accounts`). Strings returned by the library
+//! must be released with `cephalopod_free_string`.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use crate::json;
+use crate::model::{CephalopodError, Transaction};
+use crate::policy::Policy;
+use crate::tenant::Tenants;
+
+/// Transaction has been applied
+pub const CEPHALOPOD_OK: c_int = 0;
+/// Transaction has been rejected, e.g. because of insufficient funds
+pub const CEPHALOPOD_REJECTED: c_int = 1;
+/// Integrity error, no more transactions should be applied
+pub const CEPHALOPOD_INTEGRITY_ERROR: c_int = 2;
+/// Null pointer or argument that couldn't be parsed
+pub const CEPHALOPOD_INVALID_ARGUMENT: c_int = -1;
+
+/// States of all tenants, opaque to C
+pub struct CephalopodState {
+    tenants: Tenants,
+}
+
+/// Returns the string as UTF-8, `None` if it's null or invalid
+///
+/// # Safety
+///
+/// `string` must be null or point to a nul-terminated string.
+unsafe fn utf8<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        return None;
+    }
+    CStr::from_ptr(string).to_str().ok()
+}
+
+/// Passes a message to the caller through `error`, if not null
+///
+/// # Safety
+///
+/// `error` must be null or valid for writes.
+unsafe fn set_error(error: *mut *mut c_char, message: String) {
+    if !error.is_null() {
+        *error = CString::new(message).map_or(ptr::null_mut(), CString::into_raw);
+    }
+}
+
+/// Creates an empty state following the policy given as JSON, or the default policy if null
+///
+/// Returns null if the policy can't be parsed.
+///
+/// # Safety
+///
+/// `policy` must be null or point to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cephalopod_create_state(policy: *const c_char) -> *mut CephalopodState {
+    let policy = if policy.is_null() {
+        Policy::default()
+    } else {
+        match utf8(policy).and_then(|policy| serde_json::from_str(policy).ok()) {
+            Some(policy) => policy,
+            None => return ptr::null_mut(),
+        }
+    };
+    Box::into_raw(Box::new(CephalopodState {
+        tenants: Tenants::new(policy),
+    }))
+}
+
+/// Releases the state, does nothing if it's null
+///
+/// # Safety
+///
+/// `state` must be null or created by `cephalopod_create_state`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cephalopod_free_state(state: *mut CephalopodState) {
+    if !state.is_null() {
+        drop(Box::from_raw(state));
+    }
+}
+
+/// Applies the transaction given as JSON, returning one of the `CEPHALOPOD_*` codes
+///
+/// Unless the transaction has been applied, the reason is passed through
+/// `error` (if not null), to be released with `cephalopod_free_string`.
+///
+/// # Safety
+///
+/// `state` must be created by `cephalopod_create_state`, `transaction` must
+/// point to a nul-terminated string and `error` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn cephalopod_apply_transaction_json(
+    state: *mut CephalopodState,
+    transaction: *const c_char,
+    error: *mut *mut c_char,
+) -> c_int {
+    let state = match state.as_mut() {
+        Some(state) => state,
+        None => {
+            set_error(error, "state is null".to_string());
+            return CEPHALOPOD_INVALID_ARGUMENT;
+        }
+    };
+    let transaction = match utf8(transaction) {
+        Some(transaction) => transaction,
+        None => {
+            set_error(error, "transaction is null or not UTF-8".to_string());
+            return CEPHALOPOD_INVALID_ARGUMENT;
+        }
+    };
+    let transaction: Transaction = match serde_json::from_str(transaction) {
+        Ok(transaction) => transaction,
+        Err(err) => {
+            set_error(error, format!("invalid transaction: {}", err));
+            return CEPHALOPOD_INVALID_ARGUMENT;
+        }
+    };
+    match state.tenants.apply_transaction(&transaction) {
+        Ok(()) => CEPHALOPOD_OK,
+        Err(CephalopodError::TransactionError { error: err, .. }) => {
+            set_error(error, err.to_string());
+            CEPHALOPOD_REJECTED
+        }
+        Err(CephalopodError::IntegrityError { error: err, .. }) => {
+            set_error(error, err.to_string());
+            CEPHALOPOD_INTEGRITY_ERROR
+        }
+    }
+}
+
+/// Exports balances of all accounts as a JSON array, to be released with `cephalopod_free_string`
+///
+/// Returns null if the state is null.
+///
+/// # Safety
+///
+/// `state` must be null or created by `cephalopod_create_state`.
+#[no_mangle]
+pub unsafe extern "C" fn cephalopod_export_accounts_json(
+    state: *const CephalopodState,
+) -> *mut c_char {
+    match state.as_ref() {
+        Some(state) => {
+            CString::new(json::accounts(&state.tenants)).map_or(ptr::null_mut(), CString::into_raw)
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// Releases a string returned by the library, does nothing if it's null
+///
+/// # Safety
+///
+/// `string` must be null or returned by the library, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cephalopod_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
@@ -0,0 +1,109 @@
+//! Stable hashing of states, to confirm that two runs reached identical results
+//! or to find the transaction after which they diverged
+
+use std::fmt;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{Record, State};
+use crate::tag::Tag;
+
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a hash of serialized values
+///
+/// Unlike `DefaultHasher`, the hash doesn't depend on the platform nor the
+/// version of Rust, so fingerprints computed on different machines can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Fingerprint(OFFSET_BASIS)
+    }
+}
+
+impl Fingerprint {
+    /// Hashes the value as encoded by `bincode`, whose encoding is the same on all platforms
+    pub fn add<T: Serialize + ?Sized>(&mut self, value: &T) {
+        bincode::serialize_into(self, value).expect("hashing should never fail");
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl io::Write for Fingerprint {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(PRIME);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Fingerprint of the chain of processed transactions after one of them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrailEntry {
+    pub line: Option<u64>,
+    pub tenant: Option<u32>,
+    pub tx: u32,
+    /// Tag of the transaction, or of the transaction it refers to if it has none
+    pub tag: Tag,
+    pub fingerprint: Fingerprint,
+}
+
+/// Chain of fingerprints of processed transactions and the parts of the state they may have affected
+///
+/// Each fingerprint covers all the transactions before, so the first entry
+/// differing between two replays of the same input points at the transaction
+/// after which their states diverged. The entries are kept only until taken
+/// by `drain`, e.g. to be written to a file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Trail {
+    chain: Fingerprint,
+    pending: Vec<TrailEntry>,
+}
+
+impl Trail {
+    pub fn new() -> Trail {
+        Trail::default()
+    }
+
+    /// Extends the chain with the transaction, applied or not, and the state after processing it
+    pub fn record(&mut self, record: &Record, state: &State) {
+        let transaction = &record.transaction;
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.add(&(
+            self.chain.value(),
+            transaction,
+            state.transaction_fingerprint(transaction).value(),
+        ));
+        self.chain = fingerprint;
+        self.pending.push(TrailEntry {
+            line: record.line,
+            tenant: transaction.tenant,
+            tx: transaction.tx,
+            tag: state.tag(transaction),
+            fingerprint,
+        });
+    }
+
+    /// Removes and returns the entries recorded since the last call
+    pub fn drain(&mut self) -> Vec<TrailEntry> {
+        std::mem::take(&mut self.pending)
+    }
+}
@@ -0,0 +1,57 @@
+//! JSON export of accounts, shared by the bindings
+
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::Balance;
+use crate::tenant::Tenants;
+
+/// Balance of an account in a single currency
+#[derive(Debug, Serialize)]
+struct AccountBalance<'a> {
+    tenant: Option<u32>,
+    client: u16,
+    currency: Currency,
+    available: Amount,
+    held: Amount,
+    /// `null` if the sum of available and held funds doesn't fit in an amount
+    total: Option<Amount>,
+    locked: bool,
+    flags: &'a BTreeSet<u32>,
+}
+
+/// Returns balances of all accounts as a JSON array, one entry per client and currency
+///
+/// Entries have the same fields as rows of the output and are ordered by
+/// tenant. Accounts without any funds are listed in the default currency.
+pub fn accounts(tenants: &Tenants) -> String {
+    let mut balances = Vec::new();
+    for (tenant, state) in tenants.iter() {
+        for (&client, account) in state.iter_clients() {
+            let mut account_balances: Vec<(Currency, Balance)> = account
+                .balances
+                .iter()
+                .map(|(&currency, &balance)| (currency, balance))
+                .collect();
+            if account_balances.is_empty() {
+                account_balances.push(Default::default());
+            }
+            for (currency, balance) in account_balances {
+                balances.push(AccountBalance {
+                    tenant,
+                    client,
+                    currency,
+                    available: balance.available,
+                    held: balance.held,
+                    total: balance.total(),
+                    locked: account.locked,
+                    flags: &account.flags,
+                });
+            }
+        }
+    }
+    serde_json::to_string(&balances).expect("accounts should be serializable to JSON")
+}
@@ -0,0 +1,276 @@
+//! Double-entry bookkeeping of the movements applied by the engine
+//!
+//! Every change of client balances is posted against internal house accounts,
+//! so that the totals can be reconciled with the general ledger. The postings
+//! can also be kept in a journal, along with the tags of their transactions, so
+//! that they can be joined back to upstream records.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{Balance, CephalopodError, State, Transaction, TransactionType};
+use crate::tag::Tag;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerAccount {
+    /// Funds held by the operator (asset)
+    Cash,
+    /// Available funds of all clients (liability)
+    ClientFunds,
+    /// Funds of all clients held for disputes and authorizations (liability)
+    HeldFunds,
+    /// Disputed and charged back withdrawals (expense)
+    ChargebackLoss,
+    /// Fees charged to clients (income)
+    FeeIncome,
+    /// Administrative adjustments of client balances (expense)
+    Adjustments,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LedgerAccount::Cash => "cash",
+            LedgerAccount::ClientFunds => "client_funds",
+            LedgerAccount::HeldFunds => "held_funds",
+            LedgerAccount::ChargebackLoss => "chargeback_loss",
+            LedgerAccount::FeeIncome => "fee_income",
+            LedgerAccount::Adjustments => "adjustments",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Totals of the ledger which don't fit in `Amount`
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum LedgerError {
+    #[error("ledger totals overflowed while posting transaction {tx}")]
+    PostingOverflow { tx: u32 },
+
+    #[error("debits or credits in currency '{currency}' overflow when summed up")]
+    BalanceOverflow { currency: Currency },
+}
+
+/// Single row of the trial balance
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrialBalanceRow {
+    pub account: LedgerAccount,
+    pub currency: Currency,
+    pub debit: Amount,
+    pub credit: Amount,
+}
+
+/// Single posting of a transaction, as kept in the journal
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub tenant: Option<u32>,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tpe: TransactionType,
+    pub client: u16,
+    /// Tag of the transaction, or of the transaction it refers to if it has none
+    pub tag: Tag,
+    pub account: LedgerAccount,
+    pub currency: Currency,
+    pub debit: Amount,
+    pub credit: Amount,
+}
+
+/// Debit and credit totals of ledger accounts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    totals: BTreeMap<(LedgerAccount, Currency), (Amount, Amount)>,
+    /// First transaction whose postings didn't fit in the totals, which are incomplete since
+    #[serde(default)]
+    overflow: Option<u32>,
+    /// Postings not taken by `drain_journal` yet, if they are kept
+    journal: Option<Vec<JournalEntry>>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    /// Keeps postings from now on in the journal, until taken by `drain_journal`
+    pub fn keep_journal(&mut self) {
+        self.journal.get_or_insert_with(Vec::new);
+    }
+
+    /// Takes the postings kept since the last call
+    pub fn drain_journal(&mut self) -> Vec<JournalEntry> {
+        self.journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn post(
+        &mut self,
+        tx: &Transaction,
+        tag: Tag,
+        account: LedgerAccount,
+        currency: Currency,
+        amount: Amount,
+    ) {
+        if amount == Amount::ZERO {
+            return;
+        }
+        let (debit, credit) = if amount.is_sign_negative() {
+            (Amount::ZERO, -amount)
+        } else {
+            (amount, Amount::ZERO)
+        };
+        let totals = self
+            .totals
+            .entry((account, currency))
+            .or_insert((Amount::ZERO, Amount::ZERO));
+        match (totals.0.checked_add(debit), totals.1.checked_add(credit)) {
+            (Some(debits), Some(credits)) => *totals = (debits, credits),
+            _ => self.overflowed(tx),
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.push(JournalEntry {
+                tenant: tx.tenant,
+                tx: tx.tx,
+                tpe: tx.tpe,
+                client: tx.client,
+                tag,
+                account,
+                currency,
+                debit,
+                credit,
+            });
+        }
+    }
+
+    /// Notes that the postings of the transaction didn't fit, the balance changes are still
+    /// applied to the state
+    fn overflowed(&mut self, tx: &Transaction) {
+        if self.overflow.is_none() {
+            error!(
+                "Ledger totals overflowed while posting transaction {}, the trial balance won't be available",
+                tx.tx
+            );
+            self.overflow = Some(tx.tx);
+        }
+    }
+
+    /// House account the balance changes caused by `tx` are posted against
+    fn counterpart(state: &State, tx: &Transaction) -> LedgerAccount {
+        match tx.tpe {
+            TransactionType::Fee => LedgerAccount::FeeIncome,
+            TransactionType::Adjustment => LedgerAccount::Adjustments,
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Representment => match state.transaction(tx.tx) {
+                Some(referenced)
+                    if referenced.client == tx.client
+                        && matches!(
+                            referenced.tpe,
+                            TransactionType::Withdrawal | TransactionType::Transfer
+                        ) =>
+                {
+                    LedgerAccount::ChargebackLoss
+                }
+                _ => LedgerAccount::Cash,
+            },
+            _ => LedgerAccount::Cash,
+        }
+    }
+
+    /// Applies the transaction to the state and posts the resulting balance changes
+    pub fn apply(&mut self, state: &mut State, tx: &Transaction) -> Result<(), CephalopodError> {
+        let clients: Vec<u16> = std::iter::once(tx.client).chain(tx.to).collect();
+        let before: Vec<BTreeMap<Currency, Balance>> = clients
+            .iter()
+            .map(|client| {
+                state
+                    .account(*client)
+                    .map(|account| account.balances.clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        state.apply_transaction(tx)?;
+
+        let counterpart = Self::counterpart(state, tx);
+        let tag = state.tag(tx);
+        let mut net: HashMap<Currency, Amount> = HashMap::new();
+        for (client, before) in clients.iter().zip(before) {
+            let after = match state.account(*client) {
+                Some(account) => &account.balances,
+                None => continue,
+            };
+            for (&currency, balance) in after {
+                let previous = before.get(&currency).copied().unwrap_or_default();
+                let net = net.entry(currency).or_insert(Amount::ZERO);
+                let changes = balance
+                    .available
+                    .checked_sub(previous.available)
+                    .zip(balance.held.checked_sub(previous.held))
+                    .and_then(|(available, held)| {
+                        let total = net.checked_add(available)?.checked_add(held)?;
+                        Some((available, held, total))
+                    });
+                let (available, held, total) = match changes {
+                    Some(changes) => changes,
+                    None => {
+                        self.overflowed(tx);
+                        continue;
+                    }
+                };
+                *net = total;
+                // client balances are liabilities, so increases are credited
+                self.post(tx, tag, LedgerAccount::ClientFunds, currency, -available);
+                self.post(tx, tag, LedgerAccount::HeldFunds, currency, -held);
+            }
+        }
+        for (currency, amount) in net {
+            self.post(tx, tag, counterpart, currency, amount);
+        }
+        Ok(())
+    }
+
+    /// Debit and credit totals of all accounts with postings, unless some of them overflowed
+    pub fn trial_balance(&self) -> Result<Vec<TrialBalanceRow>, LedgerError> {
+        if let Some(tx) = self.overflow {
+            return Err(LedgerError::PostingOverflow { tx });
+        }
+        Ok(self
+            .totals
+            .iter()
+            .map(|(&(account, currency), &(debit, credit))| TrialBalanceRow {
+                account,
+                currency,
+                debit,
+                credit,
+            })
+            .collect())
+    }
+
+    /// Whether debits equal credits in every currency
+    pub fn is_balanced(&self) -> Result<bool, LedgerError> {
+        if let Some(tx) = self.overflow {
+            return Err(LedgerError::PostingOverflow { tx });
+        }
+        let mut sums: HashMap<Currency, (Amount, Amount)> = HashMap::new();
+        for (&(_, currency), &(debit, credit)) in &self.totals {
+            let sums = sums.entry(currency).or_default();
+            *sums = sums
+                .0
+                .checked_add(debit)
+                .zip(sums.1.checked_add(credit))
+                .ok_or(LedgerError::BalanceOverflow { currency })?;
+        }
+        Ok(sums.values().all(|(debits, credits)| debits == credits))
+    }
+}
@@ -0,0 +1,71 @@
+//! Engine processing deposits, withdrawals, disputes and other transactions of clients' accounts
+//!
+//! The `cephalopod` binary is a command line interface to it.
+
+pub mod activity;
+pub mod amount;
+#[cfg(feature = "cli")]
+pub mod anonymize;
+pub mod audit;
+pub mod currency;
+#[cfg(feature = "cli")]
+pub mod export;
+#[cfg(feature = "cli")]
+pub mod extract;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fingerprint;
+#[cfg(any(feature = "wasm", feature = "ffi", feature = "node"))]
+pub mod json;
+pub mod ledger;
+#[cfg(feature = "cli")]
+pub mod manifest;
+pub mod memory;
+#[cfg(feature = "cli")]
+pub mod merge;
+#[cfg(feature = "cli")]
+pub mod metrics;
+pub mod model;
+#[cfg(feature = "node")]
+pub mod node;
+#[cfg(feature = "cli")]
+pub mod normalize;
+pub mod ordering;
+pub mod policy;
+pub mod processor;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quarantine;
+#[cfg(feature = "cli")]
+pub mod reconcile;
+#[cfg(feature = "cli")]
+pub mod repl;
+#[cfg(feature = "cli")]
+pub mod report;
+pub mod rules;
+#[cfg(any(test, feature = "test-util"))]
+pub mod scenario;
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod settlement;
+pub mod snapshot;
+#[cfg(feature = "cli")]
+pub mod split;
+pub mod storage;
+pub mod summary;
+pub mod suspense;
+pub mod tag;
+#[cfg(feature = "templates")]
+pub mod template;
+pub mod tenant;
+#[cfg(test)]
+// older tests iterate over `vec!`s
+#[allow(clippy::useless_vec)]
+mod tests;
+#[cfg(feature = "cli")]
+pub mod verify;
+pub mod warnings;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod workload;
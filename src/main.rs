@@ -1,77 +1,2428 @@
-use std::io;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+use csv::Position;
+use rust_decimal::Decimal;
 
-use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use log::{error, info, warn};
+#[cfg(feature = "server")]
+use tracing::Instrument;
+use tracing::{error, error_span, field, info, warn};
+use tracing_subscriber::EnvFilter;
+
+use cephalopod::activity::{Activity, Thresholds};
+use cephalopod::amount::{self, Amount};
+use cephalopod::anonymize::{Anonymizer, Ids};
+use cephalopod::audit::Audit;
+use cephalopod::currency::Currency;
+use cephalopod::export::{self, ExportedClient};
+use cephalopod::extract::{self, RecordIds};
+use cephalopod::fingerprint::Trail;
+use cephalopod::ledger::{Ledger, TrialBalanceRow};
+use cephalopod::manifest::{self, DigestWriter, FileDigest, Manifest};
+#[cfg(feature = "alloc-stats")]
+use cephalopod::memory::CountingAllocator;
+use cephalopod::merge;
+use cephalopod::metrics::{Metrics, MetricsFormat, MetricsReport, Progress};
+use cephalopod::model::{Balance, Record, TransactionType};
+use cephalopod::normalize::{self, Encoding, NormalizeOptions};
+use cephalopod::ordering::{OrderingScope, OutOfOrderAction, Sequencer};
+use cephalopod::policy::{ClientSettings, ExcessPrecision, Policy, Transitions};
+use cephalopod::processor::{Processor, Severity};
+use cephalopod::quarantine::{self, Quarantine};
+use cephalopod::reconcile::{self, Discrepancy, ExpectedBalance, Tolerance};
+use cephalopod::repl::Repl;
+use cephalopod::report::{self, SummaryFormat};
+use cephalopod::rules::{Rules, RulesConfig};
+use cephalopod::schema::{FieldError, Schema, SchemaVersion};
+#[cfg(feature = "server")]
+use cephalopod::server::{self, EngineHandle};
+#[cfg(feature = "webhooks")]
+use cephalopod::server::{Retry, Webhook};
+use cephalopod::settlement::Settlement;
+use cephalopod::snapshot::{self, SnapshotError};
+use cephalopod::split;
+use cephalopod::storage::{self, StorageLocation};
+use cephalopod::summary::Summary;
+use cephalopod::suspense::Suspense;
+use cephalopod::tag::Tag;
+#[cfg(feature = "templates")]
+use cephalopod::template::{Template, TemplateContext};
+use cephalopod::tenant::Tenants;
+use cephalopod::verify;
+use cephalopod::warnings::{self, Warnings};
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Exit code after stopping on SIGINT or SIGTERM with the state saved
+///
+/// In batch mode, the accounts written to the output are partial.
+const INTERRUPTED_EXIT_CODE: i32 = 3;
+
+/// Exit code of `reconcile` or `verify` finding results that don't match the expected ones
+const DISCREPANCIES_EXIT_CODE: i32 = 4;
+
+/// Loads `Policy::transitions` from a JSON file, validating them
+fn parse_transitions_arg(s: &str) -> Result<Transitions, String> {
+    let file = fs::File::open(s).map_err(|err| err.to_string())?;
+    let transitions: Transitions =
+        serde_json::from_reader(io::BufReader::new(file)).map_err(|err| err.to_string())?;
+    transitions.validate()?;
+    Ok(transitions)
+}
+
+/// Loads a template given by `--template` or `--summary-template`, failing before processing
+/// if it doesn't parse
+#[cfg(feature = "templates")]
+fn parse_template_arg(s: &str) -> Result<Template, String> {
+    let source = fs::read_to_string(s).map_err(|err| err.to_string())?;
+    Template::parse(&source).map_err(|err| err.to_string())
+}
+
+fn parse_amount_arg(s: &str) -> Result<Amount, String> {
+    amount::parse_amount(s).map_err(|err| err.to_string())
+}
+
+fn parse_flag_amount_arg(s: &str) -> Result<(u32, Amount), String> {
+    let (flag, amount) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FLAG=AMOUNT, got {}", s))?;
+    let flag = flag
+        .parse()
+        .map_err(|err| format!("invalid flag {}: {}", flag, err))?;
+    Ok((flag, parse_amount_arg(amount)?))
+}
+
+fn parse_class_amount_arg(s: &str) -> Result<(String, Amount), String> {
+    let (class, amount) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected CLASS=AMOUNT, got {}", s))?;
+    Ok((class.to_string(), parse_amount_arg(amount)?))
+}
+
+fn parse_currency_amount_arg(s: &str) -> Result<(Currency, Amount), String> {
+    let (currency, amount) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected CURRENCY=AMOUNT, got {}", s))?;
+    let currency = currency
+        .parse()
+        .map_err(|err| format!("invalid currency {}: {}", currency, err))?;
+    Ok((currency, parse_amount_arg(amount)?))
+}
+
+fn parse_factor_arg(s: &str) -> Result<Decimal, String> {
+    let factor: Decimal = s
+        .parse()
+        .map_err(|err: rust_decimal::Error| err.to_string())?;
+    if factor <= Decimal::ZERO {
+        return Err(format!("expected a positive factor, got {}", s));
+    }
+    Ok(factor)
+}
+
+fn parse_delimiter_arg(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        s if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!(
+            "expected a single ASCII character or tab, got {}",
+            s
+        )),
+    }
+}
+
+fn parse_error_severity_arg(s: &str) -> Result<(String, Severity), String> {
+    let (kind, severity) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KIND=SEVERITY, got {}", s))?;
+    Ok((kind.to_string(), severity.parse()?))
+}
+
+/// Processes a CSV file with transactions and prints the resulting client accounts
+#[derive(Debug, Clone, Parser)]
+#[command(version, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input file with transactions
+    #[cfg_attr(
+        all(feature = "server", not(feature = "grpc")),
+        arg(required_unless_present_any = ["serve", "transition_matrix"])
+    )]
+    #[cfg_attr(
+        feature = "grpc",
+        arg(required_unless_present_any = ["serve", "grpc", "transition_matrix"])
+    )]
+    #[cfg_attr(
+        not(feature = "server"),
+        arg(required_unless_present = "transition_matrix")
+    )]
+    input: Option<PathBuf>,
+
+    /// Allow disputing withdrawals, crediting the disputed amount back as held funds
+    #[arg(long)]
+    allow_withdrawal_disputes: bool,
+
+    /// Hold disputed funds even if they have been withdrawn, letting available balance go negative
+    #[arg(long)]
+    allow_dispute_overdraft: bool,
+
+    /// Unlock accounts when a chargeback is reversed by a representment
+    #[arg(long)]
+    unlock_on_representment: bool,
+
+    /// Accept deposits to locked accounts, e.g. repayments of a negative balance
+    #[arg(long)]
+    allow_deposits_to_locked: bool,
+
+    /// Allow disputing a transaction again after its dispute has been resolved
+    #[arg(long)]
+    allow_redisputes: bool,
+
+    /// Maximum number of disputes of a single transaction
+    #[arg(long, value_name = "N")]
+    max_disputes: Option<u32>,
+
+    /// Reject disputes filed more than DAYS after the disputed transaction
+    #[arg(long, value_name = "DAYS")]
+    dispute_window: Option<u32>,
+
+    /// Skip transactions identical to an earlier one with the same id instead of rejecting them
+    #[arg(long)]
+    ignore_identical_duplicates: bool,
+
+    /// Skip a resolve of a resolved transaction or a chargeback of a charged back one instead of
+    /// rejecting it, e.g. when the upstream retries them
+    #[arg(long)]
+    ignore_repeated_outcomes: bool,
+
+    /// Charge fees even if it makes the available balance negative
+    #[arg(long)]
+    allow_fee_overdraft: bool,
+
+    /// Apply administrative debit adjustments even if it makes the available balance negative
+    #[arg(long)]
+    allow_adjustment_overdraft: bool,
+
+    /// How far below zero withdrawals may take the available balance
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_amount_arg, default_value = "0")]
+    overdraft_limit: Amount,
+
+    /// Minimum available balance withdrawals must leave for an account class, can be repeated
+    #[arg(long, value_name = "CLASS=AMOUNT", value_parser = parse_class_amount_arg)]
+    minimum_balance: Vec<(String, Amount)>,
+
+    /// Largest withdrawal, transfer or authorization of accounts with a risk flag, can be repeated
+    #[arg(long, value_name = "FLAG=AMOUNT", value_parser = parse_flag_amount_arg)]
+    flag_amount_limit: Vec<(u32, Amount)>,
+
+    /// Largest amount of a single deposit, withdrawal, transfer or authorization
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_amount_arg)]
+    max_amount: Option<Amount>,
+
+    /// Reject any transaction whose amount exceeds AMOUNT, e.g. a mistyped deposit, counting them in the summary
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_amount_arg)]
+    amount_cap: Option<Amount>,
+
+    /// Largest daily total of deposits, withdrawals, transfers and authorizations of a client
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_amount_arg)]
+    max_daily_total: Option<Amount>,
+
+    /// Maximum number of deposits, withdrawals, transfers and authorizations of a client within the transaction window
+    #[arg(long, value_name = "N")]
+    max_transactions: Option<u32>,
+
+    /// Length of the window of --max-transactions in seconds [default: 3600]
+    #[arg(long, value_name = "SECONDS")]
+    transaction_window: Option<u64>,
+
+    /// JSON file with states and types of transactions that disputes, resolves, chargebacks,
+    /// representments and reversals can be applied to, replacing --allow-withdrawal-disputes and
+    /// --allow-redisputes
+    #[arg(long, value_name = "FILE", value_parser = parse_transitions_arg)]
+    transitions: Option<Transitions>,
+
+    /// Print the outcome of disputes, resolves, chargebacks, representments and reversals of
+    /// transactions in each state as JSON, following the policy given by the other options, and exit
+    #[arg(long, conflicts_with = "input")]
+    transition_matrix: bool,
+
+    /// What to do with amounts with more than four decimal places: keep, reject, round-half-even,
+    /// round-half-up or truncate
+    #[arg(long, value_name = "ACTION", default_value = "keep")]
+    excess_precision: ExcessPrecision,
+
+    /// Validate the header of the input up front and report invalid values of each column of a row
+    #[arg(long)]
+    strict_schema: bool,
+
+    /// Ignore columns of the input not matching any field of a transaction in strict mode
+    #[arg(long, requires = "strict_schema")]
+    allow_unknown_columns: bool,
+
+    /// Write invalid columns of rejected input rows to FILE (line, column, value, reason)
+    #[arg(long, value_name = "FILE")]
+    rejects_report: Option<PathBuf>,
+
+    /// Write a fingerprint of the state after each transaction to FILE, chained so that comparing
+    /// the files of two replays shows the transaction after which they diverged
+    #[arg(long, value_name = "FILE")]
+    fingerprint_trail: Option<PathBuf>,
+
+    /// Write transactions causing an integrity error to FILE and continue processing, flagging
+    /// their accounts, instead of ending it
+    #[arg(long, value_name = "FILE")]
+    quarantine: Option<PathBuf>,
+
+    /// Flag set on accounts of quarantined transactions
+    #[arg(long, value_name = "FLAG", default_value_t = quarantine::DEFAULT_FLAG)]
+    quarantine_flag: u32,
+
+    /// What to do on errors of the kind (e.g. TransactionClientMismatch=fatal or
+    /// FundsNotLocked=quarantine): log, quarantine (requires --quarantine) or fatal
+    ///
+    /// By default, transaction errors are logged and integrity errors are fatal, or quarantined
+    /// with --quarantine.
+    #[arg(long, value_name = "KIND=SEVERITY", value_parser = parse_error_severity_arg)]
+    error_severity: Vec<(String, Severity)>,
+
+    /// Version of the input schema, v1 or v2, validating the header against it; detected from
+    /// the header if not given
+    #[arg(long, value_name = "VERSION")]
+    schema_version: Option<SchemaVersion>,
+
+    /// Check that timestamps don't decrease across all transactions (global) or per client (client)
+    #[arg(long, value_name = "SCOPE")]
+    ordering: Option<OrderingScope>,
+
+    /// What to do with out of order transactions: warn, reject or reorder
+    #[arg(
+        long,
+        value_name = "ACTION",
+        default_value = "warn",
+        requires = "ordering"
+    )]
+    out_of_order: OutOfOrderAction,
+
+    /// How many seconds of transaction time records are delayed to be reordered
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    reorder_window: u64,
+
+    /// Park transactions referencing unknown transactions and retry them when the referenced one arrives
+    #[arg(long)]
+    suspend_unknown_references: bool,
+
+    /// Give up on a parked transaction if the referenced one doesn't arrive within the next N transactions
+    #[arg(long, value_name = "N", requires = "suspend_unknown_references")]
+    suspense_lookahead: Option<u64>,
+
+    /// Check invariants of the state (balances match movements, held funds match open disputes and
+    /// authorizations) every N transactions, stopping on a violation
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "storage"
+    )]
+    audit_interval: Option<u64>,
+
+    /// Keep a double-entry ledger against house accounts and write its trial balance to FILE
+    #[arg(long, value_name = "FILE")]
+    trial_balance: Option<PathBuf>,
+
+    /// Write every posting of the double-entry ledger to FILE as it's made, with the tag of the
+    /// transaction (or of the transaction it refers to) to join it back to upstream records
+    #[arg(long, value_name = "FILE")]
+    ledger_journal: Option<PathBuf>,
+
+    /// Write daily net pay-in and pay-out amounts per client to FILE (requires timestamps)
+    #[arg(long, value_name = "FILE")]
+    settlement: Option<PathBuf>,
+
+    /// Write accounts without activity for more than --dormancy-days to FILE (requires timestamps)
+    #[arg(long, value_name = "FILE")]
+    dormant_accounts: Option<PathBuf>,
+
+    /// Number of days without activity after which an account is dormant
+    #[arg(long, value_name = "DAYS", default_value_t = 365)]
+    dormancy_days: u32,
+
+    /// Write clients with anomalous patterns of activity to FILE: many disputes, a high
+    /// chargeback ratio or rapid deposit-withdrawal cycles, see the thresholds below
+    #[arg(long, value_name = "FILE")]
+    suspicious_activity: Option<PathBuf>,
+
+    /// Number of disputes of a client's transactions from which it's suspicious
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        requires = "suspicious_activity"
+    )]
+    suspicious_disputes: u64,
+
+    /// Chargebacks per deposit from which a client is suspicious
+    #[arg(
+        long,
+        value_name = "RATIO",
+        default_value_t = 0.25,
+        requires = "suspicious_activity"
+    )]
+    suspicious_chargeback_ratio: f64,
+
+    /// Number of rapid deposit-withdrawal cycles from which a client is suspicious
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        requires = "suspicious_activity"
+    )]
+    suspicious_cycles: u64,
+
+    /// Seconds after a deposit within which a withdrawal makes a rapid cycle (requires timestamps)
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 3600,
+        requires = "suspicious_activity"
+    )]
+    cycle_window: u64,
+
+    /// Append fraud heuristics of the client to the accounts: the number of disputes of its
+    /// transactions, its ratio of chargebacks to deposits and the number of its transactions
+    /// when its account got locked (`disputes`, `chargeback_ratio` and `locked_after` columns)
+    #[arg(long)]
+    fraud_heuristics: bool,
+
+    /// Write lifetime deposited, disputed and charged back amounts of every client to FILE, with
+    /// the ratio of disputed to deposited amounts
+    #[arg(long, value_name = "FILE")]
+    exposure_report: Option<PathBuf>,
+
+    /// Restore the state from a snapshot in FILE before processing the input
+    ///
+    /// The policy options given on the command line replace the saved ones, client settings
+    /// are applied on top of the saved ones.
+    #[arg(long, value_name = "FILE")]
+    load_snapshot: Option<PathBuf>,
+
+    /// Seed balances, locks and flags of accounts from FILE, written to the output by a previous run
+    ///
+    /// Unlike a snapshot, it doesn't restore transactions of the previous run, so they can't be
+    /// disputed, and the ledger only covers transactions of this run.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["load_snapshot", "storage"]
+    )]
+    initial_accounts: Option<PathBuf>,
+
+    /// Keep accounts and transactions in persistent storage, continuing from its previous content
+    ///
+    /// Given as BACKEND:PATH, where BACKEND is sled, rocksdb or sqlite (depending on enabled features).
+    /// A Postgres database, which may be shared by several running instances, is given as
+    /// postgres:CONNECTION-STRING or as a postgres:// URL.
+    #[arg(long, value_name = "BACKEND:PATH", conflicts_with = "load_snapshot")]
+    storage: Option<StorageLocation>,
+
+    /// Number of transactions per tenant kept in memory when using --storage
+    #[arg(long, value_name = "N", default_value_t = storage::DEFAULT_CACHE_CAPACITY)]
+    cache_capacity: usize,
+
+    /// Write a snapshot of the state to FILE after processing the input
+    #[arg(long, value_name = "FILE")]
+    save_snapshot: Option<PathBuf>,
+
+    /// Periodically save the progress of processing the input to FILE, see --resume
+    #[arg(long, value_name = "FILE", conflicts_with = "storage")]
+    checkpoint: Option<PathBuf>,
+
+    /// Number of input records processed between checkpoints
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1_000_000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    checkpoint_interval: u64,
+
+    /// Continue an interrupted run from the checkpoint in the --checkpoint FILE
+    ///
+    /// Input records processed before the checkpoint are skipped, so the input file must be the
+    /// same as in the interrupted run (it may have grown since). Other options should be the same
+    /// too, policy and client settings are applied as with --load-snapshot.
+    #[arg(
+        long,
+        requires = "checkpoint",
+        conflicts_with_all = ["load_snapshot", "initial_accounts"]
+    )]
+    resume: bool,
+
+    /// CSV file with per-client settings (client, overdraft_limit, class, max_amount, max_daily_total, max_transactions, country, flags)
+    #[arg(long, value_name = "FILE")]
+    client_settings: Option<PathBuf>,
+
+    /// JSON file with compliance rules evaluated before every transaction: conditions on its type,
+    /// currency, amount and the cumulative amount of the client's transactions, and on flags,
+    /// class or country of the client, recording matches or blocking the transaction
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+
+    /// Write every match of a compliance rule to FILE (line, tenant, client, tx, type, tag, rule
+    /// and action)
+    #[arg(long, value_name = "FILE", requires = "rules")]
+    rule_audit: Option<PathBuf>,
+
+    /// Log only the first N warnings of each kind (e.g. insufficient funds), then every N-th one,
+    /// reporting the number of the rest at the end
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    warning_limit: Option<u64>,
+
+    /// Stop processing with an error after N warnings, e.g. on a corrupt input
+    #[arg(long, value_name = "N")]
+    warning_budget: Option<u64>,
+
+    /// Collapse consecutive warnings of the same kind about the same client into a single
+    /// "repeated N more times" message
+    #[arg(long)]
+    dedup_warnings: bool,
+
+    /// Report the number of repeats of a warning every N repeats while it keeps repeating
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = warnings::DEFAULT_DEDUP_FLUSH,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "dedup_warnings"
+    )]
+    dedup_flush: u64,
+
+    /// Print metrics of the run to stderr when it ends: throughput, time of each phase and
+    /// numbers of accepted and rejected transactions, as text or json (--metrics=json)
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    metrics: Option<MetricsFormat>,
+
+    /// Print progress of reading the input to stderr every SECONDS (10 by default): records
+    /// read, share of the input, records per second and the estimated time left
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    progress: Option<u64>,
+
+    /// Write the accounts and progress of the run so far to FILE as JSON on SIGUSR1, continuing
+    /// processing, e.g. to peek at interim results of a long run
+    #[cfg(unix)]
+    #[arg(long, value_name = "FILE")]
+    dump: Option<PathBuf>,
+
+    /// Print the approximate memory taken by the accounts and the history to stderr every N input
+    /// records, e.g. to plan capacity for long runs
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    memory_interval: Option<u64>,
+
+    /// Write a self-contained HTML report of the run to FILE: summary, charts of transactions by
+    /// type and rejections by kind, and accounts with the largest exposure
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Write the summary of the run to FILE, e.g. to attach it to the batch job
+    #[arg(long, value_name = "FILE")]
+    summary: Option<PathBuf>,
+
+    /// Format of --summary: json, or markdown with tables of counts by type, funds by currency
+    /// and the most frequent rejections, to be pasted into tickets or pull requests
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "json",
+        requires = "summary"
+    )]
+    summary_format: SummaryFormat,
+
+    /// Render the summary with the Handlebars template in FILE instead of --summary-format
+    #[cfg(feature = "templates")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = parse_template_arg,
+        requires = "summary"
+    )]
+    summary_template: Option<Template>,
+
+    /// Render the accounts with the Handlebars template in FILE instead of writing them as CSV,
+    /// e.g. as a fixed-width bank file; it gets the run id, the accounts and the summary (see
+    /// `src/template.rs` for the helpers)
+    #[cfg(feature = "templates")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = parse_template_arg,
+        conflicts_with = "fraud_heuristics"
+    )]
+    template: Option<Template>,
+
+    /// Identifier of the run attached to every log message, e.g. the id of the batch job;
+    /// generated from the start time and the process id if not given
+    #[arg(long, value_name = "ID")]
+    run_id: Option<String>,
+
+    /// Write a manifest of the run to FILE as JSON: engine version, arguments and policy in
+    /// effect, SHA-256 hashes of the files read and written, counts and the final fingerprint,
+    /// so that published accounts can be traced back to what produced them
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Serve the REST API on ADDR instead of processing an input file, until SIGINT or SIGTERM
+    ///
+    /// On shutdown, requests in progress are finished, the state is flushed
+    /// and the snapshot saved (if requested), then the process exits with code 3.
+    #[cfg(feature = "server")]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = [
+            "input",
+            "checkpoint",
+            "ordering",
+            "suspend_unknown_references",
+            "trial_balance",
+            "ledger_journal",
+            "rules",
+            "rule_audit",
+            "settlement",
+            "dormant_accounts",
+            "suspicious_activity",
+            "fraud_heuristics",
+            "exposure_report",
+            "manifest",
+        ]
+    )]
+    serve: Option<SocketAddr>,
+
+    /// Serve the gRPC service on ADDR instead of processing an input file, along with --serve if given
+    #[cfg(feature = "grpc")]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = [
+            "input",
+            "checkpoint",
+            "ordering",
+            "suspend_unknown_references",
+            "trial_balance",
+            "ledger_journal",
+            "rules",
+            "rule_audit",
+            "settlement",
+            "dormant_accounts",
+            "suspicious_activity",
+            "fraud_heuristics",
+            "exposure_report",
+            "manifest",
+        ]
+    )]
+    grpc: Option<SocketAddr>,
 
-pub mod model;
-#[cfg(test)]
-mod tests;
+    /// POST events of accounts as JSON to a webhook when serving, given as [EVENTS=]URL with
+    /// EVENTS a comma-separated list of account_locked, chargeback_applied and dispute_opened
+    /// (all if not given); repeatable
+    #[cfg(feature = "webhooks")]
+    #[arg(long, value_name = "[EVENTS=]URL", conflicts_with = "input")]
+    webhook: Vec<Webhook>,
 
-use model::{CephalopodError, State};
+    /// Number of attempts at delivering an event to a webhook
+    #[cfg(feature = "webhooks")]
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    webhook_attempts: u32,
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-struct ExportedClient {
+    /// Seconds to wait before retrying a failed delivery to a webhook, doubled for every
+    /// following attempt up to 5 minutes
+    #[cfg(feature = "webhooks")]
+    #[arg(long, value_name = "SECONDS", default_value_t = 1)]
+    webhook_backoff: u64,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Apply ad-hoc transactions, query accounts and undo interactively, following the policy
+    /// given by the options (see `help` in the session)
+    Repl {
+        /// Snapshot to start from, e.g. written by --save-snapshot
+        snapshot: Option<PathBuf>,
+    },
+    /// Process the input following the options and compare the resulting accounts with expected
+    /// balances, printing discrepancies as CSV instead of the accounts
+    ///
+    /// Exits with code 4 if there are any discrepancies.
+    Reconcile {
+        /// CSV file with expected balances in the format of the output (tenant, client, currency,
+        /// available, held, total, locked); columns left out or empty aren't compared
+        #[arg(long, value_name = "FILE")]
+        expected: PathBuf,
+
+        /// Largest difference of amounts still considered a match
+        #[arg(long, value_name = "AMOUNT", value_parser = parse_amount_arg, default_value = "0")]
+        tolerance: Amount,
+
+        /// Tolerance of amounts in a currency, given as CURRENCY=AMOUNT, overriding --tolerance
+        #[arg(long, value_name = "CURRENCY=AMOUNT", value_parser = parse_currency_amount_arg)]
+        currency_tolerance: Vec<(Currency, Amount)>,
+
+        /// Ignore accounts missing from the expected balances instead of expecting no funds on them
+        #[arg(long)]
+        only_listed: bool,
+
+        /// Input file with transactions
+        input: PathBuf,
+    },
+    /// Recompute the state from the transactions, following the options, and compare it with a
+    /// saved snapshot and the trial balance written along with it, printing any divergence
+    ///
+    /// Exits with code 4 if there is any divergence.
+    Verify {
+        /// Snapshot written by --save-snapshot of the run to check
+        #[arg(long, value_name = "FILE")]
+        snapshot: PathBuf,
+
+        /// Trial balance written by --trial-balance of the run to check
+        #[arg(long, value_name = "FILE")]
+        trial_balance: Option<PathBuf>,
+
+        /// Input file with the transactions processed by the run, starting from the same state
+        input: PathBuf,
+    },
+    /// Write the input with client, transaction and tenant ids replaced consistently to stdout,
+    /// e.g. to share a production file reproducing a bug
+    ///
+    /// Ids are replaced with their ranks, keeping their order. Other columns are kept as they
+    /// are, including invalid values.
+    Anonymize {
+        /// Multiply amounts by FACTOR, rounding them to four decimal places; whole factors keep
+        /// the outcome of every transaction, others may change outcomes depending on exact sums
+        #[arg(long, value_name = "FACTOR", value_parser = parse_factor_arg)]
+        scale_amounts: Option<Decimal>,
+
+        /// Input file with transactions
+        input: PathBuf,
+    },
+    /// Write the records of the input affecting the clients to stdout, e.g. to attach a small
+    /// input reproducing their accounts to a bug report
+    ///
+    /// Records of clients they've made transfers with, and other records with the same
+    /// transaction ids (e.g. disputes), are kept as well.
+    Extract {
+        /// Clients to keep records of, separated with commas
+        #[arg(long, value_name = "CLIENTS", value_delimiter = ',', required = true)]
+        clients: Vec<u16>,
+
+        /// Input file with transactions
+        input: PathBuf,
+    },
+    /// Write a raw partner file in the canonical format to stdout, printing what has been fixed
+    ///
+    /// The file is decoded to UTF-8 and split with its own delimiter, values are trimmed, types
+    /// lowercased and decimal commas of amounts replaced. Empty rows and repeated deposits,
+    /// withdrawals, transfers and authorizations are dropped.
+    Normalize {
+        /// Encoding of the file: auto, utf-8, utf-16le, utf-16be or latin1; auto detects a byte
+        /// order mark and falls back to latin1 if the file isn't valid UTF-8
+        #[arg(long, value_name = "ENCODING", default_value = "auto")]
+        encoding: Encoding,
+
+        /// Delimiter of the file, a single character or tab, detected from the header if not given
+        #[arg(long, value_name = "CHAR", value_parser = parse_delimiter_arg)]
+        delimiter: Option<u8>,
+
+        /// Sort rows by timestamp, keeping rows without one after the row before them
+        #[arg(long)]
+        sort: bool,
+
+        /// Raw input file
+        input: PathBuf,
+    },
+    /// Partition the input into shards to be processed in parallel, keeping the records of each
+    /// client together and in order
+    ///
+    /// Clients who made transfers with each other, or whose records share transaction ids, are
+    /// kept in the same shard, so that each shard can be processed on its own. Shards are written
+    /// as INPUT_STEM-N.csv, numbered from 0.
+    Split {
+        /// Number of shards
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        shards: u32,
+
+        /// Directory to write the shards to
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        out_dir: PathBuf,
+
+        /// Input file with transactions
+        input: PathBuf,
+    },
+    /// Write the accounts of runs over shards written by `split` to stdout as a single output
+    ///
+    /// Clients found in more than one shard are logged as conflicts, keeping their accounts from
+    /// the first shard listing them, and the command exits with code 4.
+    Merge {
+        /// Accounts written by the runs, or snapshots saved by them with --save-snapshot
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+}
+
+/// Results of a run to compare the recomputed ones with, read before processing
+enum Check {
+    Reconcile {
+        expected: Vec<ExpectedBalance>,
+        tolerance: Tolerance,
+        only_listed: bool,
+    },
+    Verify {
+        snapshot: Box<Tenants>,
+        trial_balance: Option<Vec<TrialBalanceRow>>,
+    },
+}
+
+impl Args {
+    /// Whether the summary is rendered with a template rather than written in --summary-format
+    fn renders_summary(&self) -> bool {
+        #[cfg(feature = "templates")]
+        if self.summary_template.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Whether the ledger should be kept, to write the trial balance or to verify it
+    fn keeps_ledger(&self) -> bool {
+        self.trial_balance.is_some()
+            || self.ledger_journal.is_some()
+            || matches!(
+                &self.command,
+                Some(Command::Verify {
+                    trial_balance: Some(_),
+                    ..
+                })
+            )
+    }
+
+    /// Whether the engine should be served over the network instead of processing an input file
+    #[cfg(feature = "server")]
+    fn serving(&self) -> bool {
+        #[cfg(feature = "grpc")]
+        if self.grpc.is_some() {
+            return true;
+        }
+        self.serve.is_some()
+    }
+
+    fn policy(&self) -> Policy {
+        Policy {
+            allow_withdrawal_disputes: self.allow_withdrawal_disputes,
+            allow_dispute_overdraft: self.allow_dispute_overdraft,
+            unlock_on_representment: self.unlock_on_representment,
+            allow_deposits_to_locked: self.allow_deposits_to_locked,
+            allow_redisputes: self.allow_redisputes,
+            max_disputes: self.max_disputes,
+            dispute_window_days: self.dispute_window,
+            ignore_identical_duplicates: self.ignore_identical_duplicates,
+            ignore_repeated_outcomes: self.ignore_repeated_outcomes,
+            allow_fee_overdraft: self.allow_fee_overdraft,
+            allow_adjustment_overdraft: self.allow_adjustment_overdraft,
+            overdraft_limit: self.overdraft_limit,
+            minimum_balances: self.minimum_balance.iter().cloned().collect(),
+            flag_amount_limits: self.flag_amount_limit.iter().copied().collect(),
+            max_amount: self.max_amount,
+            max_daily_total: self.max_daily_total,
+            amount_cap: self.amount_cap,
+            max_transactions: self.max_transactions,
+            transaction_window: self.transaction_window,
+            excess_precision: self.excess_precision,
+            transitions: self.transitions.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct DormantAccount {
+    tenant: Option<u32>,
     client: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
+    last_activity: u64,
 }
 
-fn main() -> Result<(), String> {
-    pretty_env_logger::init();
+/// Input row that couldn't be parsed, with its invalid columns if they're known
+struct RejectedRow {
+    line: Option<u64>,
+    fields: Vec<FieldError>,
+    error: String,
+}
+
+impl RejectedRow {
+    /// Writes a row of the report for each invalid column, or a single one with the error
+    fn report(&self, wtr: &mut csv::Writer<File>) -> csv::Result<()> {
+        if self.fields.is_empty() {
+            return wtr.serialize(RejectedField {
+                line: self.line,
+                column: "",
+                value: "",
+                reason: &self.error,
+            });
+        }
+        for field in &self.fields {
+            wtr.serialize(RejectedField {
+                line: self.line,
+                column: field.column,
+                value: &field.value,
+                reason: &field.reason,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RejectedRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.fields.is_empty() {
+            return write!(f, "parse error: {}", self.error);
+        }
+        let fields: Vec<String> = self.fields.iter().map(ToString::to_string).collect();
+        write!(
+            f,
+            "invalid values at line {}: {}",
+            self.line.unwrap_or_default(),
+            fields.join("; ")
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RejectedField<'a> {
+    line: Option<u64>,
+    column: &'a str,
+    value: &'a str,
+    reason: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct QuarantinedTransaction {
+    line: Option<u64>,
+    tenant: Option<u32>,
+    #[serde(rename = "type")]
+    tpe: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Amount>,
+    tag: Tag,
+    error: String,
+}
+
+/// Progress of processing the input, saved periodically to continue an interrupted run
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    processor: Processor,
+    sequencer: Option<Sequencer>,
+    /// Position of the first input record not processed yet, see `csv::Position`
+    byte: u64,
+    line: u64,
+    record: u64,
+}
+
+impl Checkpoint {
+    fn position(&self) -> Position {
+        let mut position = Position::new();
+        position
+            .set_byte(self.byte)
+            .set_line(self.line)
+            .set_record(self.record);
+        position
+    }
+
+    fn set_position(&mut self, position: &Position) {
+        self.byte = position.byte();
+        self.line = position.line();
+        self.record = position.record();
+    }
+
+    /// Saves the checkpoint, replacing the previous one only once it is fully written
+    fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        snapshot::save(Path::new(&partial), self)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+fn open_tenants(args: &Args) -> Result<Tenants, String> {
+    let location = match &args.storage {
+        Some(location) => location,
+        None => return Ok(Tenants::new(args.policy())),
+    };
+    let (existing, open) = location.open().map_err(|err| {
+        error!("Problem opening storage: {}", err);
+        format!("Problem opening storage: {}", err)
+    })?;
+    let mut tenants = Tenants::with_storage(args.policy(), open, args.cache_capacity);
+    for tenant in existing {
+        tenants.state_mut(tenant).map_err(|err| {
+            error!("Problem opening state of the tenant: {}", err);
+            format!("Problem opening state of the tenant: {}", err)
+        })?;
+    }
+    Ok(tenants)
+}
+
+/// Restores the tenants from the snapshot or opens them from the storage, as requested
+fn load_tenants(args: &Args) -> Result<Tenants, String> {
+    match &args.load_snapshot {
+        Some(path) => {
+            let mut tenants = Tenants::load(path).map_err(|err| {
+                error!("Problem loading snapshot: {}", err);
+                format!("Problem loading snapshot: {}", err)
+            })?;
+            tenants.set_policy(args.policy());
+            Ok(tenants)
+        }
+        None => open_tenants(args),
+    }
+}
 
-    let args: Vec<String> = std::env::args().collect();
+/// Loads compliance rules from a JSON file, validating them
+fn load_rules(path: &Path) -> Result<Rules, String> {
+    let config = File::open(path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            serde_json::from_reader::<_, RulesConfig>(io::BufReader::new(file))
+                .map_err(|err| err.to_string())
+        })
+        .and_then(|config| config.validate().map(|()| config))
+        .map_err(|err| {
+            error!("Problem loading rules: {}", err);
+            format!("Problem loading rules: {}", err)
+        })?;
+    Ok(Rules::new(config))
+}
 
-    if args.len() != 2 {
-        println!("Usage: {} transactions.csv", args[0]);
-        return Err(format!("Usage: {} transactions.csv", args[0]));
+/// Applies client settings and seeds initial accounts, if requested
+fn configure_tenants(args: &Args, tenants: &mut Tenants) -> Result<(), String> {
+    if let Some(path) = &args.client_settings {
+        let mut settings_rdr = csv::Reader::from_path(path).map_err(|err| {
+            error!("Problem opening client settings file: {}", err);
+            format!("Problem opening client settings file: {}", err)
+        })?;
+        for result in settings_rdr.deserialize::<ClientSettings>() {
+            let settings = result.map_err(|err| {
+                error!("Invalid client settings: {}", err);
+                format!("Invalid client settings: {}", err)
+            })?;
+            tenants.set_client_settings(settings);
+        }
     }
 
-    let ref path = args[1];
+    if let Some(path) = &args.initial_accounts {
+        let mut accounts_rdr = csv::Reader::from_path(path).map_err(|err| {
+            error!("Problem opening initial accounts file: {}", err);
+            format!("Problem opening initial accounts file: {}", err)
+        })?;
+        for result in accounts_rdr.deserialize::<ExportedClient>() {
+            let account = result.map_err(|err| {
+                error!("Invalid initial account: {}", err);
+                format!("Invalid initial account: {}", err)
+            })?;
+            seed_account(tenants, &account)?;
+        }
+    }
+    Ok(())
+}
+
+/// Persists the tenants to the storage and the snapshot, if requested
+fn save_tenants(args: &Args, tenants: &mut Tenants) -> Result<(), String> {
+    tenants.flush().map_err(|err| {
+        error!("Problem flushing storage: {}", err);
+        format!("Problem flushing storage: {}", err)
+    })?;
+
+    if let Some(path) = &args.save_snapshot {
+        tenants.save(path).map_err(|err| {
+            error!("Problem saving snapshot: {}", err);
+            format!("Problem saving snapshot: {}", err)
+        })?;
+    }
+    Ok(())
+}
+
+/// Runs an interactive session on the tenants restored from the snapshot, or empty ones
+fn repl(args: &Args, snapshot: Option<&Path>) -> Result<(), String> {
+    let mut tenants = match snapshot {
+        Some(path) => {
+            let mut tenants = Tenants::load(path).map_err(|err| {
+                error!("Problem loading snapshot: {}", err);
+                format!("Problem loading snapshot: {}", err)
+            })?;
+            tenants.set_policy(args.policy());
+            tenants
+        }
+        None => Tenants::new(args.policy()),
+    };
+    configure_tenants(args, &mut tenants)?;
+    let mut repl = Repl::new(tenants);
+    // no prompt when commands are piped, e.g. from a file reproducing a scenario
+    let interactive = io::stdin().is_terminal();
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("> ");
+            io::stdout()
+                .flush()
+                .map_err(|err| format!("Problem writing prompt: {}", err))?;
+        }
+        let line = match lines.next() {
+            Some(line) => line.map_err(|err| format!("Problem reading command: {}", err))?,
+            None => break,
+        };
+        let command = line.trim();
+        if command == "quit" || command == "exit" {
+            break;
+        }
+        match repl.execute(command) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("error: {}", err),
+        }
+    }
+    Ok(())
+}
+
+fn anonymize(input: &Path, scale: Option<Decimal>) -> Result<(), String> {
+    let open = || {
+        csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_path(input)
+            .map_err(|err| {
+                error!("Problem opening input file: {}", err);
+                format!("Problem opening input file: {}", err)
+            })
+    };
+    let read_error = |err: csv::Error| {
+        error!("Problem reading input: {}", err);
+        format!("Problem reading input: {}", err)
+    };
+    // ids are replaced with their ranks, so all of them have to be known first
+    let mut rdr = open()?;
+    let headers = rdr.headers().map_err(read_error)?.clone();
+    let mut ids = Ids::new();
+    for record in rdr.records() {
+        ids.collect(&headers, &record.map_err(read_error)?);
+    }
+    let anonymizer = Anonymizer::new(&ids, scale);
+
+    let mut wtr = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(io::stdout());
+    let write_error = |err: csv::Error| {
+        error!("Problem writing output: {}", err);
+        format!("Problem writing output: {}", err)
+    };
+    wtr.write_record(&headers).map_err(write_error)?;
+    for record in open()?.records() {
+        let record = anonymizer.anonymize(&headers, &record.map_err(read_error)?);
+        wtr.write_record(&record).map_err(write_error)?;
+    }
+    wtr.flush()
+        .map_err(|err| format!("Problem writing output: {}", err))
+}
+
+fn extract(input: &Path, clients: &[u16]) -> Result<(), String> {
+    let open = || {
+        csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_path(input)
+            .map_err(|err| {
+                error!("Problem opening input file: {}", err);
+                format!("Problem opening input file: {}", err)
+            })
+    };
+    let read_error = |err: csv::Error| {
+        error!("Problem reading input: {}", err);
+        format!("Problem reading input: {}", err)
+    };
+    // records may depend on later ones, e.g. a deposit on a dispute filed by another client
+    let mut rdr = open()?;
+    let headers = rdr.headers().map_err(read_error)?.clone();
+    let mut ids = Vec::new();
+    for record in rdr.records() {
+        ids.push(RecordIds::of(&headers, &record.map_err(read_error)?));
+    }
+    let selected = extract::select(&ids, clients);
+
+    let mut wtr = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(io::stdout());
+    let write_error = |err: csv::Error| {
+        error!("Problem writing output: {}", err);
+        format!("Problem writing output: {}", err)
+    };
+    wtr.write_record(&headers).map_err(write_error)?;
+    for (record, keep) in open()?.records().zip(selected) {
+        let record = record.map_err(read_error)?;
+        if keep {
+            wtr.write_record(&record).map_err(write_error)?;
+        }
+    }
+    wtr.flush()
+        .map_err(|err| format!("Problem writing output: {}", err))
+}
 
+fn normalize(input: &Path, options: &NormalizeOptions) -> Result<(), String> {
+    let raw = fs::read(input).map_err(|err| {
+        error!("Problem opening input file: {}", err);
+        format!("Problem opening input file: {}", err)
+    })?;
+    let stdout = io::stdout();
+    let fixes = normalize::normalize(&raw, options, stdout.lock()).map_err(|err| {
+        error!("Problem normalizing input: {}", err);
+        format!("Problem normalizing input: {}", err)
+    })?;
+    eprintln!("Normalized: {}", fixes);
+    Ok(())
+}
+
+fn split(input: &Path, shards: u32, out_dir: &Path) -> Result<(), String> {
+    let open = || {
+        csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_path(input)
+            .map_err(|err| {
+                error!("Problem opening input file: {}", err);
+                format!("Problem opening input file: {}", err)
+            })
+    };
+    let read_error = |err: csv::Error| {
+        error!("Problem reading input: {}", err);
+        format!("Problem reading input: {}", err)
+    };
+    // clients are linked by later records as well, e.g. a transfer at the end of the file
+    let mut rdr = open()?;
+    let headers = rdr.headers().map_err(read_error)?.clone();
+    let mut ids = Vec::new();
+    for record in rdr.records() {
+        ids.push(RecordIds::of(&headers, &record.map_err(read_error)?));
+    }
+    let assigned = split::assign(&ids, shards);
+
+    let stem = input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "shard".to_string());
+    let width = (shards - 1).to_string().len();
+    let write_error = |err: csv::Error| {
+        error!("Problem writing shard: {}", err);
+        format!("Problem writing shard: {}", err)
+    };
+    let mut paths = Vec::new();
+    let mut writers = Vec::new();
+    for shard in 0..shards {
+        let path = out_dir.join(format!("{}-{:0width$}.csv", stem, shard, width = width));
+        let mut wtr = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_path(&path)
+            .map_err(|err| {
+                error!("Problem creating shard file: {}", err);
+                format!("Problem creating shard file: {}", err)
+            })?;
+        wtr.write_record(&headers).map_err(write_error)?;
+        paths.push(path);
+        writers.push(wtr);
+    }
+    let mut counts = vec![0u64; shards as usize];
+    for (record, &shard) in open()?.records().zip(&assigned) {
+        writers[shard as usize]
+            .write_record(&record.map_err(read_error)?)
+            .map_err(write_error)?;
+        counts[shard as usize] += 1;
+    }
+    for ((mut wtr, path), count) in writers.into_iter().zip(&paths).zip(counts) {
+        wtr.flush()
+            .map_err(|err| format!("Problem writing shard: {}", err))?;
+        info!("Wrote {} records to {}", count, path.display());
+    }
+    Ok(())
+}
+
+/// Reads the accounts written by a run over a shard, or saved in its snapshot
+fn read_shard(path: &Path) -> Result<Vec<ExportedClient>, String> {
+    match Tenants::load(path) {
+        Ok(tenants) => return Ok(export::accounts(&tenants).collect()),
+        Err(SnapshotError::InvalidHeader) => {}
+        Err(err) => {
+            error!("Problem loading snapshot {}: {}", path.display(), err);
+            return Err(format!(
+                "Problem loading snapshot {}: {}",
+                path.display(),
+                err
+            ));
+        }
+    }
+    let mut rdr = csv::Reader::from_path(path).map_err(|err| {
+        error!("Problem opening accounts file: {}", err);
+        format!("Problem opening accounts file: {}", err)
+    })?;
+    rdr.deserialize().collect::<Result<_, _>>().map_err(|err| {
+        error!("Invalid account in {}: {}", path.display(), err);
+        format!("Invalid account in {}: {}", path.display(), err)
+    })
+}
+
+fn merge(inputs: &[PathBuf]) -> Result<(), String> {
+    let shards = inputs
+        .iter()
+        .map(|path| read_shard(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (accounts, conflicts) = merge::merge(shards);
+    info!(
+        "Merged {} accounts from {} shards",
+        accounts.len(),
+        inputs.len()
+    );
+    export::write_rows(accounts, io::stdout());
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    for conflict in &conflicts {
+        let shards: Vec<String> = conflict
+            .shards
+            .iter()
+            .map(|&shard| inputs[shard].display().to_string())
+            .collect();
+        match conflict.tenant {
+            Some(tenant) => error!(
+                "Tenant {} client {} found in more than one shard: {}",
+                tenant,
+                conflict.client,
+                shards.join(", ")
+            ),
+            None => error!(
+                "Client {} found in more than one shard: {}",
+                conflict.client,
+                shards.join(", ")
+            ),
+        }
+    }
+    error!(
+        "Shards aren't disjoint by client, conflicts: {}",
+        conflicts.len()
+    );
+    process::exit(DISCREPANCIES_EXIT_CODE);
+}
+
+#[cfg(feature = "server")]
+fn serve(args: Args) -> Result<(), String> {
+    let rest = args.serve;
+    #[cfg(feature = "grpc")]
+    let grpc = args.grpc;
+    #[cfg(feature = "webhooks")]
+    let (webhooks, retry) = (
+        args.webhook.clone(),
+        Retry {
+            attempts: args.webhook_attempts,
+            backoff: Duration::from_secs(args.webhook_backoff),
+        },
+    );
+    let init_args = args.clone();
+    let (engine, thread) = EngineHandle::spawn(
+        move || {
+            let mut tenants = load_tenants(&init_args)?;
+            configure_tenants(&init_args, &mut tenants)?;
+            Ok(tenants)
+        },
+        move |tenants, summary| {
+            let summary = Summary {
+                fees_collected: tenants.fees_collected(),
+                fingerprint: Some(tenants.fingerprint()),
+                memory: Some(tenants.memory_usage()),
+                ..summary.clone()
+            };
+            info!("Summary: {}", summary);
+            save_tenants(&args, tenants)
+        },
+    )?;
+    let runtime = tokio::runtime::Runtime::new().map_err(|err| {
+        error!("Problem starting async runtime: {}", err);
+        format!("Problem starting async runtime: {}", err)
+    })?;
+    // subscribed before serving, so that no event is missed
+    #[cfg(feature = "webhooks")]
+    let mut deliveries = Vec::new();
+    #[cfg(feature = "webhooks")]
+    for webhook in webhooks {
+        let events = runtime
+            .block_on(engine.call(|engine| engine.subscribe_events()))
+            .ok_or_else(|| "Engine stopped before serving".to_string())?;
+        deliveries.push(runtime.spawn(server::deliver(webhook, retry, events).in_current_span()));
+    }
+    let mut servers = Vec::new();
+    if let Some(addr) = rest {
+        servers.push(runtime.spawn(server::serve(addr, engine.clone()).in_current_span()));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = grpc {
+        servers.push(runtime.spawn(server::serve_grpc(addr, engine.clone()).in_current_span()));
+    }
+    // the engine stops once the servers drop their handles
+    drop(engine);
+    for server in servers {
+        runtime
+            .block_on(server)
+            .map_err(|_| "Server task panicked".to_string())??;
+    }
+    thread
+        .join()
+        .map_err(|_| "Engine thread panicked".to_string())??;
+    // events of the last transactions are delivered once the engine has stopped
+    #[cfg(feature = "webhooks")]
+    for delivery in deliveries {
+        runtime
+            .block_on(delivery)
+            .map_err(|_| "Webhook task panicked".to_string())?;
+    }
+    Ok(())
+}
+
+/// Seeds the state with an account written to the output by a previous run
+fn seed_account(tenants: &mut Tenants, account: &ExportedClient) -> Result<(), String> {
+    let balance = Balance {
+        available: account.available,
+        held: account.held,
+    };
+    if account.total != balance.total() {
+        error!(
+            "Total of client {} doesn't match its available and held funds",
+            account.client
+        );
+        return Err(format!(
+            "Total of client {} doesn't match its available and held funds",
+            account.client
+        ));
+    }
+    let flags = account
+        .flags
+        .split(';')
+        .filter(|flag| !flag.is_empty())
+        .map(|flag| {
+            flag.parse()
+                .map_err(|err| format!("invalid flag {}: {}", flag, err))
+        })
+        .collect::<Result<_, String>>()
+        .map_err(|err| {
+            error!("Invalid initial account: {}", err);
+            format!("Invalid initial account: {}", err)
+        })?;
+    tenants
+        .seed_account(
+            account.tenant,
+            account.client,
+            account.currency,
+            balance,
+            account.locked,
+            &flags,
+        )
+        .map_err(|err| {
+            error!("Problem opening state of the tenant: {}", err);
+            format!("Problem opening state of the tenant: {}", err)
+        })
+}
+
+/// Interim results of a run, written on SIGUSR1 with --dump
+#[cfg(unix)]
+#[derive(Serialize)]
+struct Dump<'a> {
+    /// Line of the input processed last
+    line: u64,
+    records: u64,
+    bytes: u64,
+    total_bytes: Option<u64>,
+    elapsed_seconds: f64,
+    summary: &'a Summary,
+    accounts: Vec<ExportedClient>,
+}
+
+#[cfg(unix)]
+impl Dump<'_> {
+    /// Writes the dump, replacing the previous one only once it is fully written
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let file = File::create(&partial).map_err(|err| err.to_string())?;
+        serde_json::to_writer(io::BufWriter::new(file), self).map_err(|err| err.to_string())?;
+        fs::rename(&partial, path).map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TrailRow {
+    line: Option<u64>,
+    tenant: Option<u32>,
+    tx: u32,
+    tag: Tag,
+    fingerprint: String,
+}
+
+/// Opens a CSV report written while processing, appending to the one of the interrupted run on resume
+fn open_report(path: &Path, resume: bool, name: &str) -> Result<csv::Writer<File>, String> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(resume)
+        .truncate(!resume)
+        .open(path)
+        .map_err(|err| {
+            error!("Problem opening {} file: {}", name, err);
+            format!("Problem opening {} file: {}", name, err)
+        })?;
+    Ok(csv::WriterBuilder::new()
+        .has_headers(!resume)
+        .from_writer(file))
+}
+
+/// Flushes rows of the report written so far, if it is written
+fn flush_report(wtr: &mut Option<csv::Writer<File>>, name: &str) -> Result<(), String> {
+    if let Some(wtr) = wtr {
+        wtr.flush().map_err(|err| {
+            error!("Problem writing {} file: {}", name, err);
+            format!("Problem writing {} file: {}", name, err)
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes fingerprints recorded by the processor since the last call
+fn write_trail(processor: &mut Processor, wtr: &mut Option<csv::Writer<File>>) {
+    let (trail, wtr) = match (&mut processor.trail, wtr) {
+        (Some(trail), Some(wtr)) => (trail, wtr),
+        _ => return,
+    };
+    for entry in trail.drain() {
+        wtr.serialize(TrailRow {
+            line: entry.line,
+            tenant: entry.tenant,
+            tx: entry.tx,
+            tag: entry.tag,
+            fingerprint: entry.fingerprint.to_string(),
+        })
+        .unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
+
+/// Writes ledger postings made by the processor since the last call
+fn write_journal(processor: &mut Processor, wtr: &mut Option<csv::Writer<File>>) {
+    let (ledger, wtr) = match (&mut processor.ledger, wtr) {
+        (Some(ledger), Some(wtr)) => (ledger, wtr),
+        _ => return,
+    };
+    for entry in ledger.drain_journal() {
+        wtr.serialize(entry).unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
+
+/// Writes matches of compliance rules recorded by the processor since the last call
+fn write_rule_matches(processor: &mut Processor, wtr: &mut Option<csv::Writer<File>>) {
+    let (rules, wtr) = match (&mut processor.rules, wtr) {
+        (Some(rules), Some(wtr)) => (rules, wtr),
+        _ => return,
+    };
+    for entry in rules.drain_matches() {
+        wtr.serialize(entry).unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
+
+/// Writes transactions quarantined by the processor since the last call
+fn write_quarantined(processor: &mut Processor, wtr: &mut Option<csv::Writer<File>>) {
+    let (quarantine, wtr) = match (&mut processor.quarantine, wtr) {
+        (Some(quarantine), Some(wtr)) => (quarantine, wtr),
+        _ => return,
+    };
+    for (record, error) in quarantine.drain() {
+        let transaction = record.transaction;
+        wtr.serialize(QuarantinedTransaction {
+            line: record.line,
+            tenant: transaction.tenant,
+            tpe: transaction.tpe,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            tag: transaction.tag,
+            error: error.message(),
+        })
+        .unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
+
+/// Seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Hashes the files the run reads, for the manifest
+fn input_digests(args: &Args) -> Result<Vec<FileDigest>, String> {
+    let mut files = vec![
+        ("input", args.input.as_deref()),
+        ("loaded_snapshot", args.load_snapshot.as_deref()),
+        ("initial_accounts", args.initial_accounts.as_deref()),
+        ("client_settings", args.client_settings.as_deref()),
+        ("rules", args.rules.as_deref()),
+        (
+            "checkpoint",
+            args.checkpoint.as_deref().filter(|_| args.resume),
+        ),
+    ];
+    match &args.command {
+        Some(Command::Reconcile { expected, .. }) => files.push(("expected", Some(expected))),
+        Some(Command::Verify {
+            snapshot,
+            trial_balance,
+            ..
+        }) => {
+            files.push(("verified_snapshot", Some(snapshot)));
+            files.push(("logged_trial_balance", trial_balance.as_deref()));
+        }
+        _ => {}
+    }
+    digest_files(&files)
+}
+
+fn digest_files(files: &[(&str, Option<&Path>)]) -> Result<Vec<FileDigest>, String> {
+    files
+        .iter()
+        .filter_map(|&(role, path)| Some((role, path?)))
+        .map(|(role, path)| {
+            FileDigest::of_file(role, path).map_err(|err| {
+                error!("Problem hashing {}: {}", path.display(), err);
+                format!("Problem hashing {}: {}", path.display(), err)
+            })
+        })
+        .collect()
+}
+
+/// Identifier of the run unique on the host, from the start time and the process id
+fn generate_run_id() -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{:x}-{}", started.as_millis(), process::id())
+}
+
+/// Reads expected balances for `reconcile`
+fn read_expected(path: &Path) -> Result<Vec<ExpectedBalance>, String> {
     let mut rdr = csv::Reader::from_path(path).map_err(|err| {
+        error!("Problem opening expected balances file: {}", err);
+        format!("Problem opening expected balances file: {}", err)
+    })?;
+    rdr.deserialize().collect::<Result<_, _>>().map_err(|err| {
+        error!("Invalid expected balance: {}", err);
+        format!("Invalid expected balance: {}", err)
+    })
+}
+
+/// Reads a trial balance for `verify`
+fn read_trial_balance(path: &Path) -> Result<Vec<TrialBalanceRow>, String> {
+    let mut rdr = csv::Reader::from_path(path).map_err(|err| {
+        error!("Problem opening trial balance file: {}", err);
+        format!("Problem opening trial balance file: {}", err)
+    })?;
+    rdr.deserialize().collect::<Result<_, _>>().map_err(|err| {
+        error!("Invalid trial balance row: {}", err);
+        format!("Invalid trial balance row: {}", err)
+    })
+}
+
+/// Writes the accounts, along with the fraud heuristics if requested
+#[cfg_attr(not(feature = "templates"), allow(unused_variables))]
+fn write_accounts<W: io::Write>(
+    args: &Args,
+    run_id: &str,
+    processor: &Processor,
+    wtr: W,
+) -> Result<(), String> {
+    #[cfg(feature = "templates")]
+    if let Some(template) = &args.template {
+        let context = TemplateContext {
+            run_id,
+            accounts: export::accounts(&processor.tenants).collect(),
+            summary: &processor.summary,
+        };
+        let mut wtr = io::BufWriter::new(wtr);
+        return template
+            .render(&context, &mut wtr)
+            .map_err(|err| err.to_string())
+            .and_then(|()| wtr.flush().map_err(|err| err.to_string()))
+            .map_err(|err| {
+                error!("Problem rendering accounts: {}", err);
+                format!("Problem rendering accounts: {}", err)
+            });
+    }
+    match (&processor.activity, args.fraud_heuristics) {
+        (Some(activity), true) => {
+            export::write_accounts_with_heuristics(&processor.tenants, activity, wtr)
+        }
+        _ => export::write_accounts(&processor.tenants, wtr),
+    }
+    Ok(())
+}
+
+fn write_discrepancies<W: io::Write>(discrepancies: &[Discrepancy], wtr: W) {
+    let mut wtr = csv::Writer::from_writer(wtr);
+    for discrepancy in discrepancies {
+        wtr.serialize(discrepancy).unwrap_or_else(|err| {
+            error!("Error serializing record: {}", err);
+        })
+    }
+}
+
+/// Prints the metrics of the run to stderr, as the output goes to stdout
+fn print_metrics(format: MetricsFormat, report: &MetricsReport) {
+    match format {
+        MetricsFormat::Text => eprint!("{}", report),
+        MetricsFormat::Json => eprintln!(
+            "{}",
+            serde_json::to_string(report).expect("metrics should be serializable to JSON")
+        ),
+    }
+}
+
+fn main() -> Result<(), String> {
+    // the output goes to stdout, so logs go to stderr; only errors unless RUST_LOG says otherwise
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error")),
+        )
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+
+    let mut args = Args::parse();
+    if let Some(Command::Reconcile { input, .. } | Command::Verify { input, .. }) = &args.command {
+        args.input = Some(input.clone());
+    }
+
+    let started_at = unix_time();
+    let run_id = args.run_id.clone().unwrap_or_else(generate_run_id);
+    // every log message of the run carries its id and the input, so that logs of batch jobs
+    // running at the same time can be told apart; at error level to be enabled by default
+    let run = error_span!("run", id = %run_id, input = field::Empty);
+    if let Some(input) = &args.input {
+        let input = fs::canonicalize(input).unwrap_or_else(|_| input.clone());
+        run.record("input", field::display(input.display()));
+    }
+    let _run = run.enter();
+
+    if args.transition_matrix {
+        let matrix = serde_json::to_string_pretty(&args.policy().transition_matrix())
+            .expect("transition matrix should be serializable to JSON");
+        println!("{}", matrix);
+        return Ok(());
+    }
+
+    if let Some(Command::Repl { snapshot }) = &args.command {
+        return repl(&args, snapshot.as_deref());
+    }
+
+    if let Some(Command::Anonymize {
+        scale_amounts,
+        input,
+    }) = &args.command
+    {
+        return anonymize(input, *scale_amounts);
+    }
+
+    if let Some(Command::Extract { clients, input }) = &args.command {
+        return extract(input, clients);
+    }
+
+    if let Some(Command::Normalize {
+        encoding,
+        delimiter,
+        sort,
+        input,
+    }) = &args.command
+    {
+        let options = NormalizeOptions {
+            encoding: *encoding,
+            delimiter: *delimiter,
+            sort: *sort,
+        };
+        return normalize(input, &options);
+    }
+
+    if let Some(Command::Split {
+        shards,
+        out_dir,
+        input,
+    }) = &args.command
+    {
+        return split(input, *shards, out_dir);
+    }
+
+    if let Some(Command::Merge { inputs }) = &args.command {
+        return merge(inputs);
+    }
+
+    #[cfg(feature = "server")]
+    if args.serving() {
+        serve(args)?;
+        info!("Shut down cleanly");
+        process::exit(INTERRUPTED_EXIT_CODE);
+    }
+
+    let mut metrics = Metrics::new();
+    metrics.phase("load");
+
+    // read before processing, not to find out it's invalid only after a long run
+    let check = match &args.command {
+        Some(Command::Reconcile {
+            expected,
+            tolerance,
+            currency_tolerance,
+            only_listed,
+            ..
+        }) => Some(Check::Reconcile {
+            expected: read_expected(expected)?,
+            tolerance: Tolerance {
+                default: *tolerance,
+                currencies: currency_tolerance.iter().copied().collect(),
+            },
+            only_listed: *only_listed,
+        }),
+        Some(Command::Verify {
+            snapshot,
+            trial_balance,
+            ..
+        }) => Some(Check::Verify {
+            snapshot: Box::new(Tenants::load(snapshot).map_err(|err| {
+                error!("Problem loading snapshot: {}", err);
+                format!("Problem loading snapshot: {}", err)
+            })?),
+            trial_balance: trial_balance
+                .as_deref()
+                .map(read_trial_balance)
+                .transpose()?,
+        }),
+        _ => None,
+    };
+    // hashed before processing, as the checkpoint gets overwritten
+    let inputs = match &args.manifest {
+        Some(_) => input_digests(&args)?,
+        None => Vec::new(),
+    };
+
+    if args.quarantine.is_none()
+        && args
+            .error_severity
+            .iter()
+            .any(|(_, severity)| *severity == Severity::Quarantine)
+    {
+        error!("Errors can be quarantined only with --quarantine");
+        return Err("Errors can be quarantined only with --quarantine".to_string());
+    }
+
+    let input = args
+        .input
+        .as_ref()
+        .expect("input is required unless serving");
+    let mut rdr = csv::Reader::from_path(input).map_err(|err| {
         error!("Problem opening input file: {}", err);
         format!("Problem opening input file: {}", err)
     })?;
-    let mut state = State::new();
+    let headers = rdr.headers().map_err(|err| {
+        error!("Problem reading header of the input: {}", err);
+        format!("Problem reading header of the input: {}", err)
+    })?;
+    let version = args
+        .schema_version
+        .unwrap_or_else(|| SchemaVersion::detect(headers));
+    info!("Reading input with schema {}", version);
+    let schema = if args.strict_schema || args.schema_version.is_some() {
+        let allow_unknown = args.allow_unknown_columns || !args.strict_schema;
+        let schema = Schema::new(headers, version, allow_unknown).map_err(|errors| {
+            for err in &errors {
+                error!("Invalid header of the input: {}", err);
+            }
+            format!("Invalid header of the input: {}", errors[0])
+        })?;
+        Some(schema)
+    } else {
+        // only used to tell which columns of rejected rows are invalid
+        Schema::new(headers, version, true).ok()
+    };
+    let headers = headers.clone();
+    let mut checkpoint = match &args.checkpoint {
+        Some(path) if args.resume => {
+            let mut checkpoint: Checkpoint = snapshot::load(path).map_err(|err| {
+                error!("Problem loading checkpoint: {}", err);
+                format!("Problem loading checkpoint: {}", err)
+            })?;
+            checkpoint.processor.tenants.set_policy(args.policy());
+            checkpoint
+                .processor
+                .warnings
+                .set_limits(args.warning_limit, args.warning_budget);
+            rdr.seek(checkpoint.position()).map_err(|err| {
+                error!("Problem seeking input file: {}", err);
+                format!("Problem seeking input file: {}", err)
+            })?;
+            info!("Resuming from line {} of the input", checkpoint.line);
+            checkpoint
+        }
+        _ => Checkpoint {
+            processor: Processor {
+                tenants: load_tenants(&args)?,
+                ledger: args.keeps_ledger().then(Ledger::new),
+                settlement: args.settlement.as_ref().map(|_| Settlement::new()),
+                activity: (args.suspicious_activity.is_some() || args.fraud_heuristics).then(
+                    || {
+                        Activity::new(Thresholds {
+                            disputes: args.suspicious_disputes,
+                            chargeback_ratio: args.suspicious_chargeback_ratio,
+                            cycle_window: args.cycle_window,
+                            rapid_cycles: args.suspicious_cycles,
+                        })
+                    },
+                ),
+                suspense: args
+                    .suspend_unknown_references
+                    .then(|| Suspense::new(args.suspense_lookahead)),
+                audit: None,
+                trail: args.fingerprint_trail.as_ref().map(|_| Trail::new()),
+                rules: None,
+                severities: BTreeMap::new(),
+                quarantine: args
+                    .quarantine
+                    .as_ref()
+                    .map(|_| Quarantine::new(args.quarantine_flag)),
+                warnings: Warnings::new(args.warning_limit, args.warning_budget),
+                summary: Summary::default(),
+            },
+            sequencer: args
+                .ordering
+                .map(|scope| Sequencer::new(scope, args.out_of_order, args.reorder_window)),
+            byte: 0,
+            line: 0,
+            record: 0,
+        },
+    };
+    configure_tenants(&args, &mut checkpoint.processor.tenants)?;
+    if let (Some(interval), None) = (args.audit_interval, &checkpoint.processor.audit) {
+        checkpoint.processor.audit = Some(Audit::new(interval, &checkpoint.processor.tenants));
+    }
+    if let (Some(_), None) = (&args.quarantine, &checkpoint.processor.quarantine) {
+        checkpoint.processor.quarantine = Some(Quarantine::new(args.quarantine_flag));
+    }
+    if let (Some(_), Some(ledger)) = (&args.ledger_journal, &mut checkpoint.processor.ledger) {
+        ledger.keep_journal();
+    }
+    if let (Some(_), None) = (&args.fingerprint_trail, &checkpoint.processor.trail) {
+        checkpoint.processor.trail = Some(Trail::new());
+    }
+    if let (Some(path), None) = (&args.rules, &checkpoint.processor.rules) {
+        checkpoint.processor.rules = Some(load_rules(path)?);
+    }
+    if let (Some(_), Some(rules)) = (&args.rule_audit, &mut checkpoint.processor.rules) {
+        rules.keep_matches();
+    }
+    checkpoint.processor.severities = args.error_severity.iter().cloned().collect();
+    checkpoint
+        .processor
+        .warnings
+        .set_dedup(args.dedup_warnings.then_some(args.dedup_flush));
+    metrics.set_baseline(&checkpoint.processor.summary);
 
-    for result in rdr.deserialize() {
-        if let Ok(transaction) =
-            result.map_err(|err| warn!("Ignoring input row because of parse error: {}.", err))
-        {
-            info!("Processing transaction {:?}", transaction);
-            state.apply_transaction(&transaction).or_else(|err| {
-                match err {
-                    CephalopodError::TransactionError { transaction, error } => {
-                        warn!("Error while processing transaction {}: {}. Transaction has not been applied.", transaction.tx, error);
-                        Ok(())
-                    }
-                    CephalopodError::IntegrityError { transaction, error } => {
-                        error!("Integrity error while processing transaction {}: {}. Ending processing.", transaction.tx, error);
-                        Err(format!("{}", error))
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || handler_interrupted.store(true, Ordering::SeqCst)).map_err(
+        |err| {
+            error!("Problem setting signal handler: {}", err);
+            format!("Problem setting signal handler: {}", err)
+        },
+    )?;
+
+    let mut rejects = match &args.rejects_report {
+        Some(path) => Some(open_report(path, args.resume, "rejects report")?),
+        None => None,
+    };
+    let mut quarantined = match &args.quarantine {
+        Some(path) => Some(open_report(path, args.resume, "quarantine")?),
+        None => None,
+    };
+    let mut trail = match &args.fingerprint_trail {
+        Some(path) => Some(open_report(path, args.resume, "fingerprint trail")?),
+        None => None,
+    };
+    let mut journal = match &args.ledger_journal {
+        Some(path) => Some(open_report(path, args.resume, "ledger journal")?),
+        None => None,
+    };
+    let mut rule_audit = match &args.rule_audit {
+        Some(path) => Some(open_report(path, args.resume, "rule audit")?),
+        None => None,
+    };
+
+    #[cfg(unix)]
+    let dump_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if args.dump.is_some() {
+        let handler_requested = dump_requested.clone();
+        // SAFETY: the action only stores to an atomic, which is async-signal-safe
+        unsafe {
+            signal_hook_registry::register(libc::SIGUSR1, move || {
+                handler_requested.store(true, Ordering::SeqCst)
+            })
+        }
+        .map_err(|err| {
+            error!("Problem setting signal handler: {}", err);
+            format!("Problem setting signal handler: {}", err)
+        })?;
+    }
+
+    metrics.phase("process");
+    let total_bytes = fs::metadata(input).map(|metadata| metadata.len()).ok();
+    let mut progress = args.progress.map(|seconds| {
+        Progress::new(
+            Duration::from_secs(seconds),
+            total_bytes,
+            rdr.position().byte(),
+        )
+    });
+    let mut ready = Vec::new();
+    let mut records = rdr.records();
+    while let Some(result) = records.next() {
+        metrics.record();
+        let processor = &mut checkpoint.processor;
+        let result = result
+            .map_err(|err| RejectedRow {
+                line: err.position().map(Position::line),
+                fields: Vec::new(),
+                error: err.to_string(),
+            })
+            .and_then(|record| {
+                let line = record.position().map(Position::line);
+                let transaction =
+                    record
+                        .deserialize(Some(&headers))
+                        .map_err(|err| RejectedRow {
+                            line,
+                            fields: match &schema {
+                                Some(schema) => schema.check_row(&record),
+                                None => Vec::new(),
+                            },
+                            error: err.to_string(),
+                        })?;
+                Ok(Record { transaction, line })
+            });
+        let record = match result {
+            Ok(record) => Some(record),
+            Err(rejected) => {
+                processor.summary.invalid_rows += 1;
+                if Processor::warning(&mut processor.warnings, "InvalidRow")? {
+                    warn!("Ignoring input row because of {}.", rejected);
+                }
+                if let Some(wtr) = &mut rejects {
+                    rejected.report(wtr).unwrap_or_else(|err| {
+                        error!("Error serializing record: {}", err);
+                    });
+                }
+                None
+            }
+        };
+        if let Some(record) = record {
+            match &mut checkpoint.sequencer {
+                Some(sequencer) => {
+                    let line = record.line;
+                    if let Err(err) = sequencer.push(record, &mut ready) {
+                        processor.summary.out_of_order += 1;
+                        if Processor::warning(&mut processor.warnings, "OutOfOrder")? {
+                            warn!(
+                                "Transaction out of order at line {}: {}.",
+                                line.unwrap_or_default(),
+                                err
+                            );
+                        }
                     }
                 }
+                None => ready.push(record),
+            }
+            for record in ready.drain(..) {
+                processor.process_record(&record)?;
+            }
+            write_quarantined(processor, &mut quarantined);
+            write_trail(processor, &mut trail);
+            write_journal(processor, &mut journal);
+            write_rule_matches(processor, &mut rule_audit);
+        }
+
+        let position = records.reader().position();
+        if let Some(report) = progress
+            .as_mut()
+            .and_then(|progress| progress.update(metrics.records(), position.byte()))
+        {
+            eprintln!("Progress: {}", report);
+        }
+        if let Some(interval) = args.memory_interval {
+            if position.record() % interval == 0 {
+                eprintln!(
+                    "Memory at line {} of the input: {}",
+                    position.line(),
+                    checkpoint.processor.tenants.memory_usage()
+                );
+            }
+        }
+        #[cfg(unix)]
+        if let Some(path) = &args.dump {
+            if dump_requested.swap(false, Ordering::SeqCst) {
+                let processor = &checkpoint.processor;
+                let dump = Dump {
+                    line: position.line(),
+                    records: metrics.records(),
+                    bytes: position.byte(),
+                    total_bytes,
+                    elapsed_seconds: metrics.elapsed().as_secs_f64(),
+                    summary: &processor.summary,
+                    accounts: export::accounts(&processor.tenants).collect(),
+                };
+                // a failed dump shouldn't end the run
+                match dump.save(path) {
+                    Ok(()) => info!("Dumped interim results at line {}", position.line()),
+                    Err(err) => error!("Problem writing dump file: {}", err),
+                }
+            }
+        }
+        let interrupt = interrupted.load(Ordering::SeqCst);
+        if let Some(path) = &args.checkpoint {
+            if interrupt || position.record() % args.checkpoint_interval == 0 {
+                // rows written before the checkpoint shouldn't be lost on resume
+                flush_report(&mut rejects, "rejects report")?;
+                flush_report(&mut quarantined, "quarantine")?;
+                flush_report(&mut trail, "fingerprint trail")?;
+                flush_report(&mut journal, "ledger journal")?;
+                flush_report(&mut rule_audit, "rule audit")?;
+                checkpoint.set_position(position);
+                checkpoint.save(path).map_err(|err| {
+                    error!("Problem saving checkpoint: {}", err);
+                    format!("Problem saving checkpoint: {}", err)
+                })?;
+            }
+        }
+        if interrupt {
+            // transactions held back by the sequencer are kept in the checkpoint, not applied
+            flush_report(&mut rejects, "rejects report")?;
+            flush_report(&mut quarantined, "quarantine")?;
+            flush_report(&mut trail, "fingerprint trail")?;
+            flush_report(&mut journal, "ledger journal")?;
+            flush_report(&mut rule_audit, "rule audit")?;
+            write_accounts(&args, &run_id, &checkpoint.processor, io::stdout())?;
+            warn!(
+                "Interrupted, summary so far: {}",
+                checkpoint.processor.summary
+            );
+            if let Some(format) = args.metrics {
+                print_metrics(format, &metrics.report(&checkpoint.processor.summary));
+            }
+            match &args.checkpoint {
+                Some(path) => eprintln!(
+                    "Interrupted at line {} of the input, the accounts written are PARTIAL. Continue with --resume --checkpoint {}",
+                    position.line(),
+                    path.display()
+                ),
+                None => eprintln!(
+                    "Interrupted at line {} of the input, the accounts written are PARTIAL",
+                    position.line()
+                ),
+            }
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    }
+    metrics.phase("finish");
+    flush_report(&mut rejects, "rejects report")?;
+    let mut processor = checkpoint.processor;
+    if let Some(sequencer) = &mut checkpoint.sequencer {
+        sequencer.finish(&mut ready);
+    }
+    for record in ready.drain(..) {
+        processor.process_record(&record)?;
+    }
+    write_quarantined(&mut processor, &mut quarantined);
+    flush_report(&mut quarantined, "quarantine")?;
+    write_trail(&mut processor, &mut trail);
+    flush_report(&mut trail, "fingerprint trail")?;
+    write_journal(&mut processor, &mut journal);
+    flush_report(&mut journal, "ledger journal")?;
+    write_rule_matches(&mut processor, &mut rule_audit);
+    flush_report(&mut rule_audit, "rule audit")?;
+
+    processor.finish();
+    info!("Summary: {}", processor.summary);
+
+    save_tenants(&args, &mut processor.tenants)?;
+
+    metrics.phase("write");
+    if let (Some(path), Some(ledger)) = (&args.trial_balance, &processor.ledger) {
+        let rows = ledger
+            .is_balanced()
+            .and_then(|balanced| {
+                if !balanced {
+                    error!("Ledger is not balanced, debits don't match credits.");
+                }
+                ledger.trial_balance()
+            })
+            .map_err(|err| {
+                error!("Problem computing trial balance: {}", err);
+                format!("Problem computing trial balance: {}", err)
             })?;
+        let mut tb_wtr = csv::Writer::from_path(path).map_err(|err| {
+            error!("Problem opening trial balance file: {}", err);
+            format!("Problem opening trial balance file: {}", err)
+        })?;
+        for row in rows {
+            tb_wtr.serialize(row).unwrap_or_else(|err| {
+                error!("Error serializing record: {}", err);
+            })
         }
     }
 
-    let mut wtr = csv::Writer::from_writer(io::stdout());
+    if let (Some(path), Some(settlement)) = (&args.settlement, &processor.settlement) {
+        let instructions = settlement.instructions().map_err(|err| {
+            error!("Problem computing settlement: {}", err);
+            format!("Problem computing settlement: {}", err)
+        })?;
+        let mut settlement_wtr = csv::Writer::from_path(path).map_err(|err| {
+            error!("Problem opening settlement file: {}", err);
+            format!("Problem opening settlement file: {}", err)
+        })?;
+        for instruction in instructions {
+            settlement_wtr.serialize(instruction).unwrap_or_else(|err| {
+                error!("Error serializing record: {}", err);
+            })
+        }
+    }
+
+    if let (Some(path), Some(activity)) = (&args.suspicious_activity, &processor.activity) {
+        let mut activity_wtr = csv::Writer::from_path(path).map_err(|err| {
+            error!("Problem opening suspicious activity file: {}", err);
+            format!("Problem opening suspicious activity file: {}", err)
+        })?;
+        for client in activity.suspicious() {
+            activity_wtr.serialize(client).unwrap_or_else(|err| {
+                error!("Error serializing record: {}", err);
+            })
+        }
+    }
 
-    for (&id, &account) in state.iter_clients() {
-        let client = ExportedClient {
-            client: id,
-            available: account.available,
-            held: account.held,
-            total: account.available + account.held,
-            locked: account.locked,
+    if let Some(path) = &args.exposure_report {
+        let mut exposure_wtr = csv::Writer::from_path(path).map_err(|err| {
+            error!("Problem opening exposure report file: {}", err);
+            format!("Problem opening exposure report file: {}", err)
+        })?;
+        for exposure in processor.tenants.exposures() {
+            exposure_wtr.serialize(exposure).unwrap_or_else(|err| {
+                error!("Error serializing record: {}", err);
+            })
+        }
+    }
+
+    if let Some(path) = &args.dormant_accounts {
+        let mut dormant_wtr = csv::Writer::from_path(path).map_err(|err| {
+            error!("Problem opening dormant accounts file: {}", err);
+            format!("Problem opening dormant accounts file: {}", err)
+        })?;
+        for (tenant, state) in processor.tenants.iter() {
+            for (client, last_activity) in state.dormant_accounts(args.dormancy_days) {
+                let account = DormantAccount {
+                    tenant,
+                    client,
+                    last_activity,
+                };
+                dormant_wtr.serialize(account).unwrap_or_else(|err| {
+                    error!("Error serializing record: {}", err);
+                })
+            }
+        }
+    }
+
+    if let Some(path) = &args.report {
+        File::create(path)
+            .map(io::BufWriter::new)
+            .and_then(|wtr| report::write_report(wtr, &processor.summary, &processor.tenants))
+            .map_err(|err| {
+                error!("Problem writing report: {}", err);
+                format!("Problem writing report: {}", err)
+            })?;
+    }
+
+    #[cfg(feature = "templates")]
+    if let (Some(path), Some(template)) = (&args.summary, &args.summary_template) {
+        let context = TemplateContext {
+            run_id: &run_id,
+            accounts: export::accounts(&processor.tenants).collect(),
+            summary: &processor.summary,
         };
-        wtr.serialize(client).unwrap_or_else(|err| {
-            error!("Error serializing record: {}", err);
-            ()
-        })
+        File::create(path)
+            .map_err(|err| err.to_string())
+            .and_then(|file| {
+                let mut wtr = io::BufWriter::new(file);
+                template
+                    .render(&context, &mut wtr)
+                    .map_err(|err| err.to_string())?;
+                wtr.flush().map_err(|err| err.to_string())
+            })
+            .map_err(|err| {
+                error!("Problem writing summary: {}", err);
+                format!("Problem writing summary: {}", err)
+            })?;
+    }
+
+    if let Some(path) = args.summary.as_ref().filter(|_| !args.renders_summary()) {
+        File::create(path)
+            .map(io::BufWriter::new)
+            .and_then(|wtr| {
+                report::write_summary(
+                    wtr,
+                    args.summary_format,
+                    &processor.summary,
+                    &processor.tenants,
+                )
+            })
+            .map_err(|err| {
+                error!("Problem writing summary: {}", err);
+                format!("Problem writing summary: {}", err)
+            })?;
+    }
+
+    let records = rdr.position().record().saturating_sub(1);
+    let mut stdout = DigestWriter::new(io::stdout());
+    let (discrepancies, printed) = match check {
+        Some(Check::Reconcile {
+            expected,
+            tolerance,
+            only_listed,
+        }) => {
+            let discrepancies =
+                reconcile::reconcile(&processor.tenants, expected, &tolerance, only_listed);
+            write_discrepancies(&discrepancies, &mut stdout);
+            (discrepancies.len(), "discrepancies")
+        }
+        Some(Check::Verify {
+            snapshot,
+            trial_balance,
+        }) => {
+            let mut divergences = verify::verify_snapshot(&snapshot, &processor.tenants);
+            if let (Some(logged), Some(ledger)) = (trial_balance, &processor.ledger) {
+                divergences.extend(verify::verify_trial_balance(logged, ledger).map_err(
+                    |err| {
+                        error!("Problem verifying trial balance: {}", err);
+                        format!("Problem verifying trial balance: {}", err)
+                    },
+                )?);
+            }
+            for divergence in &divergences {
+                writeln!(stdout, "{}", divergence).map_err(|err| {
+                    error!("Problem writing divergences: {}", err);
+                    format!("Problem writing divergences: {}", err)
+                })?;
+            }
+            (divergences.len(), "divergences")
+        }
+        None => {
+            write_accounts(&args, &run_id, &processor, &mut stdout)?;
+            (0, "accounts")
+        }
+    };
+
+    if let Some(path) = &args.manifest {
+        let mut outputs = vec![stdout.digest(printed)];
+        outputs.extend(digest_files(&[
+            ("trial_balance", args.trial_balance.as_deref()),
+            ("settlement", args.settlement.as_deref()),
+            ("suspicious_activity", args.suspicious_activity.as_deref()),
+            ("exposure_report", args.exposure_report.as_deref()),
+            ("dormant_accounts", args.dormant_accounts.as_deref()),
+            ("rejects_report", args.rejects_report.as_deref()),
+            ("quarantine", args.quarantine.as_deref()),
+            ("fingerprint_trail", args.fingerprint_trail.as_deref()),
+            ("ledger_journal", args.ledger_journal.as_deref()),
+            ("rule_audit", args.rule_audit.as_deref()),
+            ("report", args.report.as_deref()),
+            ("summary", args.summary.as_deref()),
+            ("saved_snapshot", args.save_snapshot.as_deref()),
+        ])?);
+        let manifest = Manifest {
+            engine_version: manifest::ENGINE_VERSION.to_string(),
+            run_id,
+            started_at,
+            finished_at: unix_time(),
+            arguments: std::env::args().map(|arg| manifest::redact(&arg)).collect(),
+            policy: processor.tenants.policy().clone(),
+            inputs,
+            outputs,
+            records,
+            summary: processor.summary.clone(),
+        };
+        File::create(path)
+            .map(io::BufWriter::new)
+            .and_then(|wtr| manifest.write(wtr))
+            .map_err(|err| {
+                error!("Problem writing manifest: {}", err);
+                format!("Problem writing manifest: {}", err)
+            })?;
+    }
+
+    if let Some(format) = args.metrics {
+        print_metrics(format, &metrics.report(&processor.summary));
+    }
+
+    if discrepancies > 0 {
+        error!(
+            "Results don't match the expected ones, discrepancies: {}",
+            discrepancies
+        );
+        process::exit(DISCREPANCIES_EXIT_CODE);
     }
 
     Ok(())
@@ -0,0 +1,205 @@
+//! Approximate memory usage of the state, for capacity planning
+//!
+//! Sizes are estimated as numbers of entries times their sizes, leaving out
+//! the overhead of the maps, so they're a lower bound of what is allocated.
+//! With the `alloc-stats` feature, the binary also counts the bytes actually
+//! allocated by the process, see `CountingAllocator`.
+
+use std::fmt;
+use std::mem;
+use std::ops::Add;
+#[cfg(feature = "alloc-stats")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of entries of a structure and the bytes they take
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    /// Usage of a map with `entries` entries of type `K` mapped to `V`
+    pub(crate) fn of_map<K, V>(entries: usize) -> Usage {
+        Usage {
+            entries: entries as u64,
+            bytes: (entries * (mem::size_of::<K>() + mem::size_of::<V>())) as u64,
+        }
+    }
+
+    /// Usage of a sequence with `entries` entries of type `T`
+    pub(crate) fn of_seq<T>(entries: usize) -> Usage {
+        Usage {
+            entries: entries as u64,
+            bytes: (entries * mem::size_of::<T>()) as u64,
+        }
+    }
+
+    /// Same usage with `bytes` more, taken on the heap by the entries
+    pub(crate) fn with_heap(self, bytes: usize) -> Usage {
+        Usage {
+            bytes: self.bytes + bytes as u64,
+            ..self
+        }
+    }
+
+    /// Same usage with the entries not counted, e.g. of a map indexed by the counted ones
+    pub(crate) fn bytes_only(self) -> Usage {
+        Usage { entries: 0, ..self }
+    }
+}
+
+impl Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            entries: self.entries + other.entries,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} entries, {}", self.entries, Bytes(self.bytes))
+    }
+}
+
+/// Bytes allocated by the process, counted by `CountingAllocator`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AllocatorStats {
+    /// Bytes currently allocated
+    pub allocated: u64,
+    /// Most bytes allocated at once so far
+    pub peak: u64,
+}
+
+/// Approximate memory taken by the accounts and the history of transactions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// Accounts with their balances, flags, settings and recent activity, by number of accounts
+    pub accounts: Usage,
+    /// Past transactions with their states, dispute counts and idempotency keys, by number of
+    /// transactions
+    pub history: Usage,
+    /// Bytes allocated by the process, if counted by `CountingAllocator`
+    pub allocator: Option<AllocatorStats>,
+}
+
+impl MemoryUsage {
+    /// Estimated bytes of the accounts and the history
+    pub fn bytes(&self) -> u64 {
+        self.accounts.bytes + self.history.bytes
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = MemoryUsage;
+
+    /// Sums usage of two states, e.g. of different tenants, taking allocator stats of either
+    fn add(self, other: MemoryUsage) -> MemoryUsage {
+        MemoryUsage {
+            accounts: self.accounts + other.accounts,
+            history: self.history + other.history,
+            allocator: self.allocator.or(other.allocator),
+        }
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "accounts: {}, history: {}", self.accounts, self.history)?;
+        if let Some(stats) = self.allocator {
+            write!(
+                f,
+                ", allocated: {} (peak: {})",
+                Bytes(stats.allocated),
+                Bytes(stats.peak)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of bytes formatted with a binary prefix, e.g. 1.5 MiB
+pub(crate) struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(feature = "alloc-stats")]
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-stats")]
+static PEAK: AtomicU64 = AtomicU64::new(0);
+
+/// System allocator counting the bytes allocated, to be installed with `#[global_allocator]`
+///
+/// It costs a couple of atomic operations per allocation.
+#[cfg(feature = "alloc-stats")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-stats")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+            PEAK.fetch_max(allocated + layout.size() as u64, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                let grown = (new_size - layout.size()) as u64;
+                let allocated = ALLOCATED.fetch_add(grown, Ordering::Relaxed);
+                PEAK.fetch_max(allocated + grown, Ordering::Relaxed);
+            } else {
+                ALLOCATED.fetch_sub((layout.size() - new_size) as u64, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Bytes allocated so far, if `CountingAllocator` is the global allocator
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    #[cfg(feature = "alloc-stats")]
+    {
+        let peak = PEAK.load(Ordering::Relaxed);
+        // nothing has been counted unless it's installed
+        if peak > 0 {
+            return Some(AllocatorStats {
+                allocated: ALLOCATED.load(Ordering::Relaxed),
+                peak,
+            });
+        }
+    }
+    None
+}
@@ -0,0 +1,55 @@
+//! Combination of the accounts of runs over shards, behind `cephalopod merge`
+//!
+//! Shards written by `cephalopod split` are disjoint by client, so their
+//! accounts are simply put together. A client found in more than one shard
+//! means the shards weren't split from the same input, or were processed along
+//! with other records; it's reported as a conflict, keeping its accounts from
+//! the first shard only.
+
+use std::collections::BTreeMap;
+
+use crate::export::ExportedClient;
+
+/// Client found in more than one shard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    /// Indices of the shards listing the client, in order
+    pub shards: Vec<usize>,
+}
+
+/// Accounts of all the shards ordered by client, along with the conflicts
+pub fn merge(
+    shards: impl IntoIterator<Item = Vec<ExportedClient>>,
+) -> (Vec<ExportedClient>, Vec<Conflict>) {
+    let mut clients: BTreeMap<(Option<u32>, u16), (usize, Vec<ExportedClient>)> = BTreeMap::new();
+    let mut conflicts: BTreeMap<(Option<u32>, u16), Vec<usize>> = BTreeMap::new();
+    for (shard, accounts) in shards.into_iter().enumerate() {
+        for account in accounts {
+            let key = (account.tenant, account.client);
+            let (first, kept) = clients.entry(key).or_insert_with(|| (shard, Vec::new()));
+            if *first == shard {
+                kept.push(account);
+                continue;
+            }
+            let listed = conflicts.entry(key).or_insert_with(|| vec![*first]);
+            if listed.last() != Some(&shard) {
+                listed.push(shard);
+            }
+        }
+    }
+    let accounts = clients
+        .into_values()
+        .flat_map(|(_, accounts)| accounts)
+        .collect();
+    let conflicts = conflicts
+        .into_iter()
+        .map(|((tenant, client), shards)| Conflict {
+            tenant,
+            client,
+            shards,
+        })
+        .collect();
+    (accounts, conflicts)
+}
@@ -0,0 +1,301 @@
+//! Runtime metrics of a batch run, reported with `--metrics`, and its progress, with `--progress`
+//!
+//! Collecting them costs a clock reading per phase and a counter increment
+//! per input record, so they can be enabled for every run.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::memory::Bytes;
+use crate::summary::Summary;
+
+/// How the metrics are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for MetricsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MetricsFormat, String> {
+        match s {
+            "text" => Ok(MetricsFormat::Text),
+            "json" => Ok(MetricsFormat::Json),
+            _ => Err(format!("expected text or json, got {}", s)),
+        }
+    }
+}
+
+/// Timer of the phases of a run along with the number of input records read
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    started: Instant,
+    /// Name and start of the phase in progress
+    current: Option<(&'static str, Instant)>,
+    phases: Vec<(&'static str, Duration)>,
+    records: u64,
+    /// Counters of the summary when processing started, e.g. restored from a checkpoint
+    baseline: Summary,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            current: None,
+            phases: Vec::new(),
+            records: 0,
+            baseline: Summary::default(),
+        }
+    }
+
+    /// Ends the phase in progress, if any, and starts the named one
+    pub fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.end_phase(now);
+        self.current = Some((name, now));
+    }
+
+    fn end_phase(&mut self, now: Instant) {
+        if let Some((name, started)) = self.current.take() {
+            self.phases.push((name, now - started));
+        }
+    }
+
+    /// Counts an input record, whether it could be parsed or not
+    pub fn record(&mut self) {
+        self.records += 1;
+    }
+
+    /// Number of input records read so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Time since the start of the run
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Sets the summary whose counters precede this run, so that only its own ones are reported
+    pub fn set_baseline(&mut self, summary: &Summary) {
+        self.baseline = summary.clone();
+    }
+
+    /// Ends the phase in progress and reports the metrics, with counters of the summary
+    pub fn report(&mut self, summary: &Summary) -> MetricsReport {
+        let now = Instant::now();
+        self.end_phase(now);
+        let elapsed = (now - self.started).as_secs_f64();
+        MetricsReport {
+            records: self.records,
+            accepted: summary.applied - self.baseline.applied,
+            rejected: summary.rejected - self.baseline.rejected,
+            quarantined: summary.quarantined - self.baseline.quarantined,
+            invalid_rows: summary.invalid_rows - self.baseline.invalid_rows,
+            elapsed_seconds: elapsed,
+            records_per_second: if elapsed > 0.0 {
+                self.records as f64 / elapsed
+            } else {
+                0.0
+            },
+            phases: self
+                .phases
+                .iter()
+                .map(|&(name, duration)| PhaseTiming {
+                    name,
+                    seconds: duration.as_secs_f64(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub seconds: f64,
+}
+
+/// Metrics of a run, covering only the records read by it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsReport {
+    /// Input records read, including the invalid ones
+    pub records: u64,
+    /// Transactions applied to the state
+    pub accepted: u64,
+    /// Transactions rejected with a `TransactionError`
+    pub rejected: u64,
+    pub quarantined: u64,
+    pub invalid_rows: u64,
+    /// Wall-clock time of the whole run
+    pub elapsed_seconds: f64,
+    /// Throughput over the whole run
+    pub records_per_second: f64,
+    /// Time spent in each phase, in order
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl fmt::Display for MetricsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "records: {} in {:.3} s ({:.0} records/s)",
+            self.records, self.elapsed_seconds, self.records_per_second
+        )?;
+        writeln!(
+            f,
+            "accepted: {}, rejected: {}, quarantined: {}, invalid rows: {}",
+            self.accepted, self.rejected, self.quarantined, self.invalid_rows
+        )?;
+        if !self.phases.is_empty() {
+            writeln!(f, "phases:")?;
+        }
+        for phase in &self.phases {
+            writeln!(f, "  {}: {:.3} s", phase.name, phase.seconds)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of records between readings of the clock by `Progress`
+const PROGRESS_CHECK: u64 = 1024;
+
+/// Periodic reports of progress of reading the input
+#[derive(Debug, Clone)]
+pub struct Progress {
+    interval: Duration,
+    /// Size of the input, if known
+    total_bytes: Option<u64>,
+    started: Instant,
+    /// Offset the run started reading the input at, e.g. when resuming
+    start_bytes: u64,
+    /// Time and number of records of the latest report, or the start
+    last: (Instant, u64),
+}
+
+impl Progress {
+    /// Reports every `interval` after reading the input of `total_bytes` from `start_bytes` on
+    pub fn new(interval: Duration, total_bytes: Option<u64>, start_bytes: u64) -> Progress {
+        Self::started_at(Instant::now(), interval, total_bytes, start_bytes)
+    }
+
+    pub(crate) fn started_at(
+        now: Instant,
+        interval: Duration,
+        total_bytes: Option<u64>,
+        start_bytes: u64,
+    ) -> Progress {
+        Progress {
+            interval,
+            total_bytes,
+            started: now,
+            start_bytes,
+            last: (now, 0),
+        }
+    }
+
+    /// Reports progress after reading `records` records up to `bytes` of the input, if it's time
+    pub fn update(&mut self, records: u64, bytes: u64) -> Option<ProgressReport> {
+        if !records.is_multiple_of(PROGRESS_CHECK) {
+            return None;
+        }
+        self.update_at(Instant::now(), records, bytes)
+    }
+
+    pub(crate) fn update_at(
+        &mut self,
+        now: Instant,
+        records: u64,
+        bytes: u64,
+    ) -> Option<ProgressReport> {
+        let (last_time, last_records) = self.last;
+        let since_last = now.saturating_duration_since(last_time);
+        if since_last < self.interval {
+            return None;
+        }
+        self.last = (now, records);
+        let elapsed = now.saturating_duration_since(self.started).as_secs_f64();
+        let rate = |count: u64, seconds: f64| {
+            if seconds > 0.0 {
+                count as f64 / seconds
+            } else {
+                0.0
+            }
+        };
+        let bytes_per_second = rate(bytes.saturating_sub(self.start_bytes), elapsed);
+        let eta = match self.total_bytes {
+            Some(total) if bytes_per_second > 0.0 => Some(Duration::from_secs_f64(
+                total.saturating_sub(bytes) as f64 / bytes_per_second,
+            )),
+            _ => None,
+        };
+        Some(ProgressReport {
+            records,
+            bytes,
+            total_bytes: self.total_bytes,
+            records_per_second: rate(records - last_records, since_last.as_secs_f64()),
+            average_records_per_second: rate(records, elapsed),
+            eta,
+        })
+    }
+}
+
+/// Progress of reading the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressReport {
+    /// Records read by the run so far
+    pub records: u64,
+    /// Offset in the input reached so far
+    pub bytes: u64,
+    pub total_bytes: Option<u64>,
+    /// Throughput since the previous report
+    pub records_per_second: f64,
+    /// Throughput since the start of the run
+    pub average_records_per_second: f64,
+    /// Time left at the average rate of reading the input, if its size is known
+    pub eta: Option<Duration>,
+}
+
+impl fmt::Display for ProgressReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} records", self.records)?;
+        match self.total_bytes {
+            Some(total) if total > 0 => write!(
+                f,
+                ", {} of {} ({:.1}%)",
+                Bytes(self.bytes),
+                Bytes(total),
+                self.bytes as f64 * 100.0 / total as f64
+            )?,
+            _ => write!(f, ", {}", Bytes(self.bytes))?,
+        }
+        write!(
+            f,
+            ", {:.0} records/s (average {:.0})",
+            self.records_per_second, self.average_records_per_second
+        )?;
+        if let Some(eta) = self.eta {
+            let seconds = eta.as_secs();
+            write!(
+                f,
+                ", ETA {}:{:02}:{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,44 +1,153 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::mem;
+use std::path::Path;
 
-use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
+use tracing::{error, field, info, info_span};
 
 use thiserror::Error;
 
-#[derive(Debug, Clone, Copy)]
+use rust_decimal::RoundingStrategy;
+
+use crate::amount::{self, Amount};
+use crate::currency::Currency;
+use crate::fingerprint::Fingerprint;
+use crate::memory::{self, MemoryUsage, Usage};
+use crate::policy::{
+    ClientSettings, ExcessPrecision, Policy, DEFAULT_TRANSACTION_WINDOW, TRANSITIONING_TYPES,
+};
+use crate::snapshot::{self, SnapshotError};
+use crate::storage::{Storage, StorageError, TransactionRecord, DEFAULT_CACHE_CAPACITY};
+use crate::tag::Tag;
+
+pub(crate) const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Map used by the state, ordered by key with the `ordered` feature
+///
+/// Hash maps are faster, but iterate in a different order in every run, so
+/// the order of output rows, log messages and snapshot contents changes too.
+#[cfg(not(feature = "ordered"))]
+pub(crate) type Map<K, V> = std::collections::HashMap<K, V>;
+#[cfg(feature = "ordered")]
+pub(crate) type Map<K, V> = BTreeMap<K, V>;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum AccountError {
     AccountLocked,
+    AccountNotLocked,
     NotEnoughFunds {
-        available: Decimal,
-        required: Decimal,
+        available: Amount,
+        required: Amount,
     },
     NegativeAmount {
-        amount: Decimal,
+        amount: Amount,
+    },
+    MinimumBalance {
+        minimum: Amount,
+        remaining: Amount,
     },
+    /// Result of the operation doesn't fit in `Amount`
+    Overflow,
+}
+
+/// Adds the amount to a balance, failing on overflow instead of panicking
+fn credit(balance: Amount, amount: Amount) -> Result<Amount, AccountError> {
+    balance.checked_add(amount).ok_or(AccountError::Overflow)
+}
+
+/// Subtracts the amount from a balance, failing on overflow instead of panicking
+fn debit(balance: Amount, amount: Amount) -> Result<Amount, AccountError> {
+    balance.checked_sub(amount).ok_or(AccountError::Overflow)
 }
 
 /// Error type representing some problem with the input data
 ///
 /// Such errors should be logged and ignored
-#[derive(Error, Debug, Clone, Copy)]
+#[derive(Error, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum TransactionError {
     #[error("account {client} is locked")]
     AccountLocked { client: u16 },
 
+    #[error("account {client} is not locked")]
+    AccountNotLocked { client: u16 },
+
+    #[error("account {client} is frozen")]
+    AccountFrozen { client: u16 },
+
+    #[error("account {client} is already frozen")]
+    AccountAlreadyFrozen { client: u16 },
+
+    #[error("account {client} is not frozen")]
+    AccountNotFrozen { client: u16 },
+
+    #[error("account {client} already has flag {flag}")]
+    FlagAlreadySet { client: u16, flag: u32 },
+
+    #[error("account {client} doesn't have flag {flag}")]
+    FlagNotSet { client: u16, flag: u32 },
+
+    #[error("account {client} has flag {flag}, amount limited to {limit}")]
+    FlaggedAmountLimitExceeded {
+        client: u16,
+        flag: u32,
+        limit: Amount,
+    },
+
+    #[error("receiving client not provided")]
+    CounterpartyNotProvided,
+
+    #[error("client {client} cannot transfer funds to itself")]
+    SelfTransfer { client: u16 },
+
+    #[error("reason not provided")]
+    ReasonNotProvided,
+
+    #[error("transaction {tx} cannot be reversed")]
+    TransactionNotReversible { tx: u32 },
+
+    #[error("transaction {tx} of type {target:?} cannot be referenced by this transaction")]
+    TransitionNotAllowed { tx: u32, target: TransactionType },
+
+    #[error("transaction {tx} is not an authorization")]
+    TransactionNotAuthorization { tx: u32 },
+
+    #[error("account {client} is closed")]
+    AccountClosed { client: u16 },
+
+    #[error("account {client} is already open")]
+    AccountAlreadyOpen { client: u16 },
+
+    #[error("account {client} has {count} open disputes")]
+    OpenDisputes { client: u16, count: u32 },
+
+    #[error(
+        "account {client} has non-zero {currency} balance, available: {available}, held: {held}"
+    )]
+    NonZeroBalance {
+        client: u16,
+        currency: Currency,
+        available: Amount,
+        held: Amount,
+    },
+
     #[error("amount not provided")]
     AmountNotProvided,
 
     #[error("amount not provided")]
-    NegativeAmountProvided { amount: Decimal },
+    NegativeAmountProvided { amount: Amount },
+
+    #[error("amount {amount} has more than {} decimal places", amount::MAX_SCALE)]
+    AmountTooPrecise { amount: Amount },
 
     #[error("unknown account: {client}")]
     UnknownAccount { client: u16 },
 
     #[error("not enough funds, available: {available}, required: {required}")]
-    NotEnoughFunds {
-        available: Decimal,
-        required: Decimal,
-    },
+    NotEnoughFunds { available: Amount, required: Amount },
+
+    #[error("minimum balance {minimum} not kept, remaining balance would be {remaining}")]
+    MinimumBalanceBreached { minimum: Amount, remaining: Amount },
 
     #[error("requested dispute of unknown transaction: {tx}")]
     TransactionNotFound { tx: u32 },
@@ -46,15 +155,104 @@ pub enum TransactionError {
     #[error("transaction in wrong state: {state:?}")]
     TransactionInvalidState { state: TransactionState },
 
+    #[error("transaction {tx} already exists")]
+    DuplicateTransaction { tx: u32 },
+
+    #[error("idempotency key of transaction {tx} has been used for a different transaction")]
+    IdempotencyKeyReused { tx: u32 },
+
     #[error("referenced transaction doesn't match provided client")]
     TransactionClientMismatch { tx: u32, client: u16 },
+
+    #[error("transaction {tx} is in currency {expected}, not {provided}")]
+    CurrencyMismatch {
+        tx: u32,
+        expected: Currency,
+        provided: Currency,
+    },
+
+    #[error("transaction {tx} has already been disputed {limit} times")]
+    DisputeLimitReached { tx: u32, limit: u32 },
+
+    #[error("transaction {tx} is older than the dispute window of {days} days")]
+    DisputeWindowExpired { tx: u32, days: u32 },
+
+    #[error("amount {amount} exceeds the limit of {limit}")]
+    AmountLimitExceeded { amount: Amount, limit: Amount },
+
+    #[error("amount {amount} exceeds the cap of {cap}")]
+    AmountCapExceeded { amount: Amount, cap: Amount },
+
+    #[error("daily total {total} of account {client} would exceed the limit of {limit}")]
+    DailyLimitExceeded {
+        client: u16,
+        total: Amount,
+        limit: Amount,
+    },
+
+    #[error("account {client} has reached the limit of {limit} transactions in {window} seconds")]
+    VelocityLimitExceeded {
+        client: u16,
+        limit: u32,
+        window: u64,
+    },
+    /// Rejected by a compliance rule, given by its position in the rules (counted from 1)
+    #[error("blocked by compliance rule {rule}")]
+    BlockedByRule { rule: u32 },
+}
+
+impl TransactionError {
+    /// Name of the variant, e.g. `NotEnoughFunds`, grouping errors of the same kind
+    ///
+    /// Severities and budgets of warnings are configured by these names, so they must not change.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransactionError::AccountLocked { .. } => "AccountLocked",
+            TransactionError::AccountNotLocked { .. } => "AccountNotLocked",
+            TransactionError::AccountFrozen { .. } => "AccountFrozen",
+            TransactionError::AccountAlreadyFrozen { .. } => "AccountAlreadyFrozen",
+            TransactionError::AccountNotFrozen { .. } => "AccountNotFrozen",
+            TransactionError::FlagAlreadySet { .. } => "FlagAlreadySet",
+            TransactionError::FlagNotSet { .. } => "FlagNotSet",
+            TransactionError::FlaggedAmountLimitExceeded { .. } => "FlaggedAmountLimitExceeded",
+            TransactionError::CounterpartyNotProvided => "CounterpartyNotProvided",
+            TransactionError::SelfTransfer { .. } => "SelfTransfer",
+            TransactionError::ReasonNotProvided => "ReasonNotProvided",
+            TransactionError::TransactionNotReversible { .. } => "TransactionNotReversible",
+            TransactionError::TransitionNotAllowed { .. } => "TransitionNotAllowed",
+            TransactionError::TransactionNotAuthorization { .. } => "TransactionNotAuthorization",
+            TransactionError::AccountClosed { .. } => "AccountClosed",
+            TransactionError::AccountAlreadyOpen { .. } => "AccountAlreadyOpen",
+            TransactionError::OpenDisputes { .. } => "OpenDisputes",
+            TransactionError::NonZeroBalance { .. } => "NonZeroBalance",
+            TransactionError::AmountNotProvided => "AmountNotProvided",
+            TransactionError::NegativeAmountProvided { .. } => "NegativeAmountProvided",
+            TransactionError::AmountTooPrecise { .. } => "AmountTooPrecise",
+            TransactionError::UnknownAccount { .. } => "UnknownAccount",
+            TransactionError::NotEnoughFunds { .. } => "NotEnoughFunds",
+            TransactionError::MinimumBalanceBreached { .. } => "MinimumBalanceBreached",
+            TransactionError::TransactionNotFound { .. } => "TransactionNotFound",
+            TransactionError::TransactionInvalidState { .. } => "TransactionInvalidState",
+            TransactionError::DuplicateTransaction { .. } => "DuplicateTransaction",
+            TransactionError::IdempotencyKeyReused { .. } => "IdempotencyKeyReused",
+            TransactionError::TransactionClientMismatch { .. } => "TransactionClientMismatch",
+            TransactionError::CurrencyMismatch { .. } => "CurrencyMismatch",
+            TransactionError::DisputeLimitReached { .. } => "DisputeLimitReached",
+            TransactionError::DisputeWindowExpired { .. } => "DisputeWindowExpired",
+            TransactionError::AmountLimitExceeded { .. } => "AmountLimitExceeded",
+            TransactionError::AmountCapExceeded { .. } => "AmountCapExceeded",
+            TransactionError::DailyLimitExceeded { .. } => "DailyLimitExceeded",
+            TransactionError::VelocityLimitExceeded { .. } => "VelocityLimitExceeded",
+            TransactionError::BlockedByRule { .. } => "BlockedByRule",
+        }
+    }
 }
 
 /// Error type representing major problem with the code
 ///
 /// Such errors should never occur. If it happens, the application should stop
 /// immediately as it is a symptom of data corruption or application error.
-#[derive(Error, Debug, Clone, Copy)]
+#[derive(Error, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum IntegrityError {
     #[error("state unavailable for transaction {tx}")]
     StateMissingForTransaction { tx: u32 },
@@ -66,16 +264,64 @@ pub enum IntegrityError {
     AccountMissingForTransaction { client: u16 },
 
     #[error("required funds are not locked, available: {available}, required: {required}")]
-    FundsNotLocked {
-        available: Decimal,
-        required: Decimal,
-    },
+    FundsNotLocked { available: Amount, required: Amount },
 
     #[error("unexpected account error during processing: {error:?}")]
     UnexpectedAccountError { error: AccountError },
+
+    #[error("storage failed while processing transaction {tx}")]
+    StorageFailure { tx: u32 },
+
+    #[error("amount overflow while processing transaction {tx}")]
+    AmountOverflow { tx: u32 },
+
+    #[error("account {client} has negative held funds {held} in currency '{currency}'")]
+    NegativeHeldFunds {
+        client: u16,
+        currency: Currency,
+        held: Amount,
+    },
+
+    #[error("account {client} has total {total} in currency '{currency}', but its movements net to {expected}")]
+    BalanceMismatch {
+        client: u16,
+        currency: Currency,
+        total: Amount,
+        expected: Amount,
+    },
+
+    #[error("account {client} has held funds {held} in currency '{currency}', but its open disputes and authorizations hold {expected}")]
+    HeldFundsMismatch {
+        client: u16,
+        currency: Currency,
+        held: Amount,
+        expected: Amount,
+    },
+
+    #[error("funds of account {client} in currency '{currency}' don't fit in an amount")]
+    FundsOverflow { client: u16, currency: Currency },
+}
+
+impl IntegrityError {
+    /// Name of the variant, e.g. `AmountOverflow`, like `TransactionError::kind`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IntegrityError::StateMissingForTransaction { .. } => "StateMissingForTransaction",
+            IntegrityError::AmountMissingForTransaction { .. } => "AmountMissingForTransaction",
+            IntegrityError::AccountMissingForTransaction { .. } => "AccountMissingForTransaction",
+            IntegrityError::FundsNotLocked { .. } => "FundsNotLocked",
+            IntegrityError::UnexpectedAccountError { .. } => "UnexpectedAccountError",
+            IntegrityError::StorageFailure { .. } => "StorageFailure",
+            IntegrityError::AmountOverflow { .. } => "AmountOverflow",
+            IntegrityError::NegativeHeldFunds { .. } => "NegativeHeldFunds",
+            IntegrityError::BalanceMismatch { .. } => "BalanceMismatch",
+            IntegrityError::HeldFundsMismatch { .. } => "HeldFundsMismatch",
+            IntegrityError::FundsOverflow { .. } => "FundsOverflow",
+        }
+    }
 }
 
-#[derive(Error, Debug, Clone, Copy)]
+#[derive(Error, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum CephalopodError {
     #[error("error during processing transaction")]
     TransactionError {
@@ -91,7 +337,25 @@ pub enum CephalopodError {
     },
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl CephalopodError {
+    /// Kind of the underlying error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CephalopodError::TransactionError { error, .. } => error.kind(),
+            CephalopodError::IntegrityError { error, .. } => error.kind(),
+        }
+    }
+
+    /// Message of the underlying error
+    pub fn message(&self) -> String {
+        match self {
+            CephalopodError::TransactionError { error, .. } => error.to_string(),
+            CephalopodError::IntegrityError { error, .. } => error.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -99,47 +363,309 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Representment,
+    Unlock,
+    Transfer,
+    Fee,
+    Adjustment,
+    Reversal,
+    Authorize,
+    Capture,
+    Void,
+    Open,
+    Close,
+    Freeze,
+    Unfreeze,
+    Flag,
+    Unflag,
 }
 
 // NOTE: normally I'd choose to represent it as tagged enum,
 // but the csv crate doesn't support it correctly:
 // https://github.com/BurntSushi/rust-csv/issues/211
 // in order to skip implementing manual parsing I've opted for alternative representation
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tpe: TransactionType,
     pub client: u16,
     pub tx: u32,
-    pub amount: Option<Decimal>,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub amount: Option<Amount>,
+    /// Receiving client of a transfer
+    #[serde(default)]
+    pub to: Option<u16>,
+    /// Reason code of an administrative adjustment, or the risk flag of a flag or unflag
+    #[serde(default)]
+    pub reason: Option<u32>,
+    /// Currency of the amount, disputes and similar may leave it empty
+    #[serde(default)]
+    pub currency: Currency,
+    /// Unix timestamp (in seconds) of the transaction, required by the daily and velocity limits
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Operator or merchant the transaction belongs to, tenants don't share any state
+    #[serde(default)]
+    pub tenant: Option<u32>,
+    /// Free-form reference, e.g. the upstream order id, passed through to the reports
+    #[serde(default)]
+    pub tag: Tag,
+}
+
+/// Transaction along with the line of the input it has been read from, if any
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub transaction: Transaction,
+    pub line: Option<u64>,
+}
+
+impl From<Transaction> for Record {
+    fn from(transaction: Transaction) -> Record {
+        Record {
+            transaction,
+            line: None,
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transaction {}", self.transaction.tx)?;
+        if let Some(line) = self.line {
+            write!(f, " at line {}", line)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TransactionState {
     Withdrawn,
     Deposited,
     Disputed,
     Resolved,
     Chargebacked,
+    Represented,
+    Voided,
+    Authorized,
 }
 
-/// Representation of a client's account state
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Account {
+/// Side of a referenced transaction affecting the account of the referencing client
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Leg {
+    /// Funds credited to the account, i.e. a deposit
+    Credit,
+    /// Funds debited from the account, i.e. a withdrawal or sending side of a transfer
+    Debit,
+    /// Receiving side of a transfer
+    TransferCredit,
+}
+
+/// Constraints on withdrawals from a single account
+#[derive(Debug, Clone, Copy, Default)]
+struct WithdrawalLimits {
+    /// How far below zero the available balance may go
+    overdraft: Amount,
+    /// Available balance that must be left after the withdrawal
+    minimum_balance: Option<Amount>,
+}
+
+/// Risk limits on deposits, withdrawals, transfers and authorizations of a single client
+#[derive(Debug, Clone, Copy)]
+struct TransactionLimits {
+    max_amount: Option<Amount>,
+    max_daily_total: Option<Amount>,
+    max_transactions: Option<u32>,
+    /// Length of the window of `max_transactions` in seconds
+    window: u64,
+}
+
+/// Recent transactions of a client, tracked for the daily and velocity limits
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Activity {
+    /// Day (counted from the Unix epoch) of `daily_totals`
+    day: u64,
+    daily_totals: Map<Currency, Amount>,
+    /// Timestamps of transactions within the velocity window, oldest first
+    recent: VecDeque<u64>,
+}
+
+/// Funds of an account in a single currency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
     /// Funds available to withdrawals
-    pub available: Decimal,
+    pub available: Amount,
     /// Funds locked for disputes
-    pub held: Decimal,
+    pub held: Amount,
+}
+
+impl Balance {
+    /// Sum of available and held funds, `None` if it doesn't fit in an amount
+    pub fn total(&self) -> Option<Amount> {
+        self.available.checked_add(self.held)
+    }
+}
+
+/// Lifetime totals of a client's funds in a single currency, telling its exposure to chargebacks
+///
+/// The totals are statistics only, so they saturate at the largest amount instead of failing
+/// transactions that would overflow them, e.g. repeated deposits of funds withdrawn in between,
+/// which is marked by `saturated`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientTotals {
+    /// Sum of all applied deposits
+    pub deposited: Amount,
+    /// Sum of the amounts of all applied disputes, counting each dispute of a transaction
+    pub disputed: Amount,
+    /// Sum of the amounts of all applied chargebacks
+    pub charged_back: Amount,
+    /// Whether any of the totals has been capped at the largest amount, so it's less than the sum
+    #[serde(default)]
+    pub saturated: bool,
+}
+
+impl ClientTotals {
+    /// Disputed amount per deposited amount, `None` if nothing has been deposited (e.g. only
+    /// withdrawals have been disputed), as the ratio is undefined then, or if the totals are
+    /// saturated, as it would be made up
+    pub fn dispute_ratio(&self) -> Option<f64> {
+        if self.deposited == Amount::ZERO || self.saturated {
+            return None;
+        }
+        Some(amount::to_f64(self.disputed) / amount::to_f64(self.deposited))
+    }
+
+    /// Sum of the total and the amount, the largest amount marking the totals saturated if the
+    /// sum doesn't fit
+    fn add(total: Amount, amount: Amount, saturated: &mut bool) -> Amount {
+        total.checked_add(amount).unwrap_or_else(|| {
+            *saturated = true;
+            Amount::MAX
+        })
+    }
+}
+
+/// Transactions dropped from the history by `State::prune`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PruneOptions {
+    /// Drop transactions none of the transitions allowed by the policy applies to anymore, e.g.
+    /// charged back deposits unless representments are allowed
+    pub finalized: bool,
+    /// Drop transactions timestamped more than that many seconds before the latest activity
+    pub older_than: Option<u64>,
+}
+
+/// Numbers of transactions dropped from the history by `State::prune`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pruned {
+    pub finalized: u64,
+    /// Transactions dropped for their age only
+    pub aged: u64,
+}
+
+impl Pruned {
+    pub fn total(&self) -> u64 {
+        self.finalized + self.aged
+    }
+}
+
+impl std::ops::AddAssign for Pruned {
+    fn add_assign(&mut self, other: Pruned) {
+        self.finalized += other.finalized;
+        self.aged += other.aged;
+    }
+}
+
+/// Account and lifetime totals of a client as they were before a transaction, if it had any
+type SavedClient = (
+    u16,
+    Option<Account>,
+    Option<BTreeMap<Currency, ClientTotals>>,
+);
+
+/// Accounts and totals a transaction may change, restored if it fails with an integrity error
+///
+/// Integrity errors can be quarantined and processing goes on, so a transaction
+/// failing halfway must not leave any of its changes behind.
+struct Rollback {
+    /// Client of the transaction and its counterparty, if any
+    clients: [Option<SavedClient>; 2],
+    /// Fees collected in the currency of the transaction
+    fees_collected: Option<Amount>,
+    currency: Currency,
+}
+
+impl Rollback {
+    fn take(state: &State, tx: &Transaction) -> Rollback {
+        let client = |client: u16| {
+            Some((
+                client,
+                state.accounts.get(&client).cloned(),
+                state.totals.get(&client).cloned(),
+            ))
+        };
+        Rollback {
+            clients: [client(tx.client), tx.to.and_then(client)],
+            fees_collected: state.fees_collected.get(&tx.currency).copied(),
+            currency: tx.currency,
+        }
+    }
+
+    fn restore(self, state: &mut State) {
+        let [client, counterparty] = self.clients;
+        for (client, account, totals) in client.into_iter().chain(counterparty) {
+            match account {
+                Some(account) => state.accounts.insert(client, account),
+                None => state.accounts.remove(&client),
+            };
+            match totals {
+                Some(totals) => state.totals.insert(client, totals),
+                None => state.totals.remove(&client),
+            };
+        }
+        match self.fees_collected {
+            Some(fees) => state.fees_collected.insert(self.currency, fees),
+            None => state.fees_collected.remove(&self.currency),
+        };
+    }
+}
+
+/// Representation of a client's account state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    /// Funds per currency
+    pub balances: BTreeMap<Currency, Balance>,
     /// Whether or not the account is locked
     pub locked: bool,
+    /// Whether or not the account has been closed
+    pub closed: bool,
+    /// Whether or not funds are blocked from leaving the account by compliance
+    ///
+    /// Unlike the lock, it still allows deposits and dispute processing.
+    pub frozen: bool,
+    /// Risk or KYC flags set on the account
+    pub flags: BTreeSet<u32>,
+    /// Timestamp of the latest deposit, withdrawal, transfer, authorization or capture
+    pub last_activity: Option<u64>,
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Account {
     pub fn new() -> Account {
         Account {
-            available: Decimal::new(0, 0),
-            held: Decimal::new(0, 0),
+            balances: BTreeMap::new(),
             locked: false,
+            closed: false,
+            frozen: false,
+            flags: BTreeSet::new(),
+            last_activity: None,
         }
     }
 
@@ -150,259 +676,1168 @@ impl Account {
         Ok(())
     }
 
-    fn deposit(&mut self, amount: &Decimal) -> Result<(), AccountError> {
-        self.check_lock()?;
-        if amount < &Decimal::ZERO {
+    fn deposit(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        allow_locked: bool,
+    ) -> Result<(), AccountError> {
+        if !allow_locked {
+            self.check_lock()?;
+        }
+        if amount < &Amount::ZERO {
             Err(AccountError::NegativeAmount { amount: *amount })?;
         }
 
-        self.available += amount;
+        let balance = self.balances.entry(currency).or_default();
+        balance.available = credit(balance.available, *amount)?;
         Ok(())
     }
 
-    fn withdraw(&mut self, amount: &Decimal) -> Result<(), AccountError> {
+    fn withdraw(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        limits: &WithdrawalLimits,
+    ) -> Result<(), AccountError> {
         self.check_lock()?;
-        if amount < &Decimal::ZERO {
+        if amount < &Amount::ZERO {
             Err(AccountError::NegativeAmount { amount: *amount })?;
         }
 
-        if amount > &self.available {
+        let balance = self.balances.entry(currency).or_default();
+        let spendable = credit(balance.available, limits.overdraft)?;
+        if amount > &spendable {
+            Err(AccountError::NotEnoughFunds {
+                available: spendable,
+                required: *amount,
+            })?;
+        }
+        let remaining = debit(balance.available, *amount)?;
+        if let Some(minimum) = limits.minimum_balance {
+            if remaining < minimum {
+                Err(AccountError::MinimumBalance { minimum, remaining })?;
+            }
+        }
+        balance.available = remaining;
+        Ok(())
+    }
+
+    fn lock(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        allow_overdraft: bool,
+    ) -> Result<(), AccountError> {
+        self.check_lock()?;
+        let balance = self.balances.entry(currency).or_default();
+        if !allow_overdraft && amount > &balance.available {
+            Err(AccountError::NotEnoughFunds {
+                available: balance.available,
+                required: *amount,
+            })?;
+        }
+        let available = debit(balance.available, *amount)?;
+        balance.held = credit(balance.held, *amount)?;
+        balance.available = available;
+        Ok(())
+    }
+
+    fn release(&mut self, currency: Currency, amount: &Amount) -> Result<(), AccountError> {
+        self.check_lock()?;
+        let balance = self.balances.entry(currency).or_default();
+        if amount > &balance.held {
             Err(AccountError::NotEnoughFunds {
-                available: self.available,
+                available: balance.available,
                 required: *amount,
             })?;
         }
-        self.available -= amount;
+        let held = debit(balance.held, *amount)?;
+        balance.available = credit(balance.available, *amount)?;
+        balance.held = held;
         Ok(())
     }
 
-    fn lock(&mut self, amount: &Decimal) -> Result<(), AccountError> {
+    fn chargeback(&mut self, currency: Currency, amount: &Amount) -> Result<(), AccountError> {
         self.check_lock()?;
-        if amount > &self.available {
+        let balance = self.balances.entry(currency).or_default();
+        if amount > &balance.held {
             Err(AccountError::NotEnoughFunds {
-                available: self.available,
+                available: balance.available,
                 required: *amount,
             })?;
         }
-        self.available -= amount;
-        self.held += amount;
+        balance.held = debit(balance.held, *amount)?;
+        self.locked = true;
         Ok(())
     }
 
-    fn release(&mut self, amount: &Decimal) -> Result<(), AccountError> {
+    /// Credits back a disputed withdrawal as held funds
+    fn lock_withdrawn(&mut self, currency: Currency, amount: &Amount) -> Result<(), AccountError> {
         self.check_lock()?;
-        if amount > &self.held {
+        let balance = self.balances.entry(currency).or_default();
+        balance.held = credit(balance.held, *amount)?;
+        Ok(())
+    }
+
+    /// Drops the hold of a disputed withdrawal, the withdrawal stands
+    fn release_withdrawn(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+    ) -> Result<(), AccountError> {
+        self.check_lock()?;
+        let balance = self.balances.entry(currency).or_default();
+        if amount > &balance.held {
             Err(AccountError::NotEnoughFunds {
-                available: self.available,
+                available: balance.available,
                 required: *amount,
             })?;
         }
-        self.held -= amount;
-        self.available += amount;
+        balance.held = debit(balance.held, *amount)?;
         Ok(())
     }
 
-    fn chargeback(&mut self, amount: &Decimal) -> Result<(), AccountError> {
+    /// Reverses a disputed withdrawal, making the held funds available
+    fn chargeback_withdrawn(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+    ) -> Result<(), AccountError> {
         self.check_lock()?;
-        if amount > &self.held {
+        let balance = self.balances.entry(currency).or_default();
+        if amount > &balance.held {
             Err(AccountError::NotEnoughFunds {
-                available: self.available,
+                available: balance.available,
                 required: *amount,
             })?;
         }
-        self.held -= amount;
+        let held = debit(balance.held, *amount)?;
+        balance.available = credit(balance.available, *amount)?;
+        balance.held = held;
         self.locked = true;
         Ok(())
     }
+
+    /// Restores charged back deposit after a successful representment
+    ///
+    /// The account is locked at this point, so the lock is deliberately not checked.
+    fn represent(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        unlock: bool,
+    ) -> Result<(), AccountError> {
+        let balance = self.balances.entry(currency).or_default();
+        balance.available = credit(balance.available, *amount)?;
+        if unlock {
+            self.locked = false;
+        }
+        Ok(())
+    }
+
+    /// Reinstates charged back withdrawal after a successful representment
+    ///
+    /// The funds are taken regardless of the balance, which may become negative.
+    fn represent_withdrawn(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        unlock: bool,
+    ) -> Result<(), AccountError> {
+        let balance = self.balances.entry(currency).or_default();
+        balance.available = debit(balance.available, *amount)?;
+        if unlock {
+            self.locked = false;
+        }
+        Ok(())
+    }
+
+    fn charge_fee(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        allow_overdraft: bool,
+    ) -> Result<(), AccountError> {
+        self.check_lock()?;
+        if amount < &Amount::ZERO {
+            Err(AccountError::NegativeAmount { amount: *amount })?;
+        }
+
+        let balance = self.balances.entry(currency).or_default();
+        if !allow_overdraft && amount > &balance.available {
+            Err(AccountError::NotEnoughFunds {
+                available: balance.available,
+                required: *amount,
+            })?;
+        }
+        balance.available = debit(balance.available, *amount)?;
+        Ok(())
+    }
+
+    /// Holds funds for a later capture
+    fn authorize(&mut self, currency: Currency, amount: &Amount) -> Result<(), AccountError> {
+        self.check_lock()?;
+        if amount < &Amount::ZERO {
+            Err(AccountError::NegativeAmount { amount: *amount })?;
+        }
+
+        let balance = self.balances.entry(currency).or_default();
+        if amount > &balance.available {
+            Err(AccountError::NotEnoughFunds {
+                available: balance.available,
+                required: *amount,
+            })?;
+        }
+        let available = debit(balance.available, *amount)?;
+        balance.held = credit(balance.held, *amount)?;
+        balance.available = available;
+        Ok(())
+    }
+
+    /// Takes authorized funds from the held ones, completing the withdrawal
+    fn capture(&mut self, currency: Currency, amount: &Amount) -> Result<(), AccountError> {
+        self.check_lock()?;
+        let balance = self.balances.entry(currency).or_default();
+        if amount > &balance.held {
+            Err(AccountError::NotEnoughFunds {
+                available: balance.available,
+                required: *amount,
+            })?;
+        }
+        balance.held = debit(balance.held, *amount)?;
+        Ok(())
+    }
+
+    /// Credits (positive amount) or debits (negative amount) the available funds
+    fn adjust(
+        &mut self,
+        currency: Currency,
+        amount: &Amount,
+        allow_overdraft: bool,
+    ) -> Result<(), AccountError> {
+        self.check_lock()?;
+        let balance = self.balances.entry(currency).or_default();
+        let available = credit(balance.available, *amount)?;
+        if !allow_overdraft && available < Amount::ZERO {
+            Err(AccountError::NotEnoughFunds {
+                available: balance.available,
+                required: -*amount,
+            })?;
+        }
+        balance.available = available;
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<(), AccountError> {
+        if !self.locked {
+            Err(AccountError::AccountNotLocked)?;
+        }
+        self.locked = false;
+        Ok(())
+    }
+}
+
+/// Parts of the state other than accounts and transactions, persisted when the state is flushed
+#[derive(Default, Serialize, Deserialize)]
+struct Metadata {
+    open_disputes: Map<u16, u32>,
+    totals: Map<u16, BTreeMap<Currency, ClientTotals>>,
+    admin_history: Vec<Transaction>,
+    fees_collected: BTreeMap<Currency, Amount>,
+    client_settings: Map<u16, ClientSettings>,
+    activity: Map<u16, Activity>,
+    latest_timestamp: Option<u64>,
+    submissions: Map<String, (Transaction, Result<(), CephalopodError>)>,
 }
 
 /// Representation of system state
 ///
 /// Stores information of all accounts and past transactions
+#[derive(Serialize, Deserialize)]
 pub struct State {
     /// Mapping from client's id to their account state
-    pub(crate) accounts: HashMap<u16, Account>,
+    pub(crate) accounts: Map<u16, Account>,
     /// Mapping from transaction id to original transaction (i.e. withdrawal or deposit)
-    transaction_history: HashMap<u32, Transaction>,
+    transaction_history: Map<u32, Transaction>,
     /// Mapping from transaction id to transaction state that might me affected by disputes
-    transaction_state: HashMap<u32, TransactionState>,
+    transaction_state: Map<u32, TransactionState>,
+    /// Mapping from transaction id to state of the receiving side of a transfer
+    transfer_state: Map<u32, TransactionState>,
+    /// Mapping from transaction id to number of times it has been disputed
+    dispute_count: Map<u32, u32>,
+    /// Mapping from client's id to number of disputes not resolved nor charged back yet
+    open_disputes: Map<u16, u32>,
+    /// Mapping from client's id to their lifetime totals per currency
+    totals: Map<u16, BTreeMap<Currency, ClientTotals>>,
+    /// Administrative operations applied so far, in order of application
+    admin_history: Vec<Transaction>,
+    /// Sums of all fees charged to the accounts per currency
+    fees_collected: BTreeMap<Currency, Amount>,
+    /// Mapping from client's id to settings overriding the policy
+    client_settings: Map<u16, ClientSettings>,
+    /// Mapping from client's id to their recent activity, tracked only when limits are set
+    activity: Map<u16, Activity>,
+    /// Latest timestamp of account activity
+    latest_timestamp: Option<u64>,
+    /// Mapping from idempotency key to the submitted transaction and the result of applying it
+    submissions: Map<String, (Transaction, Result<(), CephalopodError>)>,
+    /// Business rules in effect
+    policy: Policy,
+    /// Backend persisting accounts and transactions, which are then only cached in memory
+    #[serde(skip)]
+    storage: Option<Box<dyn Storage>>,
+    /// Number of transactions above which the cache is evicted
+    #[serde(skip)]
+    cache_capacity: usize,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> State {
+        Self::with_policy(Policy::default())
+    }
+
+    pub fn with_policy(policy: Policy) -> State {
         State {
-            accounts: HashMap::new(),
-            transaction_history: HashMap::new(),
-            transaction_state: HashMap::new(),
+            accounts: Map::new(),
+            transaction_history: Map::new(),
+            transaction_state: Map::new(),
+            transfer_state: Map::new(),
+            dispute_count: Map::new(),
+            open_disputes: Map::new(),
+            totals: Map::new(),
+            admin_history: Vec::new(),
+            fees_collected: BTreeMap::new(),
+            client_settings: Map::new(),
+            activity: Map::new(),
+            submissions: Map::new(),
+            latest_timestamp: None,
+            policy,
+            storage: None,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
         }
     }
 
-    fn get_mut_state<'a>(
-        data: &'a mut HashMap<u32, TransactionState>,
-        tx: &Transaction,
-    ) -> Result<&'a mut TransactionState, CephalopodError> {
-        data.get_mut(&tx.tx)
-            .ok_or_else(|| CephalopodError::IntegrityError {
-                transaction: tx.clone(),
-                error: IntegrityError::StateMissingForTransaction { tx: tx.tx },
-            })
+    /// Opens the state persisted in the storage, or a new one if the storage is empty
+    ///
+    /// The policy replaces the persisted one. At most about `cache_capacity`
+    /// transactions are kept in memory, the rest are loaded when referenced.
+    pub fn open(
+        storage: Box<dyn Storage>,
+        policy: Policy,
+        cache_capacity: usize,
+    ) -> Result<State, StorageError> {
+        let mut state = State::with_policy(policy);
+        state.accounts.extend(storage.accounts()?);
+        if let Some(metadata) = storage.metadata()? {
+            state.restore_metadata(bincode::deserialize(&metadata)?);
+        }
+        state.storage = Some(storage);
+        state.cache_capacity = cache_capacity;
+        Ok(state)
     }
 
-    fn get_mut_account<'a>(
-        data: &'a mut HashMap<u16, Account>,
-        tx: &Transaction,
-    ) -> Result<&'a mut Account, CephalopodError> {
-        data.get_mut(&tx.client)
-            .ok_or_else(|| CephalopodError::IntegrityError {
-                transaction: tx.clone(),
-                error: IntegrityError::AccountMissingForTransaction { client: tx.client },
-            })
+    /// Persists everything not persisted yet, does nothing if the state has no storage
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        let mut storage = match self.storage.take() {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+        let result = self.flush_to(storage.as_mut());
+        self.storage = Some(storage);
+        result
     }
 
-    fn assert_client_match(
-        tx: &Transaction,
-        referenced_tx: &Transaction,
-    ) -> Result<(), CephalopodError> {
-        if tx.client != referenced_tx.client {
-            Err(CephalopodError::TransactionError {
-                transaction: tx.clone(),
-                error: TransactionError::TransactionClientMismatch {
-                    tx: tx.tx,
-                    client: tx.client,
-                },
-            })
-        } else {
-            Ok(())
+    fn flush_to(&mut self, storage: &mut dyn Storage) -> Result<(), StorageError> {
+        storage.begin()?;
+        // accounts in a shared storage are written through and may have been changed by others since
+        if !storage.is_shared() {
+            for (&client, account) in &self.accounts {
+                storage.store_account(client, account)?;
+            }
         }
+        let metadata = self.take_metadata();
+        let encoded = bincode::serialize(&metadata);
+        self.restore_metadata(metadata);
+        storage.store_metadata(&encoded?)?;
+        storage.commit()?;
+        if storage.is_shared() {
+            self.accounts = storage.accounts()?.into_iter().collect();
+        }
+        storage.flush()
     }
 
-    fn assert_state(
-        tx: &Transaction,
-        state: &TransactionState,
-        expected: TransactionState,
-    ) -> Result<(), CephalopodError> {
-        if *state != expected {
-            Err(CephalopodError::TransactionError {
-                transaction: tx.clone(),
-                error: TransactionError::TransactionInvalidState { state: *state },
-            })
+    fn take_metadata(&mut self) -> Metadata {
+        Metadata {
+            open_disputes: std::mem::take(&mut self.open_disputes),
+            totals: std::mem::take(&mut self.totals),
+            admin_history: std::mem::take(&mut self.admin_history),
+            fees_collected: std::mem::take(&mut self.fees_collected),
+            client_settings: std::mem::take(&mut self.client_settings),
+            activity: std::mem::take(&mut self.activity),
+            latest_timestamp: self.latest_timestamp,
+            submissions: std::mem::take(&mut self.submissions),
+        }
+    }
+
+    fn restore_metadata(&mut self, metadata: Metadata) {
+        self.open_disputes = metadata.open_disputes;
+        self.totals = metadata.totals;
+        self.admin_history = metadata.admin_history;
+        self.fees_collected = metadata.fees_collected;
+        self.client_settings = metadata.client_settings;
+        self.activity = metadata.activity;
+        self.latest_timestamp = metadata.latest_timestamp;
+        self.submissions = metadata.submissions;
+    }
+
+    /// Makes sure the transaction with id of `tx` and the accounts it affects are up to date in memory
+    ///
+    /// Unless the storage is shared with other engines, whatever is in memory
+    /// already is up to date. The cache is evicted when it is full.
+    fn load_transaction(&mut self, tx: &Transaction) -> Result<(), StorageError> {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+        let shared = storage.is_shared();
+        let cached = self.transaction_history.contains_key(&tx.tx)
+            || self.transaction_state.contains_key(&tx.tx)
+            || self.transfer_state.contains_key(&tx.tx)
+            || self.dispute_count.contains_key(&tx.tx);
+        if cached && !shared {
+            return Ok(());
+        }
+        // everything has been written through already, so the cache can simply be dropped
+        if self.transaction_history.len() >= self.cache_capacity {
+            self.transaction_history.clear();
+            self.transaction_state.clear();
+            self.transfer_state.clear();
+            self.dispute_count.clear();
+        }
+        let record = storage.transaction(tx.tx)?.unwrap_or_default();
+        let entries = [
+            (&mut self.transaction_state, record.state),
+            (&mut self.transfer_state, record.transfer_state),
+        ];
+        for (states, state) in entries {
+            match state {
+                Some(state) => states.insert(tx.tx, state),
+                None => states.remove(&tx.tx),
+            };
+        }
+        match record.transaction {
+            Some(transaction) => self.transaction_history.insert(tx.tx, transaction),
+            None => self.transaction_history.remove(&tx.tx),
+        };
+        match record.dispute_count {
+            Some(count) => self.dispute_count.insert(tx.tx, count),
+            None => self.dispute_count.remove(&tx.tx),
+        };
+        if shared {
+            let referenced = record.transaction;
+            let clients = std::iter::once(tx.client)
+                .chain(tx.to)
+                .chain(referenced.map(|referenced| referenced.client))
+                .chain(referenced.and_then(|referenced| referenced.to));
+            for client in clients {
+                match storage.account(client)? {
+                    Some(account) => self.accounts.insert(client, account),
+                    None => self.accounts.remove(&client),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn cached_record(&self, tx: u32) -> TransactionRecord {
+        TransactionRecord {
+            transaction: self.transaction_history.get(&tx).copied(),
+            state: self.transaction_state.get(&tx).copied(),
+            transfer_state: self.transfer_state.get(&tx).copied(),
+            dispute_count: self.dispute_count.get(&tx).copied(),
+        }
+    }
+
+    /// Writes the transaction with id of `tx` and the accounts it may have affected to the storage
+    fn store_transaction(&mut self, tx: &Transaction) -> Result<(), StorageError> {
+        let record = self.cached_record(tx.tx);
+        let storage = match &mut self.storage {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+        // entries are never removed, so there is nothing to store for an empty record
+        if record != TransactionRecord::default() {
+            storage.store_transaction(tx.tx, &record)?;
+        }
+        let referenced = record.transaction.filter(|referenced| referenced != tx);
+        let clients = std::iter::once(tx.client)
+            .chain(tx.to)
+            .chain(referenced.map(|referenced| referenced.client))
+            .chain(referenced.and_then(|referenced| referenced.to));
+        for client in clients {
+            if let Some(account) = self.accounts.get(&client) {
+                storage.store_account(client, account)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Replaces the business rules, e.g. after loading a snapshot
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Writes a binary snapshot of the state to `path`
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        snapshot::save(path, self)
+    }
+
+    /// Restores the state from a snapshot written by `save`
+    pub fn load(path: &Path) -> Result<State, SnapshotError> {
+        snapshot::load(path)
+    }
+
+    /// Overrides the policy for a single client
+    ///
+    /// Flags of the settings are set on the account, now or once it is created.
+    pub fn set_client_settings(&mut self, settings: ClientSettings) {
+        if let Some(account) = self.accounts.get_mut(&settings.client) {
+            account.flags.extend(settings.flags.iter().copied());
+        }
+        self.client_settings.insert(settings.client, settings);
+    }
+
+    /// Settings overriding the policy for the client, if any
+    pub fn client_settings(&self, client: u16) -> Option<&ClientSettings> {
+        self.client_settings.get(&client)
+    }
+
+    /// Sets the balance in `currency`, the lock and the flags of the account, e.g. from a previous run's output
+    ///
+    /// Balances in other currencies are kept. Transactions of the previous run
+    /// are not known, so the seeded held funds can't be released by resolving
+    /// or charging back their disputes.
+    pub fn seed_account(
+        &mut self,
+        client: u16,
+        currency: Currency,
+        balance: Balance,
+        locked: bool,
+        flags: &BTreeSet<u32>,
+    ) {
+        let settings = &self.client_settings;
+        let account = self
+            .accounts
+            .entry(client)
+            .or_insert_with(|| Self::new_account(settings, client));
+        account.balances.insert(currency, balance);
+        account.locked = locked;
+        account.flags.extend(flags.iter().copied());
+    }
+
+    fn new_account(settings: &Map<u16, ClientSettings>, client: u16) -> Account {
+        let mut account = Account::new();
+        if let Some(settings) = settings.get(&client) {
+            account.flags.extend(settings.flags.iter().copied());
+        }
+        account
+    }
+
+    /// Rejects outgoing amounts above the limits of flags set on the account
+    fn check_flag_limits(
+        policy: &Policy,
+        tx: &Transaction,
+        account: &Account,
+        amount: &Amount,
+    ) -> Result<(), CephalopodError> {
+        for &flag in &account.flags {
+            if let Some(&limit) = policy.flag_amount_limits.get(&flag) {
+                if *amount > limit {
+                    Err(CephalopodError::TransactionError {
+                        transaction: *tx,
+                        error: TransactionError::FlaggedAmountLimitExceeded {
+                            client: tx.client,
+                            flag,
+                            limit,
+                        },
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn withdrawal_limits(&self, client: u16) -> WithdrawalLimits {
+        let settings = self.client_settings.get(&client);
+        WithdrawalLimits {
+            overdraft: settings
+                .and_then(|settings| settings.overdraft_limit)
+                .unwrap_or(self.policy.overdraft_limit),
+            minimum_balance: settings
+                .and_then(|settings| settings.class.as_ref())
+                .and_then(|class| self.policy.minimum_balances.get(class))
+                .copied(),
+        }
+    }
+
+    /// Returns limits applicable to the transaction, `None` if it is not limited
+    fn transaction_limits(&self, tx: &Transaction) -> Option<TransactionLimits> {
+        match tx.tpe {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Authorize => {}
+            _ => return None,
+        }
+        let settings = self.client_settings.get(&tx.client);
+        let limits = TransactionLimits {
+            max_amount: settings
+                .and_then(|settings| settings.max_amount)
+                .or(self.policy.max_amount),
+            max_daily_total: settings
+                .and_then(|settings| settings.max_daily_total)
+                .or(self.policy.max_daily_total),
+            max_transactions: settings
+                .and_then(|settings| settings.max_transactions)
+                .or(self.policy.max_transactions),
+            window: self
+                .policy
+                .transaction_window
+                .unwrap_or(DEFAULT_TRANSACTION_WINDOW),
+        };
+        if limits.max_amount.is_none()
+            && limits.max_daily_total.is_none()
+            && limits.max_transactions.is_none()
+        {
+            return None;
+        }
+        Some(limits)
+    }
+
+    fn check_limits(
+        &mut self,
+        tx: &Transaction,
+        limits: &TransactionLimits,
+    ) -> Result<(), CephalopodError> {
+        // missing amount is reported when the transaction is applied
+        let amount = match tx.amount {
+            Some(amount) => amount,
+            None => return Ok(()),
+        };
+        if let Some(limit) = limits.max_amount {
+            if amount > limit {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::AmountLimitExceeded { amount, limit },
+                })?;
+            }
+        }
+
+        let timestamp = match tx.timestamp {
+            Some(timestamp) => timestamp,
+            None => return Ok(()),
+        };
+        let activity = self.activity.entry(tx.client).or_default();
+        if let Some(limit) = limits.max_daily_total {
+            let total = match activity.daily_totals.get(&tx.currency) {
+                Some(&total) if activity.day == timestamp / SECONDS_PER_DAY => total
+                    .checked_add(amount)
+                    .ok_or(CephalopodError::IntegrityError {
+                        transaction: *tx,
+                        error: IntegrityError::AmountOverflow { tx: tx.tx },
+                    })?,
+                _ => amount,
+            };
+            if total > limit {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::DailyLimitExceeded {
+                        client: tx.client,
+                        total,
+                        limit,
+                    },
+                })?;
+            }
+        }
+        if let Some(limit) = limits.max_transactions {
+            while activity
+                .recent
+                .front()
+                .is_some_and(|&time| time.saturating_add(limits.window) <= timestamp)
+            {
+                activity.recent.pop_front();
+            }
+            if activity.recent.len() >= limit as usize {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::VelocityLimitExceeded {
+                        client: tx.client,
+                        limit,
+                        window: limits.window,
+                    },
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records successfully applied transaction for the daily and velocity limits
+    fn record_activity(&mut self, tx: &Transaction, limits: &TransactionLimits) {
+        let (amount, timestamp) = match (tx.amount, tx.timestamp) {
+            (Some(amount), Some(timestamp)) => (amount, timestamp),
+            _ => return,
+        };
+        let activity = self.activity.entry(tx.client).or_default();
+        let day = timestamp / SECONDS_PER_DAY;
+        if activity.day != day {
+            activity.day = day;
+            activity.daily_totals.clear();
+        }
+        // only tracked with a daily limit, whose check makes sure the total doesn't overflow
+        if limits.max_daily_total.is_some() {
+            let total = activity
+                .daily_totals
+                .entry(tx.currency)
+                .or_insert(Amount::ZERO);
+            *total = total.checked_add(amount).unwrap_or(Amount::MAX);
+        }
+        if limits.max_transactions.is_some() {
+            activity.recent.push_back(timestamp);
+        }
+    }
+
+    fn get_mut_state<'a>(
+        data: &'a mut Map<u32, TransactionState>,
+        tx: &Transaction,
+    ) -> Result<&'a mut TransactionState, CephalopodError> {
+        data.get_mut(&tx.tx).ok_or(CephalopodError::IntegrityError {
+            transaction: *tx,
+            error: IntegrityError::StateMissingForTransaction { tx: tx.tx },
+        })
+    }
+
+    fn get_mut_account<'a>(
+        data: &'a mut Map<u16, Account>,
+        tx: &Transaction,
+    ) -> Result<&'a mut Account, CephalopodError> {
+        data.get_mut(&tx.client)
+            .ok_or(CephalopodError::IntegrityError {
+                transaction: *tx,
+                error: IntegrityError::AccountMissingForTransaction { client: tx.client },
+            })
+    }
+
+    /// Finds the side of the referenced transaction belonging to the client of `tx`
+    fn find_leg(tx: &Transaction, referenced_tx: &Transaction) -> Result<Leg, CephalopodError> {
+        match referenced_tx.tpe {
+            TransactionType::Withdrawal | TransactionType::Authorize
+                if tx.client == referenced_tx.client =>
+            {
+                Ok(Leg::Debit)
+            }
+            TransactionType::Transfer if tx.client == referenced_tx.client => Ok(Leg::Debit),
+            TransactionType::Transfer if Some(tx.client) == referenced_tx.to => {
+                Ok(Leg::TransferCredit)
+            }
+            _ if tx.client == referenced_tx.client => Ok(Leg::Credit),
+            _ => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::TransactionClientMismatch {
+                    tx: tx.tx,
+                    client: tx.client,
+                },
+            }),
+        }
+    }
+
+    /// Returns the currency of the referenced transaction, unless `tx` specifies a different one
+    fn check_currency(
+        tx: &Transaction,
+        referenced_tx: &Transaction,
+    ) -> Result<Currency, CephalopodError> {
+        if !tx.currency.is_default() && tx.currency != referenced_tx.currency {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::CurrencyMismatch {
+                    tx: tx.tx,
+                    expected: referenced_tx.currency,
+                    provided: tx.currency,
+                },
+            })?;
+        }
+        Ok(referenced_tx.currency)
+    }
+
+    fn assert_state(
+        tx: &Transaction,
+        state: &TransactionState,
+        expected: TransactionState,
+    ) -> Result<(), CephalopodError> {
+        if *state != expected {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::TransactionInvalidState { state: *state },
+            })
         } else {
             Ok(())
         }
     }
 
-    fn get_amount(tx: &Transaction) -> Result<Decimal, CephalopodError> {
-        tx.amount.ok_or_else(|| CephalopodError::IntegrityError {
-            transaction: tx.clone(),
+    /// Checks the type and state of the referenced transaction, see `Policy::check_transition`
+    fn check_transition(
+        policy: &Policy,
+        tx: &Transaction,
+        referenced_tx: &Transaction,
+        leg: Leg,
+        state: &TransactionState,
+    ) -> Result<(), CephalopodError> {
+        policy
+            .check_transition(tx.tpe, tx.tx, referenced_tx.tpe, leg, *state)
+            .map_err(|error| CephalopodError::TransactionError {
+                transaction: *tx,
+                error,
+            })
+    }
+
+    fn get_amount(tx: &Transaction) -> Result<Amount, CephalopodError> {
+        tx.amount.ok_or(CephalopodError::IntegrityError {
+            transaction: *tx,
             error: IntegrityError::AmountMissingForTransaction { tx: tx.tx },
         })
     }
 
-    fn apply_deposit(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
-        let entry = self
-            .accounts
-            .entry(tx.client)
-            .or_insert_with(|| Account::new());
-
-        let amount = tx.amount.ok_or_else(|| CephalopodError::TransactionError {
-            transaction: tx.clone(),
-            error: TransactionError::AmountNotProvided,
-        })?;
-
-        entry.deposit(&amount).map_err(|err| match err {
+    fn withdrawal_error(tx: &Transaction, err: AccountError) -> CephalopodError {
+        match err {
             AccountError::AccountLocked => CephalopodError::TransactionError {
-                transaction: tx.clone(),
+                transaction: *tx,
                 error: TransactionError::AccountLocked { client: tx.client },
             },
             AccountError::NegativeAmount { amount } => CephalopodError::TransactionError {
-                transaction: tx.clone(),
+                transaction: *tx,
                 error: TransactionError::NegativeAmountProvided { amount },
             },
-            _ => CephalopodError::IntegrityError {
-                transaction: tx.clone(),
-                error: IntegrityError::UnexpectedAccountError { error: err },
+            AccountError::NotEnoughFunds {
+                available,
+                required,
+            } => CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::NotEnoughFunds {
+                    available,
+                    required,
+                },
+            },
+            AccountError::MinimumBalance { minimum, remaining } => {
+                CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::MinimumBalanceBreached { minimum, remaining },
+                }
+            }
+            _ => Self::unexpected_account_error(tx, err),
+        }
+    }
+
+    /// Maps account errors that shouldn't happen for the transaction
+    fn unexpected_account_error(tx: &Transaction, err: AccountError) -> CephalopodError {
+        let error = match err {
+            AccountError::Overflow => IntegrityError::AmountOverflow { tx: tx.tx },
+            _ => IntegrityError::UnexpectedAccountError { error: err },
+        };
+        CephalopodError::IntegrityError {
+            transaction: *tx,
+            error,
+        }
+    }
+
+    /// Maps errors of operations on funds that are expected to be held
+    fn held_funds_error(tx: &Transaction, err: AccountError) -> CephalopodError {
+        match err {
+            AccountError::AccountLocked => CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountLocked { client: tx.client },
             },
+            AccountError::NotEnoughFunds {
+                available,
+                required,
+            } => CephalopodError::IntegrityError {
+                transaction: *tx,
+                error: IntegrityError::FundsNotLocked {
+                    available,
+                    required,
+                },
+            },
+            _ => Self::unexpected_account_error(tx, err),
+        }
+    }
+
+    fn close_dispute(open_disputes: &mut Map<u16, u32>, client: u16) {
+        if let Some(count) = open_disputes.get_mut(&client) {
+            *count -= 1;
+            if *count == 0 {
+                open_disputes.remove(&client);
+            }
+        }
+    }
+
+    fn apply_deposit(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let settings = &self.client_settings;
+        let entry = self
+            .accounts
+            .entry(tx.client)
+            .or_insert_with(|| Self::new_account(settings, tx.client));
+
+        let amount = tx.amount.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::AmountNotProvided,
         })?;
+
+        entry
+            .deposit(tx.currency, &amount, self.policy.allow_deposits_to_locked)
+            .map_err(|err| match err {
+                AccountError::AccountLocked => CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::AccountLocked { client: tx.client },
+                },
+                AccountError::NegativeAmount { amount } => CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::NegativeAmountProvided { amount },
+                },
+                _ => Self::unexpected_account_error(tx, err),
+            })?;
         self.transaction_history.insert(tx.tx, *tx);
         self.transaction_state
             .insert(tx.tx, TransactionState::Deposited);
+        let totals = self.totals_mut(tx.client, tx.currency);
+        totals.deposited = ClientTotals::add(totals.deposited, amount, &mut totals.saturated);
         Ok(())
     }
 
+    fn totals_mut(&mut self, client: u16, currency: Currency) -> &mut ClientTotals {
+        self.totals
+            .entry(client)
+            .or_default()
+            .entry(currency)
+            .or_default()
+    }
+
     fn apply_withdrawal(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let limits = self.withdrawal_limits(tx.client);
         let account =
             self.accounts
                 .get_mut(&tx.client)
-                .ok_or_else(|| CephalopodError::TransactionError {
-                    transaction: tx.clone(),
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
                     error: TransactionError::UnknownAccount { client: tx.client },
                 })?;
+        Self::check_frozen(tx, account)?;
 
-        let amount = tx.amount.ok_or_else(|| CephalopodError::TransactionError {
-            transaction: tx.clone(),
+        let amount = tx.amount.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
             error: TransactionError::AmountNotProvided,
         })?;
+        Self::check_flag_limits(&self.policy, tx, account, &amount)?;
 
-        account.withdraw(&amount).map_err(|err| match err {
-            AccountError::AccountLocked => CephalopodError::TransactionError {
-                transaction: tx.clone(),
-                error: TransactionError::AccountLocked { client: tx.client },
-            },
-            AccountError::NegativeAmount { amount } => CephalopodError::TransactionError {
-                transaction: tx.clone(),
-                error: TransactionError::NegativeAmountProvided { amount },
-            },
-            AccountError::NotEnoughFunds {
-                available,
-                required,
-            } => CephalopodError::TransactionError {
-                transaction: tx.clone(),
-                error: TransactionError::NotEnoughFunds {
-                    available,
-                    required,
-                },
-            },
+        account
+            .withdraw(tx.currency, &amount, &limits)
+            .map_err(|err| Self::withdrawal_error(tx, err))?;
+        self.transaction_history.insert(tx.tx, *tx);
+        self.transaction_state
+            .insert(tx.tx, TransactionState::Withdrawn);
+        Ok(())
+    }
+
+    fn apply_transfer(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let to = tx.to.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::CounterpartyNotProvided,
+        })?;
+        if to == tx.client {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::SelfTransfer { client: to },
+            })?;
+        }
+
+        let amount = tx.amount.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::AmountNotProvided,
         })?;
+
+        // checked upfront, so that the debit is never applied without the credit
+        let receiver = self.accounts.get(&to);
+        if receiver.is_some_and(|account| account.locked) {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountLocked { client: to },
+            })?;
+        }
+        let received = receiver
+            .and_then(|account| account.balances.get(&tx.currency))
+            .map_or(Amount::ZERO, |balance| balance.available);
+        credit(received, amount).map_err(|err| Self::unexpected_account_error(tx, err))?;
+
+        let limits = self.withdrawal_limits(tx.client);
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+        Self::check_frozen(tx, account)?;
+        Self::check_flag_limits(&self.policy, tx, account, &amount)?;
+        account
+            .withdraw(tx.currency, &amount, &limits)
+            .map_err(|err| Self::withdrawal_error(tx, err))?;
+
+        let settings = &self.client_settings;
+        self.accounts
+            .entry(to)
+            .or_insert_with(|| Self::new_account(settings, to))
+            .deposit(tx.currency, &amount, false)
+            .map_err(|err| Self::unexpected_account_error(tx, err))?;
+
         self.transaction_history.insert(tx.tx, *tx);
         self.transaction_state
             .insert(tx.tx, TransactionState::Withdrawn);
+        self.transfer_state
+            .insert(tx.tx, TransactionState::Deposited);
+        Ok(())
+    }
+
+    fn apply_fee(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+
+        let amount = tx.amount.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::AmountNotProvided,
+        })?;
+
+        // checked upfront, so that the fee is never charged without being collected
+        let fees_collected = self
+            .fees_collected
+            .get(&tx.currency)
+            .copied()
+            .unwrap_or_default()
+            .checked_add(amount)
+            .ok_or(CephalopodError::IntegrityError {
+                transaction: *tx,
+                error: IntegrityError::AmountOverflow { tx: tx.tx },
+            })?;
+        account
+            .charge_fee(tx.currency, &amount, self.policy.allow_fee_overdraft)
+            .map_err(|err| Self::withdrawal_error(tx, err))?;
+        self.fees_collected.insert(tx.currency, fees_collected);
+        Ok(())
+    }
+
+    fn apply_adjustment(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+
+        let amount = tx.amount.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::AmountNotProvided,
+        })?;
+        if tx.reason.is_none() {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::ReasonNotProvided,
+            })?;
+        }
+
+        account
+            .adjust(tx.currency, &amount, self.policy.allow_adjustment_overdraft)
+            .map_err(|err| Self::withdrawal_error(tx, err))?;
+        self.admin_history.push(*tx);
         Ok(())
     }
 
     fn apply_dispute(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
         match self.transaction_history.get(&tx.tx) {
             Some(disputed_tx) => {
-                let tstate = Self::get_mut_state(&mut self.transaction_state, tx)?;
-                Self::assert_state(tx, tstate, TransactionState::Deposited)?;
-                Self::assert_client_match(tx, disputed_tx)?;
+                let leg = Self::find_leg(tx, disputed_tx)?;
+                let currency = Self::check_currency(tx, disputed_tx)?;
+                let states = match leg {
+                    Leg::TransferCredit => &mut self.transfer_state,
+                    _ => &mut self.transaction_state,
+                };
+                let tstate = Self::get_mut_state(states, tx)?;
+                Self::check_transition(&self.policy, tx, disputed_tx, leg, tstate)?;
+                if let (Some(days), Some(filed), Some(original)) = (
+                    self.policy.dispute_window_days,
+                    tx.timestamp,
+                    disputed_tx.timestamp,
+                ) {
+                    if filed.saturating_sub(original) > u64::from(days) * SECONDS_PER_DAY {
+                        Err(CephalopodError::TransactionError {
+                            transaction: *tx,
+                            error: TransactionError::DisputeWindowExpired { tx: tx.tx, days },
+                        })?;
+                    }
+                }
+                let disputes = self.dispute_count.get(&tx.tx).copied().unwrap_or(0);
+                if let Some(limit) = self.policy.max_disputes {
+                    if disputes >= limit {
+                        Err(CephalopodError::TransactionError {
+                            transaction: *tx,
+                            error: TransactionError::DisputeLimitReached { tx: tx.tx, limit },
+                        })?;
+                    }
+                }
                 let account = Self::get_mut_account(&mut self.accounts, tx)?;
-                account
-                    .lock(&Self::get_amount(disputed_tx)?)
-                    .map_err(|err| match err {
-                        AccountError::AccountLocked => CephalopodError::TransactionError {
-                            transaction: tx.clone(),
-                            error: TransactionError::AccountLocked { client: tx.client },
-                        },
-                        AccountError::NotEnoughFunds {
+                let amount = Self::get_amount(disputed_tx)?;
+                match leg {
+                    Leg::Debit => account.lock_withdrawn(currency, &amount),
+                    _ => account.lock(currency, &amount, self.policy.allow_dispute_overdraft),
+                }
+                .map_err(|err| match err {
+                    AccountError::AccountLocked => CephalopodError::TransactionError {
+                        transaction: *tx,
+                        error: TransactionError::AccountLocked { client: tx.client },
+                    },
+                    AccountError::NotEnoughFunds {
+                        available,
+                        required,
+                    } => CephalopodError::TransactionError {
+                        transaction: *tx,
+                        error: TransactionError::NotEnoughFunds {
                             available,
                             required,
-                        } => CephalopodError::TransactionError {
-                            transaction: tx.clone(),
-                            error: TransactionError::NotEnoughFunds {
-                                available,
-                                required,
-                            },
-                        },
-                        _ => CephalopodError::IntegrityError {
-                            transaction: tx.clone(),
-                            error: IntegrityError::UnexpectedAccountError { error: err },
                         },
-                    })?;
+                    },
+                    _ => Self::unexpected_account_error(tx, err),
+                })?;
                 *tstate = TransactionState::Disputed;
+                self.dispute_count.insert(tx.tx, disputes + 1);
+                *self.open_disputes.entry(tx.client).or_insert(0) += 1;
+                let totals = self.totals_mut(tx.client, currency);
+                totals.disputed = ClientTotals::add(totals.disputed, amount, &mut totals.saturated);
                 Ok(())
             }
             None => Err(CephalopodError::TransactionError {
-                transaction: tx.clone(),
+                transaction: *tx,
                 error: TransactionError::TransactionNotFound { tx: tx.tx },
             }),
         }
@@ -411,37 +1846,27 @@ impl State {
     fn apply_resolve(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
         match self.transaction_history.get(&tx.tx) {
             Some(resolved_tx) => {
-                let tstate = Self::get_mut_state(&mut self.transaction_state, tx)?;
-                Self::assert_state(tx, tstate, TransactionState::Disputed)?;
-                Self::assert_client_match(tx, resolved_tx)?;
+                let leg = Self::find_leg(tx, resolved_tx)?;
+                let currency = Self::check_currency(tx, resolved_tx)?;
+                let states = match leg {
+                    Leg::TransferCredit => &mut self.transfer_state,
+                    _ => &mut self.transaction_state,
+                };
+                let tstate = Self::get_mut_state(states, tx)?;
+                Self::check_transition(&self.policy, tx, resolved_tx, leg, tstate)?;
                 let account = Self::get_mut_account(&mut self.accounts, tx)?;
-                account
-                    .release(&Self::get_amount(resolved_tx)?)
-                    .map_err(|err| match err {
-                        AccountError::AccountLocked => CephalopodError::TransactionError {
-                            transaction: tx.clone(),
-                            error: TransactionError::AccountLocked { client: tx.client },
-                        },
-                        AccountError::NotEnoughFunds {
-                            available,
-                            required,
-                        } => CephalopodError::IntegrityError {
-                            transaction: tx.clone(),
-                            error: IntegrityError::FundsNotLocked {
-                                available,
-                                required,
-                            },
-                        },
-                        _ => CephalopodError::IntegrityError {
-                            transaction: tx.clone(),
-                            error: IntegrityError::UnexpectedAccountError { error: err },
-                        },
-                    })?;
+                let amount = Self::get_amount(resolved_tx)?;
+                match leg {
+                    Leg::Debit => account.release_withdrawn(currency, &amount),
+                    _ => account.release(currency, &amount),
+                }
+                .map_err(|err| Self::held_funds_error(tx, err))?;
                 *tstate = TransactionState::Resolved;
+                Self::close_dispute(&mut self.open_disputes, tx.client);
                 Ok(())
             }
             None => Err(CephalopodError::TransactionError {
-                transaction: tx.clone(),
+                transaction: *tx,
                 error: TransactionError::TransactionNotFound { tx: tx.tx },
             }),
         }
@@ -450,57 +1875,851 @@ impl State {
     fn apply_chargeback(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
         match self.transaction_history.get(&tx.tx) {
             Some(chargebacked_tx) => {
-                let tstate = Self::get_mut_state(&mut self.transaction_state, tx)?;
-                Self::assert_state(tx, tstate, TransactionState::Disputed)?;
-                Self::assert_client_match(tx, chargebacked_tx)?;
+                let leg = Self::find_leg(tx, chargebacked_tx)?;
+                let currency = Self::check_currency(tx, chargebacked_tx)?;
+                let states = match leg {
+                    Leg::TransferCredit => &mut self.transfer_state,
+                    _ => &mut self.transaction_state,
+                };
+                let tstate = Self::get_mut_state(states, tx)?;
+                Self::check_transition(&self.policy, tx, chargebacked_tx, leg, tstate)?;
                 let account = Self::get_mut_account(&mut self.accounts, tx)?;
-                account
-                    .chargeback(&Self::get_amount(chargebacked_tx)?)
-                    .map_err(|err| match err {
-                        AccountError::AccountLocked => CephalopodError::TransactionError {
-                            transaction: tx.clone(),
-                            error: TransactionError::AccountLocked { client: tx.client },
-                        },
-                        AccountError::NotEnoughFunds {
-                            available,
-                            required,
-                        } => CephalopodError::IntegrityError {
-                            transaction: tx.clone(),
-                            error: IntegrityError::FundsNotLocked {
-                                available,
-                                required,
-                            },
-                        },
-                        _ => CephalopodError::IntegrityError {
-                            transaction: tx.clone(),
-                            error: IntegrityError::UnexpectedAccountError { error: err },
-                        },
-                    })?;
+                let amount = Self::get_amount(chargebacked_tx)?;
+                match leg {
+                    Leg::Debit => account.chargeback_withdrawn(currency, &amount),
+                    _ => account.chargeback(currency, &amount),
+                }
+                .map_err(|err| Self::held_funds_error(tx, err))?;
                 *tstate = TransactionState::Chargebacked;
+                Self::close_dispute(&mut self.open_disputes, tx.client);
+                let totals = self.totals_mut(tx.client, currency);
+                totals.charged_back =
+                    ClientTotals::add(totals.charged_back, amount, &mut totals.saturated);
+                Ok(())
+            }
+            None => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::TransactionNotFound { tx: tx.tx },
+            }),
+        }
+    }
+
+    fn apply_representment(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        match self.transaction_history.get(&tx.tx) {
+            Some(represented_tx) => {
+                let leg = Self::find_leg(tx, represented_tx)?;
+                let currency = Self::check_currency(tx, represented_tx)?;
+                let states = match leg {
+                    Leg::TransferCredit => &mut self.transfer_state,
+                    _ => &mut self.transaction_state,
+                };
+                let tstate = Self::get_mut_state(states, tx)?;
+                Self::check_transition(&self.policy, tx, represented_tx, leg, tstate)?;
+                let account = Self::get_mut_account(&mut self.accounts, tx)?;
+                let amount = Self::get_amount(represented_tx)?;
+                let unlock = self.policy.unlock_on_representment;
+                match leg {
+                    Leg::Debit => account.represent_withdrawn(currency, &amount, unlock),
+                    _ => account.represent(currency, &amount, unlock),
+                }
+                .map_err(|err| Self::unexpected_account_error(tx, err))?;
+                *tstate = TransactionState::Represented;
+                Ok(())
+            }
+            None => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::TransactionNotFound { tx: tx.tx },
+            }),
+        }
+    }
+
+    fn apply_reversal(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        match self.transaction_history.get(&tx.tx) {
+            Some(reversed_tx) => {
+                let leg = Self::find_leg(tx, reversed_tx)?;
+                let currency = Self::check_currency(tx, reversed_tx)?;
+                let tstate = Self::get_mut_state(&mut self.transaction_state, tx)?;
+                Self::check_transition(&self.policy, tx, reversed_tx, leg, tstate)?;
+                let account = Self::get_mut_account(&mut self.accounts, tx)?;
+                let amount = Self::get_amount(reversed_tx)?;
+                match leg {
+                    Leg::Debit => account.deposit(currency, &amount, false),
+                    _ => account.withdraw(currency, &amount, &WithdrawalLimits::default()),
+                }
+                .map_err(|err| Self::withdrawal_error(tx, err))?;
+                *tstate = TransactionState::Voided;
+                Ok(())
+            }
+            None => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::TransactionNotFound { tx: tx.tx },
+            }),
+        }
+    }
+
+    fn apply_authorize(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+        Self::check_frozen(tx, account)?;
+
+        let amount = tx.amount.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::AmountNotProvided,
+        })?;
+        Self::check_flag_limits(&self.policy, tx, account, &amount)?;
+
+        account
+            .authorize(tx.currency, &amount)
+            .map_err(|err| Self::withdrawal_error(tx, err))?;
+        self.transaction_history.insert(tx.tx, *tx);
+        self.transaction_state
+            .insert(tx.tx, TransactionState::Authorized);
+        Ok(())
+    }
+
+    /// Handles both capture and void of an authorization
+    fn apply_authorization_completion(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        match self.transaction_history.get(&tx.tx) {
+            Some(authorization) if authorization.tpe == TransactionType::Authorize => {
+                Self::find_leg(tx, authorization)?;
+                let currency = Self::check_currency(tx, authorization)?;
+                let tstate = Self::get_mut_state(&mut self.transaction_state, tx)?;
+                Self::assert_state(tx, tstate, TransactionState::Authorized)?;
+                let account = Self::get_mut_account(&mut self.accounts, tx)?;
+                let amount = Self::get_amount(authorization)?;
+                let new_state = match tx.tpe {
+                    TransactionType::Capture => {
+                        account
+                            .capture(currency, &amount)
+                            .map_err(|err| Self::held_funds_error(tx, err))?;
+                        TransactionState::Withdrawn
+                    }
+                    _ => {
+                        account
+                            .release(currency, &amount)
+                            .map_err(|err| Self::held_funds_error(tx, err))?;
+                        TransactionState::Voided
+                    }
+                };
+                *tstate = new_state;
                 Ok(())
             }
+            Some(_) => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::TransactionNotAuthorization { tx: tx.tx },
+            }),
             None => Err(CephalopodError::TransactionError {
-                transaction: tx.clone(),
+                transaction: *tx,
                 error: TransactionError::TransactionNotFound { tx: tx.tx },
             }),
         }
     }
 
+    fn apply_open(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        match self.accounts.get_mut(&tx.client) {
+            Some(account) if !account.closed => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountAlreadyOpen { client: tx.client },
+            }),
+            Some(account) => {
+                account.closed = false;
+                self.admin_history.push(*tx);
+                Ok(())
+            }
+            None => {
+                let account = Self::new_account(&self.client_settings, tx.client);
+                self.accounts.insert(tx.client, account);
+                self.admin_history.push(*tx);
+                Ok(())
+            }
+        }
+    }
+
+    fn apply_close(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+
+        if let Some(&count) = self.open_disputes.get(&tx.client) {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::OpenDisputes {
+                    client: tx.client,
+                    count,
+                },
+            })?;
+        }
+        if let Some((&currency, balance)) = account
+            .balances
+            .iter()
+            .find(|(_, balance)| balance.available != Amount::ZERO || balance.held != Amount::ZERO)
+        {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::NonZeroBalance {
+                    client: tx.client,
+                    currency,
+                    available: balance.available,
+                    held: balance.held,
+                },
+            })?;
+        }
+        account.closed = true;
+        self.admin_history.push(*tx);
+        Ok(())
+    }
+
+    /// Rejects transactions reusing the id of a recorded one
+    ///
+    /// Returns `true` if the transaction is an identical replay that should be skipped.
+    fn check_duplicate(&self, tx: &Transaction) -> Result<bool, CephalopodError> {
+        match tx.tpe {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Authorize => {}
+            _ => return Ok(false),
+        }
+        match self.transaction_history.get(&tx.tx) {
+            None => Ok(false),
+            Some(original) if self.policy.ignore_identical_duplicates && original == tx => Ok(true),
+            Some(_) => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::DuplicateTransaction { tx: tx.tx },
+            }),
+        }
+    }
+
+    /// Whether the transaction repeats the resolve or chargeback the referenced transaction
+    /// has already been through, to be skipped according to `Policy::ignore_repeated_outcomes`
+    pub fn is_repeated_outcome(&self, tx: &Transaction) -> bool {
+        if !self.policy.ignore_repeated_outcomes {
+            return false;
+        }
+        let referenced_tx = match self.transaction_history.get(&tx.tx) {
+            Some(referenced_tx) => referenced_tx,
+            None => return false,
+        };
+        let states = match Self::find_leg(tx, referenced_tx) {
+            Ok(Leg::TransferCredit) => &self.transfer_state,
+            Ok(_) => &self.transaction_state,
+            Err(_) => return false,
+        };
+        states
+            .get(&tx.tx)
+            .is_some_and(|&state| self.policy.ignores_outcome(tx.tpe, state))
+    }
+
+    /// Rejects transactions affecting closed accounts, except for reopening them
+    fn check_closed(&self, tx: &Transaction) -> Result<(), CephalopodError> {
+        if tx.tpe == TransactionType::Open {
+            return Ok(());
+        }
+        for client in std::iter::once(tx.client).chain(tx.to) {
+            if self
+                .accounts
+                .get(&client)
+                .is_some_and(|account| account.closed)
+            {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::AccountClosed { client },
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects transactions taking funds out of frozen accounts
+    fn check_frozen(tx: &Transaction, account: &Account) -> Result<(), CephalopodError> {
+        if account.frozen {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountFrozen { client: tx.client },
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Handles both freeze and unfreeze of an account
+    fn apply_freeze(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+
+        let freeze = tx.tpe == TransactionType::Freeze;
+        match (freeze, account.frozen) {
+            (true, true) => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountAlreadyFrozen { client: tx.client },
+            })?,
+            (false, false) => Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountNotFrozen { client: tx.client },
+            })?,
+            _ => {}
+        }
+        account.frozen = freeze;
+        self.admin_history.push(*tx);
+        Ok(())
+    }
+
+    /// Handles both setting and clearing of a risk flag
+    fn apply_flag(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+
+        let flag = tx.reason.ok_or(CephalopodError::TransactionError {
+            transaction: *tx,
+            error: TransactionError::ReasonNotProvided,
+        })?;
+        if tx.tpe == TransactionType::Flag {
+            if !account.flags.insert(flag) {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::FlagAlreadySet {
+                        client: tx.client,
+                        flag,
+                    },
+                })?;
+            }
+        } else if !account.flags.remove(&flag) {
+            Err(CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::FlagNotSet {
+                    client: tx.client,
+                    flag,
+                },
+            })?;
+        }
+        self.admin_history.push(*tx);
+        Ok(())
+    }
+
+    fn apply_unlock(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let account =
+            self.accounts
+                .get_mut(&tx.client)
+                .ok_or(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::UnknownAccount { client: tx.client },
+                })?;
+
+        account.unlock().map_err(|err| match err {
+            AccountError::AccountNotLocked => CephalopodError::TransactionError {
+                transaction: *tx,
+                error: TransactionError::AccountNotLocked { client: tx.client },
+            },
+            _ => Self::unexpected_account_error(tx, err),
+        })?;
+        self.admin_history.push(*tx);
+        Ok(())
+    }
+
     /// Applies a transaction to the state
     ///
-    /// If error is returned it means that the transaction has not been applied
+    /// If error is returned it means that the transaction has not been applied,
+    /// changes made before an integrity error are rolled back.
+    /// Runs in an info level `transaction` span with the tenant, id, client and
+    /// type of the transaction, recording its `outcome` as `applied` or the
+    /// kind of the error (e.g. `NotEnoughFunds`).
     pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
-        match tx.tpe {
+        let span = info_span!(
+            "transaction",
+            tenant = tx.tenant,
+            tx = tx.tx,
+            client = tx.client,
+            "type" = ?tx.tpe,
+            outcome = field::Empty,
+        );
+        let _entered = span.enter();
+        let result = self.apply(tx);
+        if !span.is_disabled() {
+            match &result {
+                Ok(()) => span.record("outcome", "applied"),
+                Err(err) => span.record("outcome", err.kind()),
+            };
+        }
+        result
+    }
+
+    fn apply(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let tx = &self.limit_precision(tx)?;
+        self.check_amount_cap(tx)?;
+        let storage_error = |err: StorageError| {
+            error!(
+                "Storage error while processing transaction {}: {}",
+                tx.tx, err
+            );
+            CephalopodError::IntegrityError {
+                transaction: *tx,
+                error: IntegrityError::StorageFailure { tx: tx.tx },
+            }
+        };
+        let storage = match &mut self.storage {
+            Some(storage) => storage,
+            None => return self.apply_cached(tx),
+        };
+        // reading and writing within one storage transaction makes the apply atomic
+        storage.begin().map_err(storage_error)?;
+        let mut result = Ok(());
+        let stored = self
+            .load_transaction(tx)
+            .and_then(|()| {
+                result = self.apply_cached(tx);
+                self.store_transaction(tx)
+            })
+            .and_then(|()| match &mut self.storage {
+                Some(storage) => storage.commit(),
+                None => Ok(()),
+            });
+        if let Err(err) = stored {
+            if let Some(storage) = &mut self.storage {
+                if let Err(err) = storage.rollback() {
+                    error!("Failed to roll back transaction {}: {}", tx.tx, err);
+                }
+            }
+            return Err(storage_error(err));
+        }
+        result
+    }
+
+    /// Handles the amount of the transaction according to `Policy::excess_precision`
+    fn limit_precision(&self, tx: &Transaction) -> Result<Transaction, CephalopodError> {
+        let amount = match tx.amount {
+            Some(amount) if amount::is_too_precise(amount) => amount,
+            _ => return Ok(*tx),
+        };
+        let strategy = match self.policy.excess_precision {
+            ExcessPrecision::Keep => return Ok(*tx),
+            ExcessPrecision::Reject => {
+                return Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::AmountTooPrecise { amount },
+                })
+            }
+            ExcessPrecision::RoundHalfEven => RoundingStrategy::MidpointNearestEven,
+            ExcessPrecision::RoundHalfUp => RoundingStrategy::MidpointAwayFromZero,
+            ExcessPrecision::Truncate => RoundingStrategy::ToZero,
+        };
+        Ok(Transaction {
+            amount: Some(amount::round(amount, strategy)),
+            ..*tx
+        })
+    }
+
+    /// Rejects the transaction if its amount exceeds `Policy::amount_cap`
+    fn check_amount_cap(&self, tx: &Transaction) -> Result<(), CephalopodError> {
+        if let (Some(amount), Some(cap)) = (tx.amount, self.policy.amount_cap) {
+            if amount > cap || amount < -cap {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::AmountCapExceeded { amount, cap },
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a transaction to the state, assuming everything it references is in memory
+    fn apply_cached(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        if self.check_duplicate(tx)? {
+            return Ok(());
+        }
+        if self.is_repeated_outcome(tx) {
+            info!("Skipping repeated {:?} of transaction {}", tx.tpe, tx.tx);
+            return Ok(());
+        }
+        self.check_closed(tx)?;
+        let limits = self.transaction_limits(tx);
+        if let Some(limits) = &limits {
+            self.check_limits(tx, limits)?;
+        }
+        let rollback = Rollback::take(self, tx);
+        let result = match tx.tpe {
             TransactionType::Deposit => self.apply_deposit(tx),
             TransactionType::Withdrawal => self.apply_withdrawal(tx),
             TransactionType::Dispute => self.apply_dispute(tx),
             TransactionType::Resolve => self.apply_resolve(tx),
             TransactionType::Chargeback => self.apply_chargeback(tx),
+            TransactionType::Representment => self.apply_representment(tx),
+            TransactionType::Unlock => self.apply_unlock(tx),
+            TransactionType::Transfer => self.apply_transfer(tx),
+            TransactionType::Fee => self.apply_fee(tx),
+            TransactionType::Adjustment => self.apply_adjustment(tx),
+            TransactionType::Reversal => self.apply_reversal(tx),
+            TransactionType::Authorize => self.apply_authorize(tx),
+            TransactionType::Capture | TransactionType::Void => {
+                self.apply_authorization_completion(tx)
+            }
+            TransactionType::Open => self.apply_open(tx),
+            TransactionType::Close => self.apply_close(tx),
+            TransactionType::Freeze | TransactionType::Unfreeze => self.apply_freeze(tx),
+            TransactionType::Flag | TransactionType::Unflag => self.apply_flag(tx),
+        };
+        if let Err(CephalopodError::IntegrityError { .. }) = result {
+            rollback.restore(self);
         }
+        result?;
+        if let Some(limits) = &limits {
+            self.record_activity(tx, limits);
+        }
+        self.touch_accounts(tx);
+        Ok(())
+    }
+
+    /// Updates the last activity of accounts taking part in a successfully applied transaction
+    fn touch_accounts(&mut self, tx: &Transaction) {
+        let timestamp = match (tx.tpe, tx.timestamp) {
+            (
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Authorize
+                | TransactionType::Capture,
+                Some(timestamp),
+            ) => timestamp,
+            _ => return,
+        };
+        self.latest_timestamp = self.latest_timestamp.max(Some(timestamp));
+        for client in std::iter::once(tx.client).chain(tx.to) {
+            if let Some(account) = self.accounts.get_mut(&client) {
+                account.last_activity = account.last_activity.max(Some(timestamp));
+            }
+        }
+    }
+
+    /// Accounts without activity for more than `days` before the latest activity of any account
+    ///
+    /// Accounts that never had timestamped activity are not reported, as
+    /// their dormancy can't be determined.
+    pub fn dormant_accounts(&self, days: u32) -> Vec<(u16, u64)> {
+        let latest = match self.latest_timestamp {
+            Some(latest) => latest,
+            None => return Vec::new(),
+        };
+        let mut dormant: Vec<(u16, u64)> = self
+            .accounts
+            .iter()
+            .filter_map(|(&client, account)| Some((client, account.last_activity?)))
+            .filter(|(_, last_activity)| latest - last_activity > u64::from(days) * SECONDS_PER_DAY)
+            .collect();
+        dormant.sort_unstable();
+        dormant
+    }
+
+    /// Applies a transaction submitted with an idempotency key
+    ///
+    /// Retries with the same key return the result of the original submission
+    /// without applying the transaction again, so that sources with at-least-once
+    /// delivery don't apply it twice. Keys are remembered for the lifetime of the state.
+    pub fn apply_submission(&mut self, key: &str, tx: &Transaction) -> Result<(), CephalopodError> {
+        if let Some((original, result)) = self.submissions.get(key) {
+            if original != tx {
+                Err(CephalopodError::TransactionError {
+                    transaction: *tx,
+                    error: TransactionError::IdempotencyKeyReused { tx: tx.tx },
+                })?;
+            }
+            return *result;
+        }
+        let result = self.apply_transaction(tx);
+        self.submissions.insert(key.to_string(), (*tx, result));
+        result
+    }
+
+    /// Administrative operations (e.g. unlocks, adjustments, account closures) applied so far, oldest first
+    pub fn admin_history(&self) -> &[Transaction] {
+        &self.admin_history
+    }
+
+    /// Totals of fees charged so far per currency
+    pub fn fees_collected(&self) -> &BTreeMap<Currency, Amount> {
+        &self.fees_collected
+    }
+
+    /// Approximate memory taken by the accounts and the history kept in memory
+    ///
+    /// With storage, it only covers the cached part of the history.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let account_heap: usize = self
+            .accounts
+            .values()
+            .map(|account| {
+                account.balances.len() * mem::size_of::<(Currency, Balance)>()
+                    + account.flags.len() * mem::size_of::<u32>()
+            })
+            .sum();
+        let activity_heap: usize = self
+            .activity
+            .values()
+            .map(|activity| {
+                activity.daily_totals.len() * mem::size_of::<(Currency, Amount)>()
+                    + activity.recent.len() * mem::size_of::<u64>()
+            })
+            .sum();
+        let totals_heap: usize = self
+            .totals
+            .values()
+            .map(|totals| totals.len() * mem::size_of::<(Currency, ClientTotals)>())
+            .sum();
+        let accounts = Usage::of_map::<u16, Account>(self.accounts.len()).with_heap(account_heap)
+            + Usage::of_map::<u16, ClientSettings>(self.client_settings.len()).bytes_only()
+            + Usage::of_map::<u16, Activity>(self.activity.len())
+                .with_heap(activity_heap)
+                .bytes_only()
+            + Usage::of_map::<u16, u32>(self.open_disputes.len()).bytes_only()
+            + Usage::of_map::<u16, BTreeMap<Currency, ClientTotals>>(self.totals.len())
+                .with_heap(totals_heap)
+                .bytes_only();
+        let key_heap: usize = self.submissions.keys().map(String::len).sum();
+        let history = Usage::of_map::<u32, Transaction>(self.transaction_history.len())
+            + Usage::of_seq::<Transaction>(self.admin_history.len())
+            + Usage::of_map::<u32, TransactionState>(self.transaction_state.len()).bytes_only()
+            + Usage::of_map::<u32, TransactionState>(self.transfer_state.len()).bytes_only()
+            + Usage::of_map::<u32, u32>(self.dispute_count.len()).bytes_only()
+            + Usage::of_map::<String, (Transaction, Result<(), CephalopodError>)>(
+                self.submissions.len(),
+            )
+            .with_heap(key_heap)
+            .bytes_only();
+        MemoryUsage {
+            accounts,
+            history,
+            allocator: memory::allocator_stats(),
+        }
+    }
+
+    /// Returns the account of the client, if it exists
+    pub fn account(&self, client: u16) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Tag of the transaction, or of the recorded transaction it refers to if it has none, e.g.
+    /// the upstream order id of a disputed deposit
+    pub fn tag(&self, tx: &Transaction) -> Tag {
+        match self.transaction_history.get(&tx.tx) {
+            Some(referenced) if tx.tag.is_empty() => referenced.tag,
+            _ => tx.tag,
+        }
+    }
+
+    /// Returns the recorded transaction (i.e. deposit, withdrawal, transfer or authorization) with given id
+    pub fn transaction(&self, tx: u32) -> Option<&Transaction> {
+        self.transaction_history.get(&tx)
+    }
+
+    /// Iterates over recorded transactions, in no particular order
+    ///
+    /// With storage, only the transactions currently cached in memory are included.
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.transaction_history.values()
+    }
+
+    /// Returns everything known about the transaction with given id, reading it from the storage if necessary
+    pub fn transaction_record(&self, tx: u32) -> Result<TransactionRecord, StorageError> {
+        let record = self.cached_record(tx);
+        match &self.storage {
+            Some(storage) if record == TransactionRecord::default() || storage.is_shared() => {
+                Ok(storage.transaction(tx)?.unwrap_or_default())
+            }
+            _ => Ok(record),
+        }
+    }
+
+    /// Returns transactions with an open dispute, ordered by id
+    ///
+    /// With storage, only the transactions currently cached in memory are
+    /// considered. `open_disputes` counts all of them.
+    pub fn disputed_transactions(&self) -> Vec<&Transaction> {
+        let mut disputed: Vec<&Transaction> = self
+            .transaction_state
+            .iter()
+            .chain(&self.transfer_state)
+            .filter(|(_, &state)| state == TransactionState::Disputed)
+            .filter_map(|(tx, _)| self.transaction_history.get(tx))
+            .collect();
+        disputed.sort_unstable_by_key(|transaction| transaction.tx);
+        disputed.dedup_by_key(|transaction| transaction.tx);
+        disputed
+    }
+
+    /// Returns funds that should be held for open disputes and authorizations, by client and currency
+    ///
+    /// With storage, only the transactions currently cached in memory are considered.
+    pub fn held_by_transactions(&self) -> BTreeMap<(u16, Currency), Amount> {
+        let debits = self
+            .transaction_state
+            .iter()
+            .filter(|(_, &state)| {
+                state == TransactionState::Disputed || state == TransactionState::Authorized
+            })
+            .filter_map(|(tx, _)| {
+                let transaction = self.transaction_history.get(tx)?;
+                Some((transaction.client, transaction))
+            });
+        let credits = self
+            .transfer_state
+            .iter()
+            .filter(|(_, &state)| state == TransactionState::Disputed)
+            .filter_map(|(tx, _)| {
+                let transaction = self.transaction_history.get(tx)?;
+                Some((transaction.to?, transaction))
+            });
+        let mut held = BTreeMap::new();
+        for (client, transaction) in debits.chain(credits) {
+            let total = held
+                .entry((client, transaction.currency))
+                .or_insert(Amount::ZERO);
+            // funds held by a client always fit, so saturating only makes a mismatch apparent
+            *total = total
+                .checked_add(transaction.amount.unwrap_or_default())
+                .unwrap_or(Amount::MAX);
+        }
+        held
+    }
+
+    /// Whether none of the transitions allowed by the policy applies to the transaction anymore
+    fn is_finalized(&self, tx: &Transaction) -> bool {
+        let state = self.transaction_state.get(&tx.tx);
+        let legs = match tx.tpe {
+            TransactionType::Deposit => vec![(Leg::Credit, state)],
+            TransactionType::Transfer => vec![
+                (Leg::Debit, state),
+                (Leg::TransferCredit, self.transfer_state.get(&tx.tx)),
+            ],
+            // withdrawals and authorizations
+            _ => vec![(Leg::Debit, state)],
+        };
+        legs.into_iter()
+            .filter_map(|(leg, state)| Some((leg, *state?)))
+            .all(|(leg, state)| {
+                TRANSITIONING_TYPES.iter().all(|&tpe| {
+                    self.policy
+                        .check_transition(tpe, tx.tx, tx.tpe, leg, state)
+                        .is_err()
+                })
+            })
+    }
+
+    /// Drops transactions that won't be referenced anymore from the history, so that long-lived
+    /// states can be compacted at quiet times, returning how many were dropped
+    ///
+    /// Transactions holding funds, i.e. disputed ones and pending authorizations,
+    /// are always kept. Disputes and other transactions referencing a dropped one
+    /// are rejected as referencing an unknown transaction, and its id is no
+    /// longer detected as a duplicate. The fingerprint changes, as it covers
+    /// states of the transactions. With storage, only the cache is pruned.
+    pub fn prune(&mut self, options: &PruneOptions) -> Pruned {
+        let cutoff = options
+            .older_than
+            .and_then(|age| self.latest_timestamp?.checked_sub(age));
+        let mut pruned = Pruned::default();
+        let mut dropped = Vec::new();
+        for (&id, tx) in &self.transaction_history {
+            let holds_funds = [
+                self.transaction_state.get(&id),
+                self.transfer_state.get(&id),
+            ]
+            .iter()
+            .flatten()
+            .any(|&&state| {
+                state == TransactionState::Disputed || state == TransactionState::Authorized
+            });
+            if holds_funds {
+                continue;
+            }
+            if options.finalized && self.is_finalized(tx) {
+                pruned.finalized += 1;
+            } else if cutoff.is_some_and(|cutoff| tx.timestamp.is_some_and(|ts| ts < cutoff)) {
+                pruned.aged += 1;
+            } else {
+                continue;
+            }
+            dropped.push(id);
+        }
+        for id in dropped {
+            self.transaction_history.remove(&id);
+            self.transaction_state.remove(&id);
+            self.transfer_state.remove(&id);
+            self.dispute_count.remove(&id);
+        }
+        pruned
+    }
+
+    /// Returns the number of disputes of the client not resolved nor charged back yet
+    pub fn open_disputes(&self, client: u16) -> u32 {
+        self.open_disputes.get(&client).copied().unwrap_or_default()
+    }
+
+    /// Lifetime totals of the client per currency, empty if it has none
+    pub fn client_totals(&self, client: u16) -> BTreeMap<Currency, ClientTotals> {
+        self.totals.get(&client).cloned().unwrap_or_default()
+    }
+
+    /// Iterates over the lifetime totals of all clients with any
+    pub fn iter_totals(&self) -> impl Iterator<Item = (&u16, &BTreeMap<Currency, ClientTotals>)> {
+        self.totals.iter()
     }
 
     /// Iterates over all the accounts in the state
-    pub fn iter_clients<'a>(&'a self) -> impl Iterator<Item = (&'a u16, &'a Account)> {
+    pub fn iter_clients(&self) -> impl Iterator<Item = (&u16, &Account)> {
         self.accounts.iter()
     }
+
+    /// Stable hash of the accounts and the transaction record the transaction may have affected
+    ///
+    /// Covers the accounts of the client and the receiving client of the
+    /// transaction, and of the transaction it refers to, if any.
+    pub fn transaction_fingerprint(&self, tx: &Transaction) -> Fingerprint {
+        let referenced = self.transaction_history.get(&tx.tx);
+        let mut clients: Vec<u16> = std::iter::once(tx.client)
+            .chain(tx.to)
+            .chain(referenced.map(|referenced| referenced.client))
+            .chain(referenced.and_then(|referenced| referenced.to))
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        let accounts: Vec<_> = clients
+            .into_iter()
+            .map(|client| (client, self.accounts.get(&client)))
+            .collect();
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.add(&(accounts, self.cached_record(tx.tx)));
+        fingerprint
+    }
+
+    /// Stable hash of all accounts and states of transactions, not depending on the order of maps
+    ///
+    /// With storage, only the states of transactions cached in memory are included.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_unstable_by_key(|&(client, _)| client);
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.add(&accounts);
+        for states in [&self.transaction_state, &self.transfer_state] {
+            let mut states: Vec<_> = states.iter().collect();
+            states.sort_unstable_by_key(|&(tx, _)| tx);
+            fingerprint.add(&states);
+        }
+        fingerprint
+    }
 }
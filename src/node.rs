@@ -0,0 +1,121 @@
+//! Node.js bindings of the engine, built with the `node` feature (e.g. `npm run build`)
+//!
+//! The state is owned by an engine thread (see `server::EngineHandle`), so
+//! all methods return promises instead of blocking the event loop.
+//! Transactions are passed as JSON objects with the same fields as rows of the
+//! input, everything is returned as JSON in the same format as the REST API.
+//!
+//! ```js
+//! const { Engine } = require('./cephalopod.node')
+//!
+//! const engine = new Engine()
+//! await engine.applyTransaction(JSON.stringify({ type: 'deposit', client: 1, tx: 1, amount: '1.5' }))
+//! JSON.parse(await engine.account(1))
+//! ```
+
+use napi::{Error, Result};
+use napi_derive::napi;
+
+use crate::json;
+use crate::model::{CephalopodError, Transaction};
+use crate::policy::Policy;
+use crate::server::{AccountView, EngineHandle};
+use crate::tenant::Tenants;
+
+fn engine_stopped() -> Error {
+    Error::from_reason("engine has stopped")
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("value should be serializable to JSON")
+}
+
+/// States of all tenants, applying transactions one by one
+#[napi(js_name = "Engine")]
+pub struct NodeEngine {
+    engine: EngineHandle,
+}
+
+#[napi]
+impl NodeEngine {
+    /// Creates an engine following the policy given as JSON, or the default one
+    #[napi(constructor)]
+    pub fn new(policy: Option<String>) -> Result<NodeEngine> {
+        let policy: Policy = match policy {
+            Some(policy) => serde_json::from_str(&policy)
+                .map_err(|err| Error::from_reason(format!("invalid policy: {}", err)))?,
+            None => Policy::default(),
+        };
+        // the thread stops once the engine is garbage collected
+        let (engine, _) = EngineHandle::spawn(move || Ok(Tenants::new(policy)), |_, _| Ok(()))
+            .map_err(Error::from_reason)?;
+        Ok(NodeEngine { engine })
+    }
+
+    /// Applies the transaction given as JSON, rejects if it's invalid or has been rejected
+    #[napi]
+    pub async fn apply_transaction(&self, transaction: String) -> Result<()> {
+        let transaction: Transaction = serde_json::from_str(&transaction)
+            .map_err(|err| Error::from_reason(format!("invalid transaction: {}", err)))?;
+        self.engine
+            .call(move |engine| match engine.halted() {
+                Some(error) => Err(format!("processing halted: {}", error)),
+                None => engine.submit(&transaction, None).map_err(|err| match err {
+                    CephalopodError::TransactionError { error, .. } => error.to_string(),
+                    CephalopodError::IntegrityError { error, .. } => {
+                        format!("integrity error: {}", error)
+                    }
+                }),
+            })
+            .await
+            .ok_or_else(engine_stopped)?
+            .map_err(Error::from_reason)
+    }
+
+    /// Returns the account of the client, `null` if it doesn't exist
+    #[napi]
+    pub async fn account(&self, client: u16, tenant: Option<u32>) -> Result<Option<String>> {
+        let account = self
+            .engine
+            .call(move |engine| engine.account(tenant, client))
+            .await
+            .ok_or_else(engine_stopped)?;
+        Ok(account.map(|account| to_json(&AccountView::from(account))))
+    }
+
+    /// Returns balances of all accounts, one entry per client and currency like rows of the output
+    #[napi]
+    pub async fn accounts(&self) -> Result<String> {
+        self.engine
+            .call(|engine| json::accounts(engine.tenants()))
+            .await
+            .ok_or_else(engine_stopped)
+    }
+
+    /// Returns the recorded transaction with its dispute state, `null` if it doesn't exist
+    #[napi]
+    pub async fn transaction(&self, tx: u32, tenant: Option<u32>) -> Result<Option<String>> {
+        let record = self
+            .engine
+            .call(move |engine| match engine.tenants().state(tenant) {
+                Some(state) => state.transaction_record(tx).map_err(|err| err.to_string()),
+                None => Ok(Default::default()),
+            })
+            .await
+            .ok_or_else(engine_stopped)?
+            .map_err(Error::from_reason)?;
+        Ok(record.transaction.map(|_| to_json(&record)))
+    }
+
+    /// Returns transactions with an open dispute, ordered by id
+    #[napi]
+    pub async fn disputes(&self, tenant: Option<u32>) -> Result<String> {
+        self.engine
+            .call(move |engine| match engine.tenants().state(tenant) {
+                Some(state) => to_json(&state.disputed_transactions()),
+                None => "[]".to_string(),
+            })
+            .await
+            .ok_or_else(engine_stopped)
+    }
+}
@@ -0,0 +1,325 @@
+//! Conversion of raw partner files to the canonical input, behind `cephalopod normalize`
+//!
+//! The file is decoded (UTF-8, UTF-16 or Latin-1), split with its own
+//! delimiter and written back as comma-separated UTF-8 with the header in
+//! lower case. Along the way, whitespace around values is trimmed, types are
+//! lowercased, decimal commas of amounts are replaced with dots, and empty rows
+//! as well as repeated deposits, withdrawals, transfers and authorizations are
+//! dropped, like `Policy::ignore_identical_duplicates` would skip them. Rows can
+//! be sorted by timestamp. Everything fixed is counted in `Fixes`.
+//!
+//! The whole file is kept in memory, so that it can be sorted.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use csv::StringRecord;
+use thiserror::Error;
+
+/// Delimiters detected in the header, besides the comma
+const DELIMITERS: [u8; 3] = [b';', b'\t', b'|'];
+
+/// Types whose identical repeats are dropped, as they'd be rejected as duplicates otherwise
+const DEDUPLICATED_TYPES: [&str; 4] = ["deposit", "withdrawal", "transfer", "authorize"];
+
+/// Encoding of the raw file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Detected from the byte order mark, UTF-8 if valid, Latin-1 otherwise
+    Auto,
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s {
+            "auto" => Ok(Encoding::Auto),
+            "utf-8" => Ok(Encoding::Utf8),
+            "utf-16le" => Ok(Encoding::Utf16Le),
+            "utf-16be" => Ok(Encoding::Utf16Be),
+            "latin1" => Ok(Encoding::Latin1),
+            _ => Err(format!(
+                "expected auto, utf-8, utf-16le, utf-16be or latin1, got {}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Auto => "auto",
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NormalizeError {
+    #[error("input is not valid {0}")]
+    Encoding(Encoding),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NormalizeOptions {
+    pub encoding: Encoding,
+    /// Delimiter of the raw file, detected from the header if not given
+    pub delimiter: Option<u8>,
+    /// Sort rows by timestamp, rows without one following the row before them
+    pub sort: bool,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions {
+            encoding: Encoding::Auto,
+            delimiter: None,
+            sort: false,
+        }
+    }
+}
+
+/// What has been fixed while normalizing a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fixes {
+    /// Encoding the file has been decoded from, if not UTF-8
+    pub encoding: Option<Encoding>,
+    pub byte_order_mark: bool,
+    /// Delimiter the file has been split with, if not a comma
+    pub delimiter: Option<u8>,
+    pub crlf_line_endings: bool,
+    /// Columns of the header renamed to lower case and without whitespace
+    pub renamed_columns: Vec<(String, String)>,
+    /// Values with whitespace around them
+    pub trimmed_values: u64,
+    /// Types not in lower case
+    pub lowercased_types: u64,
+    /// Amounts with a decimal comma
+    pub decimal_commas: u64,
+    pub empty_rows: u64,
+    pub duplicates: u64,
+    /// Rows moved by sorting them by timestamp
+    pub reordered_rows: u64,
+    /// Rows written
+    pub rows: u64,
+}
+
+impl Fixes {
+    /// Whether the file was already in the canonical format
+    pub fn is_empty(&self) -> bool {
+        *self
+            == Fixes {
+                rows: self.rows,
+                ..Fixes::default()
+            }
+    }
+}
+
+impl fmt::Display for Fixes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut fixes = Vec::new();
+        if let Some(encoding) = self.encoding {
+            fixes.push(format!("decoded from {}", encoding));
+        }
+        if self.byte_order_mark {
+            fixes.push("removed byte order mark".to_string());
+        }
+        if let Some(delimiter) = self.delimiter {
+            fixes.push(format!("replaced delimiter {:?}", delimiter as char));
+        }
+        if self.crlf_line_endings {
+            fixes.push("replaced CRLF line endings".to_string());
+        }
+        for (from, to) in &self.renamed_columns {
+            fixes.push(format!("renamed column {:?} to {:?}", from, to));
+        }
+        for &(count, fix) in [
+            (self.trimmed_values, "values trimmed"),
+            (self.lowercased_types, "types lowercased"),
+            (self.decimal_commas, "decimal commas replaced"),
+            (self.empty_rows, "empty rows dropped"),
+            (self.duplicates, "duplicates dropped"),
+            (self.reordered_rows, "rows reordered by timestamp"),
+        ]
+        .iter()
+        {
+            if count > 0 {
+                fixes.push(format!("{} {}", count, fix));
+            }
+        }
+        if fixes.is_empty() {
+            fixes.push("nothing to fix".to_string());
+        }
+        write!(f, "{} rows, {}", self.rows, fixes.join(", "))
+    }
+}
+
+/// Decodes the file, removing the byte order mark
+fn decode(input: &[u8], encoding: Encoding, fixes: &mut Fixes) -> Result<String, NormalizeError> {
+    let encoding = match encoding {
+        Encoding::Auto if input.starts_with(&[0xef, 0xbb, 0xbf]) => Encoding::Utf8,
+        Encoding::Auto if input.starts_with(&[0xff, 0xfe]) => Encoding::Utf16Le,
+        Encoding::Auto if input.starts_with(&[0xfe, 0xff]) => Encoding::Utf16Be,
+        Encoding::Auto if std::str::from_utf8(input).is_ok() => Encoding::Utf8,
+        Encoding::Auto => Encoding::Latin1,
+        encoding => encoding,
+    };
+    if encoding != Encoding::Utf8 {
+        fixes.encoding = Some(encoding);
+    }
+    let mut text = match encoding {
+        Encoding::Utf8 | Encoding::Auto => String::from_utf8(input.to_vec())
+            .map_err(|_| NormalizeError::Encoding(Encoding::Utf8))?,
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            if !input.len().is_multiple_of(2) {
+                return Err(NormalizeError::Encoding(encoding));
+            }
+            let units = input.chunks(2).map(|pair| match encoding {
+                Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                _ => u16::from_be_bytes([pair[0], pair[1]]),
+            });
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .map_err(|_| NormalizeError::Encoding(encoding))?
+        }
+        Encoding::Latin1 => input.iter().map(|&b| b as char).collect(),
+    };
+    if text.starts_with('\u{feff}') {
+        text.remove(0);
+        fixes.byte_order_mark = true;
+    }
+    Ok(text)
+}
+
+/// Delimiter occurring most often in the header, a comma if none does
+fn detect_delimiter(text: &str) -> u8 {
+    let header = text.lines().next().unwrap_or_default();
+    let count = |delimiter: u8| header.bytes().filter(|&b| b == delimiter).count();
+    std::iter::once(b',')
+        .chain(DELIMITERS.iter().copied())
+        .max_by_key(|&delimiter| (count(delimiter), delimiter == b','))
+        .unwrap_or(b',')
+}
+
+/// Whether the amount is a number with a decimal comma, e.g. `-1,5`
+fn has_decimal_comma(amount: &str) -> bool {
+    let digits = amount.strip_prefix('-').unwrap_or(amount);
+    match digits.split_once(',') {
+        Some((whole, fraction)) => {
+            !whole.is_empty()
+                && !fraction.is_empty()
+                && whole.bytes().all(|b| b.is_ascii_digit())
+                && fraction.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/// Normalizes the raw file, writing it in the canonical format and returning what has been fixed
+pub fn normalize<W: Write>(
+    input: &[u8],
+    options: &NormalizeOptions,
+    output: W,
+) -> Result<Fixes, NormalizeError> {
+    let mut fixes = Fixes::default();
+    let text = decode(input, options.encoding, &mut fixes)?;
+    fixes.crlf_line_endings = text.contains("\r\n");
+    let delimiter = options.delimiter.unwrap_or_else(|| detect_delimiter(&text));
+    if delimiter != b',' {
+        fixes.delimiter = Some(delimiter);
+    }
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut headers = StringRecord::new();
+    for name in rdr.headers()?.iter() {
+        let canonical = name.trim().to_lowercase();
+        if canonical != name {
+            fixes
+                .renamed_columns
+                .push((name.to_string(), canonical.clone()));
+        }
+        headers.push_field(&canonical);
+    }
+    let column = |name| headers.iter().position(|column| column == name);
+    let (type_column, amount_column, timestamp_column) =
+        (column("type"), column("amount"), column("timestamp"));
+
+    let mut seen = HashSet::new();
+    // rows along with timestamps to sort them by
+    let mut rows: Vec<(u64, StringRecord)> = Vec::new();
+    let mut last_timestamp = 0;
+    for record in rdr.records() {
+        let record = record?;
+        let mut row = StringRecord::new();
+        for (index, value) in record.iter().enumerate() {
+            let mut value = value.trim().to_string();
+            if value.len() != record[index].len() {
+                fixes.trimmed_values += 1;
+            }
+            if Some(index) == type_column && value.to_lowercase() != value {
+                value = value.to_lowercase();
+                fixes.lowercased_types += 1;
+            }
+            if Some(index) == amount_column && has_decimal_comma(&value) {
+                value = value.replace(',', ".");
+                fixes.decimal_commas += 1;
+            }
+            row.push_field(&value);
+        }
+        if row.iter().all(str::is_empty) {
+            fixes.empty_rows += 1;
+            continue;
+        }
+        let deduplicated = type_column
+            .and_then(|index| row.get(index))
+            .is_some_and(|tpe| DEDUPLICATED_TYPES.contains(&tpe));
+        if deduplicated && !seen.insert(row.iter().map(String::from).collect::<Vec<_>>()) {
+            fixes.duplicates += 1;
+            continue;
+        }
+        if let Some(timestamp) = timestamp_column
+            .and_then(|index| row.get(index))
+            .and_then(|timestamp| timestamp.parse().ok())
+        {
+            last_timestamp = timestamp;
+        }
+        rows.push((last_timestamp, row));
+    }
+
+    if options.sort {
+        let mut order: Vec<usize> = (0..rows.len()).collect();
+        order.sort_by_key(|&index| rows[index].0);
+        fixes.reordered_rows = order
+            .iter()
+            .enumerate()
+            .filter(|&(position, &index)| position != index)
+            .count() as u64;
+        rows.sort_by_key(|&(timestamp, _)| timestamp);
+    }
+
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(output);
+    wtr.write_record(&headers)?;
+    for (_, row) in &rows {
+        wtr.write_record(row)?;
+    }
+    wtr.flush().map_err(csv::Error::from)?;
+    fixes.rows = rows.len() as u64;
+    Ok(fixes)
+}
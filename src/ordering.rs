@@ -0,0 +1,160 @@
+//! Validation of chronological order of the input
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::model::{Record, Transaction};
+
+/// Which transactions must be ordered with respect to each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderingScope {
+    /// All transactions
+    Global,
+    /// Transactions of the same client only
+    Client,
+}
+
+impl FromStr for OrderingScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OrderingScope, String> {
+        match s {
+            "global" => Ok(OrderingScope::Global),
+            "client" => Ok(OrderingScope::Client),
+            _ => Err(format!("expected global or client, got {}", s)),
+        }
+    }
+}
+
+/// What happens to records arriving out of order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutOfOrderAction {
+    /// Report the record, but process it anyway
+    Warn,
+    /// Report and drop the record
+    Reject,
+    /// Delay records by the reorder window and process them in order of timestamps
+    ///
+    /// Records arriving later than the window allows are reported and dropped.
+    Reorder,
+}
+
+impl FromStr for OutOfOrderAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutOfOrderAction, String> {
+        match s {
+            "warn" => Ok(OutOfOrderAction::Warn),
+            "reject" => Ok(OutOfOrderAction::Reject),
+            "reorder" => Ok(OutOfOrderAction::Reorder),
+            _ => Err(format!("expected warn, reject or reorder, got {}", s)),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[error("transaction {tx} with timestamp {timestamp} arrived after timestamp {latest}")]
+pub struct OutOfOrder {
+    pub tx: u32,
+    pub timestamp: u64,
+    pub latest: u64,
+}
+
+/// Checks that timestamps of transactions are non-decreasing, optionally reordering them
+///
+/// Transactions without a timestamp are never reported. When reordering they
+/// are treated as if they had the latest timestamp seen so far, so they stay
+/// behind everything received before them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sequencer {
+    scope: OrderingScope,
+    action: OutOfOrderAction,
+    /// How long (in seconds of transaction time) records are delayed when reordering
+    window: u64,
+    /// Latest timestamp passed on, per tenant and client (or under `None` for the global scope)
+    released: HashMap<Option<(Option<u32>, u16)>, u64>,
+    /// Latest timestamp seen so far
+    latest: u64,
+    /// Records delayed for reordering, keyed by timestamp and arrival order
+    pending: BTreeMap<(u64, u64), Record>,
+    received: u64,
+}
+
+impl Sequencer {
+    pub fn new(scope: OrderingScope, action: OutOfOrderAction, window: u64) -> Sequencer {
+        Sequencer {
+            scope,
+            action,
+            window,
+            released: HashMap::new(),
+            latest: 0,
+            pending: BTreeMap::new(),
+            received: 0,
+        }
+    }
+
+    fn key(&self, tx: &Transaction) -> Option<(Option<u32>, u16)> {
+        match self.scope {
+            OrderingScope::Global => None,
+            OrderingScope::Client => Some((tx.tenant, tx.client)),
+        }
+    }
+
+    /// Accepts a record, appending the ones ready for processing to `ready`
+    ///
+    /// An error is returned for records out of order. Unless the action is
+    /// `Warn`, such records are dropped.
+    pub fn push(&mut self, record: Record, ready: &mut Vec<Record>) -> Result<(), OutOfOrder> {
+        let tx = &record.transaction;
+        let key = self.key(tx);
+        let released = self.released.get(&key).copied();
+        if let (Some(timestamp), Some(latest)) = (tx.timestamp, released) {
+            if timestamp < latest {
+                if self.action == OutOfOrderAction::Warn {
+                    ready.push(record);
+                }
+                return Err(OutOfOrder {
+                    tx: tx.tx,
+                    timestamp,
+                    latest,
+                });
+            }
+        }
+
+        if self.action != OutOfOrderAction::Reorder {
+            if let Some(timestamp) = tx.timestamp {
+                self.released.insert(key, timestamp);
+            }
+            ready.push(record);
+            return Ok(());
+        }
+
+        let timestamp = tx.timestamp.unwrap_or(self.latest);
+        self.latest = self.latest.max(timestamp);
+        self.pending.insert((timestamp, self.received), record);
+        self.received += 1;
+        self.release(self.latest.saturating_sub(self.window), ready);
+        Ok(())
+    }
+
+    /// Passes on all delayed records, to be called at the end of the input
+    pub fn finish(&mut self, ready: &mut Vec<Record>) {
+        self.release(u64::MAX, ready);
+    }
+
+    fn release(&mut self, until: u64, ready: &mut Vec<Record>) {
+        while let Some(entry) = self.pending.first_entry() {
+            let timestamp = entry.key().0;
+            if timestamp > until {
+                break;
+            }
+            let record = entry.remove();
+            self.released
+                .insert(self.key(&record.transaction), timestamp);
+            ready.push(record);
+        }
+    }
+}
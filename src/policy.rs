@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::model::{Leg, TransactionError, TransactionState, TransactionType};
+
+/// Length of the window of `Policy::max_transactions` used if none is given, in seconds
+pub const DEFAULT_TRANSACTION_WINDOW: u64 = 3600;
+
+/// What happens to amounts with more than `amount::MAX_SCALE` fractional digits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExcessPrecision {
+    /// Keep the full precision
+    #[default]
+    Keep,
+    /// Reject the transaction
+    Reject,
+    /// Round to the nearest value, with ties to even (banker's rounding)
+    RoundHalfEven,
+    /// Round to the nearest value, with ties away from zero
+    RoundHalfUp,
+    /// Drop the excess digits, i.e. round toward zero
+    Truncate,
+}
+
+impl FromStr for ExcessPrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExcessPrecision, String> {
+        match s {
+            "keep" => Ok(ExcessPrecision::Keep),
+            "reject" => Ok(ExcessPrecision::Reject),
+            "round-half-even" => Ok(ExcessPrecision::RoundHalfEven),
+            "round-half-up" => Ok(ExcessPrecision::RoundHalfUp),
+            "truncate" => Ok(ExcessPrecision::Truncate),
+            _ => Err(format!(
+                "expected keep, reject, round-half-even, round-half-up or truncate, got {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Referenced transactions a dispute, resolve, chargeback, representment or reversal can be applied to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transition {
+    /// States the referenced transaction may be in
+    pub from: Vec<TransactionState>,
+    /// Types the referenced transaction may be of
+    pub targets: Vec<TransactionType>,
+}
+
+impl Transition {
+    fn validate(
+        &self,
+        name: &str,
+        from: &[TransactionState],
+        targets: &[TransactionType],
+    ) -> Result<(), String> {
+        if let Some(state) = self.from.iter().find(|state| !from.contains(state)) {
+            return Err(format!(
+                "{} can't be applied to a transaction in state {:?}",
+                name, state
+            ));
+        }
+        if let Some(tpe) = self.targets.iter().find(|tpe| !targets.contains(tpe)) {
+            return Err(format!(
+                "{} can't be applied to a transaction of type {:?}",
+                name, tpe
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Allowed transitions of referenced transactions, see `Policy::transitions`
+///
+/// The state a transaction moves to is given by the referencing one: a
+/// dispute moves it to `Disputed`, a resolve to `Resolved`, a chargeback to
+/// `Chargebacked`, a representment to `Represented` and a reversal to `Voided`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transitions {
+    pub dispute: Transition,
+    pub resolve: Transition,
+    pub chargeback: Transition,
+    pub representment: Transition,
+    pub reversal: Transition,
+}
+
+impl Transitions {
+    /// Transition of a dispute, resolve, chargeback, representment or reversal
+    fn get(&self, tpe: TransactionType) -> Option<&Transition> {
+        match tpe {
+            TransactionType::Dispute => Some(&self.dispute),
+            TransactionType::Resolve => Some(&self.resolve),
+            TransactionType::Chargeback => Some(&self.chargeback),
+            TransactionType::Representment => Some(&self.representment),
+            TransactionType::Reversal => Some(&self.reversal),
+            _ => None,
+        }
+    }
+
+    /// Checks that all transitions keep the balances consistent
+    ///
+    /// E.g. only disputed transactions hold funds, so they are the only ones
+    /// that can be resolved or charged back.
+    pub fn validate(&self) -> Result<(), String> {
+        use TransactionState::*;
+        use TransactionType::*;
+
+        let settled = [Deposited, Withdrawn, Resolved, Represented];
+        let disputable = [Deposit, Withdrawal, Transfer];
+        self.dispute.validate("dispute", &settled, &disputable)?;
+        self.resolve.validate("resolve", &[Disputed], &disputable)?;
+        self.chargeback
+            .validate("chargeback", &[Disputed], &disputable)?;
+        self.representment
+            .validate("representment", &[Chargebacked], &disputable)?;
+        self.reversal
+            .validate("reversal", &settled, &[Deposit, Withdrawal])
+    }
+}
+
+fn deserialize_transitions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Transitions>, D::Error> {
+    let transitions = Option::<Transitions>::deserialize(deserializer)?;
+    if let Some(transitions) = &transitions {
+        transitions.validate().map_err(de::Error::custom)?;
+    }
+    Ok(transitions)
+}
+
+/// Business rules that differ between card schemes and acquirers
+///
+/// The default policy reproduces the original behaviour of the engine, which
+/// is also used for the fields missing when deserializing it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// Whether withdrawals can be disputed
+    ///
+    /// The disputed amount is credited back to the account as held funds.
+    /// Resolving the dispute drops the hold (the withdrawal stands), while
+    /// a chargeback makes the funds available and locks the account.
+    pub allow_withdrawal_disputes: bool,
+
+    /// Whether a dispute holds funds even if they have already been withdrawn
+    ///
+    /// When set, the available balance may go negative as a result of a
+    /// dispute, so the money can still be recovered with a chargeback.
+    pub allow_dispute_overdraft: bool,
+
+    /// Whether a successful representment lifts the lock caused by the chargeback
+    ///
+    /// Note that the lock is lifted even if the account has other charged back
+    /// transactions.
+    pub unlock_on_representment: bool,
+
+    /// Whether deposits are accepted to locked accounts, e.g. to repay a negative balance
+    ///
+    /// Withdrawals, transfers, disputes and other transactions of a locked
+    /// account are still rejected, and the deposit doesn't lift the lock.
+    pub allow_deposits_to_locked: bool,
+
+    /// Whether a resolved transaction can be disputed again
+    pub allow_redisputes: bool,
+
+    /// Maximum number of times a single transaction can be disputed
+    pub max_disputes: Option<u32>,
+
+    /// Number of days after the disputed transaction within which a dispute can be filed
+    ///
+    /// Only enforced if both transactions have a timestamp.
+    pub dispute_window_days: Option<u32>,
+
+    /// Whether a repeated transaction identical to the original one is silently skipped
+    ///
+    /// Repeated transaction ids are rejected otherwise.
+    pub ignore_identical_duplicates: bool,
+
+    /// Whether a resolve of a resolved transaction, or a chargeback of a charged
+    /// back one, is skipped instead of rejected
+    ///
+    /// Meant for upstreams retrying such messages until acknowledged.
+    pub ignore_repeated_outcomes: bool,
+
+    /// Whether fees can be charged even if the available funds don't cover them
+    pub allow_fee_overdraft: bool,
+
+    /// Whether administrative adjustments may debit more than the available funds
+    pub allow_adjustment_overdraft: bool,
+
+    /// How far below zero withdrawals may take the available balance
+    ///
+    /// Can be overridden per client with `ClientSettings`.
+    pub overdraft_limit: Amount,
+
+    /// Minimum available balance that withdrawals must leave, per account class
+    ///
+    /// Clients are assigned to classes with `ClientSettings`.
+    pub minimum_balances: HashMap<String, Amount>,
+
+    /// Largest amount of a single withdrawal, transfer or authorization, per risk flag
+    ///
+    /// Applies while the account has the flag set.
+    pub flag_amount_limits: HashMap<u32, Amount>,
+
+    /// Largest amount of a single deposit, withdrawal, transfer or authorization
+    pub max_amount: Option<Amount>,
+
+    /// Largest total of deposits, withdrawals, transfers and authorizations
+    /// of a client within a single (UTC) day, counted separately for each currency
+    ///
+    /// Only transactions with a timestamp are counted.
+    pub max_daily_total: Option<Amount>,
+
+    /// Maximum number of deposits, withdrawals, transfers and authorizations
+    /// of a client within `transaction_window`
+    ///
+    /// Only transactions with a timestamp are counted.
+    pub max_transactions: Option<u32>,
+
+    /// Length of the window of `max_transactions` in seconds,
+    /// `DEFAULT_TRANSACTION_WINDOW` if not set
+    pub transaction_window: Option<u64>,
+
+    /// Largest amount of any single transaction, regardless of its type and client
+    ///
+    /// Unlike `max_amount`, it can't be overridden per client and also covers
+    /// fees and adjustments (whose amount is compared as absolute value). Meant
+    /// to catch mistyped records rather than to manage risk.
+    pub amount_cap: Option<Amount>,
+
+    /// What happens to transaction amounts with more than four decimal places
+    ///
+    /// Rounded amounts are applied and recorded as if they were given so.
+    /// With the `minor-units` feature such amounts can't even be parsed.
+    pub excess_precision: ExcessPrecision,
+
+    /// States and types of transactions that disputes, resolves, chargebacks,
+    /// representments and reversals can be applied to
+    ///
+    /// Lets schemes differ e.g. in whether withdrawals can be disputed or
+    /// represented transactions disputed again without changing the engine. When
+    /// set, `allow_withdrawal_disputes` and `allow_redisputes` are ignored.
+    /// Validated when deserialized, see `Transitions::validate`.
+    #[serde(deserialize_with = "deserialize_transitions")]
+    pub transitions: Option<Transitions>,
+}
+
+/// Transactions moving a referenced transaction to another state
+pub const TRANSITIONING_TYPES: [TransactionType; 5] = [
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Representment,
+    TransactionType::Reversal,
+];
+
+/// State a transaction of one of `TRANSITIONING_TYPES` moves the referenced transaction to
+fn moved_to(tpe: TransactionType) -> TransactionState {
+    match tpe {
+        TransactionType::Dispute => TransactionState::Disputed,
+        TransactionType::Resolve => TransactionState::Resolved,
+        TransactionType::Chargeback => TransactionState::Chargebacked,
+        TransactionType::Representment => TransactionState::Represented,
+        _ => TransactionState::Voided,
+    }
+}
+
+/// What happens to a transition in `Policy::transition_matrix`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum TransitionOutcome {
+    /// The referenced transaction moves to state `to`
+    Allowed { to: TransactionState },
+    /// The transaction is skipped, see `Policy::ignore_repeated_outcomes`
+    Ignored,
+    /// The transaction is rejected with an error of the kind, e.g. `TransactionInvalidState`
+    Rejected { error: String },
+}
+
+/// Entry of `Policy::transition_matrix`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransitionRule {
+    /// Type of the referencing transaction, e.g. a dispute
+    #[serde(rename = "type")]
+    pub tpe: TransactionType,
+    /// Type of the referenced transaction
+    pub target: TransactionType,
+    /// Side of the referenced transaction, the receiving side of a transfer being `transfer_credit`
+    pub leg: Leg,
+    /// State of the referenced transaction
+    pub state: TransactionState,
+    #[serde(flatten)]
+    pub outcome: TransitionOutcome,
+}
+
+impl Policy {
+    /// Checks whether a transaction of type `tpe` (one of `TRANSITIONING_TYPES`) can be applied
+    /// to transaction `tx` of type `target`, referenced on its `leg`, in `state`
+    ///
+    /// Only the types and states are checked, the transaction may still be
+    /// rejected e.g. because the account is locked.
+    pub fn check_transition(
+        &self,
+        tpe: TransactionType,
+        tx: u32,
+        target: TransactionType,
+        leg: Leg,
+        state: TransactionState,
+    ) -> Result<(), TransactionError> {
+        if tpe == TransactionType::Reversal && target == TransactionType::Transfer {
+            return Err(TransactionError::TransactionNotReversible { tx });
+        }
+        let allowed = match self
+            .transitions
+            .as_ref()
+            .and_then(|transitions| transitions.get(tpe))
+        {
+            Some(transition) => {
+                if !transition.targets.contains(&target) {
+                    return Err(TransactionError::TransitionNotAllowed { tx, target });
+                }
+                transition.from.contains(&state)
+            }
+            None => match tpe {
+                TransactionType::Dispute
+                    if self.allow_redisputes && state == TransactionState::Resolved =>
+                {
+                    true
+                }
+                TransactionType::Dispute => match leg {
+                    Leg::Debit
+                        if self.allow_withdrawal_disputes
+                            || target == TransactionType::Transfer =>
+                    {
+                        state == TransactionState::Withdrawn
+                    }
+                    _ => state == TransactionState::Deposited,
+                },
+                TransactionType::Resolve | TransactionType::Chargeback => {
+                    state == TransactionState::Disputed
+                }
+                TransactionType::Representment => state == TransactionState::Chargebacked,
+                TransactionType::Reversal => match leg {
+                    Leg::Debit => state == TransactionState::Withdrawn,
+                    _ => state == TransactionState::Deposited,
+                },
+                _ => panic!("{:?} doesn't move the referenced transaction", tpe),
+            },
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(TransactionError::TransactionInvalidState { state })
+        }
+    }
+
+    /// Whether a transaction of type `tpe` referencing one in `state` is skipped as a repeated outcome
+    pub fn ignores_outcome(&self, tpe: TransactionType, state: TransactionState) -> bool {
+        self.ignore_repeated_outcomes
+            && matches!(
+                (tpe, state),
+                (TransactionType::Resolve, TransactionState::Resolved)
+                    | (TransactionType::Chargeback, TransactionState::Chargebacked)
+            )
+    }
+
+    /// Lists the outcome of each of `TRANSITIONING_TYPES` applied to deposits, withdrawals
+    /// and both sides of transfers in each state they can be in
+    pub fn transition_matrix(&self) -> Vec<TransitionRule> {
+        use TransactionState::*;
+
+        let targets = [
+            (TransactionType::Deposit, Leg::Credit),
+            (TransactionType::Withdrawal, Leg::Debit),
+            (TransactionType::Transfer, Leg::Debit),
+            (TransactionType::Transfer, Leg::TransferCredit),
+        ];
+        let mut rules = Vec::new();
+        for tpe in TRANSITIONING_TYPES {
+            for (target, leg) in targets {
+                let recorded = match leg {
+                    Leg::Debit => Withdrawn,
+                    _ => Deposited,
+                };
+                for state in [
+                    recorded,
+                    Disputed,
+                    Resolved,
+                    Chargebacked,
+                    Represented,
+                    Voided,
+                ] {
+                    let outcome = if self.ignores_outcome(tpe, state) {
+                        TransitionOutcome::Ignored
+                    } else {
+                        match self.check_transition(tpe, 0, target, leg, state) {
+                            Ok(()) => TransitionOutcome::Allowed { to: moved_to(tpe) },
+                            Err(err) => TransitionOutcome::Rejected {
+                                error: err.kind().to_string(),
+                            },
+                        }
+                    };
+                    rules.push(TransitionRule {
+                        tpe,
+                        target,
+                        leg,
+                        state,
+                        outcome,
+                    });
+                }
+            }
+        }
+        rules
+    }
+}
+
+/// Settings of a single client overriding the global policy
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientSettings {
+    pub client: u16,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub overdraft_limit: Option<Amount>,
+    /// Account class, e.g. `savings`
+    #[serde(default)]
+    pub class: Option<String>,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub max_amount: Option<Amount>,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub max_daily_total: Option<Amount>,
+    #[serde(default)]
+    pub max_transactions: Option<u32>,
+    /// Country of the client, e.g. `DE`, checked by compliance rules
+    #[serde(default)]
+    pub country: Option<String>,
+    /// Risk flags set on the account, separated with `;`
+    #[serde(
+        default,
+        serialize_with = "serialize_flags",
+        deserialize_with = "deserialize_flags"
+    )]
+    pub flags: Vec<u32>,
+}
+
+fn serialize_flags<S: Serializer>(flags: &[u32], serializer: S) -> Result<S::Ok, S::Error> {
+    let flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+    serializer.serialize_str(&flags.join(";"))
+}
+
+fn deserialize_flags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    let flags = String::deserialize(deserializer)?;
+    flags
+        .split(';')
+        .filter(|flag| !flag.is_empty())
+        .map(|flag| flag.parse().map_err(de::Error::custom))
+        .collect()
+}
@@ -0,0 +1,336 @@
+//! Batch processing of transactions with the optional reports
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, info_span, warn};
+
+use crate::activity::Activity;
+use crate::audit::Audit;
+use crate::fingerprint::Trail;
+use crate::ledger::Ledger;
+use crate::model::{CephalopodError, Record, Transaction, TransactionError, TransactionType};
+use crate::quarantine::Quarantine;
+use crate::rules::Rules;
+use crate::settlement::Settlement;
+use crate::summary::Summary;
+use crate::suspense::Suspense;
+use crate::tenant::Tenants;
+use crate::warnings::{Repeated, Warnings};
+
+/// What to do when a transaction fails with an error of some kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Severity {
+    /// Log a warning and continue, the default for transaction errors
+    Log,
+    /// Set the transaction aside and continue, the default for integrity errors with a quarantine
+    Quarantine,
+    /// End processing, the default for integrity errors
+    Fatal,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Severity, String> {
+        match s {
+            "log" => Ok(Severity::Log),
+            "quarantine" => Ok(Severity::Quarantine),
+            "fatal" => Ok(Severity::Fatal),
+            _ => Err(format!("expected log, quarantine or fatal, got {}", s)),
+        }
+    }
+}
+
+/// Applies transactions to the states of tenants, keeping the optional ledger and suspense queue up to date
+#[derive(Serialize, Deserialize)]
+pub struct Processor {
+    pub tenants: Tenants,
+    /// Double-entry ledger of applied transactions, if a trial balance is requested
+    pub ledger: Option<Ledger>,
+    /// Net movements to settle, if requested
+    pub settlement: Option<Settlement>,
+    /// Activity of clients for the suspicious-activity report, if requested
+    pub activity: Option<Activity>,
+    /// Queue of transactions referencing unknown transactions, if they should be retried
+    pub suspense: Option<Suspense>,
+    /// Periodic checks of invariants of the state, if requested
+    pub audit: Option<Audit>,
+    /// Transactions that caused an integrity error, if processing should continue after them
+    pub quarantine: Option<Quarantine>,
+    /// Fingerprints of the state after each transaction, if requested
+    pub trail: Option<Trail>,
+    /// Compliance rules evaluated before each transaction, if given
+    pub rules: Option<Rules>,
+    /// Severities of kinds of errors (e.g. `TransactionClientMismatch`) overriding the defaults
+    ///
+    /// Errors to be quarantined end processing without a quarantine.
+    pub severities: BTreeMap<String, Severity>,
+    /// Counts of warnings, limiting how many of them are logged
+    pub warnings: Warnings,
+    pub summary: Summary,
+}
+
+impl Processor {
+    /// Applies a transaction, failing only on an integrity error which isn't quarantined
+    pub fn process(&mut self, transaction: &Transaction) -> Result<(), String> {
+        self.process_record(&Record::from(*transaction))
+    }
+
+    /// Applies a transaction read from the input, referring to its line in the log messages
+    pub fn process_record(&mut self, record: &Record) -> Result<(), String> {
+        if let Some(suspense) = &mut self.suspense {
+            for expired in suspense.advance() {
+                self.summary.unresolved += 1;
+                if Self::warning(&mut self.warnings, "UnresolvedReference")? {
+                    warn!(
+                        "Parked {} has not been applied, referenced transaction didn't arrive within {} transactions.",
+                        expired,
+                        suspense.lookahead().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        self.apply(record)
+    }
+
+    /// Counts a warning of the kind, returning whether it should be logged
+    ///
+    /// Fails once the budget of warnings is exceeded.
+    pub fn warning(warnings: &mut Warnings, kind: &str) -> Result<bool, String> {
+        Self::client_warning(warnings, kind, None)
+    }
+
+    /// Counts a warning of the kind about the client, logging repeats of the previous warning
+    /// collapsed by deduplication
+    pub fn client_warning(
+        warnings: &mut Warnings,
+        kind: &str,
+        client: Option<u16>,
+    ) -> Result<bool, String> {
+        let admission = warnings.admit_from(kind, client).map_err(|err| {
+            error!("{}. Ending processing.", err);
+            format!("{}", err)
+        })?;
+        if let Some(repeated) = admission.repeated {
+            Self::log_repeated(&repeated);
+        }
+        Ok(admission.log)
+    }
+
+    fn log_repeated(repeated: &Repeated) {
+        match repeated.client {
+            Some(client) => warn!(
+                client,
+                kind = %repeated.kind,
+                "Warning of kind {} about client {} repeated {} more times.",
+                repeated.kind,
+                client,
+                repeated.times
+            ),
+            None => warn!(
+                kind = %repeated.kind,
+                "Warning of kind {} repeated {} more times.",
+                repeated.kind,
+                repeated.times
+            ),
+        }
+    }
+
+    /// Applies a transaction, then the parked transactions referencing it
+    fn apply(&mut self, record: &Record) -> Result<(), String> {
+        // events about the transaction, e.g. its rejection, carry the line of the input
+        let span = info_span!("record", line = record.line);
+        let _entered = span.enter();
+        let transaction = &record.transaction;
+        info!("Processing transaction {:?}", transaction);
+        let state = self.tenants.state_mut(transaction.tenant).map_err(|err| {
+            error!("Problem opening state of the tenant: {}", err);
+            format!("Problem opening state of the tenant: {}", err)
+        })?;
+        let skipped = Audit::is_skipped(state, transaction);
+        let blocked = match &mut self.rules {
+            Some(rules) => rules.check(state, record),
+            None => None,
+        };
+        let result = match (blocked, &mut self.ledger) {
+            (Some(rule), _) => Err(CephalopodError::TransactionError {
+                transaction: *transaction,
+                error: TransactionError::BlockedByRule { rule },
+            }),
+            (None, Some(ledger)) => ledger.apply(state, transaction),
+            (None, None) => state.apply_transaction(transaction),
+        };
+        if let (Some(rules), Ok(()), false) = (&mut self.rules, &result, skipped) {
+            rules.record(state, transaction);
+        }
+        if let (Some(settlement), Ok(()), false) = (&mut self.settlement, &result, skipped) {
+            settlement.record(state, transaction);
+        }
+        if let (Some(activity), Ok(()), false) = (&mut self.activity, &result, skipped) {
+            activity.record(state, transaction);
+        }
+        if let (Some(audit), Ok(()), false) = (&mut self.audit, &result, skipped) {
+            audit.record(state, transaction);
+        }
+        if let (
+            Some(suspense),
+            Err(CephalopodError::TransactionError {
+                error: TransactionError::TransactionNotFound { .. },
+                ..
+            }),
+        ) = (&mut self.suspense, &result)
+        {
+            info!(
+                "Parking {} until the referenced transaction arrives",
+                record
+            );
+            suspense.park(*record);
+            self.summary.suspended += 1;
+            return Ok(());
+        }
+        self.summary.record(transaction, &result);
+        if result.is_ok() {
+            let parked = match &mut self.suspense {
+                Some(suspense) if state.transaction(transaction.tx) == Some(transaction) => {
+                    suspense.release(transaction.tenant, transaction.tx)
+                }
+                _ => Vec::new(),
+            };
+            for parked in parked {
+                self.apply(&parked)?;
+            }
+        }
+        result.or_else(|err| {
+            let severity = match (self.severities.get(err.kind()), err) {
+                (Some(Severity::Quarantine), _) if self.quarantine.is_none() => Severity::Fatal,
+                (Some(&severity), _) => severity,
+                (None, CephalopodError::TransactionError { .. }) => Severity::Log,
+                (None, CephalopodError::IntegrityError { .. }) if self.quarantine.is_some() => {
+                    Severity::Quarantine
+                }
+                (None, CephalopodError::IntegrityError { .. }) => Severity::Fatal,
+            };
+            let description = match err {
+                CephalopodError::TransactionError { .. } => "Error",
+                CephalopodError::IntegrityError { .. } => "Integrity error",
+            };
+            match severity {
+                Severity::Log => {
+                    let client = Some(transaction.client);
+                    if Self::client_warning(&mut self.warnings, err.kind(), client)? {
+                        warn!(
+                            client = transaction.client,
+                            kind = %err.kind(),
+                            "{} while processing {}: {}. Transaction has not been applied.",
+                            description,
+                            record,
+                            err.message()
+                        );
+                    }
+                    Ok(())
+                }
+                Severity::Quarantine => {
+                    error!(
+                        client = transaction.client,
+                        kind = %err.kind(),
+                        "{} while processing {}: {}. Transaction has been quarantined.",
+                        description,
+                        record,
+                        err.message()
+                    );
+                    self.quarantine_transaction(record, err)
+                }
+                Severity::Fatal => {
+                    error!(
+                        client = transaction.client,
+                        kind = %err.kind(),
+                        "{} while processing {}: {}. Ending processing.",
+                        description,
+                        record,
+                        err.message()
+                    );
+                    Err(format!("{} (while processing {})", err.message(), record))
+                }
+            }
+        })?;
+        if let (Some(trail), Some(state)) =
+            (&mut self.trail, self.tenants.state(transaction.tenant))
+        {
+            trail.record(record, state);
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.tick(&self.tenants).map_err(|err| {
+                error!("{} after {}. Ending processing.", err, record);
+                format!("{} (after {})", err, record)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Sets the transaction aside and flags the account of its client
+    fn quarantine_transaction(
+        &mut self,
+        record: &Record,
+        error: CephalopodError,
+    ) -> Result<(), String> {
+        let quarantine = match &mut self.quarantine {
+            Some(quarantine) => quarantine,
+            None => return Ok(()),
+        };
+        quarantine.add(*record, error);
+        self.summary.quarantined += 1;
+        // a flag transaction, so that the flag is stored like any other
+        let flag = Transaction {
+            tpe: TransactionType::Flag,
+            amount: None,
+            to: None,
+            reason: Some(quarantine.flag()),
+            ..record.transaction
+        };
+        let state = self.tenants.state_mut(flag.tenant).map_err(|err| {
+            error!("Problem opening state of the tenant: {}", err);
+            format!("Problem opening state of the tenant: {}", err)
+        })?;
+        match state.apply_transaction(&flag) {
+            Ok(()) => {}
+            Err(CephalopodError::TransactionError {
+                error: TransactionError::FlagAlreadySet { .. },
+                ..
+            }) => {}
+            Err(err) => warn!(
+                "Account of {} has not been flagged: {}.",
+                record,
+                err.message()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Reports transactions left in the suspense queue and warnings that haven't been logged,
+    /// completing the summary
+    pub fn finish(&mut self) {
+        if let Some(suspense) = &self.suspense {
+            for record in suspense.unresolved() {
+                warn!(
+                    "Parked {} has not been applied, referenced transaction never arrived.",
+                    record
+                );
+            }
+            self.summary.unresolved += suspense.len() as u64;
+        }
+        if let Some(repeated) = self.warnings.flush() {
+            Self::log_repeated(&repeated);
+        }
+        for (kind, count) in self.warnings.suppressed() {
+            warn!(
+                "{} more warnings of kind {} have not been logged.",
+                count, kind
+            );
+        }
+        self.summary.fees_collected = self.tenants.fees_collected();
+        self.summary.fingerprint = Some(self.tenants.fingerprint());
+        self.summary.memory = Some(self.tenants.memory_usage());
+    }
+}
@@ -0,0 +1,350 @@
+//! Python bindings of the engine, built with the `python` feature (e.g. `maturin develop`)
+//!
+//! ```python
+//! from decimal import Decimal
+//! from cephalopod import State, Transaction
+//!
+//! state = State()
+//! state.apply(Transaction("deposit", client=1, tx=1, amount=Decimal("1.5")))
+//! state.account(1).balances[""].available  # Decimal("1.5")
+//! ```
+//!
+//! Amounts are accepted as anything whose `str` is a decimal number (e.g.
+//! `Decimal`, `str` or `int`) and returned as `Decimal`. Rejected transactions
+//! raise `TransactionError`, integrity errors raise `IntegrityError`.
+
+use std::collections::HashMap;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOverflowError, PyValueError};
+use pyo3::prelude::*;
+use serde::de::value::Error as ValueError;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+
+use crate::amount::{self, Amount};
+use crate::model::{self, CephalopodError, TransactionType};
+use crate::policy::Policy;
+
+create_exception!(cephalopod, TransactionError, PyException);
+create_exception!(cephalopod, IntegrityError, PyException);
+
+/// Converts the amount to Python's `Decimal`
+fn decimal(py: Python<'_>, amount: Amount) -> PyResult<Bound<'_, PyAny>> {
+    py.import("decimal")?
+        .getattr("Decimal")?
+        .call1((amount.to_string(),))
+}
+
+fn parse_amount(value: &Bound<'_, PyAny>) -> PyResult<Amount> {
+    let value = value.str()?;
+    let value = value.to_cow()?;
+    amount::parse_amount(&value)
+        .map_err(|err| PyValueError::new_err(format!("invalid amount {}: {}", value, err)))
+}
+
+/// Transaction, with the same fields as a row of the input
+#[pyclass(name = "Transaction", frozen)]
+pub struct PyTransaction {
+    transaction: model::Transaction,
+}
+
+#[pymethods]
+impl PyTransaction {
+    #[new]
+    #[pyo3(signature = (r#type, client, tx, amount=None, to=None, reason=None, currency=None, timestamp=None, tenant=None, tag=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        r#type: &str,
+        client: u16,
+        tx: u32,
+        amount: Option<&Bound<'_, PyAny>>,
+        to: Option<u16>,
+        reason: Option<u32>,
+        currency: Option<&str>,
+        timestamp: Option<u64>,
+        tenant: Option<u32>,
+        tag: Option<&str>,
+    ) -> PyResult<PyTransaction> {
+        let tpe = TransactionType::deserialize(r#type.into_deserializer())
+            .map_err(|err: ValueError| PyValueError::new_err(err.to_string()))?;
+        Ok(PyTransaction {
+            transaction: model::Transaction {
+                tpe,
+                client,
+                tx,
+                amount: amount.map(parse_amount).transpose()?,
+                to,
+                reason,
+                currency: currency
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(|err| PyValueError::new_err(format!("invalid currency: {}", err)))?,
+                timestamp,
+                tenant,
+                tag: tag
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(|err| PyValueError::new_err(format!("invalid tag: {}", err)))?,
+            },
+        })
+    }
+
+    #[getter]
+    fn r#type(&self) -> String {
+        // names of the variants are single words, written in lowercase in the input
+        format!("{:?}", self.transaction.tpe).to_lowercase()
+    }
+
+    #[getter]
+    fn client(&self) -> u16 {
+        self.transaction.client
+    }
+
+    #[getter]
+    fn tx(&self) -> u32 {
+        self.transaction.tx
+    }
+
+    #[getter]
+    fn amount<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        self.transaction
+            .amount
+            .map(|amount| decimal(py, amount))
+            .transpose()
+    }
+
+    #[getter]
+    fn to(&self) -> Option<u16> {
+        self.transaction.to
+    }
+
+    #[getter]
+    fn reason(&self) -> Option<u32> {
+        self.transaction.reason
+    }
+
+    #[getter]
+    fn currency(&self) -> String {
+        self.transaction.currency.to_string()
+    }
+
+    #[getter]
+    fn timestamp(&self) -> Option<u64> {
+        self.transaction.timestamp
+    }
+
+    #[getter]
+    fn tenant(&self) -> Option<u32> {
+        self.transaction.tenant
+    }
+
+    #[getter]
+    fn tag(&self) -> String {
+        self.transaction.tag.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.transaction)
+    }
+}
+
+/// Funds of an account in a single currency
+#[pyclass(name = "Balance", frozen)]
+pub struct PyBalance {
+    balance: model::Balance,
+}
+
+#[pymethods]
+impl PyBalance {
+    #[getter]
+    fn available<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        decimal(py, self.balance.available)
+    }
+
+    #[getter]
+    fn held<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        decimal(py, self.balance.held)
+    }
+
+    #[getter]
+    fn total<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let total = self
+            .balance
+            .total()
+            .ok_or_else(|| PyOverflowError::new_err("total funds don't fit in an amount"))?;
+        decimal(py, total)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.balance)
+    }
+}
+
+/// Snapshot of the state of a client's account
+#[pyclass(name = "Account", frozen)]
+pub struct PyAccount {
+    client: u16,
+    account: model::Account,
+    open_disputes: u32,
+}
+
+#[pymethods]
+impl PyAccount {
+    #[getter]
+    fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Funds by currency
+    #[getter]
+    fn balances(&self) -> HashMap<String, PyBalance> {
+        self.account
+            .balances
+            .iter()
+            .map(|(currency, &balance)| (currency.to_string(), PyBalance { balance }))
+            .collect()
+    }
+
+    #[getter]
+    fn locked(&self) -> bool {
+        self.account.locked
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.account.closed
+    }
+
+    #[getter]
+    fn frozen(&self) -> bool {
+        self.account.frozen
+    }
+
+    #[getter]
+    fn flags(&self) -> Vec<u32> {
+        self.account.flags.iter().copied().collect()
+    }
+
+    /// Number of disputes not resolved nor charged back yet
+    #[getter]
+    fn open_disputes(&self) -> u32 {
+        self.open_disputes
+    }
+
+    #[getter]
+    fn last_activity(&self) -> Option<u64> {
+        self.account.last_activity
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Account(client={}, {:?})", self.client, self.account)
+    }
+}
+
+/// State of all accounts and past transactions, kept in memory
+#[pyclass(name = "State", unsendable)]
+pub struct PyState {
+    state: model::State,
+}
+
+impl PyState {
+    fn account_of(&self, client: u16) -> Option<PyAccount> {
+        Some(PyAccount {
+            client,
+            account: self.state.account(client)?.clone(),
+            open_disputes: self.state.open_disputes(client),
+        })
+    }
+}
+
+#[pymethods]
+impl PyState {
+    /// Creates an empty state following the policy given as JSON, with the fields of `Policy`
+    #[new]
+    #[pyo3(signature = (policy=None))]
+    fn new(policy: Option<&str>) -> PyResult<PyState> {
+        let policy: Policy = match policy {
+            Some(policy) => serde_json::from_str(policy)
+                .map_err(|err| PyValueError::new_err(format!("invalid policy: {}", err)))?,
+            None => Policy::default(),
+        };
+        Ok(PyState {
+            state: model::State::with_policy(policy),
+        })
+    }
+
+    /// Applies the transaction, raises `TransactionError` if it has been rejected
+    fn apply(&mut self, transaction: &PyTransaction) -> PyResult<()> {
+        self.state
+            .apply_transaction(&transaction.transaction)
+            .map_err(|err| match err {
+                CephalopodError::TransactionError { error, .. } => {
+                    TransactionError::new_err(error.to_string())
+                }
+                CephalopodError::IntegrityError { error, .. } => {
+                    IntegrityError::new_err(error.to_string())
+                }
+            })
+    }
+
+    /// Applies all the transactions, skipping rejected ones like the command line tool
+    ///
+    /// Returns ids of the rejected transactions with the reasons.
+    fn replay(&mut self, transactions: &Bound<'_, PyAny>) -> PyResult<Vec<(u32, String)>> {
+        let mut rejected = Vec::new();
+        for transaction in transactions.try_iter()? {
+            let transaction = transaction?;
+            let transaction = transaction.cast::<PyTransaction>()?.get();
+            match self.state.apply_transaction(&transaction.transaction) {
+                Ok(()) => {}
+                Err(CephalopodError::TransactionError { transaction, error }) => {
+                    rejected.push((transaction.tx, error.to_string()))
+                }
+                Err(CephalopodError::IntegrityError { error, .. }) => {
+                    return Err(IntegrityError::new_err(error.to_string()))
+                }
+            }
+        }
+        Ok(rejected)
+    }
+
+    /// Account of the client, `None` if it doesn't exist
+    fn account(&self, client: u16) -> Option<PyAccount> {
+        self.account_of(client)
+    }
+
+    /// All accounts, ordered by client
+    fn accounts(&self) -> Vec<PyAccount> {
+        let mut clients: Vec<u16> = self
+            .state
+            .iter_clients()
+            .map(|(&client, _)| client)
+            .collect();
+        clients.sort_unstable();
+        clients
+            .into_iter()
+            .filter_map(|client| self.account_of(client))
+            .collect()
+    }
+
+    /// Recorded transaction (i.e. deposit, withdrawal, transfer or authorization) with given id
+    fn transaction(&self, tx: u32) -> Option<PyTransaction> {
+        self.state
+            .transaction(tx)
+            .map(|&transaction| PyTransaction { transaction })
+    }
+}
+
+/// Module imported as `cephalopod` in Python
+#[pymodule]
+pub fn cephalopod(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = module.py();
+    module.add_class::<PyTransaction>()?;
+    module.add_class::<PyBalance>()?;
+    module.add_class::<PyAccount>()?;
+    module.add_class::<PyState>()?;
+    module.add("TransactionError", py.get_type::<TransactionError>())?;
+    module.add("IntegrityError", py.get_type::<IntegrityError>())?;
+    Ok(())
+}
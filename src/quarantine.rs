@@ -0,0 +1,42 @@
+//! Quarantine of transactions that caused an integrity error, or another error set to be quarantined
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{CephalopodError, Record};
+
+/// Default flag set on accounts of quarantined transactions, not to clash with flags assigned by operators
+pub const DEFAULT_FLAG: u32 = u32::MAX;
+
+/// Transactions that caused an integrity error, set aside instead of ending processing
+///
+/// The transactions are kept only until taken by `drain`, e.g. to be written
+/// to a file, as the processing may run for hours.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quarantine {
+    /// Flag set on the accounts of quarantined transactions
+    flag: u32,
+    /// Quarantined transactions with their errors, in order of processing
+    pending: Vec<(Record, CephalopodError)>,
+}
+
+impl Quarantine {
+    pub fn new(flag: u32) -> Quarantine {
+        Quarantine {
+            flag,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    pub fn add(&mut self, record: Record, error: CephalopodError) {
+        self.pending.push((record, error));
+    }
+
+    /// Removes and returns the transactions quarantined since the last call
+    pub fn drain(&mut self) -> Vec<(Record, CephalopodError)> {
+        std::mem::take(&mut self.pending)
+    }
+}
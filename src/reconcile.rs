@@ -0,0 +1,182 @@
+//! Reconciliation of the resulting accounts against expected balances, behind `cephalopod reconcile`
+//!
+//! Expected balances are given in the format of the output, with any of the
+//! amount columns and `locked` left out or empty to skip comparing them.
+//! Accounts missing on either side are compared as accounts without funds.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::export::{self, ExportedClient};
+use crate::tenant::Tenants;
+
+/// Balance of an account in a single currency expected after processing
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ExpectedBalance {
+    #[serde(default)]
+    pub tenant: Option<u32>,
+    pub client: u16,
+    #[serde(default)]
+    pub currency: Currency,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub available: Option<Amount>,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub held: Option<Amount>,
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub total: Option<Amount>,
+    #[serde(default)]
+    pub locked: Option<bool>,
+}
+
+/// Largest differences of amounts still considered a match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tolerance {
+    /// Tolerance of currencies without their own one
+    pub default: Amount,
+    pub currencies: BTreeMap<Currency, Amount>,
+}
+
+impl Tolerance {
+    pub fn of(&self, currency: Currency) -> Amount {
+        self.currencies
+            .get(&currency)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Field of an account whose actual value doesn't match the expected one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    pub currency: Currency,
+    /// `available`, `held`, `total` or `locked`
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+    /// Actual amount less the expected one, `None` for `locked` or if it doesn't fit in an amount
+    pub difference: Option<Amount>,
+}
+
+type AccountKey = (Option<u32>, u16, Currency);
+
+/// Compares the accounts with the expected balances, returning discrepancies ordered by account
+///
+/// Accounts without expected balances are compared as expected to have no funds, unless
+/// `only_listed` is set, in which case they are ignored.
+pub fn reconcile(
+    tenants: &Tenants,
+    expected: impl IntoIterator<Item = ExpectedBalance>,
+    tolerance: &Tolerance,
+    only_listed: bool,
+) -> Vec<Discrepancy> {
+    let mut actual: BTreeMap<AccountKey, ExportedClient> = export::accounts(tenants)
+        .map(|account| ((account.tenant, account.client, account.currency), account))
+        .collect();
+    let mut discrepancies = Vec::new();
+    for balance in expected {
+        let key = (balance.tenant, balance.client, balance.currency);
+        let account = actual.remove(&key);
+        compare(&balance, account.as_ref(), tolerance, &mut discrepancies);
+    }
+    if !only_listed {
+        for ((tenant, client, currency), account) in actual {
+            let balance = ExpectedBalance {
+                tenant,
+                client,
+                currency,
+                available: Some(Amount::ZERO),
+                held: Some(Amount::ZERO),
+                total: None,
+                locked: Some(false),
+            };
+            compare(&balance, Some(&account), tolerance, &mut discrepancies);
+        }
+    }
+    discrepancies
+        .sort_by_key(|discrepancy| (discrepancy.tenant, discrepancy.client, discrepancy.currency));
+    discrepancies
+}
+
+fn compare(
+    balance: &ExpectedBalance,
+    account: Option<&ExportedClient>,
+    tolerance: &Tolerance,
+    discrepancies: &mut Vec<Discrepancy>,
+) {
+    let (available, held, total, locked) = match account {
+        Some(account) => (
+            Some(account.available),
+            Some(account.held),
+            account.total,
+            account.locked,
+        ),
+        None => (
+            Some(Amount::ZERO),
+            Some(Amount::ZERO),
+            Some(Amount::ZERO),
+            false,
+        ),
+    };
+    let tolerance = tolerance.of(balance.currency);
+    let discrepancy = |field, expected: String, actual: String, difference| Discrepancy {
+        tenant: balance.tenant,
+        client: balance.client,
+        currency: balance.currency,
+        field,
+        expected,
+        actual,
+        difference,
+    };
+    for &(field, expected, actual) in [
+        ("available", balance.available, available),
+        ("held", balance.held, held),
+        ("total", balance.total, total),
+    ]
+    .iter()
+    {
+        if let Some(expected) = expected {
+            let actual = match actual {
+                Some(actual) => actual,
+                None => {
+                    // a total which doesn't fit in an amount can't match any expected one
+                    discrepancies.push(discrepancy(
+                        field,
+                        expected.to_string(),
+                        "overflowing".to_string(),
+                        None,
+                    ));
+                    continue;
+                }
+            };
+            let difference = actual.checked_sub(expected);
+            let magnitude = match difference {
+                Some(difference) if difference < Amount::ZERO => {
+                    Amount::ZERO.checked_sub(difference)
+                }
+                difference => difference,
+            };
+            if magnitude.is_none_or(|magnitude| magnitude > tolerance) {
+                discrepancies.push(discrepancy(
+                    field,
+                    expected.to_string(),
+                    actual.to_string(),
+                    difference,
+                ));
+            }
+        }
+    }
+    match balance.locked {
+        Some(expected) if expected != locked => discrepancies.push(discrepancy(
+            "locked",
+            expected.to_string(),
+            locked.to_string(),
+            None,
+        )),
+        _ => {}
+    }
+}
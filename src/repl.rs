@@ -0,0 +1,244 @@
+//! Interactive session applying ad-hoc transactions, behind `cephalopod repl`
+//!
+//! Each line is a command, e.g.
+//!
+//! ```text
+//! > deposit 1 1 100
+//! applied
+//! > withdrawal 1 2 150
+//! rejected (NotEnoughFunds): not enough funds, available: 100, required: 150
+//! > dispute 1 1
+//! applied
+//! > account 1
+//! client 1: available 0, held 100, total 100
+//! > undo
+//! undone dispute of transaction 1 by client 1
+//! ```
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::model::{Account, Transaction, TransactionType};
+use crate::snapshot;
+use crate::tenant::Tenants;
+
+const HELP: &str = "\
+<type> <client> <tx> [amount] [to=CLIENT] [currency=CODE] [reason=N] [timestamp=SECONDS]
+                    apply a transaction, e.g. `deposit 1 1 100` or `transfer 1 2 10 to=2`
+account <client>    show balances of the client
+accounts            show balances of all clients
+disputes            list transactions with an open dispute
+tenant [id]         switch to the tenant, or the default one without an id
+undo                revert the latest applied transaction
+save <file>         write a snapshot of the state, to be loaded with `repl <file>`
+help                show this help
+quit                end the session";
+
+/// Optional columns of a transaction, given as `name=value`
+const NAMED_COLUMNS: [&str; 4] = ["to", "currency", "reason", "timestamp"];
+
+/// State of the session along with the states to undo applied transactions to
+pub struct Repl {
+    tenants: Tenants,
+    /// Tenant transactions and queries refer to
+    tenant: Option<u32>,
+    /// Applied transactions with snapshots of the tenants from before them, latest last
+    undo: Vec<(Transaction, Vec<u8>)>,
+}
+
+impl Repl {
+    pub fn new(tenants: Tenants) -> Repl {
+        Repl {
+            tenants,
+            tenant: None,
+            undo: Vec::new(),
+        }
+    }
+
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    /// Executes a command, returning its output or the reason it failed
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|word| !word.is_empty())
+            .collect();
+        match words.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] => Ok(HELP.to_string()),
+            ["account", client] => {
+                let client = parse_client(client)?;
+                let state = self.tenants.state(self.tenant);
+                match state.and_then(|state| state.account(client)) {
+                    Some(account) => Ok(describe_account(client, account)),
+                    None => Err(format!("client {} has no account", client)),
+                }
+            }
+            ["accounts"] => {
+                let state = match self.tenants.state(self.tenant) {
+                    Some(state) => state,
+                    None => return Ok("no accounts".to_string()),
+                };
+                let mut clients: Vec<(&u16, &Account)> = state.iter_clients().collect();
+                clients.sort_unstable_by_key(|&(&client, _)| client);
+                let lines: Vec<String> = clients
+                    .into_iter()
+                    .map(|(&client, account)| describe_account(client, account))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            ["disputes"] => {
+                let disputed = match self.tenants.state(self.tenant) {
+                    Some(state) => state.disputed_transactions(),
+                    None => Vec::new(),
+                };
+                if disputed.is_empty() {
+                    return Ok("no open disputes".to_string());
+                }
+                let lines: Vec<String> = disputed.into_iter().map(describe_transaction).collect();
+                Ok(lines.join("\n"))
+            }
+            ["tenant"] => {
+                self.tenant = None;
+                Ok("switched to the default tenant".to_string())
+            }
+            ["tenant", tenant] => {
+                let tenant = tenant
+                    .parse()
+                    .map_err(|err| format!("invalid tenant {}: {}", tenant, err))?;
+                self.tenant = Some(tenant);
+                Ok(format!("switched to tenant {}", tenant))
+            }
+            ["undo"] => {
+                let (transaction, snapshot) = self
+                    .undo
+                    .pop()
+                    .ok_or_else(|| "nothing to undo".to_string())?;
+                self.tenants = snapshot::read(snapshot.as_slice())
+                    .expect("snapshot taken by the session should be readable");
+                Ok(format!("undone {}", describe_transaction(&transaction)))
+            }
+            ["save", path] => {
+                self.tenants
+                    .save(Path::new(path))
+                    .map_err(|err| format!("problem saving snapshot: {}", err))?;
+                Ok(format!("saved to {}", path))
+            }
+            _ => self.apply(&words),
+        }
+    }
+
+    /// Applies a transaction given as its type, client, id, amount and named columns
+    fn apply(&mut self, words: &[&str]) -> Result<String, String> {
+        let transaction = self.parse_transaction(words)?;
+        let mut before = Vec::new();
+        snapshot::write(&mut before, &self.tenants)
+            .map_err(|err| format!("problem taking snapshot: {}", err))?;
+        match self.tenants.apply_transaction(&transaction) {
+            Ok(()) => {
+                self.undo.push((transaction, before));
+                Ok("applied".to_string())
+            }
+            Err(err) => Ok(format!("rejected ({}): {}", err.kind(), err.message())),
+        }
+    }
+
+    fn parse_transaction(&self, words: &[&str]) -> Result<Transaction, String> {
+        let mut positional = Vec::new();
+        let mut named = Vec::new();
+        for word in words {
+            match word.split_once('=') {
+                Some((name, value)) if NAMED_COLUMNS.contains(&name) => named.push((name, value)),
+                Some((name, _)) => return Err(format!("unknown column {}", name)),
+                None => positional.push(*word),
+            }
+        }
+        let mut header = match positional.len() {
+            3 => vec!["type", "client", "tx"],
+            4 => vec!["type", "client", "tx", "amount"],
+            _ => return Err("unknown command, see help".to_string()),
+        };
+        let mut row = positional;
+        for (name, value) in named {
+            header.push(name);
+            row.push(value);
+        }
+        let header = csv::StringRecord::from(header);
+        let transaction: Transaction = csv::StringRecord::from(row)
+            .deserialize(Some(&header))
+            .map_err(|err| format!("invalid transaction: {}", err))?;
+        Ok(Transaction {
+            tenant: self.tenant,
+            ..transaction
+        })
+    }
+}
+
+fn parse_client(client: &str) -> Result<u16, String> {
+    client
+        .parse()
+        .map_err(|err| format!("invalid client {}: {}", client, err))
+}
+
+fn describe_account(client: u16, account: &Account) -> String {
+    let mut description = format!("client {}:", client);
+    if account.balances.is_empty() {
+        description.push_str(" no funds");
+    }
+    for (currency, balance) in &account.balances {
+        if !currency.is_default() {
+            let _ = write!(description, " {}", currency);
+        }
+        let _ = write!(
+            description,
+            " available {}, held {}, total {}",
+            balance.available,
+            balance.held,
+            match balance.total() {
+                Some(total) => total.to_string(),
+                None => "overflowing".to_string(),
+            }
+        );
+    }
+    for (state, set) in [
+        ("locked", account.locked),
+        ("frozen", account.frozen),
+        ("closed", account.closed),
+    ]
+    .iter()
+    {
+        if *set {
+            let _ = write!(description, ", {}", state);
+        }
+    }
+    if !account.flags.is_empty() {
+        let flags: Vec<String> = account.flags.iter().map(u32::to_string).collect();
+        let _ = write!(description, ", flags {}", flags.join(";"));
+    }
+    description
+}
+
+fn describe_transaction(transaction: &Transaction) -> String {
+    let tpe = format!("{:?}", transaction.tpe).to_lowercase();
+    let currency = if transaction.currency.is_default() {
+        String::new()
+    } else {
+        format!(" {}", transaction.currency)
+    };
+    let mut description = match transaction.amount {
+        Some(amount) => format!(
+            "{} of {}{} in transaction {} by client {}",
+            tpe, amount, currency, transaction.tx, transaction.client
+        ),
+        None => format!(
+            "{} of transaction {} by client {}",
+            tpe, transaction.tx, transaction.client
+        ),
+    };
+    if let (TransactionType::Transfer, Some(to)) = (transaction.tpe, transaction.to) {
+        let _ = write!(description, " to client {}", to);
+    }
+    description
+}
@@ -0,0 +1,428 @@
+//! Reports of a batch run: a self-contained HTML one, written with `--report`,
+//! and the summary as JSON or Markdown, written with `--summary`
+//!
+//! The HTML report doesn't load any scripts, styles or fonts, so it can be sent
+//! by email or attached to a ticket. Charts are drawn with plain HTML and CSS.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{Balance, TransactionType};
+use crate::summary::Summary;
+use crate::tenant::Tenants;
+
+/// Number of accounts listed among the top exposures
+pub const TOP_EXPOSURES: usize = 10;
+
+/// Number of kinds of errors listed among the notable rejections of the Markdown summary
+pub const NOTABLE_REJECTIONS: usize = 5;
+
+/// How the summary is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    Json,
+    /// Tables to be pasted into tickets or pull requests
+    Markdown,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SummaryFormat, String> {
+        match s {
+            "json" => Ok(SummaryFormat::Json),
+            "markdown" => Ok(SummaryFormat::Markdown),
+            _ => Err(format!("expected json or markdown, got {}", s)),
+        }
+    }
+}
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+h1 { font-size: 1.6em; }
+h2 { font-size: 1.2em; margin-top: 2em; border-bottom: 1px solid #ccc; }
+table { border-collapse: collapse; }
+th, td { padding: 0.2em 0.8em; text-align: left; }
+td.number { text-align: right; font-variant-numeric: tabular-nums; }
+tr:nth-child(even) { background: #f4f4f4; }
+.bar { background: #4a7bb7; height: 1em; min-width: 1px; }
+.bar.rejected { background: #c0504d; }
+.chart td { vertical-align: middle; }
+.chart td:nth-child(2) { width: 30em; }
+.empty { color: #888; }";
+
+/// Account at risk of a loss, i.e. with funds held for disputes or a negative balance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exposure {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    /// Held funds along with the overdrawn part of the available ones, saturating at the largest
+    /// amount as it only ranks the accounts
+    pub exposure: Amount,
+    pub locked: bool,
+}
+
+/// Accounts with the largest exposure, at most `limit` of them, largest first
+pub fn top_exposures(tenants: &Tenants, limit: usize) -> Vec<Exposure> {
+    let mut exposures: Vec<Exposure> = tenants
+        .iter()
+        .flat_map(|(tenant, state)| {
+            state.iter_clients().flat_map(move |(&client, account)| {
+                account.balances.iter().map(move |(&currency, balance)| {
+                    let overdrawn = if balance.available < Amount::ZERO {
+                        Amount::ZERO
+                            .checked_sub(balance.available)
+                            .unwrap_or(Amount::MAX)
+                    } else {
+                        Amount::ZERO
+                    };
+                    Exposure {
+                        tenant,
+                        client,
+                        currency,
+                        available: balance.available,
+                        held: balance.held,
+                        exposure: balance.held.checked_add(overdrawn).unwrap_or(Amount::MAX),
+                        locked: account.locked,
+                    }
+                })
+            })
+        })
+        .filter(|exposure| exposure.exposure > Amount::ZERO)
+        .collect();
+    // ties in the order of clients, so that reports of the same state are the same
+    exposures.sort_by(|a, b| {
+        b.exposure
+            .cmp(&a.exposure)
+            .then((a.tenant, a.client, a.currency).cmp(&(b.tenant, b.client, b.currency)))
+    });
+    exposures.truncate(limit);
+    exposures
+}
+
+/// Counters of the summary along with numbers of accounts, labelled
+fn summary_rows(summary: &Summary, tenants: &Tenants) -> Vec<(&'static str, String)> {
+    let accounts: usize = tenants
+        .iter()
+        .map(|(_, state)| state.iter_clients().count())
+        .sum();
+    let locked: usize = tenants
+        .iter()
+        .map(|(_, state)| {
+            state
+                .iter_clients()
+                .filter(|(_, account)| account.locked)
+                .count()
+        })
+        .sum();
+    let fingerprint = summary
+        .fingerprint
+        .map(|fingerprint| fingerprint.to_string())
+        .unwrap_or_default();
+    vec![
+        ("Transactions applied", summary.applied.to_string()),
+        ("Transactions rejected", summary.rejected.to_string()),
+        (
+            "Disputes after the window",
+            summary.expired_disputes.to_string(),
+        ),
+        ("Amounts over the cap", summary.over_cap.to_string()),
+        ("Transactions suspended", summary.suspended.to_string()),
+        (
+            "Suspended and never resolved",
+            summary.unresolved.to_string(),
+        ),
+        ("Transactions quarantined", summary.quarantined.to_string()),
+        ("Invalid input rows", summary.invalid_rows.to_string()),
+        (
+            "Transactions out of order",
+            summary.out_of_order.to_string(),
+        ),
+        ("Fees collected", summary.fees()),
+        ("Accounts", accounts.to_string()),
+        ("Locked accounts", locked.to_string()),
+        ("Fingerprint of the state", fingerprint),
+    ]
+}
+
+/// Numbers of errors of each kind, most frequent first
+fn rejections_by_kind(summary: &Summary) -> Vec<(String, u64)> {
+    let mut rejections: Vec<(String, u64)> = summary
+        .errors
+        .iter()
+        .map(|(kind, counts)| (kind.clone(), counts.values().sum()))
+        .collect();
+    rejections.sort_by(|(a_kind, a), (b_kind, b)| b.cmp(a).then(a_kind.cmp(b_kind)));
+    rejections
+}
+
+/// Text of a sum, `overflowing` if it doesn't fit in an amount
+fn or_overflowing(amount: Option<Amount>) -> String {
+    match amount {
+        Some(amount) => amount.to_string(),
+        None => "overflowing".to_string(),
+    }
+}
+
+/// Escapes text to be included in HTML
+fn escape(text: impl Display) -> String {
+    let mut escaped = String::new();
+    for c in text.to_string().chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a horizontal bar chart of the counts, with bars relative to the largest one
+fn write_chart<W: Write>(wtr: &mut W, counts: &[(String, u64)], class: &str) -> io::Result<()> {
+    if counts.is_empty() {
+        return writeln!(wtr, "<p class=\"empty\">None.</p>");
+    }
+    let max = counts
+        .iter()
+        .map(|&(_, count)| count)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    writeln!(wtr, "<table class=\"chart\">")?;
+    for (label, count) in counts {
+        writeln!(
+            wtr,
+            "<tr><td>{}</td><td><div class=\"bar {}\" style=\"width: {:.1}%\"></div></td><td class=\"number\">{}</td></tr>",
+            escape(label),
+            class,
+            *count as f64 * 100.0 / max as f64,
+            count
+        )?;
+    }
+    writeln!(wtr, "</table>")
+}
+
+/// Writes the report of a run with the summary and the resulting state of the tenants
+pub fn write_report<W: Write>(mut wtr: W, summary: &Summary, tenants: &Tenants) -> io::Result<()> {
+    writeln!(wtr, "<!DOCTYPE html>")?;
+    writeln!(wtr, "<html lang=\"en\">")?;
+    writeln!(wtr, "<head>")?;
+    writeln!(wtr, "<meta charset=\"utf-8\">")?;
+    writeln!(wtr, "<title>Cephalopod run report</title>")?;
+    writeln!(wtr, "<style>\n{}\n</style>", STYLE)?;
+    writeln!(wtr, "</head>")?;
+    writeln!(wtr, "<body>")?;
+    writeln!(wtr, "<h1>Cephalopod run report</h1>")?;
+
+    writeln!(wtr, "<h2>Summary</h2>")?;
+    writeln!(wtr, "<table>")?;
+    for (label, value) in summary_rows(summary, tenants) {
+        writeln!(
+            wtr,
+            "<tr><th>{}</th><td class=\"number\">{}</td></tr>",
+            label,
+            escape(value)
+        )?;
+    }
+    writeln!(wtr, "</table>")?;
+
+    writeln!(wtr, "<h2>Applied transactions by type</h2>")?;
+    let applied: Vec<(String, u64)> = summary
+        .applied_by_type
+        .iter()
+        .map(|(tpe, &count)| (format!("{:?}", tpe), count))
+        .collect();
+    write_chart(&mut wtr, &applied, "applied")?;
+
+    writeln!(wtr, "<h2>Rejections by kind of error</h2>")?;
+    let rejections = rejections_by_kind(summary);
+    write_chart(&mut wtr, &rejections, "rejected")?;
+    if !summary.errors.is_empty() {
+        let types: Vec<TransactionType> = summary
+            .errors
+            .values()
+            .flat_map(|counts| counts.keys().copied())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        writeln!(wtr, "<table>")?;
+        write!(wtr, "<tr><th>Kind of error</th>")?;
+        for tpe in &types {
+            write!(wtr, "<th>{:?}</th>", tpe)?;
+        }
+        writeln!(wtr, "</tr>")?;
+        for (kind, _) in &rejections {
+            let counts: &BTreeMap<TransactionType, u64> = &summary.errors[kind];
+            write!(wtr, "<tr><td>{}</td>", escape(kind))?;
+            for tpe in &types {
+                match counts.get(tpe) {
+                    Some(count) => write!(wtr, "<td class=\"number\">{}</td>", count)?,
+                    None => write!(wtr, "<td></td>")?,
+                }
+            }
+            writeln!(wtr, "</tr>")?;
+        }
+        writeln!(wtr, "</table>")?;
+    }
+
+    writeln!(wtr, "<h2>Top exposures</h2>")?;
+    writeln!(
+        wtr,
+        "<p>Accounts with the most funds held for disputes or overdrawn.</p>"
+    )?;
+    let exposures = top_exposures(tenants, TOP_EXPOSURES);
+    if exposures.is_empty() {
+        writeln!(wtr, "<p class=\"empty\">None.</p>")?;
+    } else {
+        writeln!(wtr, "<table>")?;
+        writeln!(
+            wtr,
+            "<tr><th>Tenant</th><th>Client</th><th>Currency</th><th>Available</th><th>Held</th><th>Exposure</th><th>Locked</th></tr>"
+        )?;
+        for exposure in exposures {
+            writeln!(
+                wtr,
+                "<tr><td>{}</td><td class=\"number\">{}</td><td>{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td>{}</td></tr>",
+                exposure
+                    .tenant
+                    .map(|tenant| tenant.to_string())
+                    .unwrap_or_default(),
+                exposure.client,
+                escape(exposure.currency),
+                exposure.available,
+                exposure.held,
+                exposure.exposure,
+                if exposure.locked { "yes" } else { "" }
+            )?;
+        }
+        writeln!(wtr, "</table>")?;
+    }
+
+    writeln!(wtr, "</body>")?;
+    writeln!(wtr, "</html>")
+}
+
+/// Writes the summary of a run in the format, the Markdown one along with totals of funds
+pub fn write_summary<W: Write>(
+    mut wtr: W,
+    format: SummaryFormat,
+    summary: &Summary,
+    tenants: &Tenants,
+) -> io::Result<()> {
+    match format {
+        SummaryFormat::Json => {
+            serde_json::to_writer_pretty(&mut wtr, summary)?;
+            writeln!(wtr)
+        }
+        SummaryFormat::Markdown => write_markdown(wtr, summary, tenants),
+    }
+}
+
+/// Writes the summary as Markdown tables: counters, applied transactions by type, funds by
+/// currency and the most frequent kinds of rejections
+pub fn write_markdown<W: Write>(
+    mut wtr: W,
+    summary: &Summary,
+    tenants: &Tenants,
+) -> io::Result<()> {
+    writeln!(wtr, "## Cephalopod run summary")?;
+    writeln!(wtr)?;
+    writeln!(wtr, "| | |")?;
+    writeln!(wtr, "|---|---:|")?;
+    for (label, value) in summary_rows(summary, tenants) {
+        if !value.is_empty() {
+            writeln!(wtr, "| {} | {} |", label, value)?;
+        }
+    }
+
+    writeln!(wtr)?;
+    writeln!(wtr, "### Applied transactions by type")?;
+    writeln!(wtr)?;
+    if summary.applied_by_type.is_empty() {
+        writeln!(wtr, "None.")?;
+    } else {
+        writeln!(wtr, "| Type | Count |")?;
+        writeln!(wtr, "|---|---:|")?;
+        for (tpe, count) in &summary.applied_by_type {
+            writeln!(wtr, "| {:?} | {} |", tpe, count)?;
+        }
+        writeln!(wtr, "| **Total** | **{}** |", summary.applied)?;
+    }
+
+    writeln!(wtr)?;
+    writeln!(wtr, "### Funds")?;
+    writeln!(wtr)?;
+    // sums which don't fit in an amount are `None`
+    let mut funds: BTreeMap<Currency, (Option<Amount>, Option<Amount>)> = BTreeMap::new();
+    for (_, state) in tenants.iter() {
+        for (_, account) in state.iter_clients() {
+            for (&currency, balance) in &account.balances {
+                let (available, held) = funds
+                    .entry(currency)
+                    .or_insert((Some(Amount::ZERO), Some(Amount::ZERO)));
+                *available = available.and_then(|sum| sum.checked_add(balance.available));
+                *held = held.and_then(|sum| sum.checked_add(balance.held));
+            }
+        }
+    }
+    if funds.is_empty() {
+        writeln!(wtr, "None.")?;
+    } else {
+        writeln!(wtr, "| Currency | Available | Held | Total |")?;
+        writeln!(wtr, "|---|---:|---:|---:|")?;
+        for (currency, (available, held)) in funds {
+            let total = available
+                .zip(held)
+                .and_then(|(available, held)| Balance { available, held }.total());
+            writeln!(
+                wtr,
+                "| {} | {} | {} | {} |",
+                if currency.is_default() {
+                    "default"
+                } else {
+                    currency.as_str()
+                },
+                or_overflowing(available),
+                or_overflowing(held),
+                or_overflowing(total)
+            )?;
+        }
+    }
+
+    writeln!(wtr)?;
+    writeln!(wtr, "### Notable rejections")?;
+    writeln!(wtr)?;
+    let rejections = rejections_by_kind(summary);
+    if rejections.is_empty() {
+        writeln!(wtr, "None.")?;
+        return Ok(());
+    }
+    writeln!(wtr, "| Kind of error | Count | By type |")?;
+    writeln!(wtr, "|---|---:|---|")?;
+    for (kind, count) in rejections.iter().take(NOTABLE_REJECTIONS) {
+        let by_type: Vec<String> = summary.errors[kind]
+            .iter()
+            .map(|(tpe, count)| format!("{:?}: {}", tpe, count))
+            .collect();
+        writeln!(wtr, "| {} | {} | {} |", kind, count, by_type.join(", "))?;
+    }
+    if rejections.len() > NOTABLE_REJECTIONS {
+        let others: Vec<String> = rejections[NOTABLE_REJECTIONS..]
+            .iter()
+            .map(|(kind, count)| format!("{} ({})", kind, count))
+            .collect();
+        writeln!(wtr)?;
+        writeln!(wtr, "Other kinds: {}.", others.join(", "))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Declarative compliance rules evaluated before every transaction (`--rules`)
+//!
+//! Rules are loaded from a JSON file, e.g.:
+//!
+//! ```json
+//! {"rules": [
+//!     {"name": "large-deposit", "types": ["deposit"], "min_amount": "10000"},
+//!     {"name": "sanctioned-country", "countries": ["KP", "IR"], "action": "block"},
+//!     {"name": "structuring", "types": ["deposit"], "currencies": ["USD"],
+//!      "cumulative": {"amount": "10000", "window": 86400}}
+//! ]}
+//! ```
+//!
+//! A rule matches a transaction when all of its conditions hold, conditions
+//! left out always hold. Flags, the class and the country are those of the
+//! transaction's client, given by its account and `ClientSettings`. Every match
+//! is recorded; a transaction matching a rule with the `block` action isn't
+//! applied and fails with `TransactionError::BlockedByRule` instead.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{Record, State, Transaction, TransactionType};
+use crate::tag::Tag;
+
+/// What happens to transactions matching a rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Only record the match
+    #[default]
+    Record,
+    /// Record the match and reject the transaction
+    Block,
+}
+
+/// Limit of the total amount of a client's transactions matching the other conditions of a rule
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CumulativeLimit {
+    /// Total, including the checked transaction, at which the rule matches
+    pub amount: Amount,
+    /// Seconds the total is summed over, by timestamps of transactions; transactions without
+    /// timestamps, or all of them without a window, are summed over the whole run
+    #[serde(default)]
+    pub window: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Name recorded with the matches
+    pub name: String,
+    #[serde(default)]
+    pub types: Vec<TransactionType>,
+    #[serde(default)]
+    pub currencies: Vec<Currency>,
+    /// Smallest amount matching, transactions without an amount (e.g. disputes) never do
+    #[serde(default, deserialize_with = "crate::amount::deserialize_optional")]
+    pub min_amount: Option<Amount>,
+    /// Risk flags, any of which has to be set on the account
+    #[serde(default)]
+    pub flags: Vec<u32>,
+    /// Classes of the client, see `ClientSettings::class`
+    #[serde(default)]
+    pub classes: Vec<String>,
+    /// Countries of the client, see `ClientSettings::country`
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub cumulative: Option<CumulativeLimit>,
+    #[serde(default)]
+    pub action: RuleAction,
+}
+
+impl Rule {
+    /// Whether the conditions other than the cumulative limit hold
+    fn holds(&self, state: &State, tx: &Transaction) -> bool {
+        let settings = state.client_settings(tx.client);
+        let class = settings.and_then(|settings| settings.class.as_ref());
+        let country = settings.and_then(|settings| settings.country.as_ref());
+        let flags = state.account(tx.client).map(|account| &account.flags);
+        (self.types.is_empty() || self.types.contains(&tx.tpe))
+            && (self.currencies.is_empty() || self.currencies.contains(&tx.currency))
+            && self
+                .min_amount
+                .is_none_or(|min| tx.amount.is_some_and(|amount| amount >= min))
+            && (self.flags.is_empty()
+                || flags.is_some_and(|flags| self.flags.iter().any(|flag| flags.contains(flag))))
+            && (self.classes.is_empty() || class.is_some_and(|class| self.classes.contains(class)))
+            && (self.countries.is_empty()
+                || country.is_some_and(|country| self.countries.contains(country)))
+    }
+}
+
+/// Rules given in a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RulesConfig {
+    pub rules: Vec<Rule>,
+}
+
+impl RulesConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.name.is_empty() {
+                return Err(format!("rule {} has no name", i + 1));
+            }
+            if self.rules[..i].iter().any(|other| other.name == rule.name) {
+                return Err(format!("rule {} is given more than once", rule.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Match of a rule, as recorded in the audit log of rules
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleMatch {
+    pub line: Option<u64>,
+    pub tenant: Option<u32>,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tpe: TransactionType,
+    pub tag: Tag,
+    pub rule: String,
+    pub action: RuleAction,
+}
+
+/// Amounts counted towards a cumulative limit of a client
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Total {
+    /// Sum of the amounts of transactions without timestamps
+    untimed: Amount,
+    /// Amounts by timestamps within the window, oldest first
+    timed: VecDeque<(u64, Amount)>,
+}
+
+impl Total {
+    /// Sum of the amounts since `window` before `now`, saturating at the largest amount
+    fn sum(&self, window: Option<u64>, now: Option<u64>) -> Amount {
+        let since = match (window, now) {
+            (Some(window), Some(now)) => Some(now.saturating_sub(window)),
+            _ => None,
+        };
+        self.timed
+            .iter()
+            .filter(|(timestamp, _)| since.is_none_or(|since| *timestamp > since))
+            .fold(self.untimed, |sum, (_, amount)| {
+                sum.checked_add(*amount).unwrap_or(Amount::MAX)
+            })
+    }
+}
+
+/// Rules with the state of their cumulative limits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// Totals of cumulative rules by their position, tenant and client
+    totals: BTreeMap<(usize, Option<u32>, u16), Total>,
+    /// Matches not taken by `drain_matches` yet, if they are kept
+    matches: Option<Vec<RuleMatch>>,
+}
+
+impl Rules {
+    pub fn new(config: RulesConfig) -> Rules {
+        Rules {
+            rules: config.rules,
+            totals: BTreeMap::new(),
+            matches: None,
+        }
+    }
+
+    /// Keeps matches from now on, until taken by `drain_matches`
+    pub fn keep_matches(&mut self) {
+        self.matches.get_or_insert_with(Vec::new);
+    }
+
+    /// Takes the matches kept since the last call
+    pub fn drain_matches(&mut self) -> Vec<RuleMatch> {
+        self.matches
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Evaluates the rules against the transaction about to be applied, recording the matches
+    ///
+    /// Returns the position (counted from 1) of the first matched rule blocking it, if any.
+    pub fn check(&mut self, state: &State, record: &Record) -> Option<u32> {
+        let tx = &record.transaction;
+        let mut blocked = None;
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.holds(state, tx) {
+                continue;
+            }
+            if let Some(limit) = &rule.cumulative {
+                let amount = match tx.amount {
+                    Some(amount) => amount,
+                    None => continue,
+                };
+                let total = self
+                    .totals
+                    .get(&(i, tx.tenant, tx.client))
+                    .map(|total| total.sum(limit.window, tx.timestamp))
+                    .unwrap_or_default();
+                // a total which doesn't fit in an amount exceeds any limit
+                if total
+                    .checked_add(amount)
+                    .is_some_and(|total| total < limit.amount)
+                {
+                    continue;
+                }
+            }
+            if rule.action == RuleAction::Block && blocked.is_none() {
+                blocked = Some(i as u32 + 1);
+            }
+            if let Some(matches) = &mut self.matches {
+                matches.push(RuleMatch {
+                    line: record.line,
+                    tenant: tx.tenant,
+                    client: tx.client,
+                    tx: tx.tx,
+                    tpe: tx.tpe,
+                    tag: state.tag(tx),
+                    rule: rule.name.clone(),
+                    action: rule.action,
+                });
+            }
+        }
+        blocked
+    }
+
+    /// Counts a transaction applied to the state towards the cumulative limits
+    pub fn record(&mut self, state: &State, tx: &Transaction) {
+        let amount = match tx.amount {
+            Some(amount) => amount,
+            None => return,
+        };
+        for (i, rule) in self.rules.iter().enumerate() {
+            let limit = match &rule.cumulative {
+                Some(limit) if rule.holds(state, tx) => limit,
+                _ => continue,
+            };
+            let total = self.totals.entry((i, tx.tenant, tx.client)).or_default();
+            match (tx.timestamp, limit.window) {
+                (Some(timestamp), Some(window)) => {
+                    let since = timestamp.saturating_sub(window);
+                    while total
+                        .timed
+                        .front()
+                        .is_some_and(|(oldest, _)| *oldest <= since)
+                    {
+                        total.timed.pop_front();
+                    }
+                    total.timed.push_back((timestamp, amount));
+                }
+                _ => total.untimed = total.untimed.checked_add(amount).unwrap_or(Amount::MAX),
+            }
+        }
+    }
+}
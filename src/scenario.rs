@@ -0,0 +1,270 @@
+//! Fluent builder of transaction sequences for tests, built with the `test-util` feature
+//!
+//! Deposits, withdrawals and transfers get consecutive ids starting with 1,
+//! disputes and settlements refer to them by id. Every transaction must be
+//! applied, unless it's followed by `rejected` or `rejected_with`, which is
+//! checked by the next step or when the scenario is dropped.
+//!
+//! ```
+//! use cephalopod::scenario::Scenario;
+//!
+//! Scenario::new()
+//!     .deposit(1, 100)
+//!     .withdraw(1, "130.5")
+//!     .rejected_with("NotEnoughFunds")
+//!     .dispute(1)
+//!     .chargeback(1)
+//!     .expect_locked(1)
+//!     .expect_available(1, 0)
+//!     .expect_held(1, 0);
+//! ```
+
+use std::collections::HashMap;
+use std::mem;
+use std::thread;
+
+use crate::amount::{self, Amount};
+use crate::currency::Currency;
+use crate::model::{Account, CephalopodError, State, Transaction, TransactionType};
+use crate::policy::Policy;
+use crate::tag::Tag;
+
+#[track_caller]
+fn parse(amount: impl ToString) -> Amount {
+    let amount = amount.to_string();
+    match amount::parse_amount(&amount) {
+        Ok(amount) => amount,
+        Err(err) => panic!("invalid amount {:?}: {}", amount, err),
+    }
+}
+
+/// State along with the transactions applied to it so far
+///
+/// Amounts can be given as anything formatted as a decimal number, e.g.
+/// `100`, `"0.0001"` or an `Amount`. Methods panic as soon as the state
+/// doesn't meet an expectation.
+pub struct Scenario {
+    state: State,
+    next_tx: u32,
+    /// Client of each deposit, withdrawal and transfer, applied or not
+    clients: HashMap<u32, u16>,
+    /// Latest transaction and its result, until it has been checked
+    pending: Option<(Transaction, Result<(), CephalopodError>)>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scenario {
+    pub fn new() -> Scenario {
+        Scenario::with_policy(Policy::default())
+    }
+
+    pub fn with_policy(policy: Policy) -> Scenario {
+        Scenario {
+            state: State::with_policy(policy),
+            next_tx: 1,
+            clients: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    /// Panics if the latest transaction has been rejected without being expected to
+    #[track_caller]
+    fn check_pending(&mut self) {
+        if let Some((transaction, Err(err))) = self.pending.take() {
+            panic!("{:?} has been rejected: {}", transaction, err);
+        }
+    }
+
+    /// Applies any transaction, e.g. one referring to a transaction that doesn't exist
+    #[track_caller]
+    pub fn apply(mut self, transaction: Transaction) -> Scenario {
+        self.check_pending();
+        let result = self.state.apply_transaction(&transaction);
+        self.pending = Some((transaction, result));
+        self
+    }
+
+    #[track_caller]
+    fn apply_new(
+        mut self,
+        tpe: TransactionType,
+        client: u16,
+        amount: Amount,
+        to: Option<u16>,
+    ) -> Scenario {
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        self.clients.insert(tx, client);
+        self.apply(Transaction {
+            amount: Some(amount),
+            to,
+            ..transaction(tpe, client, tx)
+        })
+    }
+
+    #[track_caller]
+    fn apply_referring(self, tpe: TransactionType, tx: u32) -> Scenario {
+        let client = match self.clients.get(&tx) {
+            Some(&client) => client,
+            None => panic!("transaction {} hasn't been created by the scenario", tx),
+        };
+        self.apply(transaction(tpe, client, tx))
+    }
+
+    #[track_caller]
+    pub fn deposit(self, client: u16, amount: impl ToString) -> Scenario {
+        self.apply_new(TransactionType::Deposit, client, parse(amount), None)
+    }
+
+    #[track_caller]
+    pub fn withdraw(self, client: u16, amount: impl ToString) -> Scenario {
+        self.apply_new(TransactionType::Withdrawal, client, parse(amount), None)
+    }
+
+    #[track_caller]
+    pub fn transfer(self, from: u16, to: u16, amount: impl ToString) -> Scenario {
+        self.apply_new(TransactionType::Transfer, from, parse(amount), Some(to))
+    }
+
+    /// Disputes transaction `tx`, on behalf of the client who made it
+    #[track_caller]
+    pub fn dispute(self, tx: u32) -> Scenario {
+        self.apply_referring(TransactionType::Dispute, tx)
+    }
+
+    #[track_caller]
+    pub fn resolve(self, tx: u32) -> Scenario {
+        self.apply_referring(TransactionType::Resolve, tx)
+    }
+
+    #[track_caller]
+    pub fn chargeback(self, tx: u32) -> Scenario {
+        self.apply_referring(TransactionType::Chargeback, tx)
+    }
+
+    /// Expects the latest transaction to have been rejected
+    #[track_caller]
+    pub fn rejected(mut self) -> Scenario {
+        match self.pending.take() {
+            Some((_, Err(_))) => self,
+            Some((transaction, Ok(()))) => panic!("{:?} has been applied", transaction),
+            None => panic!("no transaction to check"),
+        }
+    }
+
+    /// Expects the latest transaction to have been rejected with the kind of error, e.g. `NotEnoughFunds`
+    #[track_caller]
+    pub fn rejected_with(mut self, kind: &str) -> Scenario {
+        match self.pending.take() {
+            Some((_, Err(err))) if err.kind() == kind => self,
+            Some((transaction, Err(err))) => {
+                panic!(
+                    "{:?} has been rejected with {} instead of {}",
+                    transaction,
+                    err.kind(),
+                    kind
+                )
+            }
+            Some((transaction, Ok(()))) => panic!("{:?} has been applied", transaction),
+            None => panic!("no transaction to check"),
+        }
+    }
+
+    #[track_caller]
+    fn account(&mut self, client: u16) -> &Account {
+        self.check_pending();
+        match self.state.account(client) {
+            Some(account) => account,
+            None => panic!("client {} has no account", client),
+        }
+    }
+
+    #[track_caller]
+    pub fn expect_locked(mut self, client: u16) -> Scenario {
+        assert!(
+            self.account(client).locked,
+            "client {} isn't locked",
+            client
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn expect_unlocked(mut self, client: u16) -> Scenario {
+        assert!(!self.account(client).locked, "client {} is locked", client);
+        self
+    }
+
+    /// Expects the funds available to the client in the default currency
+    #[track_caller]
+    pub fn expect_available(mut self, client: u16, amount: impl ToString) -> Scenario {
+        let amount = parse(amount);
+        let account = self.account(client);
+        let available = account
+            .balances
+            .get(&Currency::default())
+            .map_or(Amount::ZERO, |balance| balance.available);
+        assert_eq!(available, amount, "available funds of client {}", client);
+        self
+    }
+
+    /// Expects the funds of the client held for disputes in the default currency
+    #[track_caller]
+    pub fn expect_held(mut self, client: u16, amount: impl ToString) -> Scenario {
+        let amount = parse(amount);
+        let account = self.account(client);
+        let held = account
+            .balances
+            .get(&Currency::default())
+            .map_or(Amount::ZERO, |balance| balance.held);
+        assert_eq!(held, amount, "held funds of client {}", client);
+        self
+    }
+
+    /// Id of the latest deposit, withdrawal or transfer
+    pub fn last_tx(&self) -> Option<u32> {
+        self.next_tx.checked_sub(1).filter(|&tx| tx > 0)
+    }
+
+    /// Resulting state, for checks the scenario doesn't provide
+    #[track_caller]
+    pub fn state(&mut self) -> &State {
+        self.check_pending();
+        &self.state
+    }
+
+    #[track_caller]
+    pub fn into_state(mut self) -> State {
+        self.check_pending();
+        mem::take(&mut self.state)
+    }
+}
+
+impl Drop for Scenario {
+    fn drop(&mut self) {
+        // don't turn a failed expectation into an abort
+        if !thread::panicking() {
+            self.check_pending();
+        }
+    }
+}
+
+fn transaction(tpe: TransactionType, client: u16, tx: u32) -> Transaction {
+    Transaction {
+        tpe,
+        client,
+        tx,
+        amount: None,
+        to: None,
+        reason: None,
+        currency: Currency::default(),
+        timestamp: None,
+        tenant: None,
+        tag: Tag::default(),
+    }
+}
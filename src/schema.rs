@@ -0,0 +1,268 @@
+//! Validation of the input against the fields of `Transaction`
+//!
+//! Used by the strict mode of the command line tool, to reject an input with
+//! a wrong header up front and to tell which columns of a row are invalid,
+//! instead of a single serde error per row.
+//!
+//! Inputs come in two versions. Version 1 has the original columns (`type`,
+//! `client`, `tx` and `amount`, along with `to` and `tenant`), version 2 adds
+//! `timestamp`, `currency` and an optional `reason` code. Both accept an
+//! optional `tag` passed through to the reports.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::value::Error as ValueError;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::amount;
+use crate::currency::Currency;
+use crate::model::TransactionType;
+use crate::tag::Tag;
+
+/// Version of the input schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SchemaVersion {
+    V1,
+    V2,
+}
+
+impl SchemaVersion {
+    /// Detects the version from the header, version 2 if it has any of the columns it added
+    pub fn detect<'a>(header: impl IntoIterator<Item = &'a str>) -> SchemaVersion {
+        let mut header = header.into_iter();
+        if header.any(|name| {
+            COLUMNS
+                .iter()
+                .any(|column| column.name() == name && column.since() == SchemaVersion::V2)
+        }) {
+            SchemaVersion::V2
+        } else {
+            SchemaVersion::V1
+        }
+    }
+}
+
+impl FromStr for SchemaVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SchemaVersion, String> {
+        match s {
+            "v1" => Ok(SchemaVersion::V1),
+            "v2" => Ok(SchemaVersion::V2),
+            _ => Err(format!("expected v1 or v2, got {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaVersion::V1 => f.write_str("v1"),
+            SchemaVersion::V2 => f.write_str("v2"),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    #[error("missing required column {0:?}")]
+    MissingColumn(&'static str),
+
+    #[error("unknown column {0:?}")]
+    UnknownColumn(String),
+
+    #[error("duplicate column {0:?}")]
+    DuplicateColumn(String),
+
+    #[error("column {0:?} is not part of schema {1}")]
+    NotInVersion(&'static str, SchemaVersion),
+}
+
+/// Invalid value in a single column of a row
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("column {column:?}: invalid value {value:?}, {reason}")]
+pub struct FieldError {
+    pub column: &'static str,
+    pub value: String,
+    pub reason: String,
+}
+
+/// Column of the input, i.e. a field of `Transaction`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Type,
+    Client,
+    Tx,
+    Amount,
+    To,
+    Reason,
+    Currency,
+    Timestamp,
+    Tenant,
+    Tag,
+}
+
+const COLUMNS: [Column; 10] = [
+    Column::Type,
+    Column::Client,
+    Column::Tx,
+    Column::Amount,
+    Column::To,
+    Column::Reason,
+    Column::Currency,
+    Column::Timestamp,
+    Column::Tenant,
+    Column::Tag,
+];
+
+fn parse<T: FromStr>(value: &str) -> Result<(), String>
+where
+    T::Err: ToString,
+{
+    value
+        .parse::<T>()
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+impl Column {
+    fn name(self) -> &'static str {
+        match self {
+            Column::Type => "type",
+            Column::Client => "client",
+            Column::Tx => "tx",
+            Column::Amount => "amount",
+            Column::To => "to",
+            Column::Reason => "reason",
+            Column::Currency => "currency",
+            Column::Timestamp => "timestamp",
+            Column::Tenant => "tenant",
+            Column::Tag => "tag",
+        }
+    }
+
+    /// Version of the schema that added the column
+    fn since(self) -> SchemaVersion {
+        match self {
+            Column::Timestamp | Column::Currency | Column::Reason => SchemaVersion::V2,
+            _ => SchemaVersion::V1,
+        }
+    }
+
+    /// Whether the header of the version must have the column
+    fn is_required(self, version: SchemaVersion) -> bool {
+        match self {
+            Column::Type | Column::Client | Column::Tx => true,
+            Column::Timestamp | Column::Currency => version >= SchemaVersion::V2,
+            _ => false,
+        }
+    }
+
+    /// Whether rows of the version must have a value in the column
+    fn is_value_required(self, version: SchemaVersion) -> bool {
+        match self {
+            Column::Type | Column::Client | Column::Tx => true,
+            Column::Timestamp => version >= SchemaVersion::V2,
+            _ => false,
+        }
+    }
+
+    /// Checks that the value can be parsed the way the field of `Transaction` is deserialized
+    fn check(self, value: &str, version: SchemaVersion) -> Result<(), String> {
+        if value.is_empty() {
+            return if self.is_value_required(version) {
+                Err("value is required".to_string())
+            } else {
+                Ok(())
+            };
+        }
+        match self {
+            Column::Type => TransactionType::deserialize(value.into_deserializer())
+                .map(|_| ())
+                .map_err(|err: ValueError| err.to_string()),
+            Column::Client | Column::To => parse::<u16>(value),
+            Column::Tx | Column::Reason | Column::Tenant => parse::<u32>(value),
+            Column::Amount => amount::parse_amount(value)
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            Column::Currency => parse::<Currency>(value),
+            Column::Timestamp => parse::<u64>(value),
+            Column::Tag => parse::<Tag>(value),
+        }
+    }
+}
+
+/// Columns of the input, in order of the header
+#[derive(Debug, Clone)]
+pub struct Schema {
+    version: SchemaVersion,
+    /// Column at each position, `None` for allowed unknown columns, which are ignored
+    columns: Vec<Option<Column>>,
+}
+
+impl Schema {
+    /// Validates the header against the version of the schema, returning all problems found
+    ///
+    /// Required columns are `type`, `client` and `tx`, and since version 2
+    /// also `timestamp` and `currency`. Columns not matching any field of
+    /// `Transaction` are rejected unless `allow_unknown` is set, while columns
+    /// added by a later version are always rejected.
+    pub fn new<'a>(
+        header: impl IntoIterator<Item = &'a str>,
+        version: SchemaVersion,
+        allow_unknown: bool,
+    ) -> Result<Schema, Vec<SchemaError>> {
+        let mut errors = Vec::new();
+        let mut columns = Vec::new();
+        for name in header {
+            let column = COLUMNS.iter().copied().find(|column| column.name() == name);
+            match column {
+                Some(column) if columns.contains(&Some(column)) => {
+                    errors.push(SchemaError::DuplicateColumn(name.to_string()))
+                }
+                Some(column) if column.since() > version => {
+                    errors.push(SchemaError::NotInVersion(column.name(), version))
+                }
+                None if !allow_unknown => errors.push(SchemaError::UnknownColumn(name.to_string())),
+                _ => {}
+            }
+            columns.push(column);
+        }
+        for column in COLUMNS {
+            if column.is_required(version) && !columns.contains(&Some(column)) {
+                errors.push(SchemaError::MissingColumn(column.name()));
+            }
+        }
+        if errors.is_empty() {
+            Ok(Schema { version, columns })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn version(&self) -> SchemaVersion {
+        self.version
+    }
+
+    /// Checks values of the row, given in order of the header, returning errors of all invalid columns
+    pub fn check_row<'a>(&self, row: impl IntoIterator<Item = &'a str>) -> Vec<FieldError> {
+        self.columns
+            .iter()
+            .zip(row)
+            .filter_map(|(column, value)| {
+                let column = (*column)?;
+                column
+                    .check(value, self.version)
+                    .err()
+                    .map(|reason| FieldError {
+                        column: column.name(),
+                        value: value.to_string(),
+                        reason,
+                    })
+            })
+            .collect()
+    }
+}
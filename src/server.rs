@@ -0,0 +1,434 @@
+//! Serving the engine over the network
+//!
+//! States of tenants are not `Send` (storages may hold connections bound to
+//! a thread), so they are owned by a dedicated engine thread. Request handlers
+//! send jobs to it and wait for their results, which also serializes all
+//! changes of the state.
+//!
+//! On SIGINT or SIGTERM the servers stop accepting connections, finish
+//! requests in progress and close the streams of account updates. The engine
+//! thread then applies all jobs sent so far before stopping.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{error, info, Span};
+
+use crate::model::{
+    Account, CephalopodError, IntegrityError, PruneOptions, Pruned, Transaction, TransactionType,
+};
+use crate::summary::Summary;
+use crate::tag::Tag;
+use crate::tenant::{ClientExposure, Tenants};
+
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod rest;
+#[cfg(feature = "webhooks")]
+mod webhook;
+mod ws;
+
+#[cfg(feature = "graphql")]
+pub use self::graphql::{schema, EngineSchema};
+#[cfg(feature = "grpc")]
+pub use self::grpc::{proto, serve_grpc, GrpcService};
+pub use self::rest::{router, AccountView};
+#[cfg(feature = "webhooks")]
+pub use self::webhook::{deliver, Notification, Retry, Webhook};
+pub use self::ws::Subscription;
+
+/// Number of account updates kept for subscribers lagging behind
+const UPDATES_CAPACITY: usize = 1024;
+
+type Job = Box<dyn FnOnce(&mut Engine) + Send>;
+
+/// Account of a client of a tenant, as reported by the API
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAccount {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    pub account: Account,
+    /// Number of disputes not resolved nor charged back yet
+    pub open_disputes: u32,
+}
+
+/// Kind of events of accounts, e.g. notified to webhooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    AccountLocked,
+    ChargebackApplied,
+    DisputeOpened,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 3] = [
+        EventKind::AccountLocked,
+        EventKind::ChargebackApplied,
+        EventKind::DisputeOpened,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::AccountLocked => "account_locked",
+            EventKind::ChargebackApplied => "chargeback_applied",
+            EventKind::DisputeOpened => "dispute_opened",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventKind, String> {
+        EventKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "expected account_locked, chargeback_applied or dispute_opened, got {}",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Event of an account caused by an applied transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountEvent {
+    pub kind: EventKind,
+    pub transaction: Transaction,
+    /// Tag of the transaction, or of the one it refers to if it has none
+    pub tag: Tag,
+    /// Account right after the transaction was applied
+    pub account: ClientAccount,
+}
+
+/// States of all tenants, owned by the engine thread
+pub struct Engine {
+    tenants: Tenants,
+    /// Integrity error that stopped processing of transactions
+    halted: Option<IntegrityError>,
+    /// Accounts changed by applied transactions
+    updates: broadcast::Sender<ClientAccount>,
+    /// Events of accounts caused by applied transactions
+    events: broadcast::Sender<AccountEvent>,
+    /// Outcomes of submitted transactions
+    summary: Summary,
+}
+
+impl Engine {
+    pub fn new(tenants: Tenants) -> Engine {
+        Engine {
+            tenants,
+            halted: None,
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+            events: broadcast::channel(UPDATES_CAPACITY).0,
+            summary: Summary::default(),
+        }
+    }
+
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    /// Returns the account of the client of the tenant, if it exists
+    pub fn account(&self, tenant: Option<u32>, client: u16) -> Option<ClientAccount> {
+        let state = self.tenants.state(tenant)?;
+        Some(ClientAccount {
+            tenant,
+            client,
+            account: state.account(client)?.clone(),
+            open_disputes: state.open_disputes(client),
+        })
+    }
+
+    /// Returns the lifetime totals of the client of the tenant per currency, if its account exists
+    pub fn exposure(&self, tenant: Option<u32>, client: u16) -> Option<Vec<ClientExposure>> {
+        self.tenants.state(tenant)?.account(client)?;
+        Some(self.tenants.exposure(tenant, client))
+    }
+
+    /// Outcomes of transactions submitted so far, with the fees collected and the fingerprint of the state
+    pub fn summary(&self) -> Summary {
+        Summary {
+            fees_collected: self.tenants.fees_collected(),
+            fingerprint: Some(self.tenants.fingerprint()),
+            memory: Some(self.tenants.memory_usage()),
+            ..self.summary.clone()
+        }
+    }
+
+    /// Drops transactions from the history to compact memory, see `State::prune`
+    pub fn prune(&mut self, options: &PruneOptions) -> Pruned {
+        let pruned = self.tenants.prune(options);
+        info!("Pruned transactions from the history: {:?}", pruned);
+        pruned
+    }
+
+    /// Subscribes to accounts changed by transactions applied from now on
+    ///
+    /// Subscribers lagging too far behind miss the oldest updates.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientAccount> {
+        self.updates.subscribe()
+    }
+
+    /// Subscribes to events of accounts caused by transactions applied from now on
+    ///
+    /// Just like with account updates, subscribers lagging too far behind miss the oldest events.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<AccountEvent> {
+        self.events.subscribe()
+    }
+
+    /// Integrity error that stopped processing of transactions, if any
+    ///
+    /// Just like in batch mode, no more transactions are processed after an
+    /// integrity error, while the state can still be queried.
+    pub fn halted(&self) -> Option<IntegrityError> {
+        self.halted
+    }
+
+    /// Applies a transaction, optionally submitted with an idempotency key (see `State::apply_submission`)
+    ///
+    /// Must not be called once the engine has halted.
+    pub fn submit(&mut self, tx: &Transaction, key: Option<&str>) -> Result<(), CephalopodError> {
+        // locks are compared to tell accounts locked by the transaction
+        let locks = (self.events.receiver_count() > 0).then(|| self.locks(tx));
+        let result = match key {
+            Some(key) => self
+                .tenants
+                .state_mut(tx.tenant)
+                .map_err(|err| {
+                    error!(
+                        "Storage error while processing transaction {}: {}",
+                        tx.tx, err
+                    );
+                    CephalopodError::IntegrityError {
+                        transaction: *tx,
+                        error: IntegrityError::StorageFailure { tx: tx.tx },
+                    }
+                })
+                .and_then(|state| state.apply_submission(key, tx)),
+            None => self.tenants.apply_transaction(tx),
+        };
+        self.summary.record(tx, &result);
+        if let Err(CephalopodError::IntegrityError { error, .. }) = result {
+            error!(
+                "Integrity error while processing transaction {}: {}. Halting processing.",
+                tx.tx, error
+            );
+            self.halted = Some(error);
+        }
+        if result.is_ok() && self.updates.receiver_count() > 0 {
+            self.publish(tx);
+        }
+        if let (Ok(()), Some(locks)) = (&result, locks) {
+            self.emit(tx, &locks);
+        }
+        result
+    }
+
+    /// Clients whose accounts may be changed by the transaction
+    fn affected_clients(&self, tx: &Transaction) -> Vec<u16> {
+        // a dispute of a transfer may change the account of the receiving side
+        let referenced = self
+            .tenants
+            .state(tx.tenant)
+            .and_then(|state| state.transaction(tx.tx))
+            .and_then(|referenced| referenced.to);
+        let mut clients: Vec<u16> = std::iter::once(tx.client)
+            .chain(tx.to)
+            .chain(referenced)
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        clients
+    }
+
+    /// Whether the accounts which may be changed by the transaction are locked
+    fn locks(&self, tx: &Transaction) -> Vec<(u16, bool)> {
+        self.affected_clients(tx)
+            .into_iter()
+            .map(|client| {
+                let locked = self
+                    .account(tx.tenant, client)
+                    .is_some_and(|account| account.account.locked);
+                (client, locked)
+            })
+            .collect()
+    }
+
+    /// Publishes accounts that may have been changed by the transaction
+    fn publish(&self, tx: &Transaction) {
+        for client in self.affected_clients(tx) {
+            if let Some(account) = self.account(tx.tenant, client) {
+                // there may be no subscribers left
+                let _ = self.updates.send(account);
+            }
+        }
+    }
+
+    /// Sends events caused by the applied transaction, given the locks of accounts before it
+    fn emit(&self, tx: &Transaction, locks: &[(u16, bool)]) {
+        let kind = match tx.tpe {
+            TransactionType::Dispute => Some(EventKind::DisputeOpened),
+            TransactionType::Chargeback => Some(EventKind::ChargebackApplied),
+            _ => None,
+        };
+        let mut events: Vec<(EventKind, ClientAccount)> = kind
+            .and_then(|kind| Some((kind, self.account(tx.tenant, tx.client)?)))
+            .into_iter()
+            .collect();
+        for &(client, locked) in locks {
+            match self.account(tx.tenant, client) {
+                Some(account) if account.account.locked && !locked => {
+                    events.push((EventKind::AccountLocked, account))
+                }
+                _ => {}
+            }
+        }
+        let tag = self
+            .tenants
+            .state(tx.tenant)
+            .map(|state| state.tag(tx))
+            .unwrap_or(tx.tag);
+        for (kind, account) in events {
+            let _ = self.events.send(AccountEvent {
+                kind,
+                transaction: *tx,
+                tag,
+                account,
+            });
+        }
+    }
+}
+
+/// Handle for sending jobs to the engine thread
+#[derive(Clone)]
+pub struct EngineHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl EngineHandle {
+    /// Starts the engine thread with tenants created by `init`
+    ///
+    /// The thread runs until all handles are dropped, then passes the tenants
+    /// and the summary of submitted transactions to `finish` (e.g. to flush the
+    /// storage) and returns its result. Events of the engine are logged in
+    /// the span current when it is started, e.g. of the run.
+    pub fn spawn<I, F>(
+        init: I,
+        finish: F,
+    ) -> Result<(EngineHandle, JoinHandle<Result<(), String>>), String>
+    where
+        I: FnOnce() -> Result<Tenants, String> + Send + 'static,
+        F: FnOnce(&mut Tenants, &Summary) -> Result<(), String> + Send + 'static,
+    {
+        let (jobs, received) = mpsc::channel::<Job>();
+        let (started, start) = mpsc::channel();
+        let span = Span::current();
+        let thread = thread::spawn(move || {
+            let _entered = span.enter();
+            let mut engine = match init() {
+                Ok(tenants) => {
+                    let _ = started.send(Ok(()));
+                    Engine::new(tenants)
+                }
+                Err(err) => {
+                    let _ = started.send(Err(err.clone()));
+                    return Err(err);
+                }
+            };
+            for job in received {
+                job(&mut engine);
+            }
+            info!("All handles dropped, stopping the engine");
+            finish(&mut engine.tenants, &engine.summary)
+        });
+        match start.recv() {
+            Ok(Ok(())) => Ok((EngineHandle { jobs }, thread)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err("Engine thread panicked while starting".to_string()),
+        }
+    }
+
+    /// Runs `job` on the engine thread and returns its result, `None` if the thread has stopped
+    pub async fn call<T, J>(&self, job: J) -> Option<T>
+    where
+        T: Send + 'static,
+        J: FnOnce(&mut Engine) -> T + Send + 'static,
+    {
+        let (result, received) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |engine| {
+                // the caller may have given up waiting
+                let _ = result.send(job(engine));
+            }))
+            .ok()?;
+        received.await.ok()
+    }
+}
+
+/// Serves the REST API (and the GraphQL one, if enabled) on `addr` until SIGINT or SIGTERM
+pub async fn serve(addr: SocketAddr, engine: EngineHandle) -> Result<(), String> {
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|err| {
+        error!("Problem binding {}: {}", addr, err);
+        format!("Problem binding {}: {}", addr, err)
+    })?;
+    info!("Listening on {}", addr);
+    #[cfg(feature = "graphql")]
+    let app = router(engine.clone()).merge(graphql::router(engine));
+    #[cfg(not(feature = "graphql"))]
+    let app = router(engine);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .map_err(|err| {
+            error!("Problem serving requests: {}", err);
+            format!("Problem serving requests: {}", err)
+        })
+}
+
+/// Completes when the server should shut down, i.e. on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    terminated().await;
+    info!("Shutting down, finishing requests in progress");
+}
+
+/// Completes on SIGINT or SIGTERM, which may be awaited by any number of tasks
+async fn terminated() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Problem listening for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,423 @@
+//! GraphQL query API of the engine, served on `/graphql`
+//!
+//! Queries are `POST`ed as `{"query": ..., "variables": ...}`. The schema
+//! provides:
+//!
+//! - `account(tenant, client)` and `transaction(tenant, tx)` looking up a
+//!   single account or transaction, `null` if it doesn't exist.
+//! - `accounts(tenant, locked, frozen, closed, flag, currency, withOpenDisputes)`
+//!   listing accounts, ordered by client.
+//! - `transactions(tenant, client, type, state)` listing recorded transactions
+//!   (i.e. deposits, withdrawals, transfers and authorizations), ordered by id.
+//! - `disputes(tenant, client)` listing transactions with an open dispute.
+//!
+//! Lists are paginated with `first` (100 by default, at most 1000) and
+//! `after`, taking the `endCursor` of the previous page. With storage, only
+//! transactions cached in memory are listed.
+
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, Error, Object, OutputType, Result, Schema,
+    SimpleObject,
+};
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+
+use super::{ClientAccount, EngineHandle};
+use crate::currency::Currency;
+use crate::model::{self, TransactionState};
+use crate::storage::TransactionRecord;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+pub type EngineSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema(engine: EngineHandle) -> EngineSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(engine)
+        .finish()
+}
+
+pub fn router(engine: EngineHandle) -> Router {
+    Router::new()
+        .route("/graphql", post(execute))
+        .with_state(schema(engine))
+}
+
+async fn execute(
+    State(schema): State<EngineSchema>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request).await)
+}
+
+fn engine_stopped() -> Error {
+    Error::new("engine has stopped")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(remote = "crate::model::TransactionType")]
+enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Representment,
+    Unlock,
+    Transfer,
+    Fee,
+    Adjustment,
+    Reversal,
+    Authorize,
+    Capture,
+    Void,
+    Open,
+    Close,
+    Freeze,
+    Unfreeze,
+    Flag,
+    Unflag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(remote = "crate::model::TransactionState")]
+enum TransactionStatus {
+    Withdrawn,
+    Deposited,
+    Disputed,
+    Resolved,
+    Chargebacked,
+    Represented,
+    Voided,
+    Authorized,
+}
+
+#[derive(Debug, SimpleObject)]
+#[graphql(name = "Balance")]
+struct BalanceView {
+    currency: String,
+    available: String,
+    held: String,
+    /// Null if the sum of available and held funds doesn't fit in an amount
+    total: Option<String>,
+}
+
+#[derive(Debug, SimpleObject)]
+#[graphql(name = "Account")]
+struct AccountView {
+    tenant: Option<u32>,
+    client: u16,
+    balances: Vec<BalanceView>,
+    locked: bool,
+    closed: bool,
+    frozen: bool,
+    flags: Vec<u32>,
+    /// Number of disputes not resolved nor charged back yet
+    open_disputes: u32,
+    /// Timestamp of the latest deposit, withdrawal, transfer, authorization or capture
+    last_activity: Option<u64>,
+}
+
+impl From<ClientAccount> for AccountView {
+    fn from(account: ClientAccount) -> AccountView {
+        AccountView {
+            tenant: account.tenant,
+            client: account.client,
+            balances: account
+                .account
+                .balances
+                .iter()
+                .map(|(currency, balance)| BalanceView {
+                    currency: currency.to_string(),
+                    available: balance.available.to_string(),
+                    held: balance.held.to_string(),
+                    total: balance.total().map(|total| total.to_string()),
+                })
+                .collect(),
+            locked: account.account.locked,
+            closed: account.account.closed,
+            frozen: account.account.frozen,
+            flags: account.account.flags.into_iter().collect(),
+            open_disputes: account.open_disputes,
+            last_activity: account.account.last_activity,
+        }
+    }
+}
+
+#[derive(Debug, SimpleObject)]
+#[graphql(name = "Transaction")]
+struct TransactionView {
+    #[graphql(name = "type")]
+    tpe: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
+    /// Receiving client of a transfer
+    to: Option<u16>,
+    reason: Option<u32>,
+    currency: String,
+    timestamp: Option<u64>,
+    tenant: Option<u32>,
+    state: Option<TransactionStatus>,
+    /// State of the receiving side of a transfer
+    transfer_state: Option<TransactionStatus>,
+    /// Number of times the transaction has been disputed
+    dispute_count: u32,
+}
+
+impl TransactionView {
+    fn from_record(record: TransactionRecord) -> Option<TransactionView> {
+        let transaction = record.transaction?;
+        Some(TransactionView {
+            tpe: transaction.tpe.into(),
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount.map(|amount| amount.to_string()),
+            to: transaction.to,
+            reason: transaction.reason,
+            currency: transaction.currency.to_string(),
+            timestamp: transaction.timestamp,
+            tenant: transaction.tenant,
+            state: record.state.map(Into::into),
+            transfer_state: record.transfer_state.map(Into::into),
+            dispute_count: record.dispute_count.unwrap_or_default(),
+        })
+    }
+}
+
+/// Single page of a list
+#[derive(Debug, SimpleObject)]
+#[graphql(concrete(name = "AccountPage", params(AccountView)))]
+#[graphql(concrete(name = "TransactionPage", params(TransactionView)))]
+struct Page<T: OutputType> {
+    /// Number of items matching the filters, on all pages
+    total_count: usize,
+    nodes: Vec<T>,
+    /// Id of the last item on the page, to be passed as `after` for the next one
+    end_cursor: Option<u32>,
+    has_next_page: bool,
+}
+
+/// Takes the page of `ids` (sorted) following `after` and maps it with `view`
+fn paginate<T: OutputType>(
+    ids: Vec<u32>,
+    after: Option<u32>,
+    first: Option<usize>,
+    view: impl Fn(u32) -> Result<T>,
+) -> Result<Page<T>> {
+    let total_count = ids.len();
+    let start = after.map_or(0, |after| ids.partition_point(|&id| id <= after));
+    let end = ids
+        .len()
+        .min(start + first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE));
+    let page = &ids[start..end];
+    Ok(Page {
+        total_count,
+        nodes: page.iter().map(|&id| view(id)).collect::<Result<_>>()?,
+        end_cursor: page.last().copied(),
+        has_next_page: end < ids.len(),
+    })
+}
+
+fn transaction_view(state: &model::State, tx: u32) -> Result<Option<TransactionView>> {
+    let record = state
+        .transaction_record(tx)
+        .map_err(|err| Error::new(err.to_string()))?;
+    Ok(TransactionView::from_record(record))
+}
+
+/// Lists transactions with given ids, filtered by the fields of their records
+fn transaction_page(
+    state: &model::State,
+    ids: Vec<u32>,
+    status: Option<TransactionState>,
+    after: Option<u32>,
+    first: Option<usize>,
+) -> Result<Page<TransactionView>> {
+    let mut ids = match status {
+        Some(status) => ids
+            .into_iter()
+            .filter_map(|tx| match state.transaction_record(tx) {
+                Ok(record)
+                    if record.state == Some(status) || record.transfer_state == Some(status) =>
+                {
+                    Some(Ok(tx))
+                }
+                Ok(_) => None,
+                Err(err) => Some(Err(Error::new(err.to_string()))),
+            })
+            .collect::<Result<Vec<u32>>>()?,
+        None => ids,
+    };
+    ids.sort_unstable();
+    paginate(ids, after, first, |tx| {
+        transaction_view(state, tx)?
+            .ok_or_else(|| Error::new(format!("transaction {} has disappeared", tx)))
+    })
+}
+
+fn empty_page<T: OutputType>() -> Page<T> {
+    Page {
+        total_count: 0,
+        nodes: Vec::new(),
+        end_cursor: None,
+        has_next_page: false,
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Account of the client, `null` if it doesn't exist
+    async fn account(
+        &self,
+        ctx: &Context<'_>,
+        tenant: Option<u32>,
+        client: u16,
+    ) -> Result<Option<AccountView>> {
+        let account = ctx
+            .data_unchecked::<EngineHandle>()
+            .call(move |engine| engine.account(tenant, client))
+            .await
+            .ok_or_else(engine_stopped)?;
+        Ok(account.map(Into::into))
+    }
+
+    /// Accounts matching all the given filters, ordered by client
+    #[allow(clippy::too_many_arguments)]
+    async fn accounts(
+        &self,
+        ctx: &Context<'_>,
+        tenant: Option<u32>,
+        locked: Option<bool>,
+        frozen: Option<bool>,
+        closed: Option<bool>,
+        #[graphql(desc = "Risk or KYC flag set on the account")] flag: Option<u32>,
+        #[graphql(desc = "Currency the account holds funds in")] currency: Option<String>,
+        with_open_disputes: Option<bool>,
+        #[graphql(desc = "Client after which the page starts")] after: Option<u32>,
+        first: Option<usize>,
+    ) -> Result<Page<AccountView>> {
+        let currency = currency
+            .map(|currency| currency.parse::<Currency>())
+            .transpose()
+            .map_err(|err| Error::new(format!("invalid currency: {}", err)))?;
+        ctx.data_unchecked::<EngineHandle>()
+            .call(move |engine| {
+                let state = match engine.tenants().state(tenant) {
+                    Some(state) => state,
+                    None => return Ok(empty_page()),
+                };
+                let mut clients: Vec<u32> = state
+                    .iter_clients()
+                    .filter(|(&client, account)| {
+                        locked.is_none_or(|locked| account.locked == locked)
+                            && frozen.is_none_or(|frozen| account.frozen == frozen)
+                            && closed.is_none_or(|closed| account.closed == closed)
+                            && flag.is_none_or(|flag| account.flags.contains(&flag))
+                            && currency
+                                .is_none_or(|currency| account.balances.contains_key(&currency))
+                            && with_open_disputes
+                                .is_none_or(|with| (state.open_disputes(client) > 0) == with)
+                    })
+                    .map(|(&client, _)| u32::from(client))
+                    .collect();
+                clients.sort_unstable();
+                paginate(clients, after, first, |client| {
+                    // ids have been converted from the clients
+                    let client = client as u16;
+                    engine
+                        .account(tenant, client)
+                        .map(Into::into)
+                        .ok_or_else(|| Error::new(format!("account {} has disappeared", client)))
+                })
+            })
+            .await
+            .ok_or_else(engine_stopped)?
+    }
+
+    /// Recorded transaction with its dispute state, `null` if it doesn't exist
+    async fn transaction(
+        &self,
+        ctx: &Context<'_>,
+        tenant: Option<u32>,
+        tx: u32,
+    ) -> Result<Option<TransactionView>> {
+        ctx.data_unchecked::<EngineHandle>()
+            .call(move |engine| match engine.tenants().state(tenant) {
+                Some(state) => transaction_view(state, tx),
+                None => Ok(None),
+            })
+            .await
+            .ok_or_else(engine_stopped)?
+    }
+
+    /// Recorded transactions matching all the given filters, ordered by id
+    #[allow(clippy::too_many_arguments)]
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        tenant: Option<u32>,
+        #[graphql(desc = "Client sending or receiving the transaction")] client: Option<u16>,
+        #[graphql(name = "type")] tpe: Option<TransactionType>,
+        state: Option<TransactionStatus>,
+        #[graphql(desc = "Transaction after which the page starts")] after: Option<u32>,
+        first: Option<usize>,
+    ) -> Result<Page<TransactionView>> {
+        let tpe = tpe.map(model::TransactionType::from);
+        let status = state.map(TransactionState::from);
+        ctx.data_unchecked::<EngineHandle>()
+            .call(move |engine| {
+                let state = match engine.tenants().state(tenant) {
+                    Some(state) => state,
+                    None => return Ok(empty_page()),
+                };
+                let ids = state
+                    .transactions()
+                    .filter(|transaction| {
+                        client.is_none_or(|client| {
+                            transaction.client == client || transaction.to == Some(client)
+                        }) && tpe.is_none_or(|tpe| transaction.tpe == tpe)
+                    })
+                    .map(|transaction| transaction.tx)
+                    .collect();
+                transaction_page(state, ids, status, after, first)
+            })
+            .await
+            .ok_or_else(engine_stopped)?
+    }
+
+    /// Transactions with an open dispute, ordered by id
+    async fn disputes(
+        &self,
+        ctx: &Context<'_>,
+        tenant: Option<u32>,
+        #[graphql(desc = "Client sending or receiving the transaction")] client: Option<u16>,
+        #[graphql(desc = "Transaction after which the page starts")] after: Option<u32>,
+        first: Option<usize>,
+    ) -> Result<Page<TransactionView>> {
+        ctx.data_unchecked::<EngineHandle>()
+            .call(move |engine| {
+                let state = match engine.tenants().state(tenant) {
+                    Some(state) => state,
+                    None => return Ok(empty_page()),
+                };
+                let ids = state
+                    .disputed_transactions()
+                    .into_iter()
+                    .filter(|transaction| {
+                        client.is_none_or(|client| {
+                            transaction.client == client || transaction.to == Some(client)
+                        })
+                    })
+                    .map(|transaction| transaction.tx)
+                    .collect();
+                transaction_page(state, ids, None, after, first)
+            })
+            .await
+            .ok_or_else(engine_stopped)?
+    }
+}
@@ -0,0 +1,201 @@
+//! gRPC service of the engine, defined in `proto/cephalopod.proto`
+
+// tonic's `Status` is large, but it's what the handlers have to return anyway
+#![allow(clippy::result_large_err)]
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use serde::de::value::Error as ValueError;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+use super::{ClientAccount, EngineHandle};
+use crate::amount;
+use crate::model::{CephalopodError, Transaction, TransactionType};
+
+pub mod proto {
+    tonic::include_proto!("cephalopod");
+}
+
+use self::proto::engine_server::{Engine, EngineServer};
+
+fn engine_stopped() -> Status {
+    Status::unavailable("engine has stopped")
+}
+
+fn client_id(client: u32) -> Result<u16, Status> {
+    u16::try_from(client)
+        .map_err(|_| Status::invalid_argument(format!("invalid client {}", client)))
+}
+
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = Status;
+
+    fn try_from(tx: proto::Transaction) -> Result<Transaction, Status> {
+        let tpe = TransactionType::deserialize(tx.r#type.as_str().into_deserializer())
+            .map_err(|err: ValueError| Status::invalid_argument(err.to_string()))?;
+        Ok(Transaction {
+            tpe,
+            client: client_id(tx.client)?,
+            tx: tx.tx,
+            amount: tx
+                .amount
+                .map(|amount| amount::parse_amount(&amount))
+                .transpose()
+                .map_err(|err| Status::invalid_argument(format!("invalid amount: {}", err)))?,
+            to: tx.to.map(client_id).transpose()?,
+            reason: tx.reason,
+            currency: tx
+                .currency
+                .parse()
+                .map_err(|err| Status::invalid_argument(format!("invalid currency: {}", err)))?,
+            timestamp: tx.timestamp,
+            tenant: tx.tenant,
+            tag: tx
+                .tag
+                .parse()
+                .map_err(|err| Status::invalid_argument(format!("invalid tag: {}", err)))?,
+        })
+    }
+}
+
+impl From<ClientAccount> for proto::Account {
+    fn from(account: ClientAccount) -> proto::Account {
+        proto::Account {
+            tenant: account.tenant,
+            client: u32::from(account.client),
+            balances: account
+                .account
+                .balances
+                .iter()
+                .map(|(currency, balance)| proto::Balance {
+                    currency: currency.to_string(),
+                    available: balance.available.to_string(),
+                    held: balance.held.to_string(),
+                    total: balance.total().map(|total| total.to_string()),
+                })
+                .collect(),
+            locked: account.account.locked,
+            closed: account.account.closed,
+            frozen: account.account.frozen,
+            flags: account.account.flags.iter().copied().collect(),
+            open_disputes: account.open_disputes,
+            last_activity: account.account.last_activity,
+        }
+    }
+}
+
+/// Implementation of the gRPC service, applying transactions through the engine thread
+#[derive(Clone)]
+pub struct GrpcService {
+    engine: EngineHandle,
+}
+
+impl GrpcService {
+    pub fn new(engine: EngineHandle) -> GrpcService {
+        GrpcService { engine }
+    }
+}
+
+type AccountStream = Pin<Box<dyn Stream<Item = Result<proto::Account, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Engine for GrpcService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::SubmitTransactionRequest>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let request = request.into_inner();
+        let tx = Transaction::try_from(
+            request
+                .transaction
+                .ok_or_else(|| Status::invalid_argument("transaction not provided"))?,
+        )?;
+        let key = request.idempotency_key;
+        let result = self
+            .engine
+            .call(move |engine| match engine.halted() {
+                Some(error) => Err(Status::unavailable(format!("processing halted: {}", error))),
+                None => Ok(engine.submit(&tx, key.as_deref())),
+            })
+            .await
+            .ok_or_else(engine_stopped)??;
+        match result {
+            Ok(()) => Ok(Response::new(proto::SubmitTransactionResponse {
+                tx: tx.tx,
+            })),
+            Err(CephalopodError::TransactionError { error, .. }) => {
+                Err(Status::failed_precondition(error.to_string()))
+            }
+            Err(CephalopodError::IntegrityError { error, .. }) => {
+                Err(Status::internal(error.to_string()))
+            }
+        }
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let request = request.into_inner();
+        let (tenant, client) = (request.tenant, client_id(request.client)?);
+        self.engine
+            .call(move |engine| engine.account(tenant, client))
+            .await
+            .ok_or_else(engine_stopped)?
+            .map(|account| Response::new(account.into()))
+            .ok_or_else(|| Status::not_found(format!("unknown account: {}", client)))
+    }
+
+    type StreamAccountUpdatesStream = AccountStream;
+
+    async fn stream_account_updates(
+        &self,
+        request: Request<proto::StreamAccountUpdatesRequest>,
+    ) -> Result<Response<AccountStream>, Status> {
+        let request = request.into_inner();
+        let tenant = request.tenant;
+        let client = request.client.map(client_id).transpose()?;
+        let updates = self
+            .engine
+            .call(|engine| engine.subscribe())
+            .await
+            .ok_or_else(engine_stopped)?;
+        // open streams would keep the server from shutting down
+        let updates =
+            futures_util::StreamExt::take_until(BroadcastStream::new(updates), super::terminated());
+        let stream = updates.filter_map(move |update| match update {
+            Ok(account)
+                if account.tenant == tenant
+                    && client.is_none_or(|client| client == account.client) =>
+            {
+                Some(Ok(account.into()))
+            }
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Account updates stream is lagging behind: {}", err);
+                None
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serves the gRPC service on `addr` until SIGINT or SIGTERM
+pub async fn serve_grpc(addr: SocketAddr, engine: EngineHandle) -> Result<(), String> {
+    info!("Serving gRPC on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(EngineServer::new(GrpcService::new(engine)))
+        .serve_with_shutdown(addr, super::shutdown_signal())
+        .await
+        .map_err(|err| {
+            error!("Problem serving gRPC requests: {}", err);
+            format!("Problem serving gRPC requests: {}", err)
+        })
+}
@@ -0,0 +1,310 @@
+//! REST API of the engine
+//!
+//! Bodies are JSON, amounts are given and returned as strings (e.g. `"1.50"`)
+//! to keep them exact. Endpoints accepting a `tenant` query parameter use the
+//! default tenant if it's missing.
+//!
+//! - `POST /transactions` applies a transaction, given with the same fields
+//!   as a row of the input (`type`, `client`, `tx`, `amount`, ...). An
+//!   optional `Idempotency-Key` header makes retries safe. Responds with
+//!   `200` if applied, `422` if rejected and `503` once processing has halted
+//!   on an integrity error.
+//! - `GET /accounts/{client}?tenant=N` returns balances, lock, flags and
+//!   number of open disputes of the account, `404` if it doesn't exist.
+//! - `GET /accounts/{client}/exposure?tenant=N` returns lifetime deposited,
+//!   disputed and charged back amounts of the client per currency, with the
+//!   ratio of disputed to deposited amounts, `404` if the account doesn't exist.
+//! - `GET /transactions/{tx}?tenant=N` returns the recorded transaction with
+//!   its dispute state, `404` if it doesn't exist.
+//! - `GET /disputes?tenant=N` lists transactions with an open dispute.
+//! - `GET /summary` returns counters of submitted transactions, including
+//!   errors by kind and type of the transaction.
+//! - `POST /prune` drops transactions that won't be referenced anymore from
+//!   the history, given `{"finalized": true, "older_than": SECONDS}`, and
+//!   returns how many were dropped, see `State::prune`.
+//! - `GET /transitions` returns the outcome of disputes, resolves,
+//!   chargebacks, representments and reversals of transactions in each
+//!   state under the policy in effect, see `Policy::transition_matrix`.
+//! - `GET /health` responds with `200` as long as the server is running.
+//! - `GET /ready` responds with `200` if transactions are being accepted,
+//!   `503` otherwise.
+//! - `GET /updates?tenant=N&clients=1,2` pushes account updates over a
+//!   WebSocket, see `ws`.
+//!
+//! Errors are returned as `{"error": "message"}`.
+
+use std::collections::BTreeSet;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+
+use super::{ws, ClientAccount, EngineHandle};
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{CephalopodError, PruneOptions, Pruned, Transaction};
+use crate::policy::TransitionRule;
+use crate::storage::TransactionRecord;
+use crate::summary::Summary;
+use crate::tenant::ClientExposure;
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+pub fn router(engine: EngineHandle) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transaction))
+        .route("/transactions/:tx", get(transaction))
+        .route("/accounts/:client", get(account))
+        .route("/accounts/:client/exposure", get(exposure))
+        .route("/disputes", get(disputes))
+        .route("/summary", get(summary))
+        .route("/prune", post(prune))
+        .route("/transitions", get(transitions))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/updates", get(ws::updates))
+        .with_state(engine)
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Error response with a JSON body
+pub(super) struct ApiError(pub(super) StatusCode, pub(super) String);
+
+impl ApiError {
+    pub(super) fn engine_stopped() -> ApiError {
+        ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "engine has stopped".to_string(),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let ApiError(status, error) = self;
+        (status, Json(ErrorBody { error })).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TenantQuery {
+    tenant: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Applied {
+    tx: u32,
+}
+
+async fn submit_transaction(
+    State(engine): State<EngineHandle>,
+    headers: HeaderMap,
+    Json(tx): Json<Transaction>,
+) -> Result<Json<Applied>, ApiError> {
+    let key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(key) => Some(
+            key.to_str()
+                .map_err(|_| {
+                    ApiError(
+                        StatusCode::BAD_REQUEST,
+                        "invalid idempotency key".to_string(),
+                    )
+                })?
+                .to_string(),
+        ),
+        None => None,
+    };
+    let result = engine
+        .call(move |engine| match engine.halted() {
+            Some(error) => Err(ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("processing halted: {}", error),
+            )),
+            None => Ok(engine.submit(&tx, key.as_deref())),
+        })
+        .await
+        .ok_or_else(ApiError::engine_stopped)??;
+    match result {
+        Ok(()) => Ok(Json(Applied { tx: tx.tx })),
+        Err(CephalopodError::TransactionError { error, .. }) => Err(ApiError(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            error.to_string(),
+        )),
+        Err(CephalopodError::IntegrityError { error, .. }) => Err(ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            error.to_string(),
+        )),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BalanceView {
+    currency: Currency,
+    available: Amount,
+    held: Amount,
+    /// `null` if the sum of available and held funds doesn't fit in an amount
+    total: Option<Amount>,
+}
+
+/// Account as returned by the API
+#[derive(Debug, Serialize)]
+pub struct AccountView {
+    tenant: Option<u32>,
+    client: u16,
+    balances: Vec<BalanceView>,
+    locked: bool,
+    closed: bool,
+    frozen: bool,
+    flags: BTreeSet<u32>,
+    open_disputes: u32,
+    last_activity: Option<u64>,
+}
+
+impl From<ClientAccount> for AccountView {
+    fn from(account: ClientAccount) -> AccountView {
+        AccountView {
+            tenant: account.tenant,
+            client: account.client,
+            balances: account
+                .account
+                .balances
+                .iter()
+                .map(|(&currency, balance)| BalanceView {
+                    currency,
+                    available: balance.available,
+                    held: balance.held,
+                    total: balance.total(),
+                })
+                .collect(),
+            locked: account.account.locked,
+            closed: account.account.closed,
+            frozen: account.account.frozen,
+            flags: account.account.flags,
+            open_disputes: account.open_disputes,
+            last_activity: account.account.last_activity,
+        }
+    }
+}
+
+async fn account(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<u16>,
+    Query(TenantQuery { tenant }): Query<TenantQuery>,
+) -> Result<Json<AccountView>, ApiError> {
+    engine
+        .call(move |engine| engine.account(tenant, client))
+        .await
+        .ok_or_else(ApiError::engine_stopped)?
+        .map(|account| Json(account.into()))
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("unknown account: {}", client),
+            )
+        })
+}
+
+async fn exposure(
+    State(engine): State<EngineHandle>,
+    Path(client): Path<u16>,
+    Query(TenantQuery { tenant }): Query<TenantQuery>,
+) -> Result<Json<Vec<ClientExposure>>, ApiError> {
+    engine
+        .call(move |engine| engine.exposure(tenant, client))
+        .await
+        .ok_or_else(ApiError::engine_stopped)?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("unknown account: {}", client),
+            )
+        })
+}
+
+async fn transaction(
+    State(engine): State<EngineHandle>,
+    Path(tx): Path<u32>,
+    Query(TenantQuery { tenant }): Query<TenantQuery>,
+) -> Result<Json<TransactionRecord>, ApiError> {
+    let record = engine
+        .call(move |engine| match engine.tenants().state(tenant) {
+            Some(state) => state.transaction_record(tx).map_err(|err| err.to_string()),
+            None => Ok(TransactionRecord::default()),
+        })
+        .await
+        .ok_or_else(ApiError::engine_stopped)?
+        .map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    match record.transaction {
+        Some(_) => Ok(Json(record)),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("unknown transaction: {}", tx),
+        )),
+    }
+}
+
+async fn disputes(
+    State(engine): State<EngineHandle>,
+    Query(TenantQuery { tenant }): Query<TenantQuery>,
+) -> Result<Json<Vec<Transaction>>, ApiError> {
+    engine
+        .call(move |engine| match engine.tenants().state(tenant) {
+            Some(state) => state.disputed_transactions().into_iter().copied().collect(),
+            None => Vec::new(),
+        })
+        .await
+        .map(Json)
+        .ok_or_else(ApiError::engine_stopped)
+}
+
+async fn summary(State(engine): State<EngineHandle>) -> Result<Json<Summary>, ApiError> {
+    engine
+        .call(|engine| engine.summary())
+        .await
+        .map(Json)
+        .ok_or_else(ApiError::engine_stopped)
+}
+
+async fn prune(
+    State(engine): State<EngineHandle>,
+    Json(options): Json<PruneOptions>,
+) -> Result<Json<Pruned>, ApiError> {
+    engine
+        .call(move |engine| engine.prune(&options))
+        .await
+        .map(Json)
+        .ok_or_else(ApiError::engine_stopped)
+}
+
+async fn transitions(
+    State(engine): State<EngineHandle>,
+) -> Result<Json<Vec<TransitionRule>>, ApiError> {
+    engine
+        .call(|engine| engine.tenants().policy().transition_matrix())
+        .await
+        .map(Json)
+        .ok_or_else(ApiError::engine_stopped)
+}
+
+async fn health() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn ready(State(engine): State<EngineHandle>) -> Result<StatusCode, ApiError> {
+    match engine.call(|engine| engine.halted()).await {
+        Some(None) => Ok(StatusCode::OK),
+        Some(Some(error)) => Err(ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("processing halted: {}", error),
+        )),
+        None => Err(ApiError::engine_stopped()),
+    }
+}
@@ -0,0 +1,228 @@
+//! Webhooks notified of account events, e.g. `--webhook account_locked=http://risk/hooks`
+//!
+//! Every webhook gets the events it selected POSTed as JSON (see
+//! `Notification`), one request per event in the order they happened.
+//! Deliveries failing with a connection error, a timeout or a 408, 429 or
+//! 5xx response are retried with exponential backoff; an event is given up
+//! on with an error logged once all attempts have failed, and the following
+//! ones are delivered. Only plain HTTP is supported, HTTPS endpoints have to
+//! be reached through a proxy.
+//!
+//! Events are delivered until the engine stops, including those caused by
+//! transactions applied while shutting down.
+
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, StatusCode, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, warn};
+
+use super::rest::AccountView;
+use super::{AccountEvent, EventKind};
+use crate::model::TransactionType;
+use crate::tag::Tag;
+
+/// Time to wait for a response before retrying
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait between attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Endpoint notified of the selected events
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: Uri,
+    pub events: BTreeSet<EventKind>,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    /// Parses `[EVENTS=]URL`, where EVENTS are comma-separated kinds of events, all if not given
+    fn from_str(s: &str) -> Result<Webhook, String> {
+        let selected = s.split_once('=').and_then(|(events, url)| {
+            let events = events
+                .split(',')
+                .map(|event| event.trim().parse())
+                .collect::<Result<BTreeSet<EventKind>, _>>()
+                .ok()?;
+            Some((events, url))
+        });
+        let (events, url) =
+            selected.unwrap_or_else(|| (EventKind::ALL.iter().copied().collect(), s));
+        let url: Uri = url
+            .parse()
+            .map_err(|err| format!("invalid URL {}: {}", url, err))?;
+        if url.scheme_str() != Some("http") {
+            return Err(format!("expected an http:// URL, got {}", url));
+        }
+        Ok(Webhook { url, events })
+    }
+}
+
+/// Attempts at delivering each event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Number of attempts, including the first one
+    pub attempts: u32,
+    /// Wait before the second attempt, doubled for every following one up to 5 minutes
+    pub backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Retry {
+    /// Wait after the failed attempt, counted from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+}
+
+/// JSON payload POSTed to webhooks
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    /// Identifies the event across retries, e.g. `chargeback_applied:1:27`
+    pub id: String,
+    pub event: EventKind,
+    pub tenant: Option<u32>,
+    pub client: u16,
+    /// Transaction causing the event, e.g. the chargeback locking the account
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tpe: TransactionType,
+    pub tag: Tag,
+    /// Account right after the transaction, as returned by `GET /accounts/{client}`
+    pub account: AccountView,
+}
+
+impl From<AccountEvent> for Notification {
+    fn from(event: AccountEvent) -> Notification {
+        let tenant = event.account.tenant;
+        let client = event.account.client;
+        let id = match tenant {
+            Some(tenant) => format!(
+                "{}:{}:{}:{}",
+                event.kind, tenant, client, event.transaction.tx
+            ),
+            None => format!("{}:{}:{}", event.kind, client, event.transaction.tx),
+        };
+        Notification {
+            id,
+            event: event.kind,
+            tenant,
+            client,
+            tx: event.transaction.tx,
+            tpe: event.transaction.tpe,
+            tag: event.tag,
+            account: AccountView::from(event.account),
+        }
+    }
+}
+
+/// Outcome of a single attempt at delivering a notification
+enum Attempt {
+    Delivered,
+    Failed(String),
+    /// Rejected by the endpoint in a way that retrying won't change, e.g. with 400
+    Rejected(StatusCode),
+}
+
+/// Delivers the selected events received until the engine stops
+pub async fn deliver(
+    webhook: Webhook,
+    retry: Retry,
+    mut events: broadcast::Receiver<AccountEvent>,
+) {
+    let client = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                error!(
+                    "Webhook {} is lagging behind, missed {} events",
+                    webhook.url, missed
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if !webhook.events.contains(&event.kind) {
+            continue;
+        }
+        let notification = Notification::from(event);
+        let body = Bytes::from(
+            serde_json::to_vec(&notification).expect("notification should be serializable to JSON"),
+        );
+        for attempt in 1..=retry.attempts {
+            match post(&client, &webhook.url, body.clone()).await {
+                Attempt::Delivered => break,
+                Attempt::Rejected(status) => {
+                    error!(
+                        "Webhook {} rejected event {} with {}, giving up",
+                        webhook.url, notification.id, status
+                    );
+                    break;
+                }
+                Attempt::Failed(err) if attempt == retry.attempts => {
+                    error!(
+                        "Problem notifying webhook {} of event {}: {}, giving up after {} attempts",
+                        webhook.url, notification.id, err, attempt
+                    );
+                }
+                Attempt::Failed(err) => {
+                    let backoff = retry.backoff(attempt);
+                    warn!(
+                        "Problem notifying webhook {} of event {}: {}, retrying in {:?}",
+                        webhook.url, notification.id, err, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+            }
+        }
+    }
+}
+
+async fn post(client: &Client<HttpConnector, Full<Bytes>>, url: &Uri, body: Bytes) -> Attempt {
+    let mut request = Request::new(Full::new(body));
+    *request.method_mut() = Method::POST;
+    *request.uri_mut() = url.clone();
+    request
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    match tokio::time::timeout(TIMEOUT, client.request(request)).await {
+        Err(_) => Attempt::Failed(format!("no response within {:?}", TIMEOUT)),
+        Ok(Err(err)) => Attempt::Failed(err.to_string()),
+        Ok(Ok(response)) => {
+            let status = response.status();
+            if status.is_success() {
+                Attempt::Delivered
+            } else if status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+            {
+                Attempt::Failed(format!("response {}", status))
+            } else {
+                Attempt::Rejected(status)
+            }
+        }
+    }
+}
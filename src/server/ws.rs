@@ -0,0 +1,130 @@
+//! WebSocket push of account updates
+//!
+//! `GET /updates?tenant=N&clients=1,2,3` upgrades the connection to a
+//! WebSocket, which then receives an account (in the same JSON format as
+//! `GET /accounts/{client}`) whenever balances or lock of one of the listed
+//! clients change. Without `clients`, updates of all clients of the tenant
+//! are pushed. Messages sent by the client are ignored. The socket is closed
+//! when the server shuts down.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use super::rest::{AccountView, ApiError};
+use super::{ClientAccount, EngineHandle};
+use crate::currency::Currency;
+use crate::model::Balance;
+
+/// Filter of account updates pushed to a single WebSocket client
+pub struct Subscription {
+    tenant: Option<u32>,
+    /// Clients to push updates of, all if `None`
+    clients: Option<BTreeSet<u16>>,
+    /// Balances and lock pushed last for each client
+    pushed: HashMap<u16, (BTreeMap<Currency, Balance>, bool)>,
+}
+
+impl Subscription {
+    pub fn new(tenant: Option<u32>, clients: Option<BTreeSet<u16>>) -> Subscription {
+        Subscription {
+            tenant,
+            clients,
+            pushed: HashMap::new(),
+        }
+    }
+
+    /// Returns the update if it should be pushed, i.e. it's of a subscribed
+    /// client and its balances or lock have changed since the last push
+    pub fn accept(&mut self, update: ClientAccount) -> Option<ClientAccount> {
+        let subscribed = update.tenant == self.tenant
+            && self
+                .clients
+                .as_ref()
+                .is_none_or(|clients| clients.contains(&update.client));
+        if !subscribed {
+            return None;
+        }
+        let pushed = (update.account.balances.clone(), update.account.locked);
+        if self.pushed.get(&update.client) == Some(&pushed) {
+            return None;
+        }
+        self.pushed.insert(update.client, pushed);
+        Some(update)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UpdatesQuery {
+    tenant: Option<u32>,
+    /// Comma-separated ids of clients
+    clients: Option<String>,
+}
+
+pub(super) async fn updates(
+    State(engine): State<EngineHandle>,
+    Query(query): Query<UpdatesQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let clients = query
+        .clients
+        .map(|clients| {
+            clients
+                .split(',')
+                .map(|client| client.trim().parse::<u16>())
+                .collect::<Result<BTreeSet<u16>, _>>()
+        })
+        .transpose()
+        .map_err(|err| ApiError(StatusCode::BAD_REQUEST, format!("invalid clients: {}", err)))?;
+    let updates = engine
+        .call(|engine| engine.subscribe())
+        .await
+        .ok_or_else(ApiError::engine_stopped)?;
+    let subscription = Subscription::new(query.tenant, clients);
+    Ok(upgrade.on_upgrade(move |socket| push_updates(socket, updates, subscription)))
+}
+
+/// Pushes accepted updates until the socket or the engine is closed, or the server shuts down
+async fn push_updates(
+    mut socket: WebSocket,
+    mut updates: broadcast::Receiver<ClientAccount>,
+    mut subscription: Subscription,
+) {
+    let terminated = super::terminated();
+    tokio::pin!(terminated);
+    loop {
+        tokio::select! {
+            _ = &mut terminated => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    let account = match subscription.accept(update) {
+                        Some(account) => AccountView::from(account),
+                        None => continue,
+                    };
+                    let event = serde_json::to_string(&account)
+                        .expect("account should be serializable to JSON");
+                    if socket.send(Message::Text(event)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("WebSocket client is lagging behind, missed {} updates", missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Daily netting of the funds moving in and out of client accounts
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{State, Transaction, TransactionType, SECONDS_PER_DAY};
+
+/// Net movements which don't fit in `Amount`
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum SettlementError {
+    #[error("net movements overflowed while recording transaction {tx}")]
+    TotalOverflow { tx: u32 },
+}
+
+/// Settlement instruction for a single client, day and currency
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Instruction {
+    pub tenant: Option<u32>,
+    /// Day in `YYYY-MM-DD` format (UTC)
+    pub date: String,
+    pub client: u16,
+    pub currency: Currency,
+    /// Net amount to be collected from the client
+    pub pay_in: Amount,
+    /// Net amount to be paid out to the client
+    pub pay_out: Amount,
+}
+
+/// Converts number of days since the Unix epoch to a `YYYY-MM-DD` date
+///
+/// Uses the civil-from-days algorithm of Howard Hinnant.
+fn civil_date(days: u64) -> String {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Net movements of funds between clients and the outside world, per day
+///
+/// Only deposits, withdrawals, captures and the reversals, chargebacks and
+/// representments of those move funds in or out, transfers, fees and
+/// adjustments are internal. Transactions without a timestamp can't be
+/// assigned to a day and are ignored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Settlement {
+    /// Mapping from tenant, day, client and currency to the net amount paid in
+    totals: BTreeMap<(Option<u32>, u64, u16, Currency), Amount>,
+    /// First transaction whose movement didn't fit in the totals, which are incomplete since
+    #[serde(default)]
+    overflow: Option<u32>,
+}
+
+impl Settlement {
+    pub fn new() -> Settlement {
+        Settlement::default()
+    }
+
+    /// Records a transaction successfully applied to the state
+    pub fn record(&mut self, state: &State, tx: &Transaction) {
+        let timestamp = match tx.timestamp {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+        let referenced = || {
+            state
+                .transaction(tx.tx)
+                .filter(|referenced| referenced.client == tx.client)
+        };
+        // funds paid into the account of the client, negative for pay-outs
+        let movement = match tx.tpe {
+            TransactionType::Deposit => tx.amount.map(|amount| (tx.currency, amount)),
+            TransactionType::Withdrawal => tx.amount.map(|amount| (tx.currency, -amount)),
+            TransactionType::Capture => referenced()
+                .and_then(|authorization| Some((authorization.currency, -authorization.amount?))),
+            TransactionType::Reversal | TransactionType::Chargeback => {
+                referenced().and_then(|original| match original.tpe {
+                    TransactionType::Deposit => Some((original.currency, -original.amount?)),
+                    TransactionType::Withdrawal => Some((original.currency, original.amount?)),
+                    _ => None,
+                })
+            }
+            TransactionType::Representment => {
+                referenced().and_then(|original| match original.tpe {
+                    TransactionType::Deposit => Some((original.currency, original.amount?)),
+                    TransactionType::Withdrawal => Some((original.currency, -original.amount?)),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        if let Some((currency, amount)) = movement {
+            let key = (tx.tenant, timestamp / SECONDS_PER_DAY, tx.client, currency);
+            let total = self.totals.entry(key).or_insert(Amount::ZERO);
+            match total.checked_add(amount) {
+                Some(sum) => *total = sum,
+                None => self.overflowed(tx),
+            }
+        }
+    }
+
+    fn overflowed(&mut self, tx: &Transaction) {
+        if self.overflow.is_none() {
+            error!(
+                "Net movements overflowed while recording transaction {}, the settlement won't be available",
+                tx.tx
+            );
+            self.overflow = Some(tx.tx);
+        }
+    }
+
+    /// Settlement instructions ordered by tenant, day, client and currency
+    ///
+    /// Days with no net movement produce no instruction. Fails if some movement overflowed.
+    pub fn instructions(&self) -> Result<Vec<Instruction>, SettlementError> {
+        if let Some(tx) = self.overflow {
+            return Err(SettlementError::TotalOverflow { tx });
+        }
+        Ok(self
+            .totals
+            .iter()
+            .filter(|(_, amount)| **amount != Amount::ZERO)
+            .map(|(&(tenant, day, client, currency), &amount)| Instruction {
+                tenant,
+                date: civil_date(day),
+                client,
+                currency,
+                pay_in: if amount.is_sign_negative() {
+                    Amount::ZERO
+                } else {
+                    amount
+                },
+                pay_out: if amount.is_sign_negative() {
+                    -amount
+                } else {
+                    Amount::ZERO
+                },
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,73 @@
+//! Binary snapshots of the engine state
+//!
+//! A snapshot starts with a magic number and a format version, followed by the
+//! state encoded with bincode. Snapshots of other versions are rejected rather
+//! than misread.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+const MAGIC: [u8; 4] = *b"CPHS";
+
+/// Version of the snapshot format, to be bumped whenever the encoded state changes
+pub const SNAPSHOT_VERSION: u32 = 28;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("not a snapshot file")]
+    InvalidHeader,
+
+    #[error(
+        "unsupported snapshot version {version}, expected {}",
+        SNAPSHOT_VERSION
+    )]
+    UnsupportedVersion { version: u32 },
+
+    #[error("malformed snapshot: {0}")]
+    Malformed(#[from] bincode::Error),
+}
+
+/// Writes the header and the encoded value
+pub fn write<T: Serialize, W: Write>(mut writer: W, value: &T) -> Result<(), SnapshotError> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, value)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Checks the header and decodes the value
+pub fn read<T: DeserializeOwned, R: Read>(mut reader: R) -> Result<T, SnapshotError> {
+    let mut magic = [0; 4];
+    let mut version = [0; 4];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| SnapshotError::InvalidHeader)?;
+    if magic != MAGIC {
+        Err(SnapshotError::InvalidHeader)?;
+    }
+    reader
+        .read_exact(&mut version)
+        .map_err(|_| SnapshotError::InvalidHeader)?;
+    let version = u32::from_le_bytes(version);
+    if version != SNAPSHOT_VERSION {
+        Err(SnapshotError::UnsupportedVersion { version })?;
+    }
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+pub fn save<T: Serialize>(path: &Path, value: &T) -> Result<(), SnapshotError> {
+    write(BufWriter::new(File::create(path)?), value)
+}
+
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, SnapshotError> {
+    read(BufReader::new(File::open(path)?))
+}
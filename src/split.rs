@@ -0,0 +1,112 @@
+//! Partitioning of an input into shards processed in parallel, behind `cephalopod split`
+//!
+//! Records are assigned to shards by a hash of their client, so that all the
+//! records of a client end up in one shard, in their original order. Clients
+//! whose outcomes depend on each other are kept together as well: those who
+//! made transfers with each other, and those whose records share transaction
+//! ids, e.g. a deposit reusing the id of another client's one, which would be
+//! rejected as a duplicate. Processing the shards separately then gives every
+//! account the same balance as processing the whole input.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::extract::RecordIds;
+use crate::fingerprint::Fingerprint;
+
+/// Client or transaction of a tenant linking the records which mention it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+enum Node {
+    Client(Option<u32>, u16),
+    Tx(Option<u32>, u32),
+}
+
+/// Groups of linked nodes, merged as records link them
+#[derive(Debug, Default)]
+struct Groups {
+    indices: HashMap<Node, usize>,
+    nodes: Vec<Node>,
+    parents: Vec<usize>,
+}
+
+impl Groups {
+    fn index(&mut self, node: Node) -> usize {
+        if let Some(&index) = self.indices.get(&node) {
+            return index;
+        }
+        let index = self.nodes.len();
+        self.indices.insert(node, index);
+        self.nodes.push(node);
+        self.parents.push(index);
+        index
+    }
+
+    fn root(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+        index
+    }
+
+    /// Merges the groups, keeping the smallest node as the root so that it doesn't depend on
+    /// the order of records
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        if self.nodes[a] < self.nodes[b] {
+            self.parents[b] = a;
+        } else {
+            self.parents[a] = b;
+        }
+    }
+}
+
+/// Shard of the group with the node as its smallest one, clients coming before transactions
+fn shard_of(node: Node, shards: u32) -> u32 {
+    let mut hash = Fingerprint::default();
+    hash.add(&node);
+    // FNV barely spreads ids differing in the last bytes, so it's finalized like MurmurHash3
+    let mut hash = hash.value();
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    (hash % u64::from(shards)) as u32
+}
+
+/// Shards, counted from 0, the records are assigned to
+///
+/// Records without any valid ids go to the first shard, where they'll be rejected just the same.
+pub fn assign(records: &[RecordIds], shards: u32) -> Vec<u32> {
+    assert!(shards > 0, "there should be at least one shard");
+    let mut groups = Groups::default();
+    let mut linked: Vec<Option<usize>> = Vec::with_capacity(records.len());
+    for ids in records {
+        let nodes = [
+            ids.client.map(|client| Node::Client(ids.tenant, client)),
+            ids.to.map(|to| Node::Client(ids.tenant, to)),
+            ids.tx.map(|tx| Node::Tx(ids.tenant, tx)),
+        ];
+        let mut first = None;
+        for &node in nodes.iter().flatten() {
+            let index = groups.index(node);
+            match first {
+                Some(first) => groups.join(first, index),
+                None => first = Some(index),
+            }
+        }
+        linked.push(first);
+    }
+    linked
+        .into_iter()
+        .map(|index| match index {
+            Some(index) => {
+                let root = groups.root(index);
+                shard_of(groups.nodes[root], shards)
+            }
+            None => 0,
+        })
+        .collect()
+}
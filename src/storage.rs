@@ -0,0 +1,355 @@
+//! Persistent storage of the state
+//!
+//! All accounts are kept in memory (there are at most 65536 of them), while
+//! transactions are loaded on demand and evicted when the cache grows too
+//! large. Key-value backends only need to implement `KeyValueStore`, encoding
+//! of accounts and transactions is shared.
+
+use std::collections::BTreeMap;
+#[cfg(any(feature = "sled", feature = "rocksdb", feature = "sqlite"))]
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::model::{Account, Transaction, TransactionState};
+
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::{PostgresDatabase, PostgresStorage};
+#[cfg(feature = "rocksdb")]
+pub use self::rocksdb::{RocksDatabase, RocksStorage};
+#[cfg(feature = "sled")]
+pub use self::sled::{SledDatabase, SledStorage};
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{SqliteDatabase, SqliteStorage};
+
+/// Number of transactions kept in memory if not configured otherwise
+pub const DEFAULT_CACHE_CAPACITY: usize = 1_000_000;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("malformed stored data: {0}")]
+    Malformed(#[from] bincode::Error),
+
+    #[error("invalid stored value: {0}")]
+    InvalidValue(String),
+
+    #[cfg(feature = "sled")]
+    #[error("sled error: {0}")]
+    Sled(#[from] ::sled::Error),
+
+    #[cfg(feature = "rocksdb")]
+    #[error("RocksDB error: {0}")]
+    RocksDb(#[from] ::rocksdb::Error),
+
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[cfg(feature = "postgres")]
+    #[error("Postgres error: {0}")]
+    Postgres(#[from] ::postgres::Error),
+}
+
+/// Function opening the storage of a tenant
+pub type StorageOpener = Box<dyn FnMut(Option<u32>) -> Result<Box<dyn Storage>, StorageError>>;
+
+/// Backend and location of the persistent state, given as `BACKEND:PATH`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageLocation {
+    /// sled database in a directory
+    #[cfg(feature = "sled")]
+    Sled(PathBuf),
+    /// RocksDB database in a directory
+    #[cfg(feature = "rocksdb")]
+    RocksDb(PathBuf),
+    /// SQLite database file
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+    /// Postgres database, given as a connection string (`postgres:host=... dbname=...`) or a URL
+    #[cfg(feature = "postgres")]
+    Postgres(String),
+}
+
+impl FromStr for StorageLocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StorageLocation, String> {
+        let (backend, location) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected BACKEND:PATH, got {}", s))?;
+        #[allow(clippy::match_single_binding)]
+        match backend {
+            #[cfg(feature = "sled")]
+            "sled" => Ok(StorageLocation::Sled(location.into())),
+            #[cfg(feature = "rocksdb")]
+            "rocksdb" => Ok(StorageLocation::RocksDb(location.into())),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(StorageLocation::Sqlite(location.into())),
+            // `postgres://host/db` keeps its scheme, as the URL form needs it
+            #[cfg(feature = "postgres")]
+            "postgres" | "postgresql" if location.starts_with("//") => {
+                Ok(StorageLocation::Postgres(s.to_string()))
+            }
+            #[cfg(feature = "postgres")]
+            "postgres" | "postgresql" => Ok(StorageLocation::Postgres(location.to_string())),
+            _ => Err(format!(
+                "unsupported storage backend {} (location {})",
+                backend, location
+            )),
+        }
+    }
+}
+
+impl StorageLocation {
+    /// Opens the database, returning tenants already stored in it and the function opening their storages
+    pub fn open(&self) -> Result<(Vec<Option<u32>>, StorageOpener), StorageError> {
+        match *self {
+            #[cfg(feature = "sled")]
+            StorageLocation::Sled(ref path) => {
+                let db = SledDatabase::open(path)?;
+                let tenants = db.tenants();
+                let open: StorageOpener = Box::new(move |tenant| Ok(Box::new(db.storage(tenant)?)));
+                Ok((tenants, open))
+            }
+            #[cfg(feature = "rocksdb")]
+            StorageLocation::RocksDb(ref path) => {
+                let db = RocksDatabase::open(path)?;
+                let tenants = db.tenants()?;
+                let open: StorageOpener = Box::new(move |tenant| Ok(Box::new(db.storage(tenant)?)));
+                Ok((tenants, open))
+            }
+            #[cfg(feature = "sqlite")]
+            StorageLocation::Sqlite(ref path) => {
+                let db = SqliteDatabase::open(path)?;
+                let tenants = db.tenants()?;
+                let open: StorageOpener = Box::new(move |tenant| Ok(Box::new(db.storage(tenant))));
+                Ok((tenants, open))
+            }
+            #[cfg(feature = "postgres")]
+            StorageLocation::Postgres(ref params) => {
+                let db = PostgresDatabase::connect(params)?;
+                let tenants = db.tenants()?;
+                let open: StorageOpener = Box::new(move |tenant| Ok(Box::new(db.storage(tenant))));
+                Ok((tenants, open))
+            }
+        }
+    }
+}
+
+/// Everything stored about a single transaction id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    /// Recorded transaction (i.e. deposit, withdrawal, transfer or authorization)
+    pub transaction: Option<Transaction>,
+    pub state: Option<TransactionState>,
+    /// State of the receiving side of a transfer
+    pub transfer_state: Option<TransactionState>,
+    /// Number of times the transaction has been disputed
+    pub dispute_count: Option<u32>,
+}
+
+/// Persistent store of the state of a single tenant
+pub trait Storage {
+    fn accounts(&self) -> Result<Vec<(u16, Account)>, StorageError>;
+
+    fn account(&self, client: u16) -> Result<Option<Account>, StorageError>;
+
+    fn store_account(&mut self, client: u16, account: &Account) -> Result<(), StorageError>;
+
+    fn transaction(&self, tx: u32) -> Result<Option<TransactionRecord>, StorageError>;
+
+    fn store_transaction(
+        &mut self,
+        tx: u32,
+        record: &TransactionRecord,
+    ) -> Result<(), StorageError>;
+
+    /// Remaining parts of the state, encoded by the state itself
+    fn metadata(&self) -> Result<Option<Vec<u8>>, StorageError>;
+
+    fn store_metadata(&mut self, metadata: &[u8]) -> Result<(), StorageError>;
+
+    /// Starts a group of writes to be applied atomically, if supported
+    fn begin(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Applies the group of writes started with `begin`
+    fn commit(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Discards the group of writes started with `begin`, after any of them has failed
+    fn rollback(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Makes sure everything stored so far is durable
+    fn flush(&mut self) -> Result<(), StorageError>;
+
+    /// Whether other engines may change the storage concurrently
+    ///
+    /// The state then reloads everything a transaction touches before applying it.
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+/// Key and value stored in a key-value store
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Key-value store holding the state of a single tenant
+pub trait KeyValueStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError>;
+
+    /// All entries with keys starting with `prefix`, ordered by key
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError>;
+
+    /// Makes sure everything inserted so far is durable
+    fn flush(&mut self) -> Result<(), StorageError>;
+}
+
+const ACCOUNT_PREFIX: u8 = b'a';
+const TRANSACTION_PREFIX: u8 = b't';
+const METADATA_KEY: &[u8] = b"m";
+
+fn account_key(client: u16) -> [u8; 3] {
+    let [high, low] = client.to_be_bytes();
+    [ACCOUNT_PREFIX, high, low]
+}
+
+fn transaction_key(tx: u32) -> [u8; 5] {
+    let mut key = [TRANSACTION_PREFIX; 5];
+    key[1..].copy_from_slice(&tx.to_be_bytes());
+    key
+}
+
+impl<S: KeyValueStore> Storage for S {
+    fn accounts(&self) -> Result<Vec<(u16, Account)>, StorageError> {
+        self.scan_prefix(&[ACCOUNT_PREFIX])?
+            .into_iter()
+            .filter_map(|(key, value)| match key[..] {
+                [_, high, low] => Some((u16::from_be_bytes([high, low]), value)),
+                _ => None,
+            })
+            .map(|(client, value)| Ok((client, bincode::deserialize(&value)?)))
+            .collect()
+    }
+
+    fn account(&self, client: u16) -> Result<Option<Account>, StorageError> {
+        match self.get(&account_key(client))? {
+            Some(account) => Ok(Some(bincode::deserialize(&account)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store_account(&mut self, client: u16, account: &Account) -> Result<(), StorageError> {
+        self.insert(&account_key(client), &bincode::serialize(account)?)
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<TransactionRecord>, StorageError> {
+        match self.get(&transaction_key(tx))? {
+            Some(record) => Ok(Some(bincode::deserialize(&record)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store_transaction(
+        &mut self,
+        tx: u32,
+        record: &TransactionRecord,
+    ) -> Result<(), StorageError> {
+        self.insert(&transaction_key(tx), &bincode::serialize(record)?)
+    }
+
+    fn metadata(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        self.get(METADATA_KEY)
+    }
+
+    fn store_metadata(&mut self, metadata: &[u8]) -> Result<(), StorageError> {
+        self.insert(METADATA_KEY, metadata)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        KeyValueStore::flush(self)
+    }
+}
+
+const TENANT_PREFIX: &str = "tenant";
+
+/// Name of the tree (or column family) holding the state of the tenant
+#[cfg_attr(not(any(feature = "sled", feature = "rocksdb")), allow(dead_code))]
+pub(crate) fn tenant_name(tenant: Option<u32>) -> String {
+    match tenant {
+        Some(tenant) => format!("{}-{}", TENANT_PREFIX, tenant),
+        None => TENANT_PREFIX.to_string(),
+    }
+}
+
+/// Tenant of the tree (or column family), `None` if it doesn't hold the state of a tenant
+#[cfg_attr(not(any(feature = "sled", feature = "rocksdb")), allow(dead_code))]
+pub(crate) fn parse_tenant_name(name: &str) -> Option<Option<u32>> {
+    match name.strip_prefix(TENANT_PREFIX)? {
+        "" => Some(None),
+        suffix => suffix.strip_prefix('-')?.parse().ok().map(Some),
+    }
+}
+
+/// Key-value store keeping everything in memory, meant for tests
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl KeyValueStore for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        Ok(self
+            .entries
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
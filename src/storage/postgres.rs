@@ -0,0 +1,427 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use postgres::types::ToSql;
+use postgres::{Client, NoTls, Row, Statement};
+
+use super::sql::{
+    format_flags, parse_amount, parse_currency, parse_flags, parse_tag, parse_variant, state_name,
+    tenant_id, type_name,
+};
+use super::{Storage, StorageError, TransactionRecord};
+use crate::model::{Account, Transaction};
+
+/// Tables are created on connect, amounts are numeric but exchanged as text so that they stay exact
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    tenant BIGINT NOT NULL,
+    client INTEGER NOT NULL,
+    locked BOOLEAN NOT NULL,
+    closed BOOLEAN NOT NULL,
+    frozen BOOLEAN NOT NULL,
+    flags TEXT NOT NULL,
+    last_activity BIGINT,
+    PRIMARY KEY (tenant, client)
+);
+CREATE TABLE IF NOT EXISTS balances (
+    tenant BIGINT NOT NULL,
+    client INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    available NUMERIC NOT NULL,
+    held NUMERIC NOT NULL,
+    PRIMARY KEY (tenant, client, currency)
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    tenant BIGINT NOT NULL,
+    tx BIGINT NOT NULL,
+    type TEXT,
+    client INTEGER,
+    amount NUMERIC,
+    to_client INTEGER,
+    reason BIGINT,
+    currency TEXT,
+    timestamp BIGINT,
+    state TEXT,
+    transfer_state TEXT,
+    dispute_count BIGINT,
+    PRIMARY KEY (tenant, tx)
+);
+-- databases created before tags were stored lack the column
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS tag TEXT;
+CREATE TABLE IF NOT EXISTS metadata (
+    tenant BIGINT PRIMARY KEY,
+    data BYTEA NOT NULL
+);
+";
+
+const SELECT_ACCOUNTS: &str = "SELECT client, locked, closed, frozen, flags, last_activity
+    FROM accounts WHERE tenant = $1 ORDER BY client";
+const SELECT_ACCOUNT: &str = "SELECT client, locked, closed, frozen, flags, last_activity
+    FROM accounts WHERE tenant = $1 AND client = $2 FOR UPDATE";
+const SELECT_BALANCES: &str = "SELECT client, currency, available::TEXT, held::TEXT
+    FROM balances WHERE tenant = $1";
+const SELECT_ACCOUNT_BALANCES: &str = "SELECT client, currency, available::TEXT, held::TEXT
+    FROM balances WHERE tenant = $1 AND client = $2 FOR UPDATE";
+const UPSERT_ACCOUNT: &str = "INSERT INTO accounts
+    (tenant, client, locked, closed, frozen, flags, last_activity)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+    ON CONFLICT (tenant, client) DO UPDATE SET locked = EXCLUDED.locked,
+    closed = EXCLUDED.closed, frozen = EXCLUDED.frozen, flags = EXCLUDED.flags,
+    last_activity = EXCLUDED.last_activity";
+const UPSERT_BALANCE: &str = "INSERT INTO balances (tenant, client, currency, available, held)
+    VALUES ($1, $2, $3, $4::TEXT::NUMERIC, $5::TEXT::NUMERIC)
+    ON CONFLICT (tenant, client, currency) DO UPDATE SET available = EXCLUDED.available,
+    held = EXCLUDED.held";
+const SELECT_TRANSACTION: &str = "SELECT tx, type, client, amount::TEXT, to_client, reason,
+    currency, timestamp, state, transfer_state, dispute_count, tag
+    FROM transactions WHERE tenant = $1 AND tx = $2 FOR UPDATE";
+const UPSERT_TRANSACTION: &str = "INSERT INTO transactions
+    (tenant, tx, type, client, amount, to_client, reason, currency, timestamp,
+     state, transfer_state, dispute_count, tag)
+    VALUES ($1, $2, $3, $4, $5::TEXT::NUMERIC, $6, $7, $8, $9, $10, $11, $12, $13)
+    ON CONFLICT (tenant, tx) DO UPDATE SET type = EXCLUDED.type, client = EXCLUDED.client,
+    amount = EXCLUDED.amount, to_client = EXCLUDED.to_client, reason = EXCLUDED.reason,
+    currency = EXCLUDED.currency, timestamp = EXCLUDED.timestamp, state = EXCLUDED.state,
+    transfer_state = EXCLUDED.transfer_state, dispute_count = EXCLUDED.dispute_count,
+    tag = EXCLUDED.tag";
+const SELECT_METADATA: &str = "SELECT data FROM metadata WHERE tenant = $1";
+const UPSERT_METADATA: &str = "INSERT INTO metadata (tenant, data) VALUES ($1, $2)
+    ON CONFLICT (tenant) DO UPDATE SET data = EXCLUDED.data";
+
+fn invalid<T>(value: impl std::fmt::Display) -> Result<T, StorageError> {
+    Err(StorageError::InvalidValue(value.to_string()))
+}
+
+// Postgres has no unsigned types, so values are stored in wider signed columns
+fn to_u16(value: i32) -> Result<u16, StorageError> {
+    u16::try_from(value).or_else(|_| invalid(value))
+}
+
+fn to_u32(value: i64) -> Result<u32, StorageError> {
+    u32::try_from(value).or_else(|_| invalid(value))
+}
+
+fn to_u64(value: i64) -> Result<u64, StorageError> {
+    u64::try_from(value).or_else(|_| invalid(value))
+}
+
+fn to_i64(value: u64) -> Result<i64, StorageError> {
+    i64::try_from(value).or_else(|_| invalid(value))
+}
+
+/// Connection with statements prepared so far
+struct Connection {
+    client: Client,
+    statements: HashMap<&'static str, Statement>,
+}
+
+impl Connection {
+    fn statement(&mut self, query: &'static str) -> Result<Statement, StorageError> {
+        if let Some(statement) = self.statements.get(query) {
+            return Ok(statement.clone());
+        }
+        let statement = self.client.prepare(query)?;
+        self.statements.insert(query, statement.clone());
+        Ok(statement)
+    }
+
+    fn query(
+        &mut self,
+        query: &'static str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, StorageError> {
+        let statement = self.statement(query)?;
+        Ok(self.client.query(&statement, params)?)
+    }
+
+    fn execute(
+        &mut self,
+        query: &'static str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<(), StorageError> {
+        let statement = self.statement(query)?;
+        self.client.execute(&statement, params)?;
+        Ok(())
+    }
+}
+
+/// Postgres database holding states of all tenants, distinguished by the tenant column
+///
+/// The database may be shared by several engines. Every transaction is
+/// applied within a serializable database transaction with the rows it
+/// touches locked, so concurrent conflicting transactions fail with a storage
+/// error instead of overwriting each other. Parts of the state other than
+/// accounts and transactions (e.g. open dispute counts and fees collected) are
+/// only stored on flush and are not shared. TLS connections are not supported.
+#[derive(Clone)]
+pub struct PostgresDatabase {
+    connection: Rc<RefCell<Connection>>,
+}
+
+impl PostgresDatabase {
+    /// Connects to the database given as a connection string, e.g. `host=localhost user=postgres`
+    pub fn connect(params: &str) -> Result<PostgresDatabase, StorageError> {
+        let mut client = Client::connect(params, NoTls)?;
+        client.batch_execute(SCHEMA)?;
+        Ok(PostgresDatabase {
+            connection: Rc::new(RefCell::new(Connection {
+                client,
+                statements: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Tenants with anything stored in the database
+    pub fn tenants(&self) -> Result<Vec<Option<u32>>, StorageError> {
+        let rows = self.connection.borrow_mut().client.query(
+            "SELECT tenant FROM accounts UNION SELECT tenant FROM transactions
+             UNION SELECT tenant FROM metadata ORDER BY tenant",
+            &[],
+        )?;
+        rows.iter()
+            .map(|row| Ok(u32::try_from(row.try_get::<_, i64>(0)?).ok()))
+            .collect()
+    }
+
+    pub fn storage(&self, tenant: Option<u32>) -> PostgresStorage {
+        PostgresStorage {
+            connection: Rc::clone(&self.connection),
+            tenant,
+        }
+    }
+}
+
+/// State of a single tenant kept in a Postgres database
+#[derive(Clone)]
+pub struct PostgresStorage {
+    connection: Rc<RefCell<Connection>>,
+    tenant: Option<u32>,
+}
+
+impl PostgresStorage {
+    fn read_accounts(&self, client: Option<u16>) -> Result<Vec<(u16, Account)>, StorageError> {
+        let tenant = tenant_id(self.tenant);
+        let client = client.map(i32::from);
+        let mut connection = self.connection.borrow_mut();
+        let (rows, balances) = match &client {
+            Some(client) => (
+                connection.query(SELECT_ACCOUNT, &[&tenant, client])?,
+                connection.query(SELECT_ACCOUNT_BALANCES, &[&tenant, client])?,
+            ),
+            None => (
+                connection.query(SELECT_ACCOUNTS, &[&tenant])?,
+                connection.query(SELECT_BALANCES, &[&tenant])?,
+            ),
+        };
+
+        let mut accounts = Vec::new();
+        for row in rows {
+            let flags: String = row.try_get(4)?;
+            let account = Account {
+                locked: row.try_get(1)?,
+                closed: row.try_get(2)?,
+                frozen: row.try_get(3)?,
+                flags: parse_flags(&flags)?,
+                last_activity: row.try_get::<_, Option<i64>>(5)?.map(to_u64).transpose()?,
+                ..Account::new()
+            };
+            accounts.push((to_u16(row.try_get(0)?)?, account));
+        }
+        for row in balances {
+            let client = to_u16(row.try_get(0)?)?;
+            let currency: String = row.try_get(1)?;
+            let available: String = row.try_get(2)?;
+            let held: String = row.try_get(3)?;
+            if let Ok(index) = accounts.binary_search_by_key(&client, |(client, _)| *client) {
+                let balance = accounts[index]
+                    .1
+                    .balances
+                    .entry(parse_currency(&currency)?)
+                    .or_default();
+                balance.available = parse_amount(&available)?;
+                balance.held = parse_amount(&held)?;
+            }
+        }
+        Ok(accounts)
+    }
+
+    fn read_transaction(&self, row: &Row) -> Result<Option<Transaction>, StorageError> {
+        let tpe: Option<String> = row.try_get("type")?;
+        let tpe = match tpe {
+            Some(tpe) => parse_variant(&tpe)?,
+            None => return Ok(None),
+        };
+        let client: Option<i32> = row.try_get("client")?;
+        let amount: Option<String> = row.try_get("amount")?;
+        let to: Option<i32> = row.try_get("to_client")?;
+        let reason: Option<i64> = row.try_get("reason")?;
+        let currency: Option<String> = row.try_get("currency")?;
+        let timestamp: Option<i64> = row.try_get("timestamp")?;
+        let tag: Option<String> = row.try_get("tag")?;
+        Ok(Some(Transaction {
+            tpe,
+            client: to_u16(client.unwrap_or_default())?,
+            tx: to_u32(row.try_get("tx")?)?,
+            amount: amount.as_deref().map(parse_amount).transpose()?,
+            to: to.map(to_u16).transpose()?,
+            reason: reason.map(to_u32).transpose()?,
+            currency: parse_currency(currency.as_deref().unwrap_or_default())?,
+            timestamp: timestamp.map(to_u64).transpose()?,
+            tenant: self.tenant,
+            tag: parse_tag(tag.as_deref().unwrap_or_default())?,
+        }))
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn accounts(&self) -> Result<Vec<(u16, Account)>, StorageError> {
+        self.read_accounts(None)
+    }
+
+    fn account(&self, client: u16) -> Result<Option<Account>, StorageError> {
+        Ok(self
+            .read_accounts(Some(client))?
+            .pop()
+            .map(|(_, account)| account))
+    }
+
+    fn store_account(&mut self, client: u16, account: &Account) -> Result<(), StorageError> {
+        let tenant = tenant_id(self.tenant);
+        let client = i32::from(client);
+        let last_activity = account.last_activity.map(to_i64).transpose()?;
+        let mut connection = self.connection.borrow_mut();
+        connection.execute(
+            UPSERT_ACCOUNT,
+            &[
+                &tenant,
+                &client,
+                &account.locked,
+                &account.closed,
+                &account.frozen,
+                &format_flags(account),
+                &last_activity,
+            ],
+        )?;
+        for (currency, balance) in &account.balances {
+            connection.execute(
+                UPSERT_BALANCE,
+                &[
+                    &tenant,
+                    &client,
+                    &currency.as_str(),
+                    &balance.available.to_string(),
+                    &balance.held.to_string(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<TransactionRecord>, StorageError> {
+        let rows = self.connection.borrow_mut().query(
+            SELECT_TRANSACTION,
+            &[&tenant_id(self.tenant), &i64::from(tx)],
+        )?;
+        let row = match rows.first() {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let state: Option<String> = row.try_get("state")?;
+        let transfer_state: Option<String> = row.try_get("transfer_state")?;
+        let dispute_count: Option<i64> = row.try_get("dispute_count")?;
+        Ok(Some(TransactionRecord {
+            transaction: self.read_transaction(row)?,
+            state: state.as_deref().map(parse_variant).transpose()?,
+            transfer_state: transfer_state.as_deref().map(parse_variant).transpose()?,
+            dispute_count: dispute_count.map(to_u32).transpose()?,
+        }))
+    }
+
+    fn store_transaction(
+        &mut self,
+        tx: u32,
+        record: &TransactionRecord,
+    ) -> Result<(), StorageError> {
+        let transaction = record.transaction.as_ref();
+        let timestamp = transaction
+            .and_then(|transaction| transaction.timestamp)
+            .map(to_i64)
+            .transpose()?;
+        self.connection.borrow_mut().execute(
+            UPSERT_TRANSACTION,
+            &[
+                &tenant_id(self.tenant),
+                &i64::from(tx),
+                &transaction.map(|transaction| type_name(transaction.tpe)),
+                &transaction.map(|transaction| i32::from(transaction.client)),
+                &transaction
+                    .and_then(|transaction| transaction.amount)
+                    .map(|amount| amount.to_string()),
+                &transaction
+                    .and_then(|transaction| transaction.to)
+                    .map(i32::from),
+                &transaction
+                    .and_then(|transaction| transaction.reason)
+                    .map(i64::from),
+                &transaction.map(|transaction| transaction.currency.as_str().to_string()),
+                &timestamp,
+                &record.state.map(state_name),
+                &record.transfer_state.map(state_name),
+                &record.dispute_count.map(i64::from),
+                &transaction.map(|transaction| transaction.tag.as_str().to_string()),
+            ],
+        )
+    }
+
+    fn metadata(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        let rows = self
+            .connection
+            .borrow_mut()
+            .query(SELECT_METADATA, &[&tenant_id(self.tenant)])?;
+        match rows.first() {
+            Some(row) => Ok(Some(row.try_get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store_metadata(&mut self, metadata: &[u8]) -> Result<(), StorageError> {
+        self.connection
+            .borrow_mut()
+            .execute(UPSERT_METADATA, &[&tenant_id(self.tenant), &metadata])
+    }
+
+    fn begin(&mut self) -> Result<(), StorageError> {
+        self.connection
+            .borrow_mut()
+            .client
+            .batch_execute("BEGIN ISOLATION LEVEL SERIALIZABLE")?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.connection
+            .borrow_mut()
+            .client
+            .batch_execute("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), StorageError> {
+        self.connection
+            .borrow_mut()
+            .client
+            .batch_execute("ROLLBACK")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        // committed transactions are already durable
+        Ok(())
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rocksdb::{
+    BlockBasedOptions, BoundColumnFamily, Cache, ColumnFamilyDescriptor, DBCompressionType,
+    DBWithThreadMode, MultiThreaded, Options,
+};
+
+use super::{parse_tenant_name, tenant_name, Entry, KeyValueStore, StorageError};
+
+type Db = DBWithThreadMode<MultiThreaded>;
+
+/// Size of the block cache shared by all tenants
+const BLOCK_CACHE_SIZE: usize = 256 * 1024 * 1024;
+const WRITE_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+const MAX_WRITE_BUFFERS: i32 = 4;
+const BLOOM_FILTER_BITS_PER_KEY: f64 = 10.0;
+
+/// Options tuned for the access pattern of the engine
+///
+/// Every transaction does a point lookup of its id, mostly missing for new
+/// transactions, followed by a few small writes. Bloom filters (also on the
+/// memtables) answer most of the lookups without touching disk, while large
+/// memtables absorb the writes. Only accounts are ever scanned, once on open.
+fn options(cache: &Cache) -> Options {
+    let mut table_options = BlockBasedOptions::default();
+    table_options.set_bloom_filter(BLOOM_FILTER_BITS_PER_KEY, false);
+    table_options.set_block_cache(cache);
+    table_options.set_cache_index_and_filter_blocks(true);
+
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    options.set_block_based_table_factory(&table_options);
+    options.set_memtable_whole_key_filtering(true);
+    options.set_write_buffer_size(WRITE_BUFFER_SIZE);
+    options.set_max_write_buffer_number(MAX_WRITE_BUFFERS);
+    options.set_compression_type(DBCompressionType::Lz4);
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    options.increase_parallelism(parallelism as i32);
+    options
+}
+
+/// RocksDB database with a separate column family for every tenant
+#[derive(Clone)]
+pub struct RocksDatabase {
+    db: Arc<Db>,
+    path: PathBuf,
+    cache: Cache,
+}
+
+impl RocksDatabase {
+    pub fn open(path: &Path) -> Result<RocksDatabase, StorageError> {
+        let cache = Cache::new_lru_cache(BLOCK_CACHE_SIZE);
+        let db_options = options(&cache);
+        // a database that doesn't exist yet has no column families to list
+        let names = Db::list_cf(&db_options, path).unwrap_or_default();
+        let descriptors = names
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, options(&cache)));
+        Ok(RocksDatabase {
+            db: Arc::new(Db::open_cf_descriptors(&db_options, path, descriptors)?),
+            path: path.to_path_buf(),
+            cache,
+        })
+    }
+
+    /// Tenants with a column family in the database
+    pub fn tenants(&self) -> Result<Vec<Option<u32>>, StorageError> {
+        let mut tenants: Vec<Option<u32>> = Db::list_cf(&Options::default(), &self.path)?
+            .iter()
+            .filter_map(|name| parse_tenant_name(name))
+            .collect();
+        tenants.sort_unstable();
+        Ok(tenants)
+    }
+
+    pub fn storage(&self, tenant: Option<u32>) -> Result<RocksStorage, StorageError> {
+        let name = tenant_name(tenant);
+        if self.db.cf_handle(&name).is_none() {
+            self.db.create_cf(&name, &options(&self.cache))?;
+        }
+        Ok(RocksStorage {
+            db: Arc::clone(&self.db),
+            name,
+        })
+    }
+}
+
+/// State of a single tenant kept in a RocksDB column family
+#[derive(Clone)]
+pub struct RocksStorage {
+    db: Arc<Db>,
+    name: String,
+}
+
+impl RocksStorage {
+    fn column_family(&self) -> Arc<BoundColumnFamily<'_>> {
+        // column families are never dropped, so the handle always exists
+        self.db
+            .cf_handle(&self.name)
+            .expect("column family of the tenant has been created")
+    }
+}
+
+impl KeyValueStore for RocksStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get_cf(&self.column_family(), key)?)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.db.put_cf(&self.column_family(), key, value)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        let column_family = self.column_family();
+        let mut entries = Vec::new();
+        for entry in self.db.prefix_iterator_cf(&column_family, prefix) {
+            let (key, value) = entry?;
+            // without a prefix extractor the iterator doesn't stop at the end of the prefix
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.into_vec(), value.into_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        self.db.flush_cf(&self.column_family())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use super::{parse_tenant_name, tenant_name, Entry, KeyValueStore, StorageError};
+
+/// Sled database with a separate tree for every tenant
+#[derive(Debug, Clone)]
+pub struct SledDatabase {
+    db: sled::Db,
+}
+
+impl SledDatabase {
+    pub fn open(path: &Path) -> Result<SledDatabase, StorageError> {
+        Ok(SledDatabase {
+            db: sled::open(path)?,
+        })
+    }
+
+    /// Tenants with a tree in the database
+    pub fn tenants(&self) -> Vec<Option<u32>> {
+        let mut tenants: Vec<Option<u32>> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|name| parse_tenant_name(std::str::from_utf8(name).ok()?))
+            .collect();
+        tenants.sort_unstable();
+        tenants
+    }
+
+    pub fn storage(&self, tenant: Option<u32>) -> Result<SledStorage, StorageError> {
+        Ok(SledStorage {
+            tree: self.db.open_tree(tenant_name(tenant))?,
+        })
+    }
+}
+
+/// State of a single tenant kept in a sled tree
+#[derive(Debug, Clone)]
+pub struct SledStorage {
+    tree: sled::Tree,
+}
+
+impl KeyValueStore for SledStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.tree.insert(key, value)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        self.tree
+            .scan_prefix(prefix)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        self.tree.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+//! Conversions shared by the SQL backends
+//!
+//! Amounts, currencies and enums are stored as text, the same way they appear
+//! in CSV files.
+
+use serde::de::{value, DeserializeOwned, IntoDeserializer};
+
+use super::StorageError;
+use crate::amount::{self, Amount};
+use crate::currency::{Currency, ParseCurrencyError};
+use crate::model::{Account, TransactionState, TransactionType};
+use crate::tag::{ParseTagError, Tag};
+
+/// Value of the tenant column, tenant ids are `u32` so -1 never collides with them
+pub(crate) fn tenant_id(tenant: Option<u32>) -> i64 {
+    tenant.map_or(-1, i64::from)
+}
+
+/// Name of the transaction type as used in CSV files
+pub(crate) fn type_name(tpe: TransactionType) -> String {
+    format!("{:?}", tpe).to_lowercase()
+}
+
+/// Name of the transaction state, the same as the name of the variant
+pub(crate) fn state_name(state: TransactionState) -> String {
+    format!("{:?}", state)
+}
+
+/// Parses names of variants returned by `type_name` and `state_name`
+pub(crate) fn parse_variant<T: DeserializeOwned>(name: &str) -> Result<T, StorageError> {
+    T::deserialize(IntoDeserializer::<value::Error>::into_deserializer(name))
+        .map_err(|err| StorageError::InvalidValue(err.to_string()))
+}
+
+pub(crate) fn parse_amount(amount: &str) -> Result<Amount, StorageError> {
+    amount::parse_amount(amount).map_err(|err| StorageError::InvalidValue(err.to_string()))
+}
+
+pub(crate) fn parse_currency(currency: &str) -> Result<Currency, StorageError> {
+    currency
+        .parse()
+        .map_err(|err: ParseCurrencyError| StorageError::InvalidValue(err.to_string()))
+}
+
+pub(crate) fn parse_tag(tag: &str) -> Result<Tag, StorageError> {
+    tag.parse()
+        .map_err(|err: ParseTagError| StorageError::InvalidValue(err.to_string()))
+}
+
+/// Flags of the account separated with `;`
+pub(crate) fn format_flags(account: &Account) -> String {
+    let flags: Vec<String> = account.flags.iter().map(|flag| flag.to_string()).collect();
+    flags.join(";")
+}
+
+pub(crate) fn parse_flags(flags: &str) -> Result<std::collections::BTreeSet<u32>, StorageError> {
+    flags
+        .split(';')
+        .filter(|flag| !flag.is_empty())
+        .map(|flag| flag.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|err: std::num::ParseIntError| StorageError::InvalidValue(err.to_string()))
+}
@@ -0,0 +1,311 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::rc::Rc;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::sql::{
+    format_flags, parse_amount, parse_currency, parse_flags, parse_tag, parse_variant, state_name,
+    tenant_id, type_name,
+};
+use super::{Storage, StorageError, TransactionRecord};
+use crate::model::{Account, Transaction};
+
+/// Tables are created on open, amounts are kept as text so that they stay exact
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    tenant INTEGER NOT NULL,
+    client INTEGER NOT NULL,
+    locked INTEGER NOT NULL,
+    closed INTEGER NOT NULL,
+    frozen INTEGER NOT NULL,
+    flags TEXT NOT NULL,
+    last_activity INTEGER,
+    PRIMARY KEY (tenant, client)
+);
+CREATE TABLE IF NOT EXISTS balances (
+    tenant INTEGER NOT NULL,
+    client INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    available TEXT NOT NULL,
+    held TEXT NOT NULL,
+    PRIMARY KEY (tenant, client, currency)
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    tenant INTEGER NOT NULL,
+    tx INTEGER NOT NULL,
+    type TEXT,
+    client INTEGER,
+    amount TEXT,
+    to_client INTEGER,
+    reason INTEGER,
+    currency TEXT,
+    timestamp INTEGER,
+    tag TEXT,
+    state TEXT,
+    transfer_state TEXT,
+    dispute_count INTEGER,
+    PRIMARY KEY (tenant, tx)
+);
+CREATE TABLE IF NOT EXISTS metadata (
+    tenant INTEGER PRIMARY KEY,
+    data BLOB NOT NULL
+);
+";
+
+/// SQLite database holding states of all tenants, distinguished by the tenant column
+#[derive(Debug, Clone)]
+pub struct SqliteDatabase {
+    connection: Rc<Connection>,
+}
+
+impl SqliteDatabase {
+    pub fn open(path: &Path) -> Result<SqliteDatabase, StorageError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+        // databases created before tags were stored lack the column
+        let tagged: bool = connection.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('transactions') WHERE name = 'tag'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !tagged {
+            connection.execute_batch("ALTER TABLE transactions ADD COLUMN tag TEXT")?;
+        }
+        Ok(SqliteDatabase {
+            connection: Rc::new(connection),
+        })
+    }
+
+    /// Tenants with anything stored in the database
+    pub fn tenants(&self) -> Result<Vec<Option<u32>>, StorageError> {
+        let mut statement = self.connection.prepare(
+            "SELECT tenant FROM accounts UNION SELECT tenant FROM transactions
+             UNION SELECT tenant FROM metadata ORDER BY tenant",
+        )?;
+        let tenants = statement
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .map(|tenant| Ok(u32::try_from(tenant?).ok()))
+            .collect();
+        tenants
+    }
+
+    pub fn storage(&self, tenant: Option<u32>) -> SqliteStorage {
+        SqliteStorage {
+            connection: Rc::clone(&self.connection),
+            tenant,
+        }
+    }
+}
+
+/// State of a single tenant kept in a SQLite database
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    connection: Rc<Connection>,
+    tenant: Option<u32>,
+}
+
+impl SqliteStorage {
+    fn read_transaction(&self, row: &Row) -> Result<Option<Transaction>, StorageError> {
+        let tpe: Option<String> = row.get("type")?;
+        let tpe = match tpe {
+            Some(tpe) => parse_variant(&tpe)?,
+            None => return Ok(None),
+        };
+        let amount: Option<String> = row.get("amount")?;
+        let currency: Option<String> = row.get("currency")?;
+        let tag: Option<String> = row.get("tag")?;
+        Ok(Some(Transaction {
+            tpe,
+            client: row.get("client")?,
+            tx: row.get("tx")?,
+            amount: amount.as_deref().map(parse_amount).transpose()?,
+            to: row.get("to_client")?,
+            reason: row.get("reason")?,
+            currency: parse_currency(currency.as_deref().unwrap_or_default())?,
+            timestamp: row.get("timestamp")?,
+            tenant: self.tenant,
+            tag: parse_tag(tag.as_deref().unwrap_or_default())?,
+        }))
+    }
+
+    /// Accounts of the tenant, or only the account of `client` if given
+    fn read_accounts(&self, client: Option<u16>) -> Result<Vec<(u16, Account)>, StorageError> {
+        let tenant = tenant_id(self.tenant);
+        let mut accounts = Vec::new();
+        let mut statement = self.connection.prepare_cached(
+            "SELECT client, locked, closed, frozen, flags, last_activity
+             FROM accounts WHERE tenant = ?1 AND (?2 IS NULL OR client = ?2) ORDER BY client",
+        )?;
+        let mut rows = statement.query(params![tenant, client])?;
+        while let Some(row) = rows.next()? {
+            let flags: String = row.get(4)?;
+            let account = Account {
+                locked: row.get(1)?,
+                closed: row.get(2)?,
+                frozen: row.get(3)?,
+                flags: parse_flags(&flags)?,
+                last_activity: row.get(5)?,
+                ..Account::new()
+            };
+            accounts.push((row.get(0)?, account));
+        }
+
+        let mut statement = self.connection.prepare_cached(
+            "SELECT client, currency, available, held
+             FROM balances WHERE tenant = ?1 AND (?2 IS NULL OR client = ?2)",
+        )?;
+        let mut rows = statement.query(params![tenant, client])?;
+        while let Some(row) = rows.next()? {
+            let client: u16 = row.get(0)?;
+            let currency: String = row.get(1)?;
+            let available: String = row.get(2)?;
+            let held: String = row.get(3)?;
+            if let Ok(index) = accounts.binary_search_by_key(&client, |(client, _)| *client) {
+                let balance = accounts[index]
+                    .1
+                    .balances
+                    .entry(parse_currency(&currency)?)
+                    .or_default();
+                balance.available = parse_amount(&available)?;
+                balance.held = parse_amount(&held)?;
+            }
+        }
+        Ok(accounts)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn accounts(&self) -> Result<Vec<(u16, Account)>, StorageError> {
+        self.read_accounts(None)
+    }
+
+    fn account(&self, client: u16) -> Result<Option<Account>, StorageError> {
+        Ok(self
+            .read_accounts(Some(client))?
+            .pop()
+            .map(|(_, account)| account))
+    }
+
+    fn store_account(&mut self, client: u16, account: &Account) -> Result<(), StorageError> {
+        let tenant = tenant_id(self.tenant);
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO accounts
+                 (tenant, client, locked, closed, frozen, flags, last_activity)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![
+                tenant,
+                client,
+                account.locked,
+                account.closed,
+                account.frozen,
+                format_flags(account),
+                account.last_activity,
+            ])?;
+        for (currency, balance) in &account.balances {
+            self.connection
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO balances (tenant, client, currency, available, held)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?
+                .execute(params![
+                    tenant,
+                    client,
+                    currency.as_str(),
+                    balance.available.to_string(),
+                    balance.held.to_string(),
+                ])?;
+        }
+        Ok(())
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<TransactionRecord>, StorageError> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT * FROM transactions WHERE tenant = ?1 AND tx = ?2")?;
+        let mut rows = statement.query(params![tenant_id(self.tenant), tx])?;
+        let row = match rows.next()? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let state: Option<String> = row.get("state")?;
+        let transfer_state: Option<String> = row.get("transfer_state")?;
+        Ok(Some(TransactionRecord {
+            transaction: self.read_transaction(row)?,
+            state: state.as_deref().map(parse_variant).transpose()?,
+            transfer_state: transfer_state.as_deref().map(parse_variant).transpose()?,
+            dispute_count: row.get("dispute_count")?,
+        }))
+    }
+
+    fn store_transaction(
+        &mut self,
+        tx: u32,
+        record: &TransactionRecord,
+    ) -> Result<(), StorageError> {
+        let transaction = record.transaction.as_ref();
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO transactions
+                 (tenant, tx, type, client, amount, to_client, reason, currency, timestamp,
+                  state, transfer_state, dispute_count, tag)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            )?
+            .execute(params![
+                tenant_id(self.tenant),
+                tx,
+                transaction.map(|transaction| type_name(transaction.tpe)),
+                transaction.map(|transaction| transaction.client),
+                transaction
+                    .and_then(|transaction| transaction.amount)
+                    .map(|amount| amount.to_string()),
+                transaction.and_then(|transaction| transaction.to),
+                transaction.and_then(|transaction| transaction.reason),
+                transaction.map(|transaction| transaction.currency.as_str().to_string()),
+                transaction.and_then(|transaction| transaction.timestamp),
+                record.state.map(state_name),
+                record.transfer_state.map(state_name),
+                record.dispute_count,
+                transaction.map(|transaction| transaction.tag.as_str().to_string()),
+            ])?;
+        Ok(())
+    }
+
+    fn metadata(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .connection
+            .prepare_cached("SELECT data FROM metadata WHERE tenant = ?1")?
+            .query_row([tenant_id(self.tenant)], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn store_metadata(&mut self, metadata: &[u8]) -> Result<(), StorageError> {
+        self.connection
+            .prepare_cached("INSERT OR REPLACE INTO metadata (tenant, data) VALUES (?1, ?2)")?
+            .execute(params![tenant_id(self.tenant), metadata])?;
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<(), StorageError> {
+        self.connection.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), StorageError> {
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), StorageError> {
+        self.connection.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        // committed transactions are already durable
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Statistics of a processing run
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::fingerprint::Fingerprint;
+use crate::memory::MemoryUsage;
+use crate::model::{CephalopodError, Transaction, TransactionError, TransactionType};
+
+/// Counters accumulated while processing the input
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Input rows that couldn't be parsed
+    pub invalid_rows: u64,
+    /// Transactions with timestamps out of order
+    pub out_of_order: u64,
+    /// Transactions applied to the state
+    pub applied: u64,
+    /// Numbers of transactions applied to the state by type
+    pub applied_by_type: BTreeMap<TransactionType, u64>,
+    /// Transactions rejected with a `TransactionError`
+    pub rejected: u64,
+    /// Disputes rejected because they were filed after the dispute window
+    pub expired_disputes: u64,
+    /// Transactions rejected because their amount exceeded `Policy::amount_cap`
+    pub over_cap: u64,
+    /// Transactions parked in the suspense queue because the referenced transaction was unknown
+    pub suspended: u64,
+    /// Parked transactions whose referenced transaction never arrived, or not within the lookahead
+    pub unresolved: u64,
+    /// Transactions set aside because of an integrity error
+    pub quarantined: u64,
+    /// Totals of fees charged to the accounts per currency
+    pub fees_collected: BTreeMap<Currency, Amount>,
+    /// Hash of the resulting state, to compare with other runs
+    pub fingerprint: Option<Fingerprint>,
+    /// Approximate memory taken by the resulting state
+    pub memory: Option<MemoryUsage>,
+    /// Numbers of errors of each kind (e.g. `NotEnoughFunds`) by type of the transaction
+    pub errors: BTreeMap<String, BTreeMap<TransactionType, u64>>,
+}
+
+impl Summary {
+    /// Records the outcome of applying a single transaction
+    pub fn record(&mut self, transaction: &Transaction, result: &Result<(), CephalopodError>) {
+        match result {
+            Ok(()) => {
+                self.applied += 1;
+                *self.applied_by_type.entry(transaction.tpe).or_default() += 1;
+            }
+            Err(CephalopodError::TransactionError { error, .. }) => {
+                self.rejected += 1;
+                match error {
+                    TransactionError::DisputeWindowExpired { .. } => self.expired_disputes += 1,
+                    TransactionError::AmountCapExceeded { .. } => self.over_cap += 1,
+                    _ => {}
+                }
+            }
+            Err(CephalopodError::IntegrityError { .. }) => {}
+        }
+        if let Err(
+            err @ (CephalopodError::TransactionError { transaction, .. }
+            | CephalopodError::IntegrityError { transaction, .. }),
+        ) = result
+        {
+            *self
+                .errors
+                .entry(err.kind().to_string())
+                .or_default()
+                .entry(transaction.tpe)
+                .or_default() += 1;
+        }
+    }
+
+    /// Number of errors of the kind, of transactions of the type or all of them
+    pub fn error_count(&self, kind: &str, tpe: Option<TransactionType>) -> u64 {
+        let counts = match self.errors.get(kind) {
+            Some(counts) => counts,
+            None => return 0,
+        };
+        match tpe {
+            Some(tpe) => counts.get(&tpe).copied().unwrap_or_default(),
+            None => counts.values().sum(),
+        }
+    }
+
+    /// Fees collected as text, e.g. `1.5 EUR, 2 USD`, with amounts in the default currency bare
+    pub fn fees(&self) -> String {
+        if self.fees_collected.is_empty() {
+            return Amount::ZERO.to_string();
+        }
+        let fees: Vec<String> = self
+            .fees_collected
+            .iter()
+            .map(|(currency, fees)| match currency.is_default() {
+                true => fees.to_string(),
+                false => format!("{} {}", fees, currency),
+            })
+            .collect();
+        fees.join(", ")
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "applied: {}, rejected: {} (expired disputes: {}, over cap: {}), suspended: {} (unresolved: {}), quarantined: {}, invalid rows: {}, out of order: {}, fees collected: {}",
+            self.applied,
+            self.rejected,
+            self.expired_disputes,
+            self.over_cap,
+            self.suspended,
+            self.unresolved,
+            self.quarantined,
+            self.invalid_rows,
+            self.out_of_order,
+            self.fees()
+        )?;
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .flat_map(|(kind, counts)| {
+                counts
+                    .iter()
+                    .map(move |(tpe, count)| format!("{} of {:?}: {}", kind, tpe, count))
+            })
+            .collect();
+        if let Some(fingerprint) = self.fingerprint {
+            write!(f, ", fingerprint: {}", fingerprint)?;
+        }
+        if let Some(memory) = self.memory {
+            write!(f, ", memory: {}", memory)?;
+        }
+        if !errors.is_empty() {
+            write!(f, ", errors: {}", errors.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+//! Suspense queue for transactions referencing transactions not seen yet
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::Record;
+
+/// Tenant and id of the referenced transaction
+type Reference = (Option<u32>, u32);
+
+/// Transactions (e.g. disputes) parked until the transaction they reference arrives
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Suspense {
+    /// How many transactions the referenced transaction may arrive after the parked one, unbounded if `None`
+    lookahead: Option<u64>,
+    /// Number of transactions seen so far, i.e. the position of the current one
+    position: u64,
+    /// Mapping from tenant and referenced transaction id to parked transactions with their positions, in order of arrival
+    parked: HashMap<Reference, Vec<(u64, Record)>>,
+    /// Positions and keys of parked transactions in order of arrival, used to expire them with a lookahead
+    arrivals: VecDeque<(u64, Reference)>,
+}
+
+impl Suspense {
+    pub fn new(lookahead: Option<u64>) -> Suspense {
+        Suspense {
+            lookahead,
+            ..Default::default()
+        }
+    }
+
+    pub fn lookahead(&self) -> Option<u64> {
+        self.lookahead
+    }
+
+    /// Moves to the next transaction, returning parked transactions that waited longer than the lookahead
+    pub fn advance(&mut self) -> Vec<Record> {
+        self.position += 1;
+        let lookahead = match self.lookahead {
+            Some(lookahead) => lookahead,
+            None => return Vec::new(),
+        };
+        let current = self.position;
+        let mut expired = Vec::new();
+        while let Some(&(position, key)) = self.arrivals.front() {
+            if position + lookahead >= current {
+                break;
+            }
+            self.arrivals.pop_front();
+            // transactions of the key may have been released already
+            if let Some(parked) = self.parked.get_mut(&key) {
+                let count = parked
+                    .iter()
+                    .take_while(|(parked_at, _)| parked_at + lookahead < current)
+                    .count();
+                expired.extend(parked.drain(..count).map(|(_, record)| record));
+                if parked.is_empty() {
+                    self.parked.remove(&key);
+                }
+            }
+        }
+        expired
+    }
+
+    pub fn park(&mut self, record: Record) {
+        let key = (record.transaction.tenant, record.transaction.tx);
+        if self.lookahead.is_some() {
+            self.arrivals.push_back((self.position, key));
+        }
+        self.parked
+            .entry(key)
+            .or_default()
+            .push((self.position, record));
+    }
+
+    /// Removes and returns transactions waiting for transaction `tx` of the tenant
+    pub fn release(&mut self, tenant: Option<u32>, tx: u32) -> Vec<Record> {
+        self.parked
+            .remove(&(tenant, tx))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.parked.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parked.is_empty()
+    }
+
+    /// Transactions still waiting, in order of arrival of the first one referencing each transaction
+    pub fn unresolved(&self) -> Vec<Record> {
+        let mut parked: Vec<&Vec<(u64, Record)>> = self.parked.values().collect();
+        parked.sort_by_key(|parked| parked[0].0);
+        parked
+            .into_iter()
+            .flat_map(|parked| parked.iter().map(|&(_, record)| record))
+            .collect()
+    }
+}
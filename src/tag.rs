@@ -0,0 +1,98 @@
+//! Free-form references of transactions, e.g. upstream order ids
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+const MAX_LEN: usize = 40;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum ParseTagError {
+    #[error("tag longer than {} bytes", MAX_LEN)]
+    TooLong,
+
+    #[error("tag contains a NUL character")]
+    Invalid,
+}
+
+/// Tag of up to 40 bytes of UTF-8 passed through from the input to the reports
+///
+/// Kept inline, so that transactions stay `Copy`. The default (empty) tag
+/// denotes transactions without one.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tag([u8; MAX_LEN]);
+
+impl Default for Tag {
+    fn default() -> Self {
+        Tag([0; MAX_LEN])
+    }
+}
+
+impl Tag {
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(MAX_LEN);
+        // only whole strings are ever stored
+        std::str::from_utf8(&self.0[..len]).expect("tag is not valid UTF-8")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0[0] == 0
+    }
+}
+
+impl FromStr for Tag {
+    type Err = ParseTagError;
+
+    fn from_str(s: &str) -> Result<Tag, ParseTagError> {
+        if s.len() > MAX_LEN {
+            return Err(ParseTagError::TooLong);
+        }
+        if s.contains('\0') {
+            return Err(ParseTagError::Invalid);
+        }
+        let mut tag = [0; MAX_LEN];
+        tag[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Tag(tag))
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl Serialize for Tag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+struct TagVisitor;
+
+impl<'de> Visitor<'de> for TagVisitor {
+    type Value = Tag;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a tag")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Tag, E> {
+        v.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Tag, D::Error> {
+        deserializer.deserialize_str(TagVisitor)
+    }
+}
@@ -0,0 +1,176 @@
+//! Rendering of the accounts and the summary with user-supplied Handlebars templates
+//!
+//! Templates get the id of the run, the rows of the accounts export and the
+//! summary, so that any text format a counterparty asks for, e.g. a
+//! fixed-width bank file, can be produced without changes to the engine:
+//!
+//! ```text
+//! {{#each accounts}}{{pad_left client 5 fill="0"}}{{pad_right currency 3}}{{pad_left (minor total 2) 15 fill="0"}}
+//! {{/each}}TRAILER{{pad_left summary.applied 10 fill="0"}}
+//! ```
+//!
+//! Besides the built-in helpers of Handlebars, there are:
+//! - `pad_left value width [fill=" "]` and `pad_right value width [fill=" "]`, padding the value
+//!   to the width and cutting it to the width if it's longer
+//! - `minor amount places`, the amount in minor units with `places` decimal places, e.g. `150`
+//!   for `1.5` with 2, failing if the amount has more decimal places
+//!
+//! Output isn't HTML-escaped and referring to a missing field is an error.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, JsonRender, JsonValue, PathAndJson, RenderContext,
+    RenderError, RenderErrorReason, ScopedJson, TemplateError,
+};
+use serde::Serialize;
+
+use crate::amount;
+use crate::export::ExportedClient;
+use crate::summary::Summary;
+
+const NAME: &str = "template";
+
+/// Data available to templates
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateContext<'a> {
+    pub run_id: &'a str,
+    /// Rows of the accounts export, one per client and currency
+    pub accounts: Vec<ExportedClient>,
+    pub summary: &'a Summary,
+}
+
+/// Compiled template with the helpers registered
+#[derive(Clone)]
+pub struct Template {
+    registry: Handlebars<'static>,
+}
+
+impl fmt::Debug for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Template").finish_non_exhaustive()
+    }
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, TemplateError> {
+        let mut registry = Handlebars::new();
+        registry.set_strict_mode(true);
+        registry.register_escape_fn(handlebars::no_escape);
+        registry.register_helper("pad_left", Box::new(TextHelper(pad_left)));
+        registry.register_helper("pad_right", Box::new(TextHelper(pad_right)));
+        registry.register_helper("minor", Box::new(TextHelper(minor)));
+        registry.register_template_string(NAME, source)?;
+        Ok(Template { registry })
+    }
+
+    pub fn render<W: Write>(&self, context: &TemplateContext, wtr: W) -> Result<(), RenderError> {
+        self.registry.render_to_write(NAME, context, wtr)
+    }
+}
+
+/// Helper writing text computed from its parameters, also usable in subexpressions
+struct TextHelper(fn(&Helper) -> Result<String, RenderError>);
+
+impl HelperDef for TextHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        (self.0)(h).map(|text| ScopedJson::Derived(JsonValue::String(text)))
+    }
+}
+
+/// Parameter of the helper, failing if it refers to a missing field
+fn param<'h, 'rc>(
+    h: &'h Helper<'rc>,
+    name: &'static str,
+    idx: usize,
+) -> Result<&'h PathAndJson<'rc>, RenderError> {
+    let param = h
+        .param(idx)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex(name, idx))?;
+    if param.is_value_missing() {
+        return Err(RenderError::strict_error(param.relative_path()));
+    }
+    Ok(param)
+}
+
+/// Value of the parameter as text, strings without quotes
+fn text_param(h: &Helper, name: &'static str, idx: usize) -> Result<String, RenderError> {
+    Ok(param(h, name, idx)?.value().render())
+}
+
+fn u64_param(h: &Helper, name: &'static str, idx: usize) -> Result<u64, RenderError> {
+    param(h, name, idx)?
+        .value()
+        .as_u64()
+        .ok_or_else(|| RenderErrorReason::InvalidParamType("non-negative integer").into())
+}
+
+/// Value padded or cut to the width given by the parameters of `pad_left` or `pad_right`
+fn padded(h: &Helper, name: &'static str, left: bool) -> Result<String, RenderError> {
+    let value = text_param(h, name, 0)?;
+    let width = u64_param(h, name, 1)? as usize;
+    let fill = match h.hash_get("fill") {
+        Some(fill) => {
+            let fill = fill.value().render();
+            let mut chars = fill.chars();
+            match (chars.next(), chars.next()) {
+                (Some(fill), None) => fill,
+                _ => return Err(RenderErrorReason::InvalidParamType("single character").into()),
+            }
+        }
+        None => ' ',
+    };
+    let len = value.chars().count();
+    if len >= width {
+        return Ok(value.chars().take(width).collect());
+    }
+    let padding: String = std::iter::repeat_n(fill, width - len).collect();
+    Ok(if left {
+        padding + &value
+    } else {
+        value + &padding
+    })
+}
+
+fn pad_left(h: &Helper) -> Result<String, RenderError> {
+    padded(h, "pad_left", true)
+}
+
+fn pad_right(h: &Helper) -> Result<String, RenderError> {
+    padded(h, "pad_right", false)
+}
+
+fn minor(h: &Helper) -> Result<String, RenderError> {
+    let value = text_param(h, "minor", 0)?;
+    let places = u64_param(h, "minor", 1)?;
+    let invalid = || {
+        RenderErrorReason::Other(format!(
+            "{} can't be written with {} decimal places",
+            value, places
+        ))
+    };
+    let (mut mantissa, scale) = amount::parse_fixed(&value).ok_or_else(invalid)?;
+    let mut scale = u64::from(scale);
+    // amounts are written with trailing zeros, e.g. 1.5000
+    while scale > places && mantissa % 10 == 0 {
+        mantissa /= 10;
+        scale -= 1;
+    }
+    if scale > places {
+        return Err(invalid().into());
+    }
+    let minor = u32::try_from(places - scale)
+        .ok()
+        .and_then(|exp| 10i64.checked_pow(exp))
+        .and_then(|factor| mantissa.checked_mul(factor))
+        .ok_or_else(invalid)?;
+    Ok(minor.to_string())
+}
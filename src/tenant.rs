@@ -0,0 +1,260 @@
+//! Partitioning of the engine by tenant (operator or merchant)
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::fingerprint::Fingerprint;
+use crate::memory::MemoryUsage;
+use crate::model::{
+    Balance, CephalopodError, ClientTotals, IntegrityError, PruneOptions, Pruned, State,
+    Transaction,
+};
+use crate::policy::{ClientSettings, Policy};
+use crate::snapshot::{self, SnapshotError};
+use crate::storage::{StorageError, StorageOpener};
+
+/// Lifetime totals of a client in a single currency along with its dispute ratio, as reported
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientExposure {
+    pub tenant: Option<u32>,
+    pub client: u16,
+    pub currency: Currency,
+    pub deposited: Amount,
+    pub disputed: Amount,
+    pub charged_back: Amount,
+    /// Disputed amount per deposited amount, empty if nothing has been deposited or if the
+    /// totals are saturated
+    pub dispute_ratio: Option<f64>,
+    /// Whether any of the totals has been capped at the largest amount
+    pub saturated: bool,
+}
+
+impl ClientExposure {
+    fn new(tenant: Option<u32>, client: u16, currency: Currency, totals: &ClientTotals) -> Self {
+        ClientExposure {
+            tenant,
+            client,
+            currency,
+            deposited: totals.deposited,
+            disputed: totals.disputed,
+            charged_back: totals.charged_back,
+            dispute_ratio: totals.dispute_ratio(),
+            saturated: totals.saturated,
+        }
+    }
+}
+
+/// Fully separate states of all tenants, created on first use
+///
+/// Transactions without a tenant belong to the default tenant (`None`). All
+/// tenants share the policy and client settings.
+#[derive(Serialize, Deserialize)]
+pub struct Tenants {
+    policy: Policy,
+    client_settings: Vec<ClientSettings>,
+    states: BTreeMap<Option<u32>, State>,
+    /// Opens the storage of a tenant, and the cache capacity of its state
+    #[serde(skip)]
+    storage: Option<(StorageOpener, usize)>,
+}
+
+impl Tenants {
+    pub fn new(policy: Policy) -> Tenants {
+        Tenants {
+            policy,
+            client_settings: Vec::new(),
+            states: BTreeMap::new(),
+            storage: None,
+        }
+    }
+
+    /// Creates tenants with states persisted in storages opened with `open`
+    ///
+    /// See `State::open` for the meaning of `cache_capacity`.
+    pub fn with_storage(policy: Policy, open: StorageOpener, cache_capacity: usize) -> Tenants {
+        Tenants {
+            storage: Some((open, cache_capacity)),
+            ..Tenants::new(policy)
+        }
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Replaces the business rules of every tenant, e.g. after loading a snapshot
+    pub fn set_policy(&mut self, policy: Policy) {
+        for state in self.states.values_mut() {
+            state.set_policy(policy.clone());
+        }
+        self.policy = policy;
+    }
+
+    /// Writes a binary snapshot of the states of all tenants to `path`
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        snapshot::save(path, self)
+    }
+
+    /// Restores the states of all tenants from a snapshot written by `save`
+    pub fn load(path: &Path) -> Result<Tenants, SnapshotError> {
+        snapshot::load(path)
+    }
+
+    /// Overrides the policy for a single client of every tenant
+    pub fn set_client_settings(&mut self, settings: ClientSettings) {
+        for state in self.states.values_mut() {
+            state.set_client_settings(settings.clone());
+        }
+        self.client_settings.push(settings);
+    }
+
+    /// Returns the state of the tenant, creating (or opening from the storage) it if necessary
+    pub fn state_mut(&mut self, tenant: Option<u32>) -> Result<&mut State, StorageError> {
+        if !self.states.contains_key(&tenant) {
+            let mut state = match &mut self.storage {
+                Some((open, cache_capacity)) => {
+                    State::open(open(tenant)?, self.policy.clone(), *cache_capacity)?
+                }
+                None => State::with_policy(self.policy.clone()),
+            };
+            for settings in &self.client_settings {
+                state.set_client_settings(settings.clone());
+            }
+            self.states.insert(tenant, state);
+        }
+        Ok(self
+            .states
+            .get_mut(&tenant)
+            .expect("state has just been inserted"))
+    }
+
+    /// Returns the state of the tenant, if it has been created
+    pub fn state(&self, tenant: Option<u32>) -> Option<&State> {
+        self.states.get(&tenant)
+    }
+
+    /// Seeds the account of a client of the tenant, see `State::seed_account`
+    pub fn seed_account(
+        &mut self,
+        tenant: Option<u32>,
+        client: u16,
+        currency: Currency,
+        balance: Balance,
+        locked: bool,
+        flags: &BTreeSet<u32>,
+    ) -> Result<(), StorageError> {
+        self.state_mut(tenant)?
+            .seed_account(client, currency, balance, locked, flags);
+        Ok(())
+    }
+
+    /// Applies a transaction to the state of its tenant
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), CephalopodError> {
+        let state = self.state_mut(tx.tenant).map_err(|err| {
+            error!(
+                "Storage error while processing transaction {}: {}",
+                tx.tx, err
+            );
+            CephalopodError::IntegrityError {
+                transaction: *tx,
+                error: IntegrityError::StorageFailure { tx: tx.tx },
+            }
+        })?;
+        state.apply_transaction(tx)
+    }
+
+    /// Persists states of all tenants backed by storage
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        for state in self.states.values_mut() {
+            state.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Drops transactions from the histories of all tenants, see `State::prune`
+    pub fn prune(&mut self, options: &PruneOptions) -> Pruned {
+        let mut pruned = Pruned::default();
+        for state in self.states.values_mut() {
+            pruned += state.prune(options);
+        }
+        pruned
+    }
+
+    /// Iterates over states of all tenants, ordered by tenant
+    pub fn iter(&self) -> impl Iterator<Item = (Option<u32>, &State)> {
+        self.states.iter().map(|(&tenant, state)| (tenant, state))
+    }
+
+    /// Stable hash of states of all tenants, see `State::fingerprint`
+    pub fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = Fingerprint::default();
+        for (tenant, state) in self.iter() {
+            fingerprint.add(&(tenant, state.fingerprint().value()));
+        }
+        fingerprint
+    }
+
+    /// Lifetime totals of the client of the tenant per currency, empty if it has none
+    pub fn exposure(&self, tenant: Option<u32>, client: u16) -> Vec<ClientExposure> {
+        let totals = self
+            .state(tenant)
+            .map(|state| state.client_totals(client))
+            .unwrap_or_default();
+        totals
+            .iter()
+            .map(|(&currency, totals)| ClientExposure::new(tenant, client, currency, totals))
+            .collect()
+    }
+
+    /// Lifetime totals of all clients, ordered by tenant, client and currency
+    pub fn exposures(&self) -> Vec<ClientExposure> {
+        let mut exposures: Vec<ClientExposure> = self
+            .iter()
+            .flat_map(|(tenant, state)| {
+                state.iter_totals().flat_map(move |(&client, totals)| {
+                    totals.iter().map(move |(&currency, totals)| {
+                        ClientExposure::new(tenant, client, currency, totals)
+                    })
+                })
+            })
+            .collect();
+        exposures.sort_by_key(|exposure| (exposure.tenant, exposure.client, exposure.currency));
+        exposures
+    }
+
+    /// Totals of fees charged so far by all tenants per currency
+    ///
+    /// A total which doesn't fit in `Amount` is logged and reported as the largest amount.
+    pub fn fees_collected(&self) -> BTreeMap<Currency, Amount> {
+        let mut totals: BTreeMap<Currency, Amount> = BTreeMap::new();
+        for (&currency, &fees) in self
+            .states
+            .values()
+            .flat_map(|state| state.fees_collected())
+        {
+            let total = totals.entry(currency).or_default();
+            *total = total.checked_add(fees).unwrap_or_else(|| {
+                error!(
+                    "Fees collected in currency '{}' by all tenants overflow, reporting the largest amount",
+                    currency
+                );
+                Amount::MAX
+            });
+        }
+        totals
+    }
+
+    /// Approximate memory taken by states of all tenants, see `State::memory_usage`
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.states
+            .values()
+            .fold(MemoryUsage::default(), |total, state| {
+                total + state.memory_usage()
+            })
+    }
+}
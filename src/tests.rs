@@ -1,27 +1,115 @@
+use super::activity::{Activity, Thresholds};
+use super::amount::{parse_amount, parse_fixed, parse_minor_units, Amount};
+#[cfg(feature = "cli")]
+use super::anonymize::{Anonymizer, Ids};
+use super::audit::{Audit, AuditFailure};
+use super::currency::Currency;
+#[cfg(feature = "cli")]
+use super::export::{self, ExportedClient};
+#[cfg(feature = "cli")]
+use super::extract::{self, RecordIds};
+use super::fingerprint::Trail;
+#[cfg(any(feature = "wasm", feature = "ffi", feature = "node"))]
+use super::json;
+use super::ledger::{Ledger, LedgerAccount, LedgerError};
+#[cfg(feature = "cli")]
+use super::manifest::{self, DigestWriter, FileDigest};
+#[cfg(feature = "cli")]
+use super::merge::{self, Conflict};
+#[cfg(feature = "cli")]
+use super::metrics::{Metrics, MetricsFormat, Progress};
 use super::model::{
-    Account, CephalopodError, State, Transaction, TransactionError, TransactionType,
+    Account, Balance, CephalopodError, ClientTotals, IntegrityError, Leg, PruneOptions, Pruned,
+    Record, State, Transaction, TransactionError, TransactionState, TransactionType,
 };
+#[cfg(feature = "cli")]
+use super::normalize::{self, Encoding, Fixes, NormalizeOptions};
+use super::ordering::{OrderingScope, OutOfOrderAction, Sequencer};
+use super::policy::{ClientSettings, Policy, TransitionOutcome};
+use super::processor::{Processor, Severity};
+use super::quarantine::Quarantine;
+#[cfg(feature = "cli")]
+use super::reconcile::{self, ExpectedBalance, Tolerance};
+#[cfg(feature = "cli")]
+use super::repl::Repl;
+#[cfg(feature = "cli")]
+use super::report::{self, SummaryFormat};
+use super::rules::{RuleAction, Rules, RulesConfig};
+use super::scenario::Scenario;
+use super::schema::{FieldError, Schema, SchemaError, SchemaVersion};
+#[cfg(feature = "graphql")]
+use super::server::schema;
+#[cfg(feature = "server")]
+use super::server::EngineHandle;
+#[cfg(feature = "grpc")]
+use super::server::{proto, GrpcService};
+#[cfg(feature = "server")]
+use super::server::{ClientAccount, Subscription};
+#[cfg(feature = "webhooks")]
+use super::server::{EventKind, Retry, Webhook};
+use super::settlement::{Settlement, SettlementError};
+use super::snapshot::{self, SnapshotError, SNAPSHOT_VERSION};
+#[cfg(feature = "cli")]
+use super::split;
+use super::storage::{Entry, KeyValueStore, MemoryStorage, StorageError, StorageLocation};
+use super::summary::Summary;
+use super::suspense::Suspense;
+use super::tag::Tag;
+#[cfg(feature = "templates")]
+use super::template::{Template, TemplateContext};
+use super::tenant::{ClientExposure, Tenants};
+#[cfg(feature = "cli")]
+use super::verify::{self, Divergence};
+use super::warnings::{Repeated, Warnings};
+#[cfg(feature = "wasm")]
+use super::wasm::Engine as WasmEngine;
+use super::workload::{Workload, WorkloadConfig};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use assert_matches::assert_matches;
+use proptest::prelude::*;
+use proptest::{collection, option};
+#[cfg(not(feature = "minor-units"))]
 use rust_decimal::prelude::*;
 
+mod reference;
+
+use reference::{ReferenceAccount, ReferenceEngine};
+
 // runs all transactions and returns the final state and the Result of the last one
 // fails if one of previous transactions fails
 fn run_transactions(tx: Vec<Transaction>) -> (State, Result<(), CephalopodError>) {
-    let mut state = State::new();
-    let last_result = tx
-        .iter()
-        .map(|tx| state.apply_transaction(tx))
-        .fold(Ok(()), |prev, cur| {
-            prev.unwrap();
-            cur
-        });
+    run_transactions_with(Policy::default(), tx)
+}
+
+// same as run_transactions, but with non-default policy
+fn run_transactions_with(
+    policy: Policy,
+    tx: Vec<Transaction>,
+) -> (State, Result<(), CephalopodError>) {
+    let mut state = State::with_policy(policy);
+    let (last, previous) = tx.split_last().expect("empty transaction list");
+    for tx in previous {
+        state.apply_transaction(tx).unwrap();
+    }
+    let last_result = state.apply_transaction(last);
     (state, last_result)
 }
 
-// creates Decimal with value amount * 0.01
-fn dec(amount: i64) -> Decimal {
-    Decimal::new(amount, 2)
+// returns the client's balance in the default currency and whether the account is locked
+fn balance(state: &State, client: u16) -> Option<(&Balance, bool)> {
+    let account = state.accounts.get(&client)?;
+    Some((account.balances.get(&Currency::default())?, account.locked))
+}
+
+// creates Amount with value amount * 0.01
+fn dec(amount: i64) -> Amount {
+    Amount::new(amount, 2)
 }
 
 fn tx0(tpe: TransactionType, client: u16, tx: u32) -> Transaction {
@@ -30,15 +118,33 @@ fn tx0(tpe: TransactionType, client: u16, tx: u32) -> Transaction {
         client,
         tx,
         amount: None,
+        to: None,
+        reason: None,
+        timestamp: None,
+        currency: Currency::default(),
+        tenant: None,
+        tag: Tag::default(),
     }
 }
 
 fn tx(tpe: TransactionType, client: u16, tx: u32, amount: i64) -> Transaction {
     Transaction {
-        tpe,
-        client,
-        tx,
         amount: Some(dec(amount)),
+        ..tx0(tpe, client, tx)
+    }
+}
+
+fn transfer(from: u16, to: u16, tx: u32, amount: i64) -> Transaction {
+    Transaction {
+        to: Some(to),
+        ..self::tx(TransactionType::Transfer, from, tx, amount)
+    }
+}
+
+fn adjustment(client: u16, tx: u32, amount: i64, reason: u32) -> Transaction {
+    Transaction {
+        reason: Some(reason),
+        ..self::tx(TransactionType::Adjustment, client, tx, amount)
     }
 }
 
@@ -61,7 +167,7 @@ fn withdrawals_and_deposits_should_work() {
     );
 
     assert_eq!(
-        state.accounts.get(&1).map(|acc| acc.available),
+        balance(&state, 1).map(|(balance, _)| balance.available),
         Some(dec(sequence.iter().sum()))
     );
 }
@@ -88,6 +194,61 @@ fn negative_amount_deposits_withdrawals_should_fail() {
     }
 }
 
+#[test]
+fn overflowing_deposit_should_fail_with_integrity_error() {
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = Transaction {
+        amount: Some(max),
+        ..tx0(TransactionType::Deposit, 1, 1)
+    };
+    let (state, res) = run_transactions(vec![huge, tx(TransactionType::Deposit, 1, 2, 100)]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::IntegrityError {
+            error: IntegrityError::AmountOverflow { tx: 2 },
+            ..
+        })
+    );
+    assert_eq!(
+        balance(&state, 1).map(|(balance, _)| balance.available),
+        Some(max)
+    );
+}
+
+#[test]
+fn lifetime_totals_should_saturate_instead_of_overflowing() {
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = |tpe, tx| Transaction {
+        amount: Some(max),
+        ..tx0(tpe, 1, tx)
+    };
+    let (state, res) = run_transactions(vec![
+        huge(TransactionType::Deposit, 1),
+        huge(TransactionType::Withdrawal, 2),
+        huge(TransactionType::Deposit, 3),
+        tx0(TransactionType::Dispute, 1, 3),
+    ]);
+
+    res.unwrap();
+    let totals = state.client_totals(1)[&Currency::default()];
+    assert_eq!(totals.deposited, max);
+    assert_eq!(totals.disputed, max);
+    // the ratio of clamped totals would be made up
+    assert!(totals.saturated);
+    assert_eq!(totals.dispute_ratio(), None);
+    assert_eq!(
+        balance(&state, 1).map(|(balance, _)| balance.held),
+        Some(max)
+    );
+}
+
 #[test]
 fn withdrawal_should_fail_for_unknown_account() {
     let (_, res) = run_transactions(vec![tx(TransactionType::Withdrawal, 1, 1, 100)]);
@@ -129,7 +290,7 @@ fn dispute_resolve_should_work() {
     ]);
 
     assert_matches!(res, Ok(..));
-    assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: false}) if *available == dec(100) && *held == Decimal::ZERO);
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(100) && *held == Amount::ZERO);
 }
 
 #[test]
@@ -143,7 +304,7 @@ fn dispute_chargeback_should_work() {
     ]);
 
     assert_matches!(res, Ok(..));
-    assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: true}) if *available == Decimal::ZERO && *held == Decimal::ZERO);
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, true)) if *available == Amount::ZERO && *held == Amount::ZERO);
 }
 
 #[test]
@@ -180,7 +341,7 @@ fn dispute_should_fail_when_not_enough_funds() {
             ..
         })
     );
-    assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: false}) if *available == dec(50) && *held == Decimal::ZERO);
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(50) && *held == Amount::ZERO);
 }
 
 #[test]
@@ -198,7 +359,7 @@ fn dispute_should_fail_for_withdrawal() {
             ..
         })
     );
-    assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: false}) if *available == dec(50) && *held == Decimal::ZERO);
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(50) && *held == Amount::ZERO);
 }
 
 #[test]
@@ -216,7 +377,7 @@ fn resolve_and_chargeback_should_fail_without_dispute() {
                 ..
             })
         );
-        assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: false}) if *available == dec(100) && *held == Decimal::ZERO);
+        assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(100) && *held == Amount::ZERO);
     }
 }
 
@@ -235,7 +396,7 @@ fn dispute_should_fail_for_client_id_mismatch() {
             ..
         })
     );
-    assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: false}) if *available == dec(100) && *held == Decimal::ZERO);
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(100) && *held == Amount::ZERO);
 }
 
 #[test]
@@ -255,7 +416,7 @@ fn resolve_chargeback_should_fail_for_client_id_mismatch() {
                 ..
             })
         );
-        assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: false}) if *available == Decimal::ZERO && *held == dec(100));
+        assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == Amount::ZERO && *held == dec(100));
     }
 }
 
@@ -289,6 +450,5007 @@ fn transactions_cannot_be_applied_to_locked_account() {
                 ..
             })
         );
-        assert_matches!(state.accounts.get(&1), Some(Account { available, held, locked: true }) if *available == dec(130) && *held == dec(120));
+        assert_matches!(balance(&state, 1), Some((Balance { available, held }, true)) if *available == dec(130) && *held == dec(120));
+    }
+}
+
+#[test]
+fn deposits_to_locked_account_should_be_allowed_by_policy() {
+    let policy = Policy {
+        allow_deposits_to_locked: true,
+        allow_dispute_overdraft: true,
+        ..Policy::default()
+    };
+    let initial = [
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Withdrawal, 1, 2, 80),
+        tx(TransactionType::Deposit, 2, 3, 100),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Chargeback, 1, 1),
+        // repays the negative balance left by the chargeback
+        tx(TransactionType::Deposit, 1, 4, 90),
+    ];
+    let (state, res) = run_transactions_with(policy.clone(), initial.to_vec());
+    res.unwrap();
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, true)) if *available == dec(10) && *held == Amount::ZERO);
+
+    for next in [
+        tx(TransactionType::Withdrawal, 1, 5, 10),
+        transfer(1, 2, 5, 10),
+        transfer(2, 1, 5, 10),
+        tx0(TransactionType::Dispute, 1, 4),
+    ] {
+        let (state, res) = run_transactions_with(
+            policy.clone(),
+            initial.iter().chain([next].iter()).cloned().collect(),
+        );
+        assert_matches!(
+            res,
+            Err(CephalopodError::TransactionError {
+                error: TransactionError::AccountLocked { .. },
+                ..
+            })
+        );
+        assert_matches!(balance(&state, 1), Some((Balance { available, .. }, true)) if *available == dec(10));
+    }
+}
+
+#[test]
+fn transitions_from_policy_should_replace_built_in_ones() {
+    let policy: Policy = serde_json::from_str(
+        r#"{"allow_dispute_overdraft": true, "unlock_on_representment": true, "transitions": {
+            "dispute": {"from": ["Deposited", "Withdrawn", "Represented"], "targets": ["deposit", "withdrawal"]},
+            "resolve": {"from": ["Disputed"], "targets": ["deposit", "withdrawal"]},
+            "chargeback": {"from": ["Disputed"], "targets": ["deposit", "withdrawal"]},
+            "representment": {"from": ["Chargebacked"], "targets": ["deposit", "withdrawal"]},
+            "reversal": {"from": ["Deposited"], "targets": ["deposit"]}
+        }}"#,
+    )
+    .unwrap();
+    let initial = vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Withdrawal, 1, 2, 30),
+        tx(TransactionType::Deposit, 2, 3, 100),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx0(TransactionType::Resolve, 1, 2),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Chargeback, 1, 1),
+        tx0(TransactionType::Representment, 1, 1),
+        tx0(TransactionType::Dispute, 1, 1),
+    ];
+    let (state, res) = run_transactions_with(policy.clone(), initial.clone());
+    res.unwrap();
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(-30) && *held == dec(100));
+
+    let (_, res) = run_transactions_with(
+        policy.clone(),
+        [
+            &initial[..3],
+            &[transfer(2, 1, 4, 10), tx0(TransactionType::Dispute, 1, 4)],
+        ]
+        .concat(),
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransitionNotAllowed {
+                tx: 4,
+                target: TransactionType::Transfer
+            },
+            ..
+        })
+    );
+    // resolved transactions can't be disputed again
+    let (_, res) = run_transactions_with(
+        policy,
+        [&initial[..5], &[tx0(TransactionType::Dispute, 1, 2)]].concat(),
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Resolved
+            },
+            ..
+        })
+    );
+
+    // charged back transactions can't be resolved without breaking the balances
+    assert!(serde_json::from_str::<Policy>(
+        r#"{"transitions": {
+            "dispute": {"from": ["Deposited"], "targets": ["deposit"]},
+            "resolve": {"from": ["Disputed", "Chargebacked"], "targets": ["deposit"]},
+            "chargeback": {"from": ["Disputed"], "targets": ["deposit"]},
+            "representment": {"from": ["Chargebacked"], "targets": ["deposit"]},
+            "reversal": {"from": ["Deposited"], "targets": ["deposit"]}
+        }}"#,
+    )
+    .is_err());
+}
+
+#[cfg(not(feature = "minor-units"))]
+#[test]
+fn fast_amount_parsing_should_match_general_parser() {
+    for input in [
+        "0",
+        "1",
+        "1.0",
+        "1.5",
+        "1.50",
+        "12.3456",
+        "-20.01",
+        "0.0001",
+        "999999999999999999",
+    ] {
+        let expected = Decimal::from_str(input).unwrap();
+        let parsed = parse_amount(input).unwrap();
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.to_string(), expected.to_string());
+    }
+}
+
+#[test]
+fn fast_amount_parsing_should_defer_unusual_input() {
+    for input in [
+        "",
+        "-",
+        ".5",
+        "1.",
+        "1.23456",
+        "1e5",
+        " 1",
+        "1 ",
+        "+1",
+        "1.2.3",
+        "1234567890123456789",
+    ] {
+        assert_eq!(parse_fixed(input), None, "input: {:?}", input);
+    }
+    assert_matches!(parse_amount("abc"), Err(..));
+}
+
+#[cfg(not(feature = "minor-units"))]
+#[test]
+fn general_parser_should_keep_high_precision() {
+    assert_eq!(parse_amount("1.23456").unwrap(), Decimal::new(123456, 5));
+}
+
+#[cfg(not(feature = "minor-units"))]
+#[test]
+fn excess_precision_should_be_rejected_or_rounded() {
+    use super::policy::ExcessPrecision;
+
+    let deposit = |amount: &str| Transaction {
+        amount: Some(parse_amount(amount).unwrap()),
+        ..tx0(TransactionType::Deposit, 1, 1)
+    };
+    let with = |excess_precision| Policy {
+        excess_precision,
+        ..Policy::default()
+    };
+
+    let (_, res) = run_transactions_with(with(ExcessPrecision::Reject), vec![deposit("1.23445")]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AmountTooPrecise { .. },
+            ..
+        })
+    );
+    let (_, res) = run_transactions_with(with(ExcessPrecision::Reject), vec![deposit("1.50000")]);
+    assert_matches!(res, Ok(()));
+
+    for (excess_precision, expected) in [
+        (ExcessPrecision::Keep, "1.23445"),
+        (ExcessPrecision::RoundHalfEven, "1.2344"),
+        (ExcessPrecision::RoundHalfUp, "1.2345"),
+        (ExcessPrecision::Truncate, "1.2344"),
+    ] {
+        let (state, res) = run_transactions_with(with(excess_precision), vec![deposit("1.23445")]);
+        assert_matches!(res, Ok(()));
+        let expected = parse_amount(expected).unwrap();
+        assert_eq!(
+            balance(&state, 1).map(|(balance, _)| balance.available),
+            Some(expected)
+        );
+        assert_eq!(
+            state
+                .transaction(1)
+                .and_then(|transaction| transaction.amount),
+            Some(expected)
+        );
+    }
+}
+
+#[cfg(feature = "ordered")]
+#[test]
+fn ordered_state_should_iterate_in_order_of_keys() {
+    let clients = [7, 3, 500, 1, 42];
+    let mut state = State::new();
+    for (tx, &client) in clients.iter().enumerate() {
+        state
+            .apply_transaction(&self::tx(TransactionType::Deposit, client, tx as u32, 100))
+            .unwrap();
+    }
+
+    let iterated: Vec<u16> = state.iter_clients().map(|(&client, _)| client).collect();
+    assert_eq!(iterated, [1, 3, 7, 42, 500]);
+    let transactions: Vec<u32> = state
+        .transactions()
+        .map(|transaction| transaction.tx)
+        .collect();
+    assert_eq!(transactions, [0, 1, 2, 3, 4]);
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn minor_units_should_reject_high_precision() {
+    use super::amount::ParseAmountError;
+
+    assert_eq!(parse_amount("1.50000").unwrap(), dec(150));
+    assert_eq!(parse_amount("1.23456"), Err(ParseAmountError::TooPrecise));
+    assert_eq!(
+        parse_amount("100000000000000000000"),
+        Err(ParseAmountError::OutOfRange)
+    );
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn minor_units_should_display_like_decimal() {
+    for (units, expected) in [
+        (0, "0"),
+        (15000, "1.5"),
+        (-1, "-0.0001"),
+        (123456, "12.3456"),
+    ] {
+        assert_eq!(Amount::from_minor_units(units).to_string(), expected);
+    }
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn minor_units_should_fail_instead_of_overflowing() {
+    use super::amount::ParseAmountError;
+
+    // the range is symmetric, so that negating an amount always fits
+    assert_eq!(
+        parse_amount("-922337203685477.5808"),
+        Err(ParseAmountError::OutOfRange)
+    );
+    let max = Amount::from_minor_units(i64::MAX);
+    assert_eq!((-max).checked_sub(Amount::from_minor_units(1)), None);
+
+    let deposit = |tx| Transaction {
+        amount: Some(parse_amount("900000000000000").unwrap()),
+        ..timed(tx0(TransactionType::Deposit, 1, tx), 0)
+    };
+    let policy = Policy {
+        max_daily_total: Some(max),
+        ..Policy::default()
+    };
+    let (state, res) = run_transactions_with(policy, vec![deposit(1), deposit(2)]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::IntegrityError {
+            error: IntegrityError::AmountOverflow { tx: 2 },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if available.to_string() == "900000000000000");
+}
+
+#[test]
+fn amounts_should_parse_to_minor_units() {
+    assert_eq!(parse_minor_units("1.5"), Some(15000));
+    assert_eq!(parse_minor_units("-0.0001"), Some(-1));
+    assert_eq!(parse_minor_units("42"), Some(420000));
+    assert_eq!(parse_minor_units("1.23456"), None);
+}
+
+#[test]
+fn transactions_should_deserialize_from_csv() {
+    let input = "type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1,\n";
+    let mut rdr = csv::Reader::from_reader(input.as_bytes());
+    let txs: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+
+    assert_matches!(
+        txs.as_slice(),
+        [
+            Transaction { tpe: TransactionType::Deposit, client: 1, tx: 1, amount: Some(a), .. },
+            Transaction { tpe: TransactionType::Dispute, client: 1, tx: 1, amount: None, .. },
+        ] if *a == dec(150)
+    );
+}
+
+#[test]
+fn currency_should_deserialize_from_csv() {
+    let input = "type,client,tx,amount,currency\ndeposit,1,1,1.5,usd\ndispute,1,1,,\n";
+    let mut rdr = csv::Reader::from_reader(input.as_bytes());
+    let txs: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+
+    assert_eq!(txs[0].currency.as_str(), "USD");
+    assert_eq!(txs[1].currency, Currency::default());
+    assert!("TOOLONGCODE".parse::<Currency>().is_err());
+}
+
+fn withdrawal_disputes() -> Policy {
+    Policy {
+        allow_withdrawal_disputes: true,
+        ..Policy::default()
+    }
+}
+
+#[test]
+fn withdrawal_dispute_should_credit_held_funds() {
+    let (state, res) = run_transactions_with(
+        withdrawal_disputes(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, 50),
+            tx0(TransactionType::Dispute, 1, 2),
+        ],
+    );
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(50) && *held == dec(50));
+}
+
+#[test]
+fn withdrawal_dispute_resolve_should_drop_hold() {
+    let (state, res) = run_transactions_with(
+        withdrawal_disputes(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, 50),
+            tx0(TransactionType::Dispute, 1, 2),
+            tx0(TransactionType::Resolve, 1, 2),
+        ],
+    );
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(50) && *held == Amount::ZERO);
+}
+
+#[test]
+fn withdrawal_dispute_chargeback_should_restore_funds() {
+    let (state, res) = run_transactions_with(
+        withdrawal_disputes(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, 50),
+            tx0(TransactionType::Dispute, 1, 2),
+            tx0(TransactionType::Chargeback, 1, 2),
+        ],
+    );
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, true)) if *available == dec(100) && *held == Amount::ZERO);
+}
+
+#[test]
+fn dispute_overdraft_should_allow_negative_available() {
+    let policy = Policy {
+        allow_dispute_overdraft: true,
+        ..Policy::default()
+    };
+    let (state, res) = run_transactions_with(
+        policy,
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, 80),
+            tx0(TransactionType::Dispute, 1, 1),
+            tx0(TransactionType::Chargeback, 1, 1),
+        ],
+    );
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, true)) if *available == dec(-80) && *held == Amount::ZERO);
+}
+
+#[test]
+fn representment_should_restore_charged_back_funds() {
+    for unlock in [false, true] {
+        let policy = Policy {
+            unlock_on_representment: unlock,
+            ..Policy::default()
+        };
+        let (state, res) = run_transactions_with(
+            policy,
+            vec![
+                tx(TransactionType::Deposit, 1, 1, 100),
+                tx(TransactionType::Deposit, 1, 2, 30),
+                tx0(TransactionType::Dispute, 1, 1),
+                tx0(TransactionType::Chargeback, 1, 1),
+                tx0(TransactionType::Representment, 1, 1),
+            ],
+        );
+
+        assert_matches!(res, Ok(..));
+        assert_matches!(balance(&state, 1), Some((Balance { available, held }, locked)) if *available == dec(130) && *held == Amount::ZERO && locked != unlock);
     }
 }
+
+#[test]
+fn representment_should_fail_without_chargeback() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Representment, 1, 1),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState { .. },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == Amount::ZERO && *held == dec(100));
+}
+
+#[test]
+fn resolved_transaction_should_not_be_disputed_again_by_default() {
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Resolve, 1, 1),
+        tx0(TransactionType::Dispute, 1, 1),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState { .. },
+            ..
+        })
+    );
+}
+
+#[test]
+fn redispute_should_respect_limit() {
+    let policy = Policy {
+        allow_redisputes: true,
+        max_disputes: Some(2),
+        ..Policy::default()
+    };
+    let history = vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Resolve, 1, 1),
+        tx0(TransactionType::Dispute, 1, 1),
+    ];
+
+    let (state, res) = run_transactions_with(policy.clone(), history.clone());
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == Amount::ZERO && *held == dec(100));
+
+    let (state, res) = run_transactions_with(
+        policy,
+        history
+            .into_iter()
+            .chain(vec![
+                tx0(TransactionType::Resolve, 1, 1),
+                tx0(TransactionType::Dispute, 1, 1),
+            ])
+            .collect(),
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DisputeLimitReached { tx: 1, limit: 2 },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(100) && *held == Amount::ZERO);
+}
+
+#[test]
+fn unlock_should_reopen_locked_account() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 1, 2, 30),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Chargeback, 1, 1),
+        tx0(TransactionType::Unlock, 1, 3),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(30) && *held == Amount::ZERO);
+    assert_matches!(
+        state.admin_history(),
+        [Transaction {
+            tpe: TransactionType::Unlock,
+            client: 1,
+            tx: 3,
+            ..
+        }]
+    );
+}
+
+#[test]
+fn unlock_should_fail_for_unlocked_account() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Unlock, 1, 2),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountNotLocked { client: 1 },
+            ..
+        })
+    );
+    assert!(state.admin_history().is_empty());
+}
+
+#[test]
+fn transfer_should_move_funds_between_clients() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        transfer(1, 2, 2, 30),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(70));
+    assert_matches!(balance(&state, 2), Some((Balance { available, .. }, _)) if *available == dec(30));
+}
+
+#[test]
+fn transfer_should_be_atomic() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 2, 2, 100),
+        tx0(TransactionType::Dispute, 2, 2),
+        tx0(TransactionType::Chargeback, 2, 2),
+        transfer(1, 2, 3, 30),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountLocked { client: 2 },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        transfer(1, 2, 2, 130),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { .. },
+            ..
+        })
+    );
+    assert_eq!(state.accounts.get(&2), None);
+
+    // the credit overflowing the receiver's balance doesn't debit the sender
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        Transaction {
+            amount: Some(max),
+            ..tx0(TransactionType::Deposit, 2, 2)
+        },
+        transfer(1, 2, 3, 100),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::IntegrityError {
+            error: IntegrityError::AmountOverflow { tx: 3 },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+    assert_matches!(balance(&state, 2), Some((Balance { available, .. }, _)) if *available == max);
+}
+
+#[test]
+fn transfer_legs_should_be_disputed_individually() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        transfer(1, 2, 2, 30),
+        tx0(TransactionType::Dispute, 2, 2),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx0(TransactionType::Chargeback, 2, 2),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(70) && *held == dec(30));
+    assert_matches!(balance(&state, 2), Some((Balance { available, held }, true)) if *available == Amount::ZERO && *held == Amount::ZERO);
+
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        transfer(1, 2, 2, 30),
+        tx0(TransactionType::Dispute, 3, 2),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionClientMismatch { .. },
+            ..
+        })
+    );
+}
+
+#[test]
+fn fees_should_be_collected() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 2, 2, 100),
+        tx(TransactionType::Fee, 1, 3, 5),
+        tx(TransactionType::Fee, 2, 4, 7),
+        in_currency(tx(TransactionType::Deposit, 1, 5, 200), "EUR"),
+        in_currency(tx(TransactionType::Fee, 1, 6, 150), "EUR"),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(95));
+    // fees in different currencies are never added up
+    let eur: Currency = "EUR".parse().unwrap();
+    assert_eq!(
+        state.fees_collected(),
+        &BTreeMap::from([(Currency::default(), dec(12)), (eur, dec(150))])
+    );
+    let summary = Summary {
+        fees_collected: state.fees_collected().clone(),
+        ..Summary::default()
+    };
+    assert_eq!(summary.fees(), format!("{}, {} EUR", dec(12), dec(150)));
+}
+
+#[test]
+fn fee_overdraft_should_follow_policy() {
+    let txs = vec![
+        tx(TransactionType::Deposit, 1, 1, 3),
+        tx(TransactionType::Fee, 1, 2, 5),
+    ];
+
+    let (state, res) = run_transactions(txs.clone());
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { .. },
+            ..
+        })
+    );
+    assert!(state.fees_collected().is_empty());
+
+    let policy = Policy {
+        allow_fee_overdraft: true,
+        ..Policy::default()
+    };
+    let (state, res) = run_transactions_with(policy, txs);
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(-2));
+    assert_eq!(state.fees_collected()[&Currency::default()], dec(5));
+}
+
+#[test]
+fn adjustments_should_credit_and_debit() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        adjustment(1, 2, 25, 7),
+        adjustment(1, 3, -50, 8),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(75));
+    assert_eq!(state.admin_history().len(), 2);
+}
+
+#[test]
+fn adjustment_overdraft_should_follow_policy() {
+    let txs = vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        adjustment(1, 2, -150, 7),
+    ];
+
+    let (_, res) = run_transactions(txs.clone());
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { .. },
+            ..
+        })
+    );
+
+    let policy = Policy {
+        allow_adjustment_overdraft: true,
+        ..Policy::default()
+    };
+    let (state, res) = run_transactions_with(policy, txs);
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(-50));
+}
+
+#[test]
+fn adjustment_should_require_reason() {
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Adjustment, 1, 2, 10),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::ReasonNotProvided,
+            ..
+        })
+    );
+}
+
+#[test]
+fn reversal_should_undo_deposits_and_withdrawals() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 1, 2, 40),
+        tx(TransactionType::Withdrawal, 1, 3, 30),
+        tx0(TransactionType::Reversal, 1, 2),
+        tx0(TransactionType::Reversal, 1, 3),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(100) && *held == Amount::ZERO);
+}
+
+#[test]
+fn voided_transaction_should_not_be_disputed() {
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Reversal, 1, 1),
+        tx0(TransactionType::Dispute, 1, 1),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Voided
+            },
+            ..
+        })
+    );
+}
+
+#[test]
+fn disputed_transaction_should_not_be_reversed() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Reversal, 1, 1),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Disputed
+            },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, _)) if *available == Amount::ZERO && *held == dec(100));
+}
+
+#[test]
+fn authorization_should_hold_funds_until_captured() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Authorize, 1, 2, 60),
+    ]);
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, _)) if *available == dec(40) && *held == dec(60));
+
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Authorize, 1, 2, 60),
+        tx0(TransactionType::Capture, 1, 2),
+    ]);
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, _)) if *available == dec(40) && *held == Amount::ZERO);
+}
+
+#[test]
+fn voided_authorization_should_release_funds() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Authorize, 1, 2, 60),
+        tx0(TransactionType::Void, 1, 2),
+    ]);
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, _)) if *available == dec(100) && *held == Amount::ZERO);
+
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Authorize, 1, 2, 60),
+        tx0(TransactionType::Void, 1, 2),
+        tx0(TransactionType::Capture, 1, 2),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Voided
+            },
+            ..
+        })
+    );
+}
+
+#[test]
+fn capture_should_fail_for_non_authorization() {
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Capture, 1, 1),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionNotAuthorization { tx: 1 },
+            ..
+        })
+    );
+}
+
+#[test]
+fn closed_account_should_reject_transactions() {
+    let (state, res) = run_transactions(vec![
+        tx0(TransactionType::Open, 1, 1),
+        tx(TransactionType::Deposit, 1, 2, 100),
+        tx(TransactionType::Withdrawal, 1, 3, 100),
+        tx0(TransactionType::Close, 1, 4),
+        tx(TransactionType::Deposit, 1, 5, 100),
+    ]);
+
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountClosed { client: 1 },
+            ..
+        })
+    );
+    assert_matches!(state.accounts.get(&1), Some(account) if account.closed);
+
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Withdrawal, 1, 2, 100),
+        tx0(TransactionType::Close, 1, 3),
+        tx(TransactionType::Deposit, 2, 4, 100),
+        transfer(2, 1, 5, 10),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountClosed { client: 1 },
+            ..
+        })
+    );
+}
+
+#[test]
+fn close_should_fail_with_open_disputes_or_balance() {
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Close, 1, 2),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NonZeroBalance { client: 1, .. },
+            ..
+        })
+    );
+
+    let (_, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 1, 2, 0),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx(TransactionType::Withdrawal, 1, 3, 100),
+        tx0(TransactionType::Close, 1, 4),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::OpenDisputes {
+                client: 1,
+                count: 1
+            },
+            ..
+        })
+    );
+}
+
+#[test]
+fn closed_account_should_be_reopened() {
+    let (state, res) = run_transactions(vec![
+        tx0(TransactionType::Open, 1, 1),
+        tx0(TransactionType::Close, 1, 2),
+        tx0(TransactionType::Open, 1, 3),
+        tx(TransactionType::Deposit, 1, 4, 100),
+    ]);
+
+    assert_matches!(res, Ok(..));
+    assert_matches!(state.accounts.get(&1), Some(account) if !account.closed);
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+
+    let (_, res) = run_transactions(vec![
+        tx0(TransactionType::Open, 1, 1),
+        tx0(TransactionType::Open, 1, 2),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountAlreadyOpen { client: 1 },
+            ..
+        })
+    );
+}
+
+#[test]
+fn withdrawal_should_respect_overdraft_limit() {
+    let policy = Policy {
+        overdraft_limit: dec(50),
+        ..Policy::default()
+    };
+
+    let (state, res) = run_transactions_with(
+        policy.clone(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, 150),
+        ],
+    );
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(-50));
+
+    let (_, res) = run_transactions_with(
+        policy,
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, 151),
+        ],
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { available, .. },
+            ..
+        }) if available == dec(150)
+    );
+}
+
+#[test]
+fn client_settings_should_override_overdraft_limit() {
+    let mut state = State::new();
+    state.set_client_settings(ClientSettings {
+        client: 1,
+        overdraft_limit: Some(dec(20)),
+        ..ClientSettings::default()
+    });
+
+    state
+        .apply_transaction(&tx(TransactionType::Deposit, 1, 1, 100))
+        .unwrap();
+    state
+        .apply_transaction(&tx(TransactionType::Deposit, 2, 2, 100))
+        .unwrap();
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 1, 3, 120)),
+        Ok(..)
+    );
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 2, 4, 120)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { .. },
+            ..
+        })
+    );
+}
+
+#[test]
+fn withdrawal_should_keep_class_minimum_balance() {
+    let policy = Policy {
+        minimum_balances: vec![("savings".to_string(), dec(50))].into_iter().collect(),
+        ..Policy::default()
+    };
+    let mut state = State::with_policy(policy);
+    state.set_client_settings(ClientSettings {
+        client: 1,
+        class: Some("savings".to_string()),
+        ..ClientSettings::default()
+    });
+    for client in [1, 2] {
+        state
+            .apply_transaction(&tx(TransactionType::Deposit, client, client.into(), 100))
+            .unwrap();
+    }
+
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 1, 3, 60)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::MinimumBalanceBreached { minimum, remaining },
+            ..
+        }) if minimum == dec(50) && remaining == dec(40)
+    );
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 1, 4, 50)),
+        Ok(..)
+    );
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 2, 5, 100)),
+        Ok(..)
+    );
+}
+
+fn timed(tx: Transaction, timestamp: u64) -> Transaction {
+    Transaction {
+        timestamp: Some(timestamp),
+        ..tx
+    }
+}
+
+#[test]
+fn transactions_should_respect_max_amount() {
+    let policy = Policy {
+        max_amount: Some(dec(100)),
+        ..Policy::default()
+    };
+    let (_, res) = run_transactions_with(
+        policy.clone(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Deposit, 1, 2, 150),
+        ],
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AmountLimitExceeded { .. },
+            ..
+        })
+    );
+
+    // disputes are not limited
+    let (_, res) = run_transactions_with(
+        policy,
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx0(TransactionType::Dispute, 1, 1),
+        ],
+    );
+    assert_matches!(res, Ok(..));
+}
+
+#[test]
+fn transactions_should_respect_daily_total() {
+    let policy = Policy {
+        max_daily_total: Some(dec(300)),
+        ..Policy::default()
+    };
+    let day = 24 * 60 * 60;
+    let (state, res) = run_transactions_with(
+        policy,
+        vec![
+            timed(tx(TransactionType::Deposit, 1, 1, 200), day + 10),
+            timed(tx(TransactionType::Withdrawal, 1, 2, 100), day + 20),
+            timed(tx(TransactionType::Deposit, 2, 3, 300), day + 30),
+            timed(tx(TransactionType::Deposit, 1, 4, 200), 2 * day),
+            timed(tx(TransactionType::Deposit, 1, 5, 150), 2 * day + 10),
+        ],
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DailyLimitExceeded { client: 1, total, .. },
+            ..
+        }) if total == dec(350)
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(300));
+}
+
+#[test]
+fn transactions_should_respect_velocity_limit() {
+    let policy = Policy {
+        max_transactions: Some(2),
+        transaction_window: Some(60),
+        ..Policy::default()
+    };
+    let (_, res) = run_transactions_with(
+        policy.clone(),
+        vec![
+            timed(tx(TransactionType::Deposit, 1, 1, 100), 0),
+            timed(tx(TransactionType::Withdrawal, 1, 2, 10), 30),
+            timed(tx(TransactionType::Withdrawal, 1, 3, 10), 59),
+        ],
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::VelocityLimitExceeded {
+                client: 1,
+                limit: 2,
+                window: 60
+            },
+            ..
+        })
+    );
+
+    let (_, res) = run_transactions_with(
+        policy,
+        vec![
+            timed(tx(TransactionType::Deposit, 1, 1, 100), 0),
+            timed(tx(TransactionType::Withdrawal, 1, 2, 10), 30),
+            timed(tx(TransactionType::Withdrawal, 1, 3, 10), 60),
+        ],
+    );
+    assert_matches!(res, Ok(..));
+
+    // the window of the latest timestamps ends past the largest one
+    let (_, res) = run_transactions_with(
+        Policy {
+            max_transactions: Some(5),
+            ..Policy::default()
+        },
+        vec![
+            timed(tx(TransactionType::Deposit, 1, 1, 100), u64::MAX),
+            timed(tx(TransactionType::Deposit, 1, 2, 100), u64::MAX),
+        ],
+    );
+    assert_matches!(res, Ok(..));
+}
+
+#[test]
+fn client_settings_should_override_limits() {
+    let mut state = State::with_policy(Policy {
+        max_amount: Some(dec(100)),
+        ..Policy::default()
+    });
+    state.set_client_settings(ClientSettings {
+        client: 1,
+        max_amount: Some(dec(500)),
+        ..ClientSettings::default()
+    });
+
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Deposit, 1, 1, 500)),
+        Ok(..)
+    );
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Deposit, 2, 2, 500)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AmountLimitExceeded { .. },
+            ..
+        })
+    );
+}
+
+fn in_currency(tx: Transaction, currency: &str) -> Transaction {
+    Transaction {
+        currency: currency.parse().unwrap(),
+        ..tx
+    }
+}
+
+#[test]
+fn balances_should_be_kept_per_currency() {
+    let (state, res) = run_transactions(vec![
+        in_currency(tx(TransactionType::Deposit, 1, 1, 100), "USD"),
+        in_currency(tx(TransactionType::Deposit, 1, 2, 50), "EUR"),
+        in_currency(tx(TransactionType::Withdrawal, 1, 3, 80), "EUR"),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { .. },
+            ..
+        })
+    );
+
+    let account = state.accounts.get(&1).unwrap();
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    assert_eq!(
+        account.balances.get(&usd).map(|b| b.available),
+        Some(dec(100))
+    );
+    assert_eq!(
+        account.balances.get(&eur).map(|b| b.available),
+        Some(dec(50))
+    );
+}
+
+#[test]
+fn dispute_should_hold_funds_in_original_currency() {
+    let (state, res) = run_transactions(vec![
+        in_currency(tx(TransactionType::Deposit, 1, 1, 100), "USD"),
+        in_currency(tx(TransactionType::Deposit, 1, 2, 100), "EUR"),
+        in_currency(tx0(TransactionType::Dispute, 1, 1), "EUR"),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::CurrencyMismatch { tx: 1, .. },
+            ..
+        })
+    );
+
+    let mut state = state;
+    state
+        .apply_transaction(&tx0(TransactionType::Dispute, 1, 1))
+        .unwrap();
+    let account = state.accounts.get(&1).unwrap();
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    assert_matches!(account.balances.get(&usd), Some(Balance { available, held }) if *available == Amount::ZERO && *held == dec(100));
+    assert_matches!(account.balances.get(&eur), Some(Balance { available, held }) if *available == dec(100) && *held == Amount::ZERO);
+}
+
+#[test]
+fn dispute_should_respect_dispute_window() {
+    let policy = Policy {
+        dispute_window_days: Some(120),
+        ..Policy::default()
+    };
+    let day = 24 * 60 * 60;
+    let mut state = State::with_policy(policy);
+    let mut summary = Summary::default();
+    let mut apply = |tx: Transaction| {
+        let result = state.apply_transaction(&tx);
+        summary.record(&tx, &result);
+        result
+    };
+
+    apply(timed(tx(TransactionType::Deposit, 1, 1, 100), 0)).unwrap();
+    apply(timed(tx(TransactionType::Deposit, 1, 2, 100), 0)).unwrap();
+    apply(timed(tx0(TransactionType::Dispute, 1, 1), 120 * day)).unwrap();
+    assert_matches!(
+        apply(timed(tx0(TransactionType::Dispute, 1, 2), 120 * day + 1)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DisputeWindowExpired { tx: 2, days: 120 },
+            ..
+        })
+    );
+    // without a timestamp the window can't be enforced
+    apply(tx0(TransactionType::Dispute, 1, 2)).unwrap();
+
+    assert_eq!(summary.applied, 4);
+    assert_eq!(summary.rejected, 1);
+    assert_eq!(summary.expired_disputes, 1);
+}
+
+#[test]
+fn amounts_over_cap_should_be_rejected_and_counted() {
+    let policy = Policy {
+        amount_cap: Some(dec(100_000)),
+        ..Policy::default()
+    };
+    let mut state = State::with_policy(policy);
+    let mut summary = Summary::default();
+    let mut apply = |tx: Transaction| {
+        let result = state.apply_transaction(&tx);
+        summary.record(&tx, &result);
+        result
+    };
+
+    apply(tx(TransactionType::Deposit, 1, 1, 100_000)).unwrap();
+    assert_matches!(
+        apply(tx(TransactionType::Deposit, 1, 2, 100_001)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AmountCapExceeded { .. },
+            ..
+        })
+    );
+    assert_matches!(
+        apply(adjustment(1, 3, -100_001, 1)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AmountCapExceeded { .. },
+            ..
+        })
+    );
+    apply(adjustment(1, 4, -100_000, 1)).unwrap();
+
+    assert_eq!(summary.applied, 2);
+    assert_eq!(summary.rejected, 2);
+    assert_eq!(summary.over_cap, 2);
+}
+
+#[test]
+fn duplicate_transaction_ids_should_be_rejected() {
+    let (state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 1, 1, 100),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DuplicateTransaction { tx: 1 },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+}
+
+#[test]
+fn repeated_resolves_and_chargebacks_should_be_skipped_if_allowed() {
+    let policy = Policy {
+        ignore_repeated_outcomes: true,
+        ..Policy::default()
+    };
+    let (state, res) = run_transactions_with(
+        policy.clone(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Deposit, 1, 2, 50),
+            tx0(TransactionType::Dispute, 1, 1),
+            tx0(TransactionType::Resolve, 1, 1),
+            tx0(TransactionType::Resolve, 1, 1),
+            tx0(TransactionType::Dispute, 1, 2),
+            tx0(TransactionType::Chargeback, 1, 2),
+            tx0(TransactionType::Chargeback, 1, 2),
+        ],
+    );
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, true)) if *available == dec(100) && *held == Amount::ZERO);
+
+    // only the same outcome is skipped
+    let (_, res) = run_transactions_with(
+        policy,
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx0(TransactionType::Dispute, 1, 1),
+            tx0(TransactionType::Resolve, 1, 1),
+            tx0(TransactionType::Chargeback, 1, 1),
+        ],
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Resolved
+            },
+            ..
+        })
+    );
+}
+
+#[test]
+fn identical_duplicates_should_be_skipped_if_allowed() {
+    let policy = Policy {
+        ignore_identical_duplicates: true,
+        ..Policy::default()
+    };
+    let (state, res) = run_transactions_with(
+        policy.clone(),
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Deposit, 1, 1, 100),
+        ],
+    );
+    assert_matches!(res, Ok(..));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+
+    let (_, res) = run_transactions_with(
+        policy,
+        vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 1, 100),
+        ],
+    );
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DuplicateTransaction { tx: 1 },
+            ..
+        })
+    );
+}
+
+#[test]
+fn retried_submission_should_return_original_result() {
+    let mut state = State::new();
+    let deposit = tx(TransactionType::Deposit, 1, 1, 100);
+    let withdrawal = tx(TransactionType::Withdrawal, 1, 2, 150);
+
+    for _ in 0..2 {
+        assert_matches!(state.apply_submission("a", &deposit), Ok(..));
+        assert_matches!(
+            state.apply_submission("b", &withdrawal),
+            Err(CephalopodError::TransactionError {
+                error: TransactionError::NotEnoughFunds { .. },
+                ..
+            })
+        );
+    }
+    assert_matches!(
+        state.apply_submission("a", &withdrawal),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::IdempotencyKeyReused { tx: 2 },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+}
+
+// feeds transactions to the sequencer and returns ids of the ones passed on and of the reported ones
+fn sequence(mut sequencer: Sequencer, txs: Vec<Transaction>) -> (Vec<u32>, Vec<u32>) {
+    let mut ready = Vec::new();
+    let mut reported = Vec::new();
+    for tx in txs {
+        if let Err(err) = sequencer.push(tx.into(), &mut ready) {
+            reported.push(err.tx);
+        }
+    }
+    sequencer.finish(&mut ready);
+    (
+        ready.iter().map(|record| record.transaction.tx).collect(),
+        reported,
+    )
+}
+
+#[test]
+fn sequencer_should_report_out_of_order_transactions() {
+    let txs = vec![
+        timed(tx(TransactionType::Deposit, 1, 1, 100), 10),
+        timed(tx(TransactionType::Deposit, 2, 2, 100), 20),
+        timed(tx(TransactionType::Deposit, 1, 3, 100), 15),
+        tx(TransactionType::Deposit, 1, 4, 100),
+    ];
+
+    let global = Sequencer::new(OrderingScope::Global, OutOfOrderAction::Warn, 0);
+    assert_eq!(sequence(global, txs.clone()), (vec![1, 2, 3, 4], vec![3]));
+
+    let global = Sequencer::new(OrderingScope::Global, OutOfOrderAction::Reject, 0);
+    assert_eq!(sequence(global, txs.clone()), (vec![1, 2, 4], vec![3]));
+
+    let per_client = Sequencer::new(OrderingScope::Client, OutOfOrderAction::Reject, 0);
+    assert_eq!(sequence(per_client, txs), (vec![1, 2, 3, 4], vec![]));
+}
+
+#[test]
+fn sequencer_should_reorder_within_window() {
+    let txs = vec![
+        timed(tx(TransactionType::Deposit, 1, 1, 100), 10),
+        timed(tx(TransactionType::Deposit, 1, 2, 100), 30),
+        timed(tx(TransactionType::Deposit, 1, 3, 100), 20),
+        timed(tx(TransactionType::Deposit, 1, 4, 100), 100),
+        timed(tx(TransactionType::Deposit, 1, 5, 100), 25),
+        tx(TransactionType::Deposit, 1, 6, 100),
+    ];
+
+    let sequencer = Sequencer::new(OrderingScope::Global, OutOfOrderAction::Reorder, 30);
+    assert_eq!(sequence(sequencer, txs), (vec![1, 3, 2, 4, 6], vec![5]));
+}
+
+#[test]
+fn sequencer_should_keep_delayed_transactions_in_snapshot() {
+    let mut txs = vec![
+        timed(tx(TransactionType::Deposit, 1, 1, 100), 10),
+        timed(tx(TransactionType::Deposit, 1, 2, 100), 30),
+        timed(tx(TransactionType::Deposit, 1, 3, 100), 20),
+        timed(tx(TransactionType::Deposit, 1, 4, 100), 100),
+        timed(tx(TransactionType::Deposit, 1, 5, 100), 25),
+    ];
+    let rest = txs.split_off(3);
+    let mut sequencer = Sequencer::new(OrderingScope::Global, OutOfOrderAction::Reorder, 30);
+    let mut ready = Vec::new();
+    for tx in txs {
+        sequencer.push(tx.into(), &mut ready).unwrap();
+    }
+    assert!(ready.is_empty());
+
+    let mut buffer = Vec::new();
+    snapshot::write(&mut buffer, &sequencer).unwrap();
+    let restored: Sequencer = snapshot::read(buffer.as_slice()).unwrap();
+    assert_eq!(sequence(restored, rest), (vec![1, 3, 2, 4], vec![5]));
+}
+
+#[test]
+fn ledger_should_balance_client_funds_against_house_accounts() {
+    let mut state = State::with_policy(withdrawal_disputes());
+    let mut ledger = Ledger::new();
+    for tx in [
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Withdrawal, 1, 2, 30),
+        transfer(1, 2, 3, 20),
+        tx(TransactionType::Fee, 2, 4, 5),
+        tx(TransactionType::Deposit, 1, 5, 40),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx0(TransactionType::Dispute, 1, 5),
+        tx0(TransactionType::Chargeback, 1, 5),
+    ] {
+        ledger.apply(&mut state, &tx).unwrap();
+    }
+
+    assert_eq!(ledger.is_balanced(), Ok(true));
+    let totals = |account| {
+        ledger
+            .trial_balance()
+            .unwrap()
+            .into_iter()
+            .find(|row| row.account == account)
+            .map(|row| (row.debit, row.credit))
+    };
+    assert_eq!(totals(LedgerAccount::Cash), Some((dec(140), dec(70))));
+    assert_eq!(
+        totals(LedgerAccount::FeeIncome),
+        Some((Amount::ZERO, dec(5)))
+    );
+    assert_eq!(totals(LedgerAccount::HeldFunds), Some((dec(40), dec(70))));
+    assert_eq!(
+        totals(LedgerAccount::ChargebackLoss),
+        Some((dec(30), Amount::ZERO))
+    );
+    // client funds net to the sum of available balances
+    let (debit, credit) = totals(LedgerAccount::ClientFunds).unwrap();
+    let available: Amount = state
+        .iter_clients()
+        .map(|(_, account)| account.balances[&Currency::default()].available)
+        .try_fold(Amount::ZERO, |sum, amount| sum.checked_add(amount))
+        .unwrap();
+    assert_eq!(credit.checked_sub(debit), Some(available));
+}
+
+#[test]
+fn ledger_should_report_overflowing_totals() {
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = |tpe, tx| Transaction {
+        amount: Some(max),
+        ..tx0(tpe, 1, tx)
+    };
+    let mut state = State::new();
+    let mut ledger = Ledger::new();
+    for tx in [
+        huge(TransactionType::Deposit, 1),
+        huge(TransactionType::Withdrawal, 2),
+        huge(TransactionType::Deposit, 3),
+        tx(TransactionType::Deposit, 2, 4, 100),
+    ] {
+        ledger.apply(&mut state, &tx).unwrap();
+    }
+
+    // the state is kept up to date, only the trial balance is lost
+    assert_eq!(
+        state.account(2).map(|account| account.balances.len()),
+        Some(1)
+    );
+    assert_eq!(
+        ledger.trial_balance(),
+        Err(LedgerError::PostingOverflow { tx: 3 })
+    );
+    assert_eq!(
+        ledger.is_balanced(),
+        Err(LedgerError::PostingOverflow { tx: 3 })
+    );
+}
+
+#[test]
+fn ledger_journal_should_carry_tags_of_referenced_transactions() {
+    let input = "type,client,tx,amount,tag\n\
+        deposit,1,1,1.0,order-1\n\
+        deposit,1,2,0.3,\n\
+        dispute,1,1,,\n\
+        chargeback,1,1,,case-9\n";
+    let mut rdr = csv::Reader::from_reader(input.as_bytes());
+    let txs: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+    let mut state = State::new();
+    let mut ledger = Ledger::new();
+    ledger.keep_journal();
+    for tx in &txs {
+        ledger.apply(&mut state, tx).unwrap();
+    }
+
+    assert_eq!(state.transaction(1).unwrap().tag.as_str(), "order-1");
+    let tags: Vec<(u32, TransactionType, String)> = ledger
+        .drain_journal()
+        .iter()
+        .filter(|entry| entry.account == LedgerAccount::ClientFunds)
+        .map(|entry| (entry.tx, entry.tpe, entry.tag.to_string()))
+        .collect();
+    // the dispute has no tag of its own, so it's joined to the order of the deposit
+    assert_eq!(
+        tags,
+        [
+            (1, TransactionType::Deposit, "order-1".to_string()),
+            (2, TransactionType::Deposit, String::new()),
+            (1, TransactionType::Dispute, "order-1".to_string()),
+        ]
+    );
+    assert!(ledger.drain_journal().is_empty());
+    assert!("x".repeat(41).parse::<Tag>().is_err());
+}
+
+#[test]
+fn audit_should_pass_for_correct_processing_and_catch_corrupted_balances() {
+    let policy = Policy {
+        allow_withdrawal_disputes: true,
+        unlock_on_representment: true,
+        ..Policy::default()
+    };
+    let mut tenants = Tenants::new(policy);
+    tenants
+        .seed_account(
+            None,
+            3,
+            Currency::default(),
+            Balance {
+                available: dec(100),
+                held: dec(50),
+            },
+            false,
+            &Default::default(),
+        )
+        .unwrap();
+    let audit = Audit::new(1, &tenants);
+    let mut processor = Processor {
+        tenants,
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: Some(audit),
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for tx in [
+        tx(TransactionType::Deposit, 1, 1, 1000),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Resolve, 1, 1),
+        tx(TransactionType::Withdrawal, 1, 2, 200),
+        transfer(1, 2, 3, 300),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx0(TransactionType::Chargeback, 1, 2),
+        tx0(TransactionType::Representment, 1, 2),
+        tx0(TransactionType::Dispute, 2, 3),
+        tx0(TransactionType::Chargeback, 2, 3),
+        tx(TransactionType::Authorize, 1, 4, 100),
+        tx0(TransactionType::Capture, 1, 4),
+        tx(TransactionType::Fee, 1, 5, 10),
+        adjustment(1, 6, -20, 1),
+        tx(TransactionType::Withdrawal, 3, 7, 100),
+    ] {
+        processor.process(&tx).unwrap();
+    }
+    assert_eq!(processor.summary.rejected, 0);
+
+    let state = processor.tenants.state_mut(None).unwrap();
+    state.accounts.get_mut(&2).unwrap().balances.insert(
+        Currency::default(),
+        Balance {
+            available: dec(1),
+            held: Amount::ZERO,
+        },
+    );
+    assert_matches!(
+        processor.audit.as_ref().unwrap().check(&processor.tenants),
+        Err(AuditFailure {
+            tenant: None,
+            error: IntegrityError::BalanceMismatch { client: 2, .. },
+            ..
+        })
+    );
+    assert!(processor
+        .process(&tx(TransactionType::Deposit, 1, 8, 1))
+        .is_err());
+}
+
+#[test]
+fn audit_should_fail_instead_of_overflowing() {
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = |tx| Transaction {
+        amount: Some(max),
+        ..tx0(TransactionType::Deposit, 1, tx)
+    };
+    let mut tenants = Tenants::new(Policy::default());
+    let mut audit = Audit::new(1, &tenants);
+    // accepted by the engine, but the total of available and held funds doesn't fit
+    for tx in [huge(1), tx0(TransactionType::Dispute, 1, 1), huge(2)] {
+        tenants.apply_transaction(&tx).unwrap();
+        audit.record(tenants.state(None).unwrap(), &tx);
+    }
+
+    assert_matches!(
+        audit.check(&tenants),
+        Err(AuditFailure {
+            tenant: None,
+            error: IntegrityError::AmountOverflow { tx: 2 },
+            ..
+        })
+    );
+    assert_matches!(
+        Audit::new(1, &tenants).check(&tenants),
+        Err(AuditFailure {
+            error: IntegrityError::FundsOverflow { client: 1, .. },
+            ..
+        })
+    );
+}
+
+#[test]
+fn suspended_dispute_should_be_retried_when_deposit_arrives() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: Some(Suspense::new(None)),
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for tx in [
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx(TransactionType::Deposit, 1, 1, 100),
+    ] {
+        processor.process(&tx).unwrap();
+    }
+    processor.finish();
+
+    assert_matches!(balance(processor.tenants.state_mut(None).unwrap(), 1), Some((Balance { available, held }, _)) if *available == Amount::ZERO && *held == dec(100));
+    assert_eq!(processor.summary.suspended, 2);
+    assert_eq!(processor.summary.unresolved, 1);
+    assert_eq!(processor.summary.applied, 2);
+}
+
+#[test]
+fn suspended_dispute_should_expire_after_lookahead() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: Some(Suspense::new(Some(2))),
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for tx in [
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Dispute, 1, 2),
+        // two transactions after the first dispute, still within the lookahead
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 2, 3, 10),
+        tx(TransactionType::Deposit, 2, 4, 10),
+        // three transactions after the second dispute, which has expired
+        tx(TransactionType::Deposit, 1, 2, 100),
+        tx0(TransactionType::Dispute, 2, 5),
+    ] {
+        processor.process(&tx).unwrap();
+    }
+    processor.finish();
+
+    assert_matches!(balance(processor.tenants.state_mut(None).unwrap(), 1), Some((Balance { available, held }, _)) if *available == dec(100) && *held == dec(100));
+    assert_eq!(processor.summary.suspended, 3);
+    // the expired dispute and the one left at the end
+    assert_eq!(processor.summary.unresolved, 2);
+}
+
+#[test]
+fn strict_schema_should_report_header_and_column_errors() {
+    assert_eq!(
+        Schema::new(
+            vec!["type", "tx", "amount", "amount", "note"],
+            SchemaVersion::V1,
+            false
+        )
+        .unwrap_err(),
+        vec![
+            SchemaError::DuplicateColumn("amount".to_string()),
+            SchemaError::UnknownColumn("note".to_string()),
+            SchemaError::MissingColumn("client"),
+        ]
+    );
+
+    let schema = Schema::new(
+        vec!["type", "client", "tx", "amount", "note"],
+        SchemaVersion::V1,
+        true,
+    )
+    .unwrap();
+    assert!(schema
+        .check_row(vec!["deposit", "1", "1", "1.5", "anything"])
+        .is_empty());
+    assert!(schema
+        .check_row(vec!["dispute", "1", "1", "", ""])
+        .is_empty());
+    assert_matches!(
+        schema
+            .check_row(vec!["deposit", "-1", "", "abc", ""])
+            .as_slice(),
+        [
+            FieldError {
+                column: "client",
+                ..
+            },
+            FieldError { column: "tx", .. },
+            FieldError {
+                column: "amount",
+                ..
+            },
+        ]
+    );
+}
+
+#[test]
+fn schema_version_should_be_detected_and_validated() {
+    let v1 = vec!["type", "client", "tx", "amount"];
+    let v2 = vec!["type", "client", "tx", "amount", "timestamp", "currency"];
+    assert_eq!(SchemaVersion::detect(v1.clone()), SchemaVersion::V1);
+    assert_eq!(SchemaVersion::detect(v2.clone()), SchemaVersion::V2);
+    assert_eq!(
+        SchemaVersion::detect(vec!["type", "client", "tx", "reason"]),
+        SchemaVersion::V2
+    );
+
+    assert!(Schema::new(v1.clone(), SchemaVersion::V1, false).is_ok());
+    assert_eq!(
+        Schema::new(v1, SchemaVersion::V2, false).unwrap_err(),
+        vec![
+            SchemaError::MissingColumn("currency"),
+            SchemaError::MissingColumn("timestamp"),
+        ]
+    );
+    assert_eq!(
+        Schema::new(v2.clone(), SchemaVersion::V1, false).unwrap_err(),
+        vec![
+            SchemaError::NotInVersion("timestamp", SchemaVersion::V1),
+            SchemaError::NotInVersion("currency", SchemaVersion::V1),
+        ]
+    );
+
+    let schema = Schema::new(v2, SchemaVersion::V2, false).unwrap();
+    assert!(schema
+        .check_row(vec!["deposit", "1", "1", "1.5", "1700000000", ""])
+        .is_empty());
+    assert_matches!(
+        schema
+            .check_row(vec!["deposit", "1", "2", "1.5", "", "EUR"])
+            .as_slice(),
+        [FieldError {
+            column: "timestamp",
+            ..
+        }]
+    );
+}
+
+#[test]
+fn rejected_rows_should_report_each_invalid_field() {
+    let schema = Schema::new(
+        vec!["type", "client", "tx", "amount"],
+        SchemaVersion::V1,
+        true,
+    )
+    .unwrap();
+    let errors = schema.check_row(vec!["refund", "70000", "1", "1.2.3"]);
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[0].column, "type");
+    assert!(errors[0].reason.starts_with("unknown variant `refund`"));
+    assert_eq!(
+        errors[1],
+        FieldError {
+            column: "client",
+            value: "70000".to_string(),
+            reason: "number too large to fit in target type".to_string(),
+        }
+    );
+    // the reason depends on the representation of amounts
+    assert_eq!(
+        (errors[2].column, errors[2].value.as_str()),
+        ("amount", "1.2.3")
+    );
+}
+
+#[test]
+fn repeated_warnings_should_be_collapsed() {
+    let mut warnings = Warnings::new(None, None);
+    warnings.set_dedup(Some(3));
+    let mut admit = |kind: &str, client| {
+        let admission = warnings.admit_from(kind, client).unwrap();
+        (
+            admission.log,
+            admission.repeated.map(|repeated| repeated.times),
+        )
+    };
+    assert_eq!(admit("UnknownAccount", Some(7)), (true, None));
+    assert_eq!(admit("UnknownAccount", Some(7)), (false, None));
+    assert_eq!(admit("UnknownAccount", Some(7)), (false, None));
+    // flushed periodically while repeating
+    assert_eq!(admit("UnknownAccount", Some(7)), (false, Some(3)));
+    assert_eq!(admit("UnknownAccount", Some(7)), (false, None));
+    // and before a different warning
+    assert_eq!(admit("UnknownAccount", Some(8)), (true, Some(1)));
+    assert_eq!(admit("NotEnoughFunds", Some(8)), (true, None));
+    assert_eq!(admit("NotEnoughFunds", Some(8)), (false, None));
+    assert_eq!(
+        warnings.flush(),
+        Some(Repeated {
+            kind: "NotEnoughFunds".to_string(),
+            client: Some(8),
+            times: 1
+        })
+    );
+    assert_eq!(warnings.flush(), None);
+    assert_eq!(warnings.total(), 8);
+
+    // repeats are collapsed rather than sampled, so they aren't reported as suppressed
+    let mut warnings = Warnings::new(Some(1), None);
+    warnings.set_dedup(Some(10));
+    let logged: Vec<bool> = (0..4)
+        .map(|_| warnings.admit_from("InvalidRow", None).unwrap().log)
+        .collect();
+    assert_eq!(logged, [true, false, false, false]);
+    assert_eq!(warnings.flush().map(|repeated| repeated.times), Some(3));
+    assert!(warnings.suppressed().is_empty());
+}
+
+#[test]
+fn warnings_should_be_sampled_and_limited_by_budget() {
+    let mut warnings = Warnings::new(Some(2), None);
+    let logged: Vec<bool> = (0..7)
+        .map(|_| warnings.admit("NotEnoughFunds").unwrap())
+        .collect();
+    assert_eq!(logged, [true, true, false, true, false, true, false]);
+    assert!(warnings.admit("InvalidRow").unwrap());
+    assert_eq!(warnings.suppressed(), [("NotEnoughFunds", 3)]);
+
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::new(None, Some(1)),
+        summary: Summary::default(),
+    };
+    processor
+        .process(&tx(TransactionType::Withdrawal, 1, 1, 100))
+        .unwrap();
+    processor
+        .process(&tx(TransactionType::Deposit, 1, 2, 100))
+        .unwrap();
+    assert!(processor
+        .process(&tx(TransactionType::Withdrawal, 1, 3, 200))
+        .is_err());
+    assert_eq!(processor.warnings.total(), 2);
+}
+
+#[test]
+fn processing_errors_should_refer_to_input_lines() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: Some(Suspense::new(None)),
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    let at = |transaction, line| Record {
+        transaction,
+        line: Some(line),
+    };
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    processor
+        .process_record(&at(tx0(TransactionType::Dispute, 1, 1), 2))
+        .unwrap();
+    processor
+        .process_record(&at(tx(TransactionType::Deposit, 2, 2, 100), 3))
+        .unwrap();
+    let unresolved = processor.suspense.as_ref().unwrap().unresolved();
+    assert_eq!(unresolved.len(), 1);
+    assert_eq!(unresolved[0].to_string(), "transaction 1 at line 2");
+
+    let huge = Transaction {
+        amount: Some(max),
+        ..tx0(TransactionType::Deposit, 3, 3)
+    };
+    processor.process_record(&at(huge, 4)).unwrap();
+    let err = processor
+        .process(&tx(TransactionType::Deposit, 3, 4, 100))
+        .unwrap_err();
+    assert!(err.ends_with("(while processing transaction 4)"), "{}", err);
+    let err = processor
+        .process_record(&at(tx(TransactionType::Deposit, 3, 5, 100), 6))
+        .unwrap_err();
+    assert!(
+        err.ends_with("(while processing transaction 5 at line 6)"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn integrity_errors_should_be_quarantined_if_requested() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: Some(Quarantine::new(7)),
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = Transaction {
+        amount: Some(max),
+        ..tx0(TransactionType::Deposit, 1, 1)
+    };
+    processor.process(&huge).unwrap();
+    processor
+        .process(&tx(TransactionType::Deposit, 1, 2, 100))
+        .unwrap();
+    processor
+        .process(&tx(TransactionType::Deposit, 1, 3, 100))
+        .unwrap();
+    processor
+        .process(&tx(TransactionType::Deposit, 2, 4, 100))
+        .unwrap();
+
+    let quarantined = processor.quarantine.as_mut().unwrap().drain();
+    assert_matches!(
+        quarantined.as_slice(),
+        [
+            (
+                _,
+                CephalopodError::IntegrityError {
+                    error: IntegrityError::AmountOverflow { tx: 2 },
+                    ..
+                }
+            ),
+            (
+                _,
+                CephalopodError::IntegrityError {
+                    error: IntegrityError::AmountOverflow { tx: 3 },
+                    ..
+                }
+            ),
+        ]
+    );
+    assert!(processor.quarantine.as_mut().unwrap().drain().is_empty());
+    assert_eq!(processor.summary.quarantined, 2);
+    assert_eq!(processor.summary.applied, 2);
+    let state = processor.tenants.state(None).unwrap();
+    assert!(state.account(1).unwrap().flags.contains(&7));
+    assert!(state.account(2).unwrap().flags.is_empty());
+}
+
+#[test]
+fn quarantined_transfer_should_leave_no_changes_behind() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: Some(Quarantine::new(7)),
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    processor
+        .process(&tx(TransactionType::Deposit, 1, 1, 100))
+        .unwrap();
+    processor
+        .process(&Transaction {
+            amount: Some(max),
+            ..tx0(TransactionType::Deposit, 2, 2)
+        })
+        .unwrap();
+    processor.process(&transfer(1, 2, 3, 100)).unwrap();
+
+    assert_matches!(
+        processor.quarantine.as_mut().unwrap().drain().as_slice(),
+        [(
+            _,
+            CephalopodError::IntegrityError {
+                error: IntegrityError::AmountOverflow { tx: 3 },
+                ..
+            }
+        )]
+    );
+    let state = processor.tenants.state(None).unwrap();
+    assert_matches!(balance(state, 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+    assert_matches!(balance(state, 2), Some((Balance { available, .. }, _)) if *available == max);
+    assert_eq!(state.transaction(3), None);
+    processor
+        .process(&tx(TransactionType::Withdrawal, 1, 4, 100))
+        .unwrap();
+    assert_eq!(processor.summary.applied, 3);
+}
+
+#[test]
+fn error_kinds_should_be_names_of_variants() {
+    let transaction = tx(TransactionType::Withdrawal, 1, 2, 100);
+    let rejected = CephalopodError::TransactionError {
+        transaction,
+        error: TransactionError::NotEnoughFunds {
+            available: Amount::ZERO,
+            required: dec(100),
+        },
+    };
+    let failed = CephalopodError::IntegrityError {
+        transaction,
+        error: IntegrityError::AmountOverflow { tx: 2 },
+    };
+    assert_eq!(rejected.kind(), "NotEnoughFunds");
+    assert_eq!(failed.kind(), "AmountOverflow");
+    assert_eq!(
+        TransactionError::AmountNotProvided.kind(),
+        "AmountNotProvided"
+    );
+}
+
+#[test]
+fn error_severities_should_override_defaults() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: vec![
+            ("TransactionClientMismatch".to_string(), Severity::Fatal),
+            ("NotEnoughFunds".to_string(), Severity::Quarantine),
+            ("AmountOverflow".to_string(), Severity::Log),
+        ]
+        .into_iter()
+        .collect(),
+        quarantine: Some(Quarantine::new(7)),
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = Transaction {
+        amount: Some(max),
+        ..tx0(TransactionType::Deposit, 1, 1)
+    };
+    processor.process(&huge).unwrap();
+    processor
+        .process(&tx(TransactionType::Deposit, 1, 2, 100))
+        .unwrap();
+    processor
+        .process(&tx(TransactionType::Withdrawal, 2, 3, 100))
+        .unwrap();
+    processor
+        .process(&tx(TransactionType::Deposit, 2, 4, 100))
+        .unwrap();
+    processor
+        .process(&tx(TransactionType::Withdrawal, 2, 5, 200))
+        .unwrap();
+    assert_matches!(
+        processor.quarantine.as_mut().unwrap().drain().as_slice(),
+        [(
+            _,
+            CephalopodError::TransactionError {
+                error: TransactionError::NotEnoughFunds { .. },
+                ..
+            }
+        )]
+    );
+    assert!(processor
+        .tenants
+        .state(None)
+        .unwrap()
+        .account(2)
+        .unwrap()
+        .flags
+        .contains(&7));
+
+    let err = processor
+        .process(&tx0(TransactionType::Dispute, 2, 1))
+        .unwrap_err();
+    assert!(err.ends_with("(while processing transaction 1)"), "{}", err);
+}
+
+#[test]
+fn summary_should_count_errors_by_kind_and_type() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for transaction in [
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Withdrawal, 1, 2, 150),
+        tx(TransactionType::Withdrawal, 1, 3, 150),
+        transfer(1, 2, 4, 150),
+        tx0(TransactionType::Dispute, 1, 5),
+        tx0(TransactionType::Resolve, 1, 1),
+    ] {
+        processor.process(&transaction).unwrap();
+    }
+
+    let summary = &processor.summary;
+    assert_eq!(summary.rejected, 5);
+    assert_eq!(
+        summary.error_count("NotEnoughFunds", Some(TransactionType::Withdrawal)),
+        2
+    );
+    assert_eq!(summary.error_count("NotEnoughFunds", None), 3);
+    assert_eq!(summary.error_count("TransactionNotFound", None), 1);
+    assert_eq!(summary.error_count("TransactionInvalidState", None), 1);
+    assert_eq!(summary.error_count("AccountLocked", None), 0);
+    assert!(summary
+        .to_string()
+        .ends_with("errors: NotEnoughFunds of Withdrawal: 2, NotEnoughFunds of Transfer: 1, TransactionInvalidState of Resolve: 1, TransactionNotFound of Dispute: 1"));
+}
+
+#[test]
+fn fingerprint_should_not_depend_on_order_of_independent_transactions() {
+    let transactions = [
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 2, 2, 200),
+        tx(TransactionType::Deposit, 3, 3, 300),
+        tx0(TransactionType::Dispute, 2, 2),
+    ];
+    let mut state = State::new();
+    let mut reordered = State::new();
+    for transaction in &transactions {
+        state.apply_transaction(transaction).unwrap();
+    }
+    for transaction in transactions.iter().rev() {
+        // the dispute still has to follow the deposit
+        if transaction.tpe == TransactionType::Deposit {
+            reordered.apply_transaction(transaction).unwrap();
+        }
+    }
+    reordered.apply_transaction(&transactions[3]).unwrap();
+    assert_eq!(state.fingerprint(), reordered.fingerprint());
+    assert_ne!(state.fingerprint(), State::new().fingerprint());
+
+    reordered
+        .apply_transaction(&tx0(TransactionType::Resolve, 2, 2))
+        .unwrap();
+    assert_ne!(state.fingerprint(), reordered.fingerprint());
+    assert_eq!(state.fingerprint().to_string().len(), 16);
+}
+
+#[test]
+fn fingerprint_trail_should_show_where_replays_diverge() {
+    let replay = |withdrawn| {
+        let mut processor = Processor {
+            tenants: Tenants::new(Policy::default()),
+            ledger: None,
+            settlement: None,
+            activity: None,
+            suspense: None,
+            audit: None,
+            trail: Some(Trail::new()),
+            rules: None,
+            severities: BTreeMap::new(),
+            quarantine: None,
+            warnings: Warnings::default(),
+            summary: Summary::default(),
+        };
+        for transaction in [
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Withdrawal, 1, 2, withdrawn),
+            tx(TransactionType::Withdrawal, 2, 3, 100),
+            tx(TransactionType::Deposit, 2, 4, 100),
+        ] {
+            processor.process(&transaction).unwrap();
+        }
+        processor.trail.as_mut().unwrap().drain()
+    };
+    let trail = replay(50);
+    let diverged = replay(60);
+    // rejected transactions are recorded too
+    assert_eq!(trail.len(), 4);
+    assert_eq!(trail[0], diverged[0]);
+    assert_eq!(diverged[1].tx, 2);
+    assert_ne!(trail[1].fingerprint, diverged[1].fingerprint);
+    // the chain keeps differing
+    assert_ne!(trail[3].fingerprint, diverged[3].fingerprint);
+    assert_eq!(trail, replay(50));
+}
+
+#[test]
+fn rules_should_record_matches_and_block_transactions() {
+    let config: RulesConfig = serde_json::from_str(
+        r#"{"rules": [
+            {"name": "large-deposit", "types": ["deposit"], "min_amount": "5"},
+            {"name": "sanctioned", "countries": ["KP"], "action": "block"},
+            {"name": "structuring", "types": ["deposit"], "cumulative": {"amount": "2", "window": 60}}
+        ]}"#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    let mut rules = Rules::new(config);
+    rules.keep_matches();
+    let mut tenants = Tenants::new(Policy::default());
+    tenants
+        .state_mut(None)
+        .unwrap()
+        .set_client_settings(ClientSettings {
+            client: 2,
+            country: Some("KP".to_string()),
+            ..ClientSettings::default()
+        });
+    let mut processor = Processor {
+        tenants,
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: Some(rules),
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for transaction in [
+        timed(tx(TransactionType::Deposit, 1, 1, 600), 0),
+        timed(tx(TransactionType::Deposit, 1, 2, 100), 100),
+        timed(tx(TransactionType::Deposit, 1, 3, 100), 110),
+        // the deposits of tx 2 and 3 are out of the window
+        timed(tx(TransactionType::Deposit, 1, 4, 100), 170),
+        timed(tx(TransactionType::Deposit, 2, 5, 100), 170),
+    ] {
+        processor.process(&transaction).unwrap();
+    }
+
+    let matches: Vec<_> = processor
+        .rules
+        .as_mut()
+        .unwrap()
+        .drain_matches()
+        .into_iter()
+        .map(|entry| (entry.tx, entry.rule, entry.action))
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            (1, "large-deposit".to_string(), RuleAction::Record),
+            (1, "structuring".to_string(), RuleAction::Record),
+            (3, "structuring".to_string(), RuleAction::Record),
+            (5, "sanctioned".to_string(), RuleAction::Block),
+        ]
+    );
+    assert!(processor.rules.as_mut().unwrap().drain_matches().is_empty());
+    assert_eq!(processor.summary.error_count("BlockedByRule", None), 1);
+    assert!(processor.tenants.state(None).unwrap().account(2).is_none());
+
+    let duplicated: RulesConfig = serde_json::from_str(
+        r#"{"rules": [{"name": "large"}, {"name": "large", "action": "block"}]}"#,
+    )
+    .unwrap();
+    assert!(duplicated.validate().is_err());
+}
+
+#[test]
+fn cumulative_rules_should_match_totals_overflowing_amounts() {
+    #[cfg(not(feature = "minor-units"))]
+    let (big, limit) = (
+        "50000000000000000000000000000",
+        "70000000000000000000000000000",
+    );
+    #[cfg(feature = "minor-units")]
+    let (big, limit) = ("500000000000000", "700000000000000");
+    let config: RulesConfig = serde_json::from_str(&format!(
+        r#"{{"rules": [
+            {{"name": "timed", "types": ["deposit"], "cumulative": {{"amount": "{limit}", "window": 60}}}},
+            {{"name": "untimed", "types": ["deposit"], "cumulative": {{"amount": "{limit}"}}}}
+        ]}}"#,
+        limit = limit
+    ))
+    .unwrap();
+    let mut rules = Rules::new(config);
+    rules.keep_matches();
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: Some(rules),
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    let big = |tpe, tx| Transaction {
+        amount: Some(parse_amount(big).unwrap()),
+        ..timed(tx0(tpe, 1, tx), 100)
+    };
+    for transaction in [
+        big(TransactionType::Deposit, 1),
+        big(TransactionType::Withdrawal, 2),
+        big(TransactionType::Deposit, 3),
+        timed(tx(TransactionType::Deposit, 1, 4, 100), 110),
+    ] {
+        processor.process(&transaction).unwrap();
+    }
+
+    let matches: Vec<_> = processor
+        .rules
+        .as_mut()
+        .unwrap()
+        .drain_matches()
+        .into_iter()
+        .map(|entry| (entry.tx, entry.rule))
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            (3, "timed".to_string()),
+            (3, "untimed".to_string()),
+            (4, "timed".to_string()),
+            (4, "untimed".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn tenants_should_not_share_state() {
+    let mut tenants = Tenants::new(Policy::default());
+    let of_tenant = |tenant, tx| Transaction {
+        tenant: Some(tenant),
+        ..tx
+    };
+
+    tenants
+        .apply_transaction(&of_tenant(1, tx(TransactionType::Deposit, 1, 1, 100)))
+        .unwrap();
+    // the same transaction id can be used by another tenant
+    tenants
+        .apply_transaction(&of_tenant(2, tx(TransactionType::Deposit, 1, 1, 50)))
+        .unwrap();
+    assert_matches!(
+        tenants.apply_transaction(&of_tenant(2, tx(TransactionType::Withdrawal, 1, 2, 100))),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::NotEnoughFunds { .. },
+            ..
+        })
+    );
+    assert_matches!(
+        tenants.apply_transaction(&tx(TransactionType::Withdrawal, 1, 3, 10)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::UnknownAccount { .. },
+            ..
+        })
+    );
+
+    assert_matches!(balance(tenants.state_mut(Some(1)).unwrap(), 1), Some((Balance { available, .. }, _)) if *available == dec(100));
+    assert_matches!(balance(tenants.state_mut(Some(2)).unwrap(), 1), Some((Balance { available, .. }, _)) if *available == dec(50));
+    assert_eq!(
+        tenants.iter().map(|(tenant, _)| tenant).collect::<Vec<_>>(),
+        vec![None, Some(1), Some(2)]
+    );
+}
+
+#[test]
+fn settlement_should_net_daily_movements() {
+    let mut state = State::new();
+    let mut settlement = Settlement::new();
+    let day = 24 * 60 * 60;
+    // 2021-01-01
+    let start = 18628 * day;
+    for tx in [
+        timed(tx(TransactionType::Deposit, 1, 1, 100), start),
+        timed(tx(TransactionType::Withdrawal, 1, 2, 30), start + 10),
+        timed(transfer(1, 2, 3, 20), start + 20),
+        timed(tx(TransactionType::Withdrawal, 1, 4, 40), start + day),
+        timed(tx(TransactionType::Deposit, 1, 5, 50), start + day),
+        timed(tx0(TransactionType::Dispute, 1, 5), start + day),
+        timed(tx0(TransactionType::Chargeback, 1, 5), start + day),
+        tx(TransactionType::Deposit, 2, 6, 100),
+    ] {
+        state.apply_transaction(&tx).unwrap();
+        settlement.record(&state, &tx);
+    }
+
+    let instructions: Vec<_> = settlement
+        .instructions()
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.date, i.client, i.pay_in, i.pay_out))
+        .collect();
+    assert_eq!(
+        instructions,
+        vec![
+            ("2021-01-01".to_string(), 1, dec(70), Amount::ZERO),
+            ("2021-01-02".to_string(), 1, Amount::ZERO, dec(40)),
+        ]
+    );
+}
+
+#[test]
+fn settlement_should_report_overflowing_movements() {
+    #[cfg(not(feature = "minor-units"))]
+    let big = "50000000000000000000000000000";
+    #[cfg(feature = "minor-units")]
+    let big = "500000000000000";
+    let big = parse_amount(big).unwrap();
+    let mut state = State::new();
+    let mut settlement = Settlement::new();
+    for tx in [
+        Transaction {
+            amount: Some(big),
+            ..timed(tx0(TransactionType::Deposit, 1, 1), 0)
+        },
+        // adjustments are internal, so they don't offset the deposits
+        Transaction {
+            amount: Some(Amount::ZERO.checked_sub(big).unwrap()),
+            ..timed(adjustment(1, 2, 0, 7), 0)
+        },
+        Transaction {
+            amount: Some(big),
+            ..timed(tx0(TransactionType::Deposit, 1, 3), 0)
+        },
+    ] {
+        state.apply_transaction(&tx).unwrap();
+        settlement.record(&state, &tx);
+    }
+
+    assert_eq!(
+        settlement.instructions(),
+        Err(SettlementError::TotalOverflow { tx: 3 })
+    );
+}
+
+#[test]
+fn activity_should_report_clients_reaching_thresholds() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: Some(Activity::new(Thresholds::default())),
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    let hour = 60 * 60;
+    let mut transactions = Vec::new();
+    for id in 1..=3 {
+        transactions.push(tx(TransactionType::Deposit, 1, id, 100));
+        transactions.push(tx0(TransactionType::Dispute, 1, id));
+        transactions.push(tx0(TransactionType::Resolve, 1, id));
+    }
+    transactions.extend(vec![
+        tx(TransactionType::Deposit, 2, 4, 100),
+        tx(TransactionType::Deposit, 2, 5, 100),
+        tx(TransactionType::Deposit, 2, 6, 100),
+        tx0(TransactionType::Dispute, 2, 4),
+        tx0(TransactionType::Chargeback, 2, 4),
+    ]);
+    for cycle in 0..3 {
+        let start = cycle * 10 * hour;
+        let id = 10 + 2 * cycle as u32;
+        transactions.push(timed(tx(TransactionType::Deposit, 3, id, 100), start));
+        transactions.push(timed(
+            tx(TransactionType::Withdrawal, 3, id + 1, 90),
+            start + 60,
+        ));
+    }
+    transactions.extend(vec![
+        timed(tx(TransactionType::Deposit, 4, 20, 100), 0),
+        timed(tx(TransactionType::Withdrawal, 4, 21, 90), 2 * hour),
+        timed(tx(TransactionType::Deposit, 4, 22, 100), 3 * hour),
+        // rejected for insufficient funds, so not a cycle
+        timed(tx(TransactionType::Withdrawal, 4, 23, 500), 3 * hour),
+    ]);
+    for transaction in &transactions {
+        processor.process(transaction).unwrap();
+    }
+
+    let activity = processor.activity.unwrap();
+    let client = activity.client(None, 4).unwrap();
+    assert_eq!((client.withdrawals, client.rapid_cycles), (1, 0));
+    let suspicious: Vec<(u16, String, f64)> = activity
+        .suspicious()
+        .into_iter()
+        .map(|client| (client.client, client.reasons, client.chargeback_ratio))
+        .collect();
+    assert_eq!(
+        suspicious,
+        vec![
+            (1, "disputes".to_string(), 0.0),
+            (2, "chargeback_ratio".to_string(), 1.0 / 3.0),
+            (3, "rapid_cycles".to_string(), 0.0),
+        ]
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn heuristics_should_be_appended_to_exported_accounts() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: Some(Activity::new(Thresholds::default())),
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for transaction in &[
+        tx(TransactionType::Deposit, 1, 1, 500),
+        tx(TransactionType::Deposit, 1, 2, 500),
+        tx(TransactionType::Deposit, 1, 3, 500),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx0(TransactionType::Resolve, 1, 2),
+        tx0(TransactionType::Chargeback, 1, 1),
+        // rejected as the account is locked
+        tx(TransactionType::Deposit, 1, 4, 500),
+        tx(TransactionType::Deposit, 2, 5, 100),
+    ] {
+        processor.process(transaction).unwrap();
+    }
+
+    let mut output = Vec::new();
+    export::write_accounts_with_heuristics(
+        &processor.tenants,
+        processor.activity.as_ref().unwrap(),
+        &mut output,
+    );
+    let output = String::from_utf8(output).unwrap();
+    // client followed by the heuristics, leaving out balances
+    let mut rows: Vec<String> = output
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            [&fields[1..2], &fields[8..]].concat().join(",")
+        })
+        .collect();
+    rows[1..].sort_unstable();
+    assert_eq!(
+        rows,
+        [
+            "client,disputes,chargeback_ratio,locked_after".to_string(),
+            format!("1,2,{},7", 1.0 / 3.0),
+            "2,0,0.0,".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn exposure_should_total_deposits_disputes_and_chargebacks_per_client() {
+    let mut tenants = Tenants::new(Policy::default());
+    for transaction in &[
+        tx(TransactionType::Deposit, 1, 1, 600),
+        tx(TransactionType::Deposit, 1, 2, 400),
+        in_currency(tx(TransactionType::Deposit, 1, 3, 200), "EUR"),
+        in_currency(tx0(TransactionType::Dispute, 1, 3), "EUR"),
+        in_currency(tx0(TransactionType::Resolve, 1, 3), "EUR"),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Chargeback, 1, 1),
+        tx(TransactionType::Deposit, 2, 4, 100),
+        // rejected as the account is locked, so not deposited
+        tx(TransactionType::Deposit, 1, 5, 900),
+    ] {
+        let _ = tenants.apply_transaction(transaction);
+    }
+
+    let exposure =
+        |client, currency: &str, deposited, disputed, charged_back, dispute_ratio| ClientExposure {
+            tenant: None,
+            client,
+            currency: currency.parse().unwrap(),
+            deposited: dec(deposited),
+            disputed: dec(disputed),
+            charged_back: dec(charged_back),
+            dispute_ratio,
+            saturated: false,
+        };
+    assert_eq!(
+        tenants.exposures(),
+        vec![
+            exposure(1, "", 1000, 600, 600, Some(0.6)),
+            exposure(1, "EUR", 200, 200, 0, Some(1.0)),
+            exposure(2, "", 100, 0, 0, Some(0.0)),
+        ]
+    );
+    assert_eq!(
+        tenants.exposure(None, 2),
+        vec![exposure(2, "", 100, 0, 0, Some(0.0))]
+    );
+    assert!(tenants.exposure(Some(1), 1).is_empty());
+    // e.g. only a withdrawal disputed
+    let totals = ClientTotals {
+        disputed: dec(50),
+        ..ClientTotals::default()
+    };
+    assert_eq!(totals.dispute_ratio(), None);
+}
+
+#[test]
+fn prune_should_drop_finalized_and_aged_transactions_but_keep_holds() {
+    let mut state = State::new();
+    for transaction in &[
+        timed(tx(TransactionType::Deposit, 1, 1, 100), 1000),
+        timed(tx(TransactionType::Deposit, 1, 2, 50), 1000),
+        tx0(TransactionType::Dispute, 1, 2),
+        tx0(TransactionType::Resolve, 1, 2),
+        timed(tx(TransactionType::Deposit, 2, 3, 70), 1000),
+        tx0(TransactionType::Dispute, 2, 3),
+        timed(tx(TransactionType::Withdrawal, 1, 4, 30), 1000),
+        timed(tx(TransactionType::Deposit, 1, 5, 10), 10_000),
+    ] {
+        state.apply_transaction(transaction).unwrap();
+    }
+    let fingerprint = state.fingerprint();
+    let accounts: Vec<Account> = [1, 2]
+        .iter()
+        .map(|&client| state.account(client).unwrap().clone())
+        .collect();
+
+    // the resolved deposit can't be disputed again under the default policy
+    let finalized = PruneOptions {
+        finalized: true,
+        older_than: None,
+    };
+    assert_eq!(
+        state.prune(&finalized),
+        Pruned {
+            finalized: 1,
+            aged: 0,
+        }
+    );
+    assert_ne!(state.fingerprint(), fingerprint);
+    let aged = PruneOptions {
+        finalized: false,
+        older_than: Some(3600),
+    };
+    // the disputed deposit is kept despite its age
+    assert_eq!(state.prune(&aged).total(), 2);
+    assert_eq!(state.prune(&aged).total(), 0);
+    let mut kept: Vec<u32> = state
+        .transactions()
+        .map(|transaction| transaction.tx)
+        .collect();
+    kept.sort_unstable();
+    assert_eq!(kept, [3, 5]);
+    assert_eq!(state.memory_usage().history.entries, 2);
+
+    // balances are untouched, only references to dropped transactions are rejected
+    for (&client, account) in [1, 2].iter().zip(&accounts) {
+        assert_eq!(state.account(client), Some(account));
+    }
+    assert!(state
+        .apply_transaction(&tx0(TransactionType::Dispute, 1, 1))
+        .is_err());
+    state
+        .apply_transaction(&tx0(TransactionType::Resolve, 2, 3))
+        .unwrap();
+    assert_eq!(
+        balance(&state, 2).map(|(balance, _)| balance.available),
+        Some(dec(70))
+    );
+}
+
+#[test]
+fn frozen_account_should_only_block_outgoing_funds() {
+    let (mut state, res) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx0(TransactionType::Freeze, 1, 2),
+        tx(TransactionType::Withdrawal, 1, 3, 10),
+    ]);
+    assert_matches!(
+        res,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountFrozen { client: 1 },
+            ..
+        })
+    );
+    for tx in [
+        tx(TransactionType::Deposit, 1, 4, 50),
+        tx0(TransactionType::Dispute, 1, 4),
+        tx0(TransactionType::Resolve, 1, 4),
+    ] {
+        assert_matches!(state.apply_transaction(&tx), Ok(..));
+    }
+    assert_matches!(
+        state.apply_transaction(&transfer(1, 2, 5, 10)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountFrozen { client: 1 },
+            ..
+        })
+    );
+    assert_matches!(
+        state.apply_transaction(&tx0(TransactionType::Freeze, 1, 6)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountAlreadyFrozen { client: 1 },
+            ..
+        })
+    );
+
+    state
+        .apply_transaction(&tx0(TransactionType::Unfreeze, 1, 7))
+        .unwrap();
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 1, 8, 10)),
+        Ok(..)
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, false)) if *available == dec(140));
+    assert_eq!(state.admin_history().len(), 2);
+}
+
+#[test]
+fn flagged_account_should_respect_flag_limits() {
+    let policy = Policy {
+        flag_amount_limits: vec![(7, dec(50))].into_iter().collect(),
+        ..Policy::default()
+    };
+    let mut state = State::with_policy(policy);
+    state.set_client_settings(ClientSettings {
+        client: 2,
+        flags: vec![7],
+        ..ClientSettings::default()
+    });
+    let flag = |tpe, tx, flag| Transaction {
+        reason: Some(flag),
+        ..tx0(tpe, 1, tx)
+    };
+
+    for tx in [
+        tx(TransactionType::Deposit, 1, 1, 200),
+        tx(TransactionType::Deposit, 2, 2, 200),
+        flag(TransactionType::Flag, 3, 7),
+        flag(TransactionType::Flag, 4, 8),
+        tx(TransactionType::Withdrawal, 1, 5, 50),
+    ] {
+        state.apply_transaction(&tx).unwrap();
+    }
+    for tx in [
+        tx(TransactionType::Withdrawal, 1, 6, 60),
+        tx(TransactionType::Withdrawal, 2, 7, 60),
+    ] {
+        assert_matches!(
+            state.apply_transaction(&tx),
+            Err(CephalopodError::TransactionError {
+                error: TransactionError::FlaggedAmountLimitExceeded { flag: 7, .. },
+                ..
+            })
+        );
+    }
+
+    state
+        .apply_transaction(&flag(TransactionType::Unflag, 8, 7))
+        .unwrap();
+    assert_matches!(
+        state.apply_transaction(&flag(TransactionType::Unflag, 9, 7)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::FlagNotSet { client: 1, flag: 7 },
+            ..
+        })
+    );
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Withdrawal, 1, 10, 60)),
+        Ok(..)
+    );
+    assert_eq!(
+        state
+            .account(1)
+            .map(|account| account.flags.iter().copied().collect()),
+        Some(vec![8])
+    );
+}
+
+#[test]
+fn client_settings_flags_should_deserialize_from_csv() {
+    let input = "client,flags\n1,3;7\n2,\n";
+    let mut rdr = csv::Reader::from_reader(input.as_bytes());
+    let settings: Vec<ClientSettings> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+
+    assert_eq!(settings[0].flags, vec![3, 7]);
+    assert!(settings[1].flags.is_empty());
+}
+
+#[test]
+fn dormant_accounts_should_be_detected() {
+    let day = 24 * 60 * 60;
+    let (state, _) = run_transactions(vec![
+        timed(tx(TransactionType::Deposit, 1, 1, 100), 0),
+        timed(tx(TransactionType::Deposit, 2, 2, 100), 0),
+        tx(TransactionType::Deposit, 3, 3, 100),
+        timed(tx(TransactionType::Withdrawal, 2, 4, 10), 50 * day),
+        timed(tx(TransactionType::Deposit, 4, 5, 100), 100 * day),
+        // fees don't count as activity
+        timed(tx(TransactionType::Fee, 1, 6, 1), 100 * day),
+    ]);
+
+    assert_eq!(state.dormant_accounts(60), vec![(1, 0)]);
+    assert_eq!(state.dormant_accounts(30), vec![(1, 0), (2, 50 * day)]);
+}
+
+#[test]
+fn snapshot_should_restore_state() {
+    let (state, _) = run_transactions_with(
+        Policy {
+            max_disputes: Some(2),
+            ..Policy::default()
+        },
+        vec![
+            in_currency(tx(TransactionType::Deposit, 1, 1, 100), "USD"),
+            tx(TransactionType::Deposit, 1, 2, 50),
+            tx(TransactionType::Deposit, 2, 3, 30),
+            tx(TransactionType::Dispute, 1, 2, 0),
+        ],
+    );
+
+    let mut buffer = Vec::new();
+    snapshot::write(&mut buffer, &state).unwrap();
+    let mut restored: State = snapshot::read(buffer.as_slice()).unwrap();
+
+    assert_eq!(restored.policy(), state.policy());
+    assert_eq!(restored.account(1), state.account(1));
+    assert_eq!(restored.account(2), state.account(2));
+    assert_eq!(restored.transaction(1), state.transaction(1));
+    // the dispute is still open and transaction ids are still known
+    restored
+        .apply_transaction(&tx(TransactionType::Resolve, 1, 2, 0))
+        .unwrap();
+    assert_matches!(
+        restored.apply_transaction(&tx(TransactionType::Deposit, 3, 3, 10)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DuplicateTransaction { tx: 3 },
+            ..
+        })
+    );
+}
+
+#[test]
+fn snapshot_should_reject_unknown_format() {
+    let result: Result<State, _> = snapshot::read(&b"client,available"[..]);
+    assert_matches!(result.err(), Some(SnapshotError::InvalidHeader));
+
+    let mut buffer = Vec::new();
+    snapshot::write(&mut buffer, &State::new()).unwrap();
+    buffer[4..8].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+    let result: Result<State, _> = snapshot::read(buffer.as_slice());
+    assert_matches!(result.err(), Some(SnapshotError::UnsupportedVersion { .. }));
+}
+
+// memory storage surviving the state it has been opened with
+#[derive(Clone, Default)]
+struct SharedStorage(Rc<RefCell<MemoryStorage>>);
+
+impl KeyValueStore for SharedStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.borrow().get(key)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.0.borrow_mut().insert(key, value)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, StorageError> {
+        self.0.borrow().scan_prefix(prefix)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        KeyValueStore::flush(&mut *self.0.borrow_mut())
+    }
+}
+
+#[test]
+fn evicted_transactions_should_be_loaded_from_storage() {
+    let storage = SharedStorage::default();
+    let mut state = State::open(Box::new(storage.clone()), Policy::default(), 2).unwrap();
+    for tx in [
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 1, 2, 50),
+        tx(TransactionType::Deposit, 2, 3, 30),
+        tx(TransactionType::Deposit, 2, 4, 20),
+        tx(TransactionType::Dispute, 1, 1, 0),
+        tx(TransactionType::Dispute, 2, 4, 0),
+        tx(TransactionType::Resolve, 1, 1, 0),
+    ] {
+        state.apply_transaction(&tx).unwrap();
+    }
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Deposit, 2, 2, 10)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::DuplicateTransaction { tx: 2 },
+            ..
+        })
+    );
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Dispute, 1, 1, 0)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Resolved
+            },
+            ..
+        })
+    );
+    assert_matches!(balance(&state, 1), Some((Balance { available, held }, false)) if *available == dec(150) && *held == Amount::ZERO);
+    assert_matches!(balance(&state, 2), Some((Balance { available, held }, false)) if *available == dec(30) && *held == dec(20));
+}
+
+#[test]
+fn state_should_be_reopened_from_storage() {
+    let storage = SharedStorage::default();
+    let mut state = State::open(Box::new(storage.clone()), Policy::default(), 10).unwrap();
+    for tx in [
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 2, 2, 30),
+        tx(TransactionType::Fee, 1, 3, 5),
+        tx(TransactionType::Dispute, 2, 2, 0),
+    ] {
+        state.apply_transaction(&tx).unwrap();
+    }
+    state.flush().unwrap();
+    drop(state);
+
+    let mut state = State::open(Box::new(storage), Policy::default(), 10).unwrap();
+    assert_eq!(state.fees_collected()[&Currency::default()], dec(5));
+    assert_matches!(balance(&state, 1), Some((Balance { available, .. }, false)) if *available == dec(95));
+    state
+        .apply_transaction(&tx(TransactionType::Chargeback, 2, 2, 0))
+        .unwrap();
+    assert_matches!(balance(&state, 2), Some((Balance { available, held }, true)) if *available == Amount::ZERO && *held == Amount::ZERO);
+}
+
+#[test]
+fn seeded_accounts_should_be_used_by_transactions() {
+    let mut state = State::new();
+    let seeded = Balance {
+        available: dec(100),
+        held: dec(20),
+    };
+    state.seed_account(1, Currency::default(), seeded, false, &[7].into());
+    state.seed_account(2, Currency::default(), seeded, true, &Default::default());
+
+    state
+        .apply_transaction(&tx(TransactionType::Withdrawal, 1, 1, 90))
+        .unwrap();
+    assert_eq!(
+        balance(&state, 1),
+        Some((
+            &Balance {
+                available: dec(10),
+                held: dec(20)
+            },
+            false
+        ))
+    );
+    assert!(state.account(1).unwrap().flags.contains(&7));
+    assert_matches!(
+        state.apply_transaction(&tx(TransactionType::Deposit, 2, 2, 10)),
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::AccountLocked { client: 2 },
+            ..
+        })
+    );
+}
+
+#[test]
+fn disputed_transactions_should_be_listed_until_resolved() {
+    let (mut state, _) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Deposit, 2, 2, 50),
+        tx(TransactionType::Deposit, 1, 3, 20),
+        tx(TransactionType::Dispute, 1, 3, 0),
+        tx(TransactionType::Dispute, 2, 2, 0),
+    ]);
+    let disputed = |state: &State| -> Vec<u32> {
+        state
+            .disputed_transactions()
+            .iter()
+            .map(|transaction| transaction.tx)
+            .collect()
+    };
+    assert_eq!(disputed(&state), vec![2, 3]);
+    assert_eq!(state.open_disputes(1), 1);
+
+    state
+        .apply_transaction(&tx(TransactionType::Resolve, 1, 3, 0))
+        .unwrap();
+    assert_eq!(disputed(&state), vec![2]);
+    assert_eq!(state.open_disputes(1), 0);
+    let record = state.transaction_record(3).unwrap();
+    assert_eq!(record.state, Some(TransactionState::Resolved));
+    assert_eq!(record.dispute_count, Some(1));
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn engine_should_apply_submitted_transactions() {
+    let (engine, thread) =
+        EngineHandle::spawn(|| Ok(Tenants::new(Policy::default())), |_, _| Ok(())).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let deposit = tx(TransactionType::Deposit, 1, 1, 100);
+        let result = engine
+            .call(move |engine| engine.submit(&deposit, Some("key")))
+            .await;
+        assert_matches!(result, Some(Ok(())));
+        // a retry with the same key isn't applied again
+        let result = engine
+            .call(move |engine| engine.submit(&deposit, Some("key")))
+            .await;
+        assert_matches!(result, Some(Ok(())));
+        let withdrawal = tx(TransactionType::Withdrawal, 1, 2, 150);
+        let result = engine
+            .call(move |engine| engine.submit(&withdrawal, None))
+            .await;
+        assert_matches!(
+            result,
+            Some(Err(CephalopodError::TransactionError {
+                error: TransactionError::NotEnoughFunds { .. },
+                ..
+            }))
+        );
+        let balance = engine
+            .call(|engine| {
+                let state = engine.tenants().state(None)?;
+                Some(state.account(1)?.balances[&Currency::default()])
+            })
+            .await;
+        assert_eq!(
+            balance,
+            Some(Some(Balance {
+                available: dec(100),
+                held: dec(0)
+            }))
+        );
+    });
+    drop(engine);
+    thread.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn subscription_should_accept_changed_accounts_of_subscribed_clients() {
+    let update = |tenant, client, available, locked| {
+        let mut account = super::model::Account::new();
+        account.balances.insert(
+            Currency::default(),
+            Balance {
+                available: dec(available),
+                held: dec(0),
+            },
+        );
+        account.locked = locked;
+        ClientAccount {
+            tenant,
+            client,
+            account,
+            open_disputes: 0,
+        }
+    };
+    let mut subscription = Subscription::new(None, Some(vec![1, 2].into_iter().collect()));
+    assert!(subscription.accept(update(None, 1, 100, false)).is_some());
+    assert!(subscription.accept(update(None, 2, 100, false)).is_some());
+    assert!(subscription.accept(update(None, 3, 100, false)).is_none());
+    assert!(subscription.accept(update(Some(1), 1, 50, false)).is_none());
+    // nothing pushed has changed
+    let mut flagged = update(None, 1, 100, false);
+    flagged.account.flags.insert(7);
+    assert!(subscription.accept(flagged).is_none());
+    assert!(subscription.accept(update(None, 1, 100, true)).is_some());
+    assert!(subscription.accept(update(None, 1, 50, true)).is_some());
+
+    let mut subscription = Subscription::new(Some(1), None);
+    assert!(subscription
+        .accept(update(Some(1), 3, 100, false))
+        .is_some());
+    assert!(subscription.accept(update(None, 3, 100, false)).is_none());
+}
+
+#[cfg(feature = "webhooks")]
+#[test]
+fn webhooks_should_be_notified_of_selected_events_with_retries() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use axum::routing::post;
+
+    let webhook: Webhook = "dispute_opened,account_locked=http://127.0.0.1:1/hooks?a=b"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        webhook.events.into_iter().collect::<Vec<_>>(),
+        vec![EventKind::AccountLocked, EventKind::DisputeOpened]
+    );
+    assert_eq!(webhook.url.query(), Some("a=b"));
+    let webhook: Webhook = "http://127.0.0.1:1/?a=b".parse().unwrap();
+    assert_eq!(webhook.events.len(), 3);
+    assert!("https://example.com/hooks".parse::<Webhook>().is_err());
+    let retry = Retry {
+        attempts: 3,
+        backoff: Duration::from_millis(10),
+    };
+    assert_eq!(retry.backoff(3), Duration::from_millis(40));
+
+    let (engine, thread) =
+        EngineHandle::spawn(|| Ok(Tenants::new(Policy::default())), |_, _| Ok(())).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        // the endpoint fails every other request
+        let received = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let requests = Arc::new(Mutex::new(0));
+        let app = axum::Router::new().route(
+            "/hooks",
+            post({
+                let received = received.clone();
+                move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    let mut requests = requests.lock().unwrap();
+                    *requests += 1;
+                    if *requests % 2 == 1 {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    received.lock().unwrap().push(body);
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook: Webhook = format!("chargeback_applied,account_locked=http://{}/hooks", addr)
+            .parse()
+            .unwrap();
+        let events = engine
+            .call(|engine| engine.subscribe_events())
+            .await
+            .unwrap();
+        let delivery = tokio::spawn(super::server::deliver(webhook, retry, events));
+        for tx in [
+            Transaction {
+                tag: "order-1".parse().unwrap(),
+                ..tx(TransactionType::Deposit, 1, 1, 100)
+            },
+            tx0(TransactionType::Dispute, 1, 1),
+            tx0(TransactionType::Chargeback, 1, 1),
+        ] {
+            let result = engine.call(move |engine| engine.submit(&tx, None)).await;
+            assert_matches!(result, Some(Ok(())));
+        }
+        drop(engine);
+        tokio::task::spawn_blocking(move || thread.join().unwrap().unwrap())
+            .await
+            .unwrap();
+        delivery.await.unwrap();
+
+        let received = received.lock().unwrap();
+        let events: Vec<(&str, &str, &str)> = received
+            .iter()
+            .map(|body| {
+                (
+                    body["id"].as_str().unwrap(),
+                    body["tag"].as_str().unwrap(),
+                    body["type"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("chargeback_applied:1:1", "order-1", "chargeback"),
+                ("account_locked:1:1", "order-1", "chargeback"),
+            ]
+        );
+        assert_eq!(received[1]["account"]["locked"], true);
+    });
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_service_should_stream_account_updates() {
+    use proto::engine_server::Engine;
+    use tokio_stream::StreamExt;
+    use tonic::{Code, Request};
+
+    let (engine, thread) =
+        EngineHandle::spawn(|| Ok(Tenants::new(Policy::default())), |_, _| Ok(())).unwrap();
+    let service = GrpcService::new(engine);
+    // listening for shutdown signals requires the IO driver
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let submit = |tpe: &str, client, tx, amount: Option<&str>| {
+            service.submit_transaction(Request::new(proto::SubmitTransactionRequest {
+                transaction: Some(proto::Transaction {
+                    r#type: tpe.to_string(),
+                    client,
+                    tx,
+                    amount: amount.map(str::to_string),
+                    ..Default::default()
+                }),
+                idempotency_key: None,
+            }))
+        };
+        let mut updates = service
+            .stream_account_updates(Request::new(proto::StreamAccountUpdatesRequest {
+                tenant: None,
+                client: Some(2),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        submit("deposit", 1, 1, Some("10")).await.unwrap();
+        submit("deposit", 2, 2, Some("5.5")).await.unwrap();
+        let status = submit("withdrawal", 2, 3, Some("6")).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let status = submit("bogus", 2, 4, None).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        submit("dispute", 2, 2, None).await.unwrap();
+
+        let update = updates.next().await.unwrap().unwrap();
+        assert_eq!(update.client, 2);
+        assert_eq!(update.balances[0].available, "5.5");
+        let update = updates.next().await.unwrap().unwrap();
+        assert_eq!(update.balances[0].held, "5.5");
+        assert_eq!(update.open_disputes, 1);
+
+        let account = service
+            .get_account(Request::new(proto::GetAccountRequest {
+                tenant: None,
+                client: 1,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(account.balances[0].total.as_deref(), Some("10"));
+        let status = service
+            .get_account(Request::new(proto::GetAccountRequest {
+                tenant: Some(7),
+                client: 1,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    });
+    drop(service);
+    thread.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "graphql")]
+#[test]
+fn graphql_schema_should_filter_and_paginate() {
+    use async_graphql::value;
+
+    let (engine, thread) =
+        EngineHandle::spawn(|| Ok(Tenants::new(Policy::default())), |_, _| Ok(())).unwrap();
+    let schema = schema(engine.clone());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let transactions = vec![
+            tx(TransactionType::Deposit, 1, 1, 100),
+            tx(TransactionType::Deposit, 2, 2, 100),
+            tx(TransactionType::Deposit, 3, 3, 100),
+            tx(TransactionType::Withdrawal, 3, 4, 50),
+            tx(TransactionType::Deposit, 3, 5, 100),
+            tx0(TransactionType::Dispute, 2, 2),
+            tx0(TransactionType::Dispute, 3, 3),
+        ];
+        engine
+            .call(move |engine| {
+                for tx in &transactions {
+                    engine.submit(tx, None).unwrap();
+                }
+            })
+            .await
+            .unwrap();
+
+        let response = schema
+            .execute(
+                "{ accounts(withOpenDisputes: true, first: 1) { totalCount nodes { client openDisputes } endCursor hasNextPage } }",
+            )
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data,
+            value!({
+                "accounts": {
+                    "totalCount": 2,
+                    "nodes": [{ "client": 2, "openDisputes": 1 }],
+                    "endCursor": 2,
+                    "hasNextPage": true,
+                }
+            })
+        );
+        let response = schema
+            .execute(
+                "{ accounts(withOpenDisputes: true, after: 2) { nodes { client } hasNextPage } }",
+            )
+            .await;
+        assert_eq!(
+            response.data,
+            value!({ "accounts": { "nodes": [{ "client": 3 }], "hasNextPage": false } })
+        );
+
+        let response = schema
+            .execute("{ transactions(client: 3) { nodes { tx type state } } disputes(client: 2) { nodes { tx disputeCount } } }")
+            .await;
+        assert_eq!(
+            response.data,
+            value!({
+                "transactions": {
+                    "nodes": [
+                        { "tx": 3, "type": "DEPOSIT", "state": "DISPUTED" },
+                        { "tx": 4, "type": "WITHDRAWAL", "state": "WITHDRAWN" },
+                        { "tx": 5, "type": "DEPOSIT", "state": "DEPOSITED" },
+                    ]
+                },
+                "disputes": { "nodes": [{ "tx": 2, "disputeCount": 1 }] },
+            })
+        );
+
+        let response = schema
+            .execute("{ transactions(state: DEPOSITED) { totalCount } transaction(tx: 9) { tx } account(tenant: 7, client: 1) { client } }")
+            .await;
+        assert_eq!(
+            response.data,
+            value!({ "transactions": { "totalCount": 2 }, "transaction": null, "account": null })
+        );
+    });
+    drop(schema);
+    drop(engine);
+    thread.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "wasm")]
+#[test]
+fn wasm_engine_should_apply_transactions_given_as_json() {
+    let mut engine = WasmEngine::with_policy(r#"{"allow_withdrawal_disputes": true}"#)
+        .unwrap_or_else(|_| panic!("policy should be parsed"));
+    for transaction in [
+        r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.7"}"#,
+        r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "0.5"}"#,
+        r#"{"type": "dispute", "client": 1, "tx": 2}"#,
+        r#"{"type": "deposit", "client": 2, "tx": 3, "amount": "2", "tenant": 7}"#,
+    ] {
+        assert!(engine.apply_transaction(transaction).is_ok());
+    }
+    assert_eq!(
+        engine.accounts(),
+        r#"[{"tenant":null,"client":1,"currency":"","available":"1.2","held":"0.5","total":"1.7","locked":false,"flags":[]},{"tenant":7,"client":2,"currency":"","available":"2","held":"0","total":"2","locked":false,"flags":[]}]"#
+    );
+}
+
+#[cfg(feature = "python")]
+#[test]
+fn python_module_should_replay_transactions() {
+    use pyo3::ffi::c_str;
+    use pyo3::types::IntoPyDict;
+    use pyo3::{wrap_pymodule, Python};
+
+    Python::initialize();
+    Python::attach(|py| {
+        let module = wrap_pymodule!(super::python::cephalopod)(py);
+        let locals = [("cephalopod", module)].into_py_dict(py).unwrap();
+        py.run(
+            c_str!(
+                r#"
+from decimal import Decimal
+state = cephalopod.State()
+state.apply(cephalopod.Transaction("deposit", 1, 1, amount=Decimal("1.5")))
+try:
+    state.apply(cephalopod.Transaction("withdrawal", 1, 2, amount="2"))
+    assert False, "withdrawal should be rejected"
+except cephalopod.TransactionError:
+    pass
+rejected = state.replay([
+    cephalopod.Transaction("deposit", 2, 3, amount=2),
+    cephalopod.Transaction("withdrawal", 2, 4, amount="3"),
+    cephalopod.Transaction("dispute", 1, 1),
+])
+assert [tx for tx, _ in rejected] == [4], rejected
+assert [account.client for account in state.accounts()] == [1, 2]
+balance = state.account(1).balances[""]
+assert (balance.available, balance.held, balance.total) == (0, Decimal("1.5"), Decimal("1.5"))
+assert state.account(1).open_disputes == 1
+assert state.transaction(1).type == "deposit"
+assert state.account(3) is None
+"#
+            ),
+            None,
+            Some(&locals),
+        )
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn ffi_should_apply_transactions_and_export_accounts() {
+    use super::ffi::*;
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    unsafe {
+        let state = cephalopod_create_state(ptr::null());
+        assert!(!state.is_null());
+        let deposit =
+            CString::new(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2"}"#).unwrap();
+        assert_eq!(
+            cephalopod_apply_transaction_json(state, deposit.as_ptr(), ptr::null_mut()),
+            CEPHALOPOD_OK
+        );
+        let withdrawal =
+            CString::new(r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "3"}"#).unwrap();
+        let mut error = ptr::null_mut();
+        assert_eq!(
+            cephalopod_apply_transaction_json(state, withdrawal.as_ptr(), &mut error),
+            CEPHALOPOD_REJECTED
+        );
+        assert_eq!(
+            CStr::from_ptr(error).to_str().unwrap(),
+            "not enough funds, available: 2, required: 3"
+        );
+        cephalopod_free_string(error);
+        let invalid = CString::new("{}").unwrap();
+        assert_eq!(
+            cephalopod_apply_transaction_json(state, invalid.as_ptr(), ptr::null_mut()),
+            CEPHALOPOD_INVALID_ARGUMENT
+        );
+
+        let accounts = cephalopod_export_accounts_json(state);
+        assert_eq!(
+            CStr::from_ptr(accounts).to_str().unwrap(),
+            r#"[{"tenant":null,"client":1,"currency":"","available":"2","held":"0","total":"2","locked":false,"flags":[]}]"#
+        );
+        cephalopod_free_string(accounts);
+        cephalopod_free_state(state);
+
+        let policy = CString::new("not a policy").unwrap();
+        assert!(cephalopod_create_state(policy.as_ptr()).is_null());
+    }
+}
+
+#[test]
+fn storage_location_should_parse() {
+    assert!("nowhere".parse::<StorageLocation>().is_err());
+    assert!("unknown:/tmp/state".parse::<StorageLocation>().is_err());
+    #[cfg(feature = "sled")]
+    assert_eq!(
+        "sled:/tmp/state".parse::<StorageLocation>(),
+        Ok(StorageLocation::Sled("/tmp/state".into()))
+    );
+    #[cfg(feature = "postgres")]
+    {
+        assert_eq!(
+            "postgres:host=localhost dbname=engine".parse::<StorageLocation>(),
+            Ok(StorageLocation::Postgres(
+                "host=localhost dbname=engine".into()
+            ))
+        );
+        assert_eq!(
+            "postgres://localhost/engine".parse::<StorageLocation>(),
+            Ok(StorageLocation::Postgres(
+                "postgres://localhost/engine".into()
+            ))
+        );
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn state_should_be_reopened_from_sqlite() {
+    let path = std::env::temp_dir().join(format!("cephalopod-test-{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let location = StorageLocation::Sqlite(path.clone());
+
+    let (existing, mut open) = location.open().unwrap();
+    assert!(existing.is_empty());
+    let mut state = State::open(open(Some(3)).unwrap(), Policy::default(), 10).unwrap();
+    for tx in [
+        in_currency(tx(TransactionType::Deposit, 1, 1, 100), "USD"),
+        timed(tx(TransactionType::Deposit, 2, 2, 30), 1000),
+        tx(TransactionType::Dispute, 2, 2, 0),
+    ] {
+        state.apply_transaction(&tx).unwrap();
+    }
+    state.flush().unwrap();
+    drop((state, open));
+
+    let (existing, mut open) = location.open().unwrap();
+    assert_eq!(existing, vec![Some(3)]);
+    let mut state = State::open(open(Some(3)).unwrap(), Policy::default(), 10).unwrap();
+    assert_eq!(
+        state.account(2).and_then(|account| account.last_activity),
+        Some(1000)
+    );
+    assert_matches!(
+        state.account(1).map(|account| account.balances.get(&"USD".parse().unwrap())),
+        Some(Some(Balance { available, .. })) if *available == dec(100)
+    );
+    state
+        .apply_transaction(&tx(TransactionType::Resolve, 2, 2, 0))
+        .unwrap();
+    assert_matches!(balance(&state, 2), Some((Balance { available, held }, false)) if *available == dec(30) && *held == Amount::ZERO);
+    drop((state, open));
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let (available, held): (String, String) = connection
+        .query_row(
+            "SELECT available, held FROM balances WHERE tenant = 3 AND client = 2",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(parse_amount(&available).unwrap(), dec(30));
+    assert_eq!(parse_amount(&held).unwrap(), Amount::ZERO);
+    let state: String = connection
+        .query_row("SELECT state FROM transactions WHERE tx = 2", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(state, "Resolved");
+    std::fs::remove_file(&path).unwrap();
+}
+
+fn arbitrary_transaction() -> impl Strategy<Value = Transaction> {
+    let tpe = prop_oneof![
+        Just(TransactionType::Deposit),
+        Just(TransactionType::Withdrawal),
+        Just(TransactionType::Transfer),
+        Just(TransactionType::Dispute),
+        Just(TransactionType::Resolve),
+        Just(TransactionType::Chargeback),
+        Just(TransactionType::Unlock),
+    ];
+    // a few clients and ids, so that transactions often refer to each other
+    (
+        tpe,
+        1u16..4,
+        1u32..12,
+        option::of(-100i64..1000),
+        option::of(1u16..4),
+    )
+        .prop_map(|(tpe, client, tx, amount, to)| Transaction {
+            amount: amount.map(dec),
+            to,
+            ..tx0(tpe, client, tx)
+        })
+}
+
+proptest! {
+    #[test]
+    fn invariants_should_hold_for_arbitrary_transactions(
+        transactions in collection::vec(arbitrary_transaction(), 0..64)
+    ) {
+        let tenants = Tenants::new(Policy::default());
+        // the audit checks that balances match the net of applied movements,
+        // i.e. that funds are neither created nor lost
+        let audit = Audit::new(1, &tenants);
+        let mut processor = Processor {
+            tenants,
+            ledger: None,
+            settlement: None,
+            activity: None,
+            suspense: None,
+            audit: Some(audit),
+            trail: None,
+            rules: None,
+            severities: BTreeMap::new(),
+            quarantine: None,
+            warnings: Warnings::default(),
+            summary: Summary::default(),
+        };
+        for transaction in &transactions {
+            let locked: BTreeMap<u16, super::model::Account> = processor
+                .tenants
+                .state(None)
+                .into_iter()
+                .flat_map(State::iter_clients)
+                .filter(|(_, account)| account.locked)
+                .map(|(&client, account)| (client, account.clone()))
+                .collect();
+            let result = processor.process(transaction);
+            prop_assert!(result.is_ok(), "{:?} after {:?}", result, transaction);
+
+            let state = processor.tenants.state(None).unwrap();
+            for (client, account) in state.iter_clients() {
+                for balance in account.balances.values() {
+                    prop_assert!(balance.held >= dec(0), "{:?} after {:?}", account, transaction);
+                }
+                if let Some(before) = locked.get(client) {
+                    if transaction.tpe != TransactionType::Unlock {
+                        prop_assert_eq!(account, before, "after {:?}", transaction);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// transactions supported by the reference engine, with amounts it would accept or reject
+fn reference_transaction() -> impl Strategy<Value = Transaction> {
+    let tpe = prop_oneof![
+        3 => Just(TransactionType::Deposit),
+        2 => Just(TransactionType::Withdrawal),
+        2 => Just(TransactionType::Dispute),
+        1 => Just(TransactionType::Resolve),
+        1 => Just(TransactionType::Chargeback),
+    ];
+    (tpe, 1u16..4, 1u32..16, option::weighted(0.95, -10i64..500)).prop_map(
+        |(tpe, client, tx, amount)| Transaction {
+            amount: amount.map(dec),
+            ..tx0(tpe, client, tx)
+        },
+    )
+}
+
+// balances of the default currency of all accounts, in the format of the reference engine
+fn reference_accounts(state: &State) -> BTreeMap<u16, ReferenceAccount> {
+    state
+        .iter_clients()
+        .map(|(&client, account)| {
+            let balance = account
+                .balances
+                .get(&Currency::default())
+                .cloned()
+                .unwrap_or_default();
+            (
+                client,
+                ReferenceAccount {
+                    available: balance.available,
+                    held: balance.held,
+                    locked: account.locked,
+                },
+            )
+        })
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn engine_should_match_reference_implementation(
+        transactions in collection::vec(reference_transaction(), 0..96)
+    ) {
+        let mut reference = ReferenceEngine::new();
+        let mut state = State::new();
+        // a tiny cache, so that most transactions are evicted to the storage and loaded back
+        let mut stored = State::open(Box::new(MemoryStorage::default()), Policy::default(), 2).unwrap();
+        for transaction in &transactions {
+            let applied = reference.apply(transaction);
+            let result = state.apply_transaction(transaction);
+            prop_assert_eq!(result.is_ok(), applied, "{:?} of {:?}", result, transaction);
+            let result = stored.apply_transaction(transaction);
+            prop_assert_eq!(result.is_ok(), applied, "{:?} of {:?} with storage", result, transaction);
+        }
+        prop_assert_eq!(&reference_accounts(&state), &reference.accounts);
+        prop_assert_eq!(&reference_accounts(&stored), &reference.accounts);
+    }
+}
+
+proptest! {
+    #[test]
+    fn workload_should_be_deterministic_and_rejected_only_where_invalid(seed in any::<u64>()) {
+        // enough clients that chargebacks don't lock all of them
+        let config = WorkloadConfig {
+            clients: 50,
+            dispute_probability: 0.2,
+            invalid_rate: 0.2,
+            seed,
+        };
+        let transactions: Vec<Transaction> = Workload::new(config).take(300).collect();
+        prop_assert_eq!(&Workload::new(config).take(300).collect::<Vec<_>>(), &transactions);
+
+        let mut workload = Workload::new(config);
+        let mut reference = ReferenceEngine::new();
+        let mut state = State::new();
+        let mut rejected = 0;
+        for transaction in workload.by_ref().take(300) {
+            let applied = reference.apply(&transaction);
+            prop_assert_eq!(state.apply_transaction(&transaction).is_ok(), applied);
+            if !applied {
+                rejected += 1;
+            }
+        }
+        prop_assert_eq!(rejected, workload.invalid());
+        prop_assert_eq!(&reference_accounts(&state), &reference.accounts);
+    }
+}
+
+#[test]
+fn scenario_should_apply_transactions_and_check_expectations() {
+    let state = Scenario::new()
+        .deposit(1, 100)
+        .deposit(2, "0.0001")
+        .transfer(2, 1, "0.0001")
+        .withdraw(1, "100.5")
+        .rejected_with("NotEnoughFunds")
+        .dispute(1)
+        .expect_available(1, "0.0001")
+        .expect_held(1, 100)
+        .chargeback(1)
+        .resolve(1)
+        .rejected()
+        .expect_locked(1)
+        .expect_held(1, 0)
+        .expect_unlocked(2)
+        .expect_available(2, 0)
+        .into_state();
+    assert_eq!(
+        state.transaction(3).map(|tx| tx.tpe),
+        Some(TransactionType::Transfer)
+    );
+}
+
+#[test]
+#[should_panic(expected = "has been rejected")]
+fn scenario_should_fail_on_unexpected_rejection() {
+    Scenario::new().deposit(1, 100).withdraw(1, 200);
+}
+
+// covers edge cases of the output: zero balances, an account without any funds,
+// held funds, a locked account, high-precision amounts, currencies, flags and tenants
+fn representative_tenants() -> Tenants {
+    let amount = |amount: &str| Some(parse_amount(amount).unwrap());
+    let mut tenants = Tenants::new(Policy::default());
+    for transaction in [
+        Transaction {
+            amount: amount("10.00"),
+            ..tx0(TransactionType::Deposit, 1, 1)
+        },
+        Transaction {
+            amount: amount("10"),
+            ..tx0(TransactionType::Withdrawal, 1, 2)
+        },
+        Transaction {
+            amount: amount("12345678.9999"),
+            ..tx0(TransactionType::Deposit, 2, 3)
+        },
+        Transaction {
+            amount: amount("0.0001"),
+            ..tx0(TransactionType::Deposit, 2, 4)
+        },
+        tx0(TransactionType::Dispute, 2, 4),
+        Transaction {
+            amount: amount("1.5"),
+            ..tx0(TransactionType::Deposit, 3, 5)
+        },
+        Transaction {
+            amount: amount("0.25"),
+            ..tx0(TransactionType::Deposit, 3, 6)
+        },
+        tx0(TransactionType::Dispute, 3, 5),
+        tx0(TransactionType::Chargeback, 3, 5),
+        in_currency(
+            Transaction {
+                amount: amount("99.99"),
+                ..tx0(TransactionType::Deposit, 4, 7)
+            },
+            "EUR",
+        ),
+        Transaction {
+            amount: amount("1"),
+            ..tx0(TransactionType::Deposit, 4, 8)
+        },
+        Transaction {
+            reason: Some(7),
+            ..tx0(TransactionType::Flag, 4, 9)
+        },
+        Transaction {
+            reason: Some(3),
+            ..tx0(TransactionType::Flag, 4, 10)
+        },
+        Transaction {
+            amount: amount("5"),
+            tenant: Some(2),
+            ..tx0(TransactionType::Deposit, 1, 1)
+        },
+    ] {
+        tenants.apply_transaction(&transaction).unwrap();
+    }
+    // the account is opened even though the deposit is rejected
+    assert!(tenants
+        .apply_transaction(&tx0(TransactionType::Deposit, 5, 11))
+        .is_err());
+    tenants
+}
+
+// asserts the output matches the reviewed snapshot in src/snapshots, separate for
+// each representation of amounts, as `Decimal` keeps trailing zeros of the input
+fn assert_output_snapshot(name: &str, output: &str) {
+    let suffix = if cfg!(feature = "minor-units") {
+        "minor-units"
+    } else {
+        "decimal"
+    };
+    insta::with_settings!({ snapshot_suffix => suffix, prepend_module_to_snapshot => false }, {
+        insta::assert_snapshot!(name, output);
+    });
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn exported_csv_should_match_snapshot() {
+    let mut output = Vec::new();
+    export::write_accounts(&representative_tenants(), &mut output);
+    let output = String::from_utf8(output).unwrap();
+    // accounts are written in the order of hash maps
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort_unstable();
+    assert_output_snapshot("accounts_csv", &lines.join("\n"));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn exported_total_should_be_empty_if_it_overflows() {
+    #[cfg(not(feature = "minor-units"))]
+    let max = Decimal::MAX;
+    #[cfg(feature = "minor-units")]
+    let max = Amount::from_minor_units(i64::MAX);
+    let huge = |tx| Transaction {
+        amount: Some(max),
+        ..tx0(TransactionType::Deposit, 1, tx)
+    };
+    let mut tenants = Tenants::new(Policy::default());
+    for transaction in [huge(1), tx0(TransactionType::Dispute, 1, 1), huge(2)] {
+        tenants.apply_transaction(&transaction).unwrap();
+    }
+
+    let mut output = Vec::new();
+    export::write_accounts(&tenants, &mut output);
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        output.lines().nth(1),
+        Some(format!(",1,,{},{},,false,", max, max).as_str())
+    );
+    // the row is read back as written, e.g. to seed accounts
+    let rows: Vec<ExportedClient> = csv::Reader::from_reader(output.as_bytes())
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_matches!(rows.as_slice(), [ExportedClient { total: None, .. }]);
+}
+
+#[cfg(any(feature = "wasm", feature = "ffi", feature = "node"))]
+#[test]
+fn exported_json_should_match_snapshot() {
+    let output = json::accounts(&representative_tenants());
+    let mut accounts: Vec<serde_json::Value> = serde_json::from_str(&output).unwrap();
+    accounts.sort_by_key(|account| {
+        (
+            account["tenant"].as_u64(),
+            account["client"].as_u64(),
+            account["currency"].as_str().map(str::to_string),
+        )
+    });
+    assert_output_snapshot(
+        "accounts_json",
+        &serde_json::to_string_pretty(&accounts).unwrap(),
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn repl_should_apply_transactions_and_undo_them() {
+    let mut repl = Repl::new(Tenants::new(Policy::default()));
+    let mut execute = |line: &str| repl.execute(line);
+    assert_eq!(execute("deposit 1 1 100"), Ok("applied".to_string()));
+    assert_eq!(
+        execute("withdrawal, 1, 2, 150"),
+        Ok(
+            "rejected (NotEnoughFunds): not enough funds, available: 100, required: 150"
+                .to_string()
+        )
+    );
+    assert_eq!(execute("transfer 1 3 30 to=2"), Ok("applied".to_string()));
+    assert_eq!(execute("deposit 1 4 50"), Ok("applied".to_string()));
+    assert_eq!(execute("dispute 1 4"), Ok("applied".to_string()));
+    assert_eq!(
+        execute("disputes"),
+        Ok("deposit of 50 in transaction 4 by client 1".to_string())
+    );
+    assert_eq!(
+        execute("accounts"),
+        Ok(
+            "client 1: available 70, held 50, total 120\nclient 2: available 30, held 0, total 30"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        execute("undo"),
+        Ok("undone dispute of transaction 4 by client 1".to_string())
+    );
+    assert_eq!(
+        execute("account 1"),
+        Ok("client 1: available 120, held 0, total 120".to_string())
+    );
+    assert_eq!(execute("disputes"), Ok("no open disputes".to_string()));
+
+    // tenants don't share accounts
+    assert_eq!(execute("tenant 7"), Ok("switched to tenant 7".to_string()));
+    assert_eq!(
+        execute("account 1"),
+        Err("client 1 has no account".to_string())
+    );
+    assert_eq!(execute("deposit 1 1 5"), Ok("applied".to_string()));
+    assert_matches!(execute("deposit 1 x 5"), Err(err) if err.starts_with("invalid transaction"));
+    assert_matches!(execute("deposit 1 2 5 fee=1"), Err(err) if err == "unknown column fee");
+    assert_eq!(
+        repl.tenants()
+            .state(Some(7))
+            .and_then(|state| state.account(1))
+            .map(|account| account.balances[&Currency::default()].available),
+        Some(dec(500))
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn progress_should_estimate_time_left_from_bytes_rate() {
+    use std::time::{Duration, Instant};
+
+    let start = Instant::now();
+    let seconds = |seconds| start + Duration::from_secs(seconds);
+    // resumed at byte 1000 of 11000
+    let mut progress = Progress::started_at(start, Duration::from_secs(10), Some(11_000), 1_000);
+    assert_eq!(progress.update_at(seconds(5), 500, 2_000), None);
+    let report = progress.update_at(seconds(10), 1_000, 3_000).unwrap();
+    assert_eq!(report.records_per_second, 100.0);
+    assert_eq!(report.eta, Some(Duration::from_secs(40)));
+    // slowing down shows in the instantaneous rate first
+    let report = progress.update_at(seconds(20), 1_500, 3_500).unwrap();
+    assert_eq!(report.records_per_second, 50.0);
+    assert_eq!(report.average_records_per_second, 75.0);
+    assert_eq!(report.eta, Some(Duration::from_secs(60)));
+    assert_eq!(
+        report.to_string(),
+        "1500 records, 3.4 KiB of 10.7 KiB (31.8%), 50 records/s (average 75), ETA 0:01:00"
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn metrics_should_cover_only_the_run() {
+    let mut metrics = Metrics::new();
+    metrics.phase("load");
+    // counters restored from a checkpoint of an interrupted run
+    let mut summary = Summary {
+        applied: 10,
+        rejected: 2,
+        ..Summary::default()
+    };
+    metrics.set_baseline(&summary);
+    metrics.phase("process");
+    for _ in 0..4 {
+        metrics.record();
+    }
+    summary.applied += 2;
+    summary.rejected += 1;
+    summary.invalid_rows += 1;
+    let report = metrics.report(&summary);
+    assert_eq!(
+        (
+            report.records,
+            report.accepted,
+            report.rejected,
+            report.invalid_rows
+        ),
+        (4, 2, 1, 1)
+    );
+    let phases: Vec<&str> = report.phases.iter().map(|phase| phase.name).collect();
+    assert_eq!(phases, ["load", "process"]);
+    assert!(report.phases.iter().all(|phase| phase.seconds >= 0.0));
+
+    let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["accepted"], 2);
+    assert_eq!(json["phases"][1]["name"], "process");
+    assert!(report.to_string().contains("accepted: 2, rejected: 1"));
+    assert_eq!("json".parse(), Ok(MetricsFormat::Json));
+    assert!("xml".parse::<MetricsFormat>().is_err());
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn report_should_show_counts_rejections_and_exposures() {
+    let mut processor = Processor {
+        tenants: Tenants::new(Policy::default()),
+        ledger: None,
+        settlement: None,
+        activity: None,
+        suspense: None,
+        audit: None,
+        trail: None,
+        rules: None,
+        severities: BTreeMap::new(),
+        quarantine: None,
+        warnings: Warnings::default(),
+        summary: Summary::default(),
+    };
+    for tx in [
+        tx(TransactionType::Deposit, 1, 1, 500),
+        tx(TransactionType::Deposit, 2, 2, 300),
+        tx(TransactionType::Deposit, 3, 3, 100),
+        tx(TransactionType::Withdrawal, 3, 4, 900),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx0(TransactionType::Dispute, 2, 2),
+    ] {
+        processor.process(&tx).unwrap();
+    }
+    processor.finish();
+    assert_eq!(
+        processor.summary.applied_by_type,
+        [(TransactionType::Deposit, 3), (TransactionType::Dispute, 2)]
+            .iter()
+            .copied()
+            .collect()
+    );
+
+    let exposures = report::top_exposures(&processor.tenants, 1);
+    assert_eq!(exposures.len(), 1);
+    assert_eq!((exposures[0].client, exposures[0].exposure), (1, dec(500)));
+
+    let mut html = Vec::new();
+    report::write_report(&mut html, &processor.summary, &processor.tenants).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(!html.contains("<script") && !html.contains("<link"));
+    assert!(html.contains("<tr><th>Transactions applied</th><td class=\"number\">5</td></tr>"));
+    assert!(html.contains("<td>Deposit</td><td><div class=\"bar applied\" style=\"width: 100.0%\"></div></td><td class=\"number\">3</td>"));
+    assert!(html.contains(
+        "<td>Dispute</td><td><div class=\"bar applied\" style=\"width: 66.7%\"></div></td>"
+    ));
+    assert!(html.contains("<td>NotEnoughFunds</td><td class=\"number\">1</td>"));
+    let client_1 = html.find("<td class=\"number\">1</td><td>").unwrap();
+    let client_2 = html.find("<td class=\"number\">2</td><td>").unwrap();
+    assert!(client_1 < client_2, "exposures should be sorted");
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn markdown_summary_should_list_counts_funds_and_rejections() {
+    let mut tenants = Tenants::new(Policy::default());
+    let mut summary = Summary::default();
+    for transaction in &[
+        tx(TransactionType::Deposit, 1, 1, 500),
+        tx(TransactionType::Deposit, 2, 2, 300),
+        tx0(TransactionType::Dispute, 1, 1),
+        tx(TransactionType::Withdrawal, 2, 3, 400),
+        tx(TransactionType::Withdrawal, 2, 4, 400),
+    ] {
+        let result = tenants.apply_transaction(transaction);
+        summary.record(transaction, &result);
+    }
+    // more kinds of errors than listed
+    for (kind, count) in [("A", 1), ("B", 1), ("C", 3), ("D", 3), ("E", 3), ("F", 1)].iter() {
+        summary
+            .errors
+            .entry(kind.to_string())
+            .or_default()
+            .insert(TransactionType::Dispute, *count);
+    }
+
+    let mut markdown = Vec::new();
+    report::write_summary(&mut markdown, SummaryFormat::Markdown, &summary, &tenants).unwrap();
+    let markdown = String::from_utf8(markdown).unwrap();
+    assert!(markdown.contains("| Transactions applied | 3 |\n| Transactions rejected | 2 |"));
+    assert!(markdown.contains("| Deposit | 2 |\n| Dispute | 1 |\n| **Total** | **3** |"));
+    assert!(markdown.contains(&format!(
+        "| default | {} | {} | {} |",
+        dec(300),
+        dec(500),
+        dec(800)
+    )));
+    assert!(markdown.contains("| NotEnoughFunds | 2 | Withdrawal: 2 |"));
+    let notable = markdown
+        .lines()
+        .filter(|line| line.starts_with("| ") && line.contains(": "))
+        .count();
+    assert_eq!(notable, report::NOTABLE_REJECTIONS);
+    assert!(markdown.contains("| C | 3 | Dispute: 3 |"));
+    assert!(markdown.ends_with("Other kinds: B (1), F (1).\n"));
+
+    let mut json = Vec::new();
+    report::write_summary(&mut json, SummaryFormat::Json, &summary, &tenants).unwrap();
+    let json: Summary = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json.errors, summary.errors);
+    assert_eq!("markdown".parse(), Ok(SummaryFormat::Markdown));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn reconcile_should_report_discrepancies_beyond_tolerance() {
+    let mut tenants = Tenants::new(Policy::default());
+    for transaction in &[
+        tx(TransactionType::Deposit, 1, 1, 500),
+        tx(TransactionType::Deposit, 2, 2, 300),
+        tx0(TransactionType::Dispute, 2, 2),
+        tx0(TransactionType::Chargeback, 2, 2),
+        tx(TransactionType::Deposit, 3, 3, 100),
+    ] {
+        tenants.apply_transaction(transaction).unwrap();
+    }
+    let expected = vec![
+        ExpectedBalance {
+            client: 1,
+            available: Some(dec(499)),
+            total: Some(dec(490)),
+            ..ExpectedBalance::default()
+        },
+        ExpectedBalance {
+            client: 2,
+            available: Some(Amount::ZERO),
+            locked: Some(false),
+            ..ExpectedBalance::default()
+        },
+        // no account at all
+        ExpectedBalance {
+            client: 4,
+            held: Some(dec(100)),
+            ..ExpectedBalance::default()
+        },
+    ];
+    let tolerance = Tolerance {
+        default: dec(1),
+        ..Tolerance::default()
+    };
+
+    let discrepancies = reconcile::reconcile(&tenants, expected.clone(), &tolerance, false);
+    let fields: Vec<(u16, &str, Option<Amount>)> = discrepancies
+        .iter()
+        .map(|discrepancy| {
+            (
+                discrepancy.client,
+                discrepancy.field,
+                discrepancy.difference,
+            )
+        })
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            (1, "total", Some(dec(10))),
+            (2, "locked", None),
+            // not expected, so compared with an account without funds
+            (3, "available", Some(dec(100))),
+            (4, "held", Some(-dec(100))),
+        ]
+    );
+    assert_eq!(
+        (
+            discrepancies[1].expected.as_str(),
+            discrepancies[1].actual.as_str()
+        ),
+        ("false", "true")
+    );
+
+    let strict = Tolerance::default();
+    assert_eq!(
+        reconcile::reconcile(&tenants, expected, &strict, true).len(),
+        4
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn verify_should_report_divergence_of_snapshot_and_trial_balance() {
+    let run = |transactions: &[Transaction]| {
+        let mut processor = Processor {
+            tenants: Tenants::new(Policy::default()),
+            ledger: Some(Ledger::new()),
+            settlement: None,
+            activity: None,
+            suspense: None,
+            audit: None,
+            trail: None,
+            rules: None,
+            severities: BTreeMap::new(),
+            quarantine: None,
+            warnings: Warnings::default(),
+            summary: Summary::default(),
+        };
+        for transaction in transactions {
+            processor.process(transaction).unwrap();
+        }
+        processor
+    };
+    let transactions = [
+        tx(TransactionType::Deposit, 1, 1, 500),
+        tx(TransactionType::Deposit, 2, 2, 300),
+        tx0(TransactionType::Dispute, 1, 1),
+    ];
+    let saved = run(&transactions);
+    let trial_balance = saved.ledger.as_ref().unwrap().trial_balance().unwrap();
+
+    let same = run(&transactions);
+    assert_eq!(
+        verify::verify_snapshot(&saved.tenants, &same.tenants),
+        Vec::new()
+    );
+    assert_eq!(
+        verify::verify_trial_balance(trial_balance.clone(), same.ledger.as_ref().unwrap()),
+        Ok(Vec::new())
+    );
+
+    // the dispute is missing from the log
+    let partial = run(&transactions[..2]);
+    let divergences = verify::verify_snapshot(&saved.tenants, &partial.tenants);
+    assert_matches!(divergences[0], Divergence::Fingerprint { .. });
+    let fields: Vec<(&str, Option<Amount>)> = divergences[1..]
+        .iter()
+        .map(|divergence| match divergence {
+            Divergence::Account(discrepancy) => (discrepancy.field, discrepancy.difference),
+            divergence => panic!("unexpected divergence {:?}", divergence),
+        })
+        .collect();
+    assert_eq!(
+        fields,
+        [("available", Some(dec(500))), ("held", Some(-dec(500)))]
+    );
+    assert!(divergences[2].to_string().starts_with("client 1 held: 5"));
+    let divergences =
+        verify::verify_trial_balance(trial_balance, partial.ledger.as_ref().unwrap()).unwrap();
+    assert_matches!(
+        divergences.as_slice(),
+        [
+            Divergence::TrialBalance {
+                account: LedgerAccount::ClientFunds,
+                side: "debit",
+                ..
+            },
+            Divergence::TrialBalance {
+                account: LedgerAccount::HeldFunds,
+                side: "credit",
+                ..
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn anonymize_should_remap_ids_consistently_keeping_outcomes() {
+    let input = "type,client,tx,amount,to\n\
+                 deposit,500,90,1.5,\n\
+                 transfer,500,95,1.25,42\n\
+                 withdrawal,42,7,2,\n\
+                 dispute,500,90,,\n\
+                 deposit,x,3,1,\n";
+    let records = |input: &str| -> (csv::StringRecord, Vec<csv::StringRecord>) {
+        let mut rdr = csv::Reader::from_reader(input.as_bytes());
+        let headers = rdr.headers().unwrap().clone();
+        (headers, rdr.records().map(Result::unwrap).collect())
+    };
+    let (headers, rows) = records(input);
+    let mut ids = Ids::new();
+    for row in &rows {
+        ids.collect(&headers, row);
+    }
+    let anonymizer = Anonymizer::new(&ids, Some(rust_decimal::Decimal::from(3)));
+    let anonymized: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            anonymizer
+                .anonymize(&headers, row)
+                .iter()
+                .map(str::to_string)
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        anonymized,
+        [
+            ["deposit", "2", "3", "4.5", ""],
+            ["transfer", "2", "4", "3.75", "1"],
+            ["withdrawal", "1", "2", "6", ""],
+            ["dispute", "2", "3", "", ""],
+            // invalid values are kept
+            ["deposit", "x", "1", "3", ""],
+        ]
+    );
+
+    let outcomes = |rows: Vec<csv::StringRecord>| -> Vec<bool> {
+        let mut state = State::new();
+        rows.iter()
+            .filter_map(|row| row.deserialize::<Transaction>(Some(&headers)).ok())
+            .map(|transaction| state.apply_transaction(&transaction).is_ok())
+            .collect()
+    };
+    let anonymized = anonymized
+        .iter()
+        .map(|row| csv::StringRecord::from(row.clone()))
+        .collect();
+    assert_eq!(outcomes(rows), outcomes(anonymized));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn extract_should_keep_records_the_clients_depend_on() {
+    let input = "type,client,tx,amount,to\n\
+                 deposit,1,1,5,\n\
+                 deposit,2,2,5,\n\
+                 deposit,3,3,5,\n\
+                 transfer,3,4,2,4\n\
+                 dispute,2,1,,\n\
+                 deposit,12,5,1,\n\
+                 transfer,5,6,1,12\n\
+                 deposit,5,7,3,\n\
+                 transfer,8,8,1,5\n\
+                 deposit,6,5,9,\n";
+    let mut rdr = csv::Reader::from_reader(input.as_bytes());
+    let headers = rdr.headers().unwrap().clone();
+    let records: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+    let ids: Vec<RecordIds> = records
+        .iter()
+        .map(|record| RecordIds::of(&headers, record))
+        .collect();
+
+    let selected = extract::select(&ids, &[12, 1]);
+    let txs: Vec<u32> = ids
+        .iter()
+        .zip(&selected)
+        .filter(|(_, &keep)| keep)
+        .map(|(ids, _)| ids.tx.unwrap())
+        .collect();
+    // the dispute of the deposit of client 1 by another client, the transfer to client 12 along
+    // with everything its sender depends on, and a deposit reusing the id of one of client 12
+    assert_eq!(txs, [1, 1, 5, 6, 7, 8, 5]);
+
+    let accounts = |keep: &[bool]| {
+        let mut state = State::new();
+        for (record, _) in records.iter().zip(keep).filter(|(_, &keep)| keep) {
+            let transaction: Transaction = record.deserialize(Some(&headers)).unwrap();
+            let _ = state.apply_transaction(&transaction);
+        }
+        [1, 12]
+            .iter()
+            .map(|client| state.account(*client).cloned())
+            .collect::<Vec<Option<Account>>>()
+    };
+    assert_eq!(accounts(&selected), accounts(&vec![true; records.len()]));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn normalize_should_rewrite_partner_file_in_canonical_format() {
+    let raw: Vec<u8> = "\u{feff} Type ;Client;TX;Amount;Timestamp\r\n\
+                        Deposit; 1 ;1;1,5;20\r\n\
+                        deposit;1;1;1.5;20\r\n\
+                        ;;;;\r\n\
+                        dispute;1;1;;30\r\n\
+                        dispute;1;1;;\r\n\
+                        withdrawal;2;2;0,25;10\r\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    let options = NormalizeOptions {
+        sort: true,
+        ..NormalizeOptions::default()
+    };
+    let mut output = Vec::new();
+    let fixes = normalize::normalize(&raw, &options, &mut output).unwrap();
+
+    // repeated disputes are kept, as only the first of them may be rejected
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "type,client,tx,amount,timestamp\n\
+         withdrawal,2,2,0.25,10\n\
+         deposit,1,1,1.5,20\n\
+         dispute,1,1,,30\n\
+         dispute,1,1,,\n"
+    );
+    assert_eq!(fixes.encoding, Some(Encoding::Utf16Le));
+    assert!(fixes.byte_order_mark && fixes.crlf_line_endings);
+    assert_eq!(fixes.delimiter, Some(b';'));
+    assert_eq!(fixes.renamed_columns.len(), 5);
+    assert_eq!(
+        (
+            fixes.trimmed_values,
+            fixes.lowercased_types,
+            fixes.decimal_commas
+        ),
+        (1, 1, 2)
+    );
+    assert_eq!((fixes.empty_rows, fixes.duplicates), (1, 1));
+    assert_eq!((fixes.reordered_rows, fixes.rows), (4, 4));
+
+    let mut output = Vec::new();
+    let canonical = "type,client,tx,amount\ndeposit,1,1,2\n";
+    let fixes = normalize::normalize(canonical.as_bytes(), &options, &mut output).unwrap();
+    assert_eq!(output, canonical.as_bytes());
+    assert!(fixes.is_empty());
+    assert_eq!(
+        fixes,
+        Fixes {
+            rows: 1,
+            ..Fixes::default()
+        }
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn split_should_keep_dependent_clients_in_one_shard() {
+    let mut input = String::from("type,client,tx,amount,to\n");
+    for client in 1..=40 {
+        input += &format!("deposit,{},{},5,\n", client, client);
+    }
+    input += "transfer,3,41,2,4\n\
+              transfer,4,42,6,5\n\
+              deposit,7,8,1,\n\
+              dispute,9,9,,\n\
+              withdrawal,10,43,1,\n\
+              bogus,x,y,,\n";
+    let mut rdr = csv::Reader::from_reader(input.as_bytes());
+    let headers = rdr.headers().unwrap().clone();
+    let records: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+    let ids: Vec<RecordIds> = records
+        .iter()
+        .map(|record| RecordIds::of(&headers, record))
+        .collect();
+
+    let shards = split::assign(&ids, 4);
+    let shard_of = |client: u16| shards[usize::from(client) - 1];
+    // linked by transfers, transitively, and by the reused transaction id
+    assert_eq!(shard_of(3), shard_of(4));
+    assert_eq!(shard_of(4), shard_of(5));
+    assert_eq!(shard_of(7), shard_of(8));
+    assert_eq!(
+        shards[40..],
+        [
+            shard_of(3),
+            shard_of(3),
+            shard_of(7),
+            shard_of(9),
+            shard_of(10),
+            0
+        ]
+    );
+    assert!((0..4).all(|shard| shards.contains(&shard)));
+
+    let accounts = |keep: &dyn Fn(usize) -> bool| {
+        let mut state = State::new();
+        for (_, record) in records.iter().enumerate().filter(|(index, _)| keep(*index)) {
+            if let Ok(transaction) = record.deserialize::<Transaction>(Some(&headers)) {
+                let _ = state.apply_transaction(&transaction);
+            }
+        }
+        (1..=40)
+            .filter_map(|client| Some((client, state.account(client)?.clone())))
+            .collect::<Vec<(u16, Account)>>()
+    };
+    let mut sharded: Vec<(u16, Account)> = (0..4)
+        .flat_map(|shard| accounts(&|index| shards[index] == shard))
+        .collect();
+    sharded.sort_by_key(|(client, _)| *client);
+    assert_eq!(sharded, accounts(&|_| true));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn merge_should_combine_shards_and_report_clients_in_several() {
+    let account = |tenant, client, currency: &str, available| ExportedClient {
+        tenant,
+        client,
+        currency: currency.parse().unwrap(),
+        available: dec(available),
+        held: Amount::ZERO,
+        total: Some(dec(available)),
+        locked: false,
+        flags: String::new(),
+    };
+    let shards = vec![
+        vec![account(None, 3, "", 100), account(None, 3, "EUR", 200)],
+        vec![account(None, 1, "", 300), account(Some(1), 3, "", 400)],
+        vec![account(None, 2, "", 500), account(None, 3, "", 600)],
+        vec![account(None, 3, "EUR", 700)],
+    ];
+
+    let (accounts, conflicts) = merge::merge(shards);
+    let merged: Vec<(Option<u32>, u16, Amount)> = accounts
+        .iter()
+        .map(|account| (account.tenant, account.client, account.available))
+        .collect();
+    // client 3 of the default tenant is kept from the first shard, in both currencies
+    assert_eq!(
+        merged,
+        [
+            (None, 1, dec(300)),
+            (None, 2, dec(500)),
+            (None, 3, dec(100)),
+            (None, 3, dec(200)),
+            (Some(1), 3, dec(400)),
+        ]
+    );
+    assert_eq!(
+        conflicts,
+        [Conflict {
+            tenant: None,
+            client: 3,
+            shards: vec![0, 2, 3],
+        }]
+    );
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn manifest_should_hash_files_read_and_written() {
+    use std::io::Write;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let read = FileDigest::read("input", None, &b"abc"[..]).unwrap();
+    assert_eq!((read.bytes, read.sha256.as_str()), (3, ABC_SHA256));
+
+    // hashed as written, in however many pieces
+    let mut wtr = DigestWriter::new(Vec::new());
+    wtr.write_all(b"a").unwrap();
+    wtr.write_all(b"bc").unwrap();
+    let written = wtr.digest("accounts");
+    assert_eq!(written.sha256, ABC_SHA256);
+    assert_eq!(written.bytes, 3);
+
+    assert_eq!(
+        manifest::redact("postgres://bob:secret@db:5432/ledger"),
+        "postgres://bob:***@db:5432/ledger"
+    );
+    assert_eq!(
+        manifest::redact("postgres:host=db password=secret dbname=ledger"),
+        "postgres:host=db password=*** dbname=ledger"
+    );
+    assert_eq!(
+        manifest::redact("sled:/var/lib/state"),
+        "sled:/var/lib/state"
+    );
+}
+
+#[cfg(feature = "templates")]
+#[test]
+fn template_should_render_fixed_width_accounts() {
+    let mut tenants = Tenants::new(Policy::default());
+    tenants
+        .apply_transaction(&tx(TransactionType::Deposit, 7, 1, 2050))
+        .unwrap();
+    let summary = Summary {
+        applied: 1,
+        ..Summary::default()
+    };
+    let context = TemplateContext {
+        run_id: "job-7",
+        accounts: export::accounts(&tenants).collect(),
+        summary: &summary,
+    };
+    let render = |source: &str| {
+        let mut output = Vec::new();
+        Template::parse(source)
+            .map_err(|err| err.to_string())?
+            .render(&context, &mut output)
+            .map_err(|err| err.to_string())?;
+        Ok::<_, String>(String::from_utf8(output).unwrap())
+    };
+
+    // padded, cut and not escaped
+    assert_eq!(
+        render(
+            "{{#each accounts}}{{pad_left client 4 fill=\"0\"}}{{pad_right (minor total 2) 6}}\n{{/each}}{{pad_right run_id 3}}<{{summary.applied}}>"
+        ),
+        Ok("00072050  \njob<1>".to_string())
+    );
+    assert_eq!(render("{{minor \"1.505\" 3}}"), Ok("1505".to_string()));
+    // amounts aren't rounded and fields must exist
+    assert!(render("{{minor \"1.505\" 2}}").is_err());
+    assert!(render("{{pad_left summary.unknown 3}}").is_err());
+    assert!(render("{{#each accounts}").is_err());
+}
+
+#[test]
+fn memory_usage_should_grow_with_accounts_and_history() {
+    let state = Scenario::new()
+        .deposit(1, 100)
+        .deposit(2, 50)
+        .withdraw(1, 30)
+        .dispute(2)
+        .into_state();
+    let usage = state.memory_usage();
+    assert_eq!(usage.accounts.entries, 2);
+    assert_eq!(usage.history.entries, 3);
+    assert!(usage.accounts.bytes >= 2 * std::mem::size_of::<Account>() as u64);
+    assert!(usage.history.bytes >= 3 * std::mem::size_of::<Transaction>() as u64);
+    assert!(usage.bytes() > State::new().memory_usage().bytes());
+
+    let mut tenants = Tenants::new(Policy::default());
+    for tenant in [None, Some(1)].iter() {
+        tenants
+            .state_mut(*tenant)
+            .unwrap()
+            .apply_transaction(&tx(TransactionType::Deposit, 1, 1, 10))
+            .unwrap();
+    }
+    let usage = tenants.memory_usage();
+    assert_eq!(usage.accounts.entries, 2);
+    assert_eq!(usage.history.entries, 2);
+}
+
+#[test]
+fn transition_matrix_should_follow_policy() {
+    let outcome = |policy: &Policy, tpe, target, leg, state| {
+        policy
+            .transition_matrix()
+            .into_iter()
+            .find(|rule| {
+                rule.tpe == tpe && rule.target == target && rule.leg == leg && rule.state == state
+            })
+            .map(|rule| rule.outcome)
+            .unwrap()
+    };
+    let rejected = |error: &str| TransitionOutcome::Rejected {
+        error: error.to_string(),
+    };
+
+    let policy = Policy::default();
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Dispute,
+            TransactionType::Deposit,
+            Leg::Credit,
+            TransactionState::Deposited
+        ),
+        TransitionOutcome::Allowed {
+            to: TransactionState::Disputed
+        }
+    );
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Dispute,
+            TransactionType::Withdrawal,
+            Leg::Debit,
+            TransactionState::Withdrawn
+        ),
+        rejected("TransactionInvalidState")
+    );
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Reversal,
+            TransactionType::Transfer,
+            Leg::Debit,
+            TransactionState::Withdrawn
+        ),
+        rejected("TransactionNotReversible")
+    );
+    // the matrix agrees with the engine
+    let (_, result) = run_transactions(vec![
+        tx(TransactionType::Deposit, 1, 1, 100),
+        tx(TransactionType::Withdrawal, 1, 2, 50),
+        tx0(TransactionType::Dispute, 1, 2),
+    ]);
+    assert_matches!(
+        result,
+        Err(CephalopodError::TransactionError {
+            error: TransactionError::TransactionInvalidState {
+                state: TransactionState::Withdrawn
+            },
+            ..
+        })
+    );
+
+    let policy = Policy {
+        allow_withdrawal_disputes: true,
+        ignore_repeated_outcomes: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Dispute,
+            TransactionType::Withdrawal,
+            Leg::Debit,
+            TransactionState::Withdrawn
+        ),
+        TransitionOutcome::Allowed {
+            to: TransactionState::Disputed
+        }
+    );
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Resolve,
+            TransactionType::Deposit,
+            Leg::Credit,
+            TransactionState::Resolved
+        ),
+        TransitionOutcome::Ignored
+    );
+
+    let policy: Policy = serde_json::from_str(
+        r#"{"transitions": {
+            "dispute": {"from": ["Deposited", "Resolved"], "targets": ["deposit"]},
+            "resolve": {"from": ["Disputed"], "targets": ["deposit"]},
+            "chargeback": {"from": ["Disputed"], "targets": ["deposit"]},
+            "representment": {"from": ["Chargebacked"], "targets": ["deposit"]},
+            "reversal": {"from": ["Deposited"], "targets": ["deposit"]}
+        }}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Dispute,
+            TransactionType::Deposit,
+            Leg::Credit,
+            TransactionState::Resolved
+        ),
+        TransitionOutcome::Allowed {
+            to: TransactionState::Disputed
+        }
+    );
+    assert_eq!(
+        outcome(
+            &policy,
+            TransactionType::Dispute,
+            TransactionType::Transfer,
+            Leg::TransferCredit,
+            TransactionState::Deposited
+        ),
+        rejected("TransitionNotAllowed")
+    );
+}
+
+type SpanFields = BTreeMap<&'static str, String>;
+
+// records fields of all spans, in order of creation
+#[derive(Clone, Default)]
+struct SpanRecorder(Arc<Mutex<Vec<(&'static str, SpanFields)>>>);
+
+struct FieldVisitor<'a>(&'a mut SpanFields);
+
+impl tracing::field::Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _: &tracing::Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+        let mut spans = self.0.lock().unwrap();
+        let mut fields = BTreeMap::new();
+        span.record(&mut FieldVisitor(&mut fields));
+        spans.push((span.metadata().name(), fields));
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record) {
+        let mut spans = self.0.lock().unwrap();
+        let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut FieldVisitor(fields));
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event) {}
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[test]
+fn transactions_should_be_applied_in_spans_with_outcome() {
+    let recorder = SpanRecorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let mut state = State::new();
+        state
+            .apply_transaction(&tx(TransactionType::Deposit, 1, 1, 100))
+            .unwrap();
+        assert!(state
+            .apply_transaction(&tx(TransactionType::Withdrawal, 2, 2, 50))
+            .is_err());
+    });
+    let spans = recorder.0.lock().unwrap();
+    let fields = |tx: &str, client: &str, tpe: &str, outcome: &str| {
+        (
+            "transaction",
+            [
+                ("tx", tx),
+                ("client", client),
+                ("type", tpe),
+                ("outcome", outcome),
+            ]
+            .iter()
+            .map(|&(name, value)| (name, value.to_string()))
+            .collect(),
+        )
+    };
+    assert_eq!(
+        *spans,
+        vec![
+            fields("1", "1", "Deposit", "applied"),
+            fields("2", "2", "Withdrawal", "UnknownAccount"),
+        ]
+    );
+}
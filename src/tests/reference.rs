@@ -0,0 +1,165 @@
+//! Deliberately simple engine following the default policy, to test `State` against
+//!
+//! Supports only deposits, withdrawals, disputes, resolves and chargebacks in
+//! the default currency. Every rule is checked in the most obvious way, with
+//! no regard to speed, so that a difference from `State` points at a bug in
+//! one of its paths (e.g. storage and caching) rather than in this code.
+
+use std::collections::BTreeMap;
+
+use crate::amount::Amount;
+use crate::model::{Transaction, TransactionType};
+
+/// Balances of an account, as written to the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReferenceAccount {
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    /// Withdrawals can't be disputed with the default policy
+    Withdrawn,
+    Deposited,
+    Disputed,
+    Resolved,
+    Chargebacked,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Recorded {
+    client: u16,
+    amount: Amount,
+    status: Status,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReferenceEngine {
+    pub accounts: BTreeMap<u16, ReferenceAccount>,
+    /// Deposits and withdrawals by id
+    recorded: BTreeMap<u32, Recorded>,
+}
+
+impl ReferenceEngine {
+    pub fn new() -> ReferenceEngine {
+        ReferenceEngine::default()
+    }
+
+    /// Applies the transaction, returning whether it has been applied
+    pub fn apply(&mut self, tx: &Transaction) -> bool {
+        match tx.tpe {
+            TransactionType::Deposit => self.deposit(tx),
+            TransactionType::Withdrawal => self.withdrawal(tx),
+            TransactionType::Dispute => self.dispute(tx),
+            TransactionType::Resolve => self.settle(tx, Status::Resolved),
+            TransactionType::Chargeback => self.settle(tx, Status::Chargebacked),
+            _ => panic!("unsupported transaction type {:?}", tx.tpe),
+        }
+    }
+
+    fn deposit(&mut self, tx: &Transaction) -> bool {
+        if self.recorded.contains_key(&tx.tx) {
+            return false;
+        }
+        // the account is opened even if the deposit is then rejected
+        let account = self.accounts.entry(tx.client).or_insert(ReferenceAccount {
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            locked: false,
+        });
+        let amount = match tx.amount {
+            Some(amount) if amount >= Amount::ZERO && !account.locked => amount,
+            _ => return false,
+        };
+        account.available = match account.available.checked_add(amount) {
+            Some(available) => available,
+            None => return false,
+        };
+        self.recorded.insert(
+            tx.tx,
+            Recorded {
+                client: tx.client,
+                amount,
+                status: Status::Deposited,
+            },
+        );
+        true
+    }
+
+    fn withdrawal(&mut self, tx: &Transaction) -> bool {
+        if self.recorded.contains_key(&tx.tx) {
+            return false;
+        }
+        let account = match self.accounts.get_mut(&tx.client) {
+            Some(account) => account,
+            None => return false,
+        };
+        let amount = match tx.amount {
+            Some(amount) => amount,
+            None => return false,
+        };
+        if account.locked || amount < Amount::ZERO || amount > account.available {
+            return false;
+        }
+        // can't overflow, as the amount isn't negative and at most the available funds
+        account.available = account.available.checked_sub(amount).unwrap();
+        self.recorded.insert(
+            tx.tx,
+            Recorded {
+                client: tx.client,
+                amount,
+                status: Status::Withdrawn,
+            },
+        );
+        true
+    }
+
+    fn dispute(&mut self, tx: &Transaction) -> bool {
+        let recorded = match self.recorded.get_mut(&tx.tx) {
+            Some(recorded) if recorded.client == tx.client => recorded,
+            _ => return false,
+        };
+        let account = self.accounts.get_mut(&tx.client).unwrap();
+        if recorded.status != Status::Deposited
+            || account.locked
+            || recorded.amount > account.available
+        {
+            return false;
+        }
+        let held = match account.held.checked_add(recorded.amount) {
+            Some(held) => held,
+            None => return false,
+        };
+        // can't overflow, as the amount is at most the available funds
+        account.available = account.available.checked_sub(recorded.amount).unwrap();
+        account.held = held;
+        recorded.status = Status::Disputed;
+        true
+    }
+
+    /// Resolves or charges back a disputed deposit
+    fn settle(&mut self, tx: &Transaction, outcome: Status) -> bool {
+        let recorded = match self.recorded.get_mut(&tx.tx) {
+            Some(recorded) if recorded.client == tx.client => recorded,
+            _ => return false,
+        };
+        let account = self.accounts.get_mut(&tx.client).unwrap();
+        if recorded.status != Status::Disputed || account.locked {
+            return false;
+        }
+        if outcome == Status::Resolved {
+            account.available = match account.available.checked_add(recorded.amount) {
+                Some(available) => available,
+                None => return false,
+            };
+        } else {
+            account.locked = true;
+        }
+        // can't overflow, as the held funds include the amount
+        account.held = account.held.checked_sub(recorded.amount).unwrap();
+        recorded.status = outcome;
+        true
+    }
+}
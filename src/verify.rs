@@ -0,0 +1,142 @@
+//! Integrity check of a saved snapshot against the state recomputed from the transactions,
+//! behind `cephalopod verify`
+//!
+//! The fingerprints of the two states are compared first, as they cover the
+//! accounts along with the states of transactions, e.g. open disputes. The
+//! balances are compared to tell which accounts diverged, and the trial
+//! balance written by the run, if any, to tell which ledger accounts did.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::export;
+use crate::fingerprint::Fingerprint;
+use crate::ledger::{Ledger, LedgerAccount, LedgerError, TrialBalanceRow};
+use crate::reconcile::{self, Discrepancy, ExpectedBalance, Tolerance};
+use crate::tenant::Tenants;
+
+/// Difference between the snapshot, or the trial balance, and the recomputed state
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    Fingerprint {
+        snapshot: Fingerprint,
+        recomputed: Fingerprint,
+    },
+    /// Field of an account, expected to be the one in the snapshot
+    Account(Discrepancy),
+    TrialBalance {
+        account: LedgerAccount,
+        currency: Currency,
+        /// `debit` or `credit`
+        side: &'static str,
+        logged: Amount,
+        recomputed: Amount,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::Fingerprint {
+                snapshot,
+                recomputed,
+            } => write!(
+                f,
+                "fingerprint: {} in the snapshot, {} recomputed",
+                snapshot, recomputed
+            ),
+            Divergence::Account(discrepancy) => {
+                if let Some(tenant) = discrepancy.tenant {
+                    write!(f, "tenant {} ", tenant)?;
+                }
+                write!(f, "client {}", discrepancy.client)?;
+                if !discrepancy.currency.is_default() {
+                    write!(f, " {}", discrepancy.currency)?;
+                }
+                write!(
+                    f,
+                    " {}: {} in the snapshot, {} recomputed",
+                    discrepancy.field, discrepancy.expected, discrepancy.actual
+                )
+            }
+            Divergence::TrialBalance {
+                account,
+                currency,
+                side,
+                logged,
+                recomputed,
+            } => {
+                write!(f, "trial balance {}", account)?;
+                if !currency.is_default() {
+                    write!(f, " {}", currency)?;
+                }
+                write!(f, " {}: {} logged, {} recomputed", side, logged, recomputed)
+            }
+        }
+    }
+}
+
+/// Compares the snapshot with the recomputed state, returning nothing if they're the same
+pub fn verify_snapshot(snapshot: &Tenants, recomputed: &Tenants) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    let (expected, actual) = (snapshot.fingerprint(), recomputed.fingerprint());
+    if expected != actual {
+        divergences.push(Divergence::Fingerprint {
+            snapshot: expected,
+            recomputed: actual,
+        });
+    }
+    let expected = export::accounts(snapshot).map(|account| ExpectedBalance {
+        tenant: account.tenant,
+        client: account.client,
+        currency: account.currency,
+        available: Some(account.available),
+        held: Some(account.held),
+        total: None,
+        locked: Some(account.locked),
+    });
+    divergences.extend(
+        reconcile::reconcile(recomputed, expected, &Tolerance::default(), false)
+            .into_iter()
+            .map(Divergence::Account),
+    );
+    divergences
+}
+
+/// Compares the trial balance written by a run with the recomputed ledger
+///
+/// Fails if the recomputed ledger has no trial balance, as its totals overflowed.
+pub fn verify_trial_balance(
+    logged: impl IntoIterator<Item = TrialBalanceRow>,
+    recomputed: &Ledger,
+) -> Result<Vec<Divergence>, LedgerError> {
+    let mut totals: BTreeMap<(LedgerAccount, Currency), [(Amount, Amount); 2]> = BTreeMap::new();
+    for row in logged {
+        totals.entry((row.account, row.currency)).or_default()[0] = (row.debit, row.credit);
+    }
+    for row in recomputed.trial_balance()? {
+        totals.entry((row.account, row.currency)).or_default()[1] = (row.debit, row.credit);
+    }
+    let mut divergences = Vec::new();
+    for ((account, currency), [logged, recomputed]) in totals {
+        for &(side, logged, recomputed) in [
+            ("debit", logged.0, recomputed.0),
+            ("credit", logged.1, recomputed.1),
+        ]
+        .iter()
+        {
+            if logged != recomputed {
+                divergences.push(Divergence::TrialBalance {
+                    account,
+                    currency,
+                    side,
+                    logged,
+                    recomputed,
+                });
+            }
+        }
+    }
+    Ok(divergences)
+}
@@ -0,0 +1,163 @@
+//! Rate limiting and deduplication of warnings about rejected transactions and invalid input
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Number of repeats of a warning after which they're reported, while it keeps repeating
+pub const DEFAULT_DEDUP_FLUSH: u64 = 1000;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[error("warning budget of {budget} exceeded")]
+pub struct BudgetExceeded {
+    pub budget: u64,
+}
+
+/// Repeats of the latest logged warning that haven't been logged themselves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repeated {
+    pub kind: String,
+    pub client: Option<u16>,
+    pub times: u64,
+}
+
+/// Whether a warning should be logged, and the repeats of the previous one to report before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Admission {
+    pub log: bool,
+    pub repeated: Option<Repeated>,
+}
+
+/// Counts warnings by kind (e.g. `NotEnoughFunds`), deciding which of them are logged
+///
+/// The first `limit` warnings of each kind are logged, then only every
+/// `limit`-th one, so that a corrupt input doesn't flood the log.
+///
+/// With deduplication, warnings of the same kind about the same client as
+/// the latest logged one aren't logged nor counted by kind. Their number is
+/// reported once a different warning arrives, every `flush` repeats and at
+/// the end, so that a single mis-keyed client takes a few lines of the log.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Warnings {
+    /// Number of warnings of each kind logged before sampling them, unlimited if `None`
+    limit: Option<u64>,
+    /// Number of warnings after which processing should be aborted, unlimited if `None`
+    budget: Option<u64>,
+    counts: BTreeMap<String, u64>,
+    total: u64,
+    /// Number of repeats after which they're reported, if repeats are deduplicated
+    dedup: Option<u64>,
+    /// Kind and client of the latest logged warning, while deduplicating
+    last: Option<(String, Option<u16>)>,
+    /// Repeats of the latest logged warning not reported yet
+    repeats: u64,
+}
+
+impl Warnings {
+    pub fn new(limit: Option<u64>, budget: Option<u64>) -> Warnings {
+        Warnings {
+            limit,
+            budget,
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the limit and budget, keeping the counts, e.g. when resuming from a checkpoint
+    pub fn set_limits(&mut self, limit: Option<u64>, budget: Option<u64>) {
+        self.limit = limit;
+        self.budget = budget;
+    }
+
+    /// Deduplicates repeats of warnings, reporting them every `flush` repeats, or stops if `None`
+    pub fn set_dedup(&mut self, flush: Option<u64>) {
+        self.dedup = flush;
+        if flush.is_none() {
+            self.last = None;
+        }
+    }
+
+    /// Counts a warning of the kind, returning whether it should be logged
+    ///
+    /// Repeats of the previous warning are only reported by `admit_from`.
+    pub fn admit(&mut self, kind: &str) -> Result<bool, BudgetExceeded> {
+        self.admit_from(kind, None).map(|admission| admission.log)
+    }
+
+    /// Counts a warning of the kind about the client, if any, deduplicating repeats if enabled
+    pub fn admit_from(
+        &mut self,
+        kind: &str,
+        client: Option<u16>,
+    ) -> Result<Admission, BudgetExceeded> {
+        self.total += 1;
+        if let Some(budget) = self.budget {
+            if self.total > budget {
+                return Err(BudgetExceeded { budget });
+            }
+        }
+        if let (Some(flush), Some((last_kind, last_client))) = (self.dedup, &self.last) {
+            if last_kind == kind && *last_client == client {
+                self.repeats += 1;
+                let repeated = if self.repeats >= flush {
+                    self.flush()
+                } else {
+                    None
+                };
+                return Ok(Admission {
+                    log: false,
+                    repeated,
+                });
+            }
+        }
+        let repeated = self.flush();
+        let count = match self.counts.get_mut(kind) {
+            Some(count) => count,
+            None => self.counts.entry(kind.to_string()).or_insert(0),
+        };
+        *count += 1;
+        let log = match self.limit {
+            Some(limit) => *count <= limit || count.is_multiple_of(limit),
+            None => true,
+        };
+        // repeats of a warning that hasn't been logged are sampled like the rest
+        self.last = match self.dedup {
+            Some(_) if log => Some((kind.to_string(), client)),
+            _ => None,
+        };
+        Ok(Admission { log, repeated })
+    }
+
+    /// Takes the repeats of the latest logged warning not reported yet, if any
+    pub fn flush(&mut self) -> Option<Repeated> {
+        if self.repeats == 0 {
+            return None;
+        }
+        let (kind, client) = self.last.clone()?;
+        let times = self.repeats;
+        self.repeats = 0;
+        Some(Repeated {
+            kind,
+            client,
+            times,
+        })
+    }
+
+    /// Total number of warnings
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Kinds of warnings with the number of them that haven't been logged, if any
+    pub fn suppressed(&self) -> Vec<(&str, u64)> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Vec::new(),
+        };
+        self.counts
+            .iter()
+            .filter(|(_, &count)| count > limit)
+            .map(|(kind, &count)| (kind.as_str(), count - limit - (count / limit - 1)))
+            .collect()
+    }
+}
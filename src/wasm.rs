@@ -0,0 +1,64 @@
+//! JavaScript bindings of the engine, built with the `wasm` feature for `wasm32-unknown-unknown`
+//!
+//! Transactions are passed as JSON objects with the same fields as rows of
+//! the input (`type`, `client`, `tx`, `amount`, ...), with amounts given as
+//! strings to keep them exact. Accounts are returned as JSON too, see
+//! `json::accounts`.
+
+use wasm_bindgen::prelude::*;
+
+use crate::json;
+use crate::model::{CephalopodError, Transaction};
+use crate::policy::Policy;
+use crate::tenant::Tenants;
+
+/// States of all tenants, applying transactions one by one
+#[wasm_bindgen]
+pub struct Engine {
+    tenants: Tenants,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Engine {
+    /// Creates an engine following the default policy
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Engine {
+        Engine {
+            tenants: Tenants::new(Policy::default()),
+        }
+    }
+
+    /// Creates an engine following the policy given as JSON, with the fields of `Policy`
+    #[wasm_bindgen(js_name = withPolicy)]
+    pub fn with_policy(policy: &str) -> Result<Engine, JsError> {
+        let policy: Policy = serde_json::from_str(policy)?;
+        Ok(Engine {
+            tenants: Tenants::new(policy),
+        })
+    }
+
+    /// Applies the transaction given as JSON, throws if it's invalid or has been rejected
+    #[wasm_bindgen(js_name = applyTransaction)]
+    pub fn apply_transaction(&mut self, transaction: &str) -> Result<(), JsError> {
+        let transaction: Transaction = serde_json::from_str(transaction)?;
+        self.tenants
+            .apply_transaction(&transaction)
+            .map_err(|err| match err {
+                CephalopodError::TransactionError { error, .. } => JsError::new(&error.to_string()),
+                CephalopodError::IntegrityError { error, .. } => {
+                    JsError::new(&format!("integrity error: {}", error))
+                }
+            })
+    }
+
+    /// Returns balances of all accounts as a JSON array, see `json::accounts`
+    pub fn accounts(&self) -> String {
+        json::accounts(&self.tenants)
+    }
+}
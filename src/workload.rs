@@ -0,0 +1,295 @@
+//! Deterministic synthetic workloads, shared by tests, benchmarks and fuzzing
+//!
+//! A workload mimics real traffic with the default policy: deposits and
+//! withdrawals of a set of clients, some of the deposits disputed and later
+//! resolved or charged back, with a configurable share of invalid
+//! transactions mixed in. The same configuration, including the seed, yields
+//! the same transactions on every platform.
+//!
+//! ```
+//! use cephalopod::model::State;
+//! use cephalopod::workload::{Workload, WorkloadConfig};
+//!
+//! let config = WorkloadConfig {
+//!     seed: 42,
+//!     ..Default::default()
+//! };
+//! let mut state = State::new();
+//! for transaction in Workload::new(config).take(1000) {
+//!     state.apply_transaction(&transaction).unwrap();
+//! }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::model::{Transaction, TransactionType};
+use crate::tag::Tag;
+
+/// Share of settled disputes that end with a chargeback, locking the account
+const CHARGEBACK_RATE: f64 = 0.25;
+
+/// Largest amount of a deposit, in cents
+const MAX_DEPOSIT: u64 = 100_000;
+
+/// Parameters of a workload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkloadConfig {
+    /// Number of clients, with ids from 1, at least one
+    pub clients: u16,
+    /// Probability that a deposit is disputed later on
+    pub dispute_probability: f64,
+    /// Share of transactions that are invalid, i.e. rejected by the engine
+    ///
+    /// These are withdrawals exceeding the available funds, disputes of
+    /// unknown transactions, deposits with a duplicate id, a negative amount
+    /// or no amount at all.
+    pub invalid_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        WorkloadConfig {
+            clients: 100,
+            dispute_probability: 0.05,
+            invalid_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// SplitMix64, which unlike the generators of `rand` is guaranteed to stay the same
+#[derive(Debug, Clone)]
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed number below `bound`, which must not be zero
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// Whether an event of given probability happens
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// Deposit the workload has made, along with its amount in cents
+#[derive(Debug, Clone, Copy)]
+struct Deposit {
+    client: u16,
+    tx: u32,
+    amount: u64,
+}
+
+/// Infinite iterator over the transactions of a workload
+///
+/// Keeps track of the balances the engine should end up with, so that all
+/// transactions except the intended invalid ones are accepted, as long as
+/// some clients remain unlocked.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    config: WorkloadConfig,
+    random: Random,
+    next_tx: u32,
+    /// Available funds of each client in cents, by client id minus one
+    available: Vec<u64>,
+    locked: Vec<bool>,
+    /// Deposits chosen to be disputed, in order of arrival
+    to_dispute: VecDeque<Deposit>,
+    disputed: Vec<Deposit>,
+    /// Id of the latest deposit or withdrawal, which a duplicate can reuse
+    last_recorded: Option<u32>,
+    invalid: u64,
+}
+
+impl Workload {
+    pub fn new(config: WorkloadConfig) -> Workload {
+        assert!(config.clients > 0, "workload needs at least one client");
+        let clients = config.clients as usize;
+        Workload {
+            config,
+            random: Random(config.seed),
+            next_tx: 1,
+            available: vec![0; clients],
+            locked: vec![false; clients],
+            to_dispute: VecDeque::new(),
+            disputed: Vec::new(),
+            last_recorded: None,
+            invalid: 0,
+        }
+    }
+
+    pub fn config(&self) -> &WorkloadConfig {
+        &self.config
+    }
+
+    /// Number of invalid transactions generated so far
+    pub fn invalid(&self) -> u64 {
+        self.invalid
+    }
+
+    fn new_tx(&mut self) -> u32 {
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        tx
+    }
+
+    /// Random unlocked client, or any client if all are locked
+    fn client(&mut self) -> u16 {
+        let unlocked: Vec<usize> = (0..self.locked.len())
+            .filter(|&index| !self.locked[index])
+            .collect();
+        let index = if unlocked.is_empty() {
+            self.random.below(self.locked.len() as u64) as usize
+        } else {
+            unlocked[self.random.below(unlocked.len() as u64) as usize]
+        };
+        index as u16 + 1
+    }
+
+    fn deposit(&mut self) -> Transaction {
+        let client = self.client();
+        let amount = 1 + self.random.below(MAX_DEPOSIT);
+        let tx = self.new_tx();
+        self.last_recorded = Some(tx);
+        self.available[client as usize - 1] += amount;
+        if self.random.chance(self.config.dispute_probability) {
+            self.to_dispute.push_back(Deposit { client, tx, amount });
+        }
+        transaction(TransactionType::Deposit, client, tx, Some(amount as i64))
+    }
+
+    /// Withdrawal of a part of the funds of a random client, `None` if the client has none
+    fn withdrawal(&mut self) -> Option<Transaction> {
+        let client = self.client();
+        let index = client as usize - 1;
+        if self.available[index] == 0 || self.locked[index] {
+            return None;
+        }
+        let amount = 1 + self.random.below(self.available[index]);
+        self.available[index] -= amount;
+        let tx = self.new_tx();
+        self.last_recorded = Some(tx);
+        Some(transaction(
+            TransactionType::Withdrawal,
+            client,
+            tx,
+            Some(amount as i64),
+        ))
+    }
+
+    /// Dispute of the oldest deposit chosen to be disputed, skipping those whose funds have been withdrawn since
+    fn dispute(&mut self) -> Option<Transaction> {
+        while let Some(deposit) = self.to_dispute.pop_front() {
+            let index = deposit.client as usize - 1;
+            if self.locked[index] || self.available[index] < deposit.amount {
+                continue;
+            }
+            self.available[index] -= deposit.amount;
+            self.disputed.push(deposit);
+            return Some(transaction(
+                TransactionType::Dispute,
+                deposit.client,
+                deposit.tx,
+                None,
+            ));
+        }
+        None
+    }
+
+    /// Resolve or chargeback of a random open dispute, if any
+    fn settlement(&mut self) -> Option<Transaction> {
+        if self.disputed.is_empty() {
+            return None;
+        }
+        let position = self.random.below(self.disputed.len() as u64) as usize;
+        let deposit = self.disputed.swap_remove(position);
+        let index = deposit.client as usize - 1;
+        if self.locked[index] {
+            // the account has been locked by another chargeback meanwhile
+            return None;
+        }
+        let tpe = if self.random.chance(CHARGEBACK_RATE) {
+            self.locked[index] = true;
+            TransactionType::Chargeback
+        } else {
+            self.available[index] += deposit.amount;
+            TransactionType::Resolve
+        };
+        Some(transaction(tpe, deposit.client, deposit.tx, None))
+    }
+
+    fn invalid_transaction(&mut self) -> Transaction {
+        self.invalid += 1;
+        let client = self.client();
+        match self.random.below(5) {
+            0 => {
+                let tx = self.new_tx();
+                let amount = self.available[client as usize - 1] + 1 + self.random.below(1000);
+                transaction(TransactionType::Withdrawal, client, tx, Some(amount as i64))
+            }
+            // ids are assigned sequentially, so these are never used
+            1 => {
+                let tx = u32::MAX - self.random.below(1000) as u32;
+                transaction(TransactionType::Dispute, client, tx, None)
+            }
+            2 if self.last_recorded.is_some() => {
+                let tx = self.last_recorded.unwrap();
+                let amount = 1 + self.random.below(MAX_DEPOSIT);
+                transaction(TransactionType::Deposit, client, tx, Some(amount as i64))
+            }
+            3 => {
+                let tx = self.new_tx();
+                let amount = 1 + self.random.below(MAX_DEPOSIT);
+                transaction(TransactionType::Deposit, client, tx, Some(-(amount as i64)))
+            }
+            _ => {
+                let tx = self.new_tx();
+                transaction(TransactionType::Deposit, client, tx, None)
+            }
+        }
+    }
+}
+
+impl Iterator for Workload {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if self.random.chance(self.config.invalid_rate) {
+            return Some(self.invalid_transaction());
+        }
+        let transaction = match self.random.below(10) {
+            0 => self.dispute(),
+            1 => self.settlement(),
+            2..=4 => self.withdrawal(),
+            _ => None,
+        };
+        Some(transaction.unwrap_or_else(|| self.deposit()))
+    }
+}
+
+fn transaction(tpe: TransactionType, client: u16, tx: u32, cents: Option<i64>) -> Transaction {
+    Transaction {
+        tpe,
+        client,
+        tx,
+        amount: cents.map(|cents| Amount::new(cents, 2)),
+        to: None,
+        reason: None,
+        currency: Currency::default(),
+        timestamp: None,
+        tenant: None,
+        tag: Tag::default(),
+    }
+}